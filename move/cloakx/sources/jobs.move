@@ -250,6 +250,9 @@ public fun complete_job<E: drop>(
     encl: &Enclave<E>,
     timestamp_ms: u64,
     response: MLTrainingResponse,
+    nonce: vector<u8>,
+    counter: u64,
+    reference_ms: u64,
     signature: &vector<u8>,
     job_id: u64,
     ctx: &mut TxContext,
@@ -270,6 +273,9 @@ public fun complete_job<E: drop>(
         PROCESS_DATA_INTENT,
         timestamp_ms,
         response,
+        nonce,
+        counter,
+        reference_ms,
         signature,
     );
     assert!(ok, EInvalidSignature);
@@ -423,3 +429,15 @@ public fun get_jobs_by_status(reg: &JobRegistry, status: u64): &vector<u64> {
 public fun get_job_result(reg: &JobRegistry, job_id: u64): &vector<u8> {
     table::borrow(&reg.job_results, job_id)
 }
+
+public fun job_exists(reg: &JobRegistry, job_id: u64): bool {
+    table::contains(&reg.jobs, job_id)
+}
+
+public fun job_pool_id(job: &Job): u64 {
+    job.pool_id
+}
+
+public fun is_job_pending(job: &Job): bool {
+    job.status == JobStatus::Pending
+}
@@ -0,0 +1,39 @@
+module cloakx::seal_policy;
+
+use cloakx::jobs::{JOBS, JobRegistry, job_exists, get_job, job_pool_id, is_job_pending};
+use cloakx::pools::{PoolRegistry, is_pool_active};
+use enclave::enclave::Enclave;
+use sui::bcs;
+use sui::hash::blake2b256;
+
+const ENoAccess: u64 = 0;
+
+// Seal key servers dry run this to decide who gets the key shares of
+// `cloakx`-namespaced ids. Contributors seal to `pool_id (u64 LE) || nonce`.
+// Only the registered CloakX enclave, signing with its ephemeral key, passes,
+// and only for the pool of a pending job, while the pool is active.
+entry fun seal_approve(
+    id: vector<u8>,
+    enclave: &Enclave<JOBS>,
+    jobs: &JobRegistry,
+    job_id: u64,
+    pools: &PoolRegistry,
+    ctx: &TxContext,
+) {
+    assert!(ctx.sender().to_bytes() == pk_to_address(enclave.pk()), ENoAccess);
+    assert!(id.length() >= 8, ENoAccess);
+    assert!(job_exists(jobs, job_id), ENoAccess);
+    let job = get_job(jobs, job_id);
+    assert!(is_job_pending(job), ENoAccess);
+    let mut prefix = bcs::new(id);
+    let pool_id = prefix.peel_u64();
+    assert!(pool_id == job_pool_id(job), ENoAccess);
+    assert!(is_pool_active(pools, pool_id), ENoAccess);
+}
+
+// Address of an ed25519 key: blake2b256(flag 0x00 || pk).
+fun pk_to_address(pk: &vector<u8>): vector<u8> {
+    let mut arr = vector[0u8];
+    arr.append(*pk);
+    blake2b256(&arr)
+}
@@ -50,10 +50,17 @@ public struct Cap<phantom T> has key, store {
 }
 
 // An intent message, used for wrapping enclave messages.
+// - `nonce` echoes the request's nonce, empty when it had none.
+// - `counter` numbers the responses signed since the enclave booted.
+// - `reference_ms` is the timestamp of the Sui checkpoint `timestamp_ms`
+//   was checked against, 0 when the enclave clock had none.
 public struct IntentMessage<T: drop> has copy, drop {
     intent: u8,
     timestamp_ms: u64,
     payload: T,
+    nonce: vector<u8>,
+    counter: u64,
+    reference_ms: u64,
 }
 
 /// Create a new `Cap` using a `witness` T from a module.
@@ -104,9 +111,19 @@ public fun verify_signature<T, P: drop>(
     intent_scope: u8,
     timestamp_ms: u64,
     payload: P,
+    nonce: vector<u8>,
+    counter: u64,
+    reference_ms: u64,
     signature: &vector<u8>,
 ): bool {
-    let intent_message = create_intent_message(intent_scope, timestamp_ms, payload);
+    let intent_message = create_intent_message(
+        intent_scope,
+        timestamp_ms,
+        payload,
+        nonce,
+        counter,
+        reference_ms,
+    );
     let payload = bcs::to_bytes(&intent_message);
     return ed25519::ed25519_verify(signature, &enclave.pk, &payload)
 }
@@ -171,11 +188,21 @@ fun to_pcrs(document: &NitroAttestationDocument): Pcrs {
     Pcrs(*pcrs[0].value(), *pcrs[1].value(), *pcrs[2].value())
 }
 
-fun create_intent_message<P: drop>(intent: u8, timestamp_ms: u64, payload: P): IntentMessage<P> {
+fun create_intent_message<P: drop>(
+    intent: u8,
+    timestamp_ms: u64,
+    payload: P,
+    nonce: vector<u8>,
+    counter: u64,
+    reference_ms: u64,
+): IntentMessage<P> {
     IntentMessage {
         intent,
         timestamp_ms,
         payload,
+        nonce,
+        counter,
+        reference_ms,
     }
 }
 
@@ -193,7 +220,7 @@ public struct SigningPayload has copy, drop {
 
 #[test]
 fun test_serde() {
-    // serialization should be consistent with rust test see `fn test_serde` in `src/nautilus-server/app.rs`.
+    // serialization should be consistent with rust test see `fn test_serde` in `src/nautilus-server/src/common.rs`.
     let scope = 0;
     let timestamp = 1744038900000;
     let signing_payload = create_intent_message(
@@ -203,7 +230,13 @@ fun test_serde() {
             location: b"San Francisco".to_string(),
             temperature: 13,
         },
+        x"8f2b",
+        42,
+        1744038890000,
     );
     let bytes = bcs::to_bytes(&signing_payload);
-    assert!(bytes == x"0020b1d110960100000d53616e204672616e636973636f0d00000000000000", 0);
+    assert!(
+        bytes == x"0020b1d110960100000d53616e204672616e636973636f0d00000000000000028f2b2a00000000000000108ad11096010000",
+        0,
+    );
 }
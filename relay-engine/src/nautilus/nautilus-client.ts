@@ -1,9 +1,12 @@
 import axios from 'axios';
 
 export interface MLTrainingRequest {
+  // On-chain job id, the enclave checks the request against the Job and its pool.
+  job_id: number;
   data_blob_ids: string[];
   model_config_blob_id: string;
   key_id: string;
+  // On-chain Job.learning_rate, fixed point: the enclave uses learning_rate / 10000.
   learning_rate: number;
   epochs: number;
 }
@@ -18,8 +21,13 @@ export interface MLTrainingResponse {
 
 export interface ProcessedDataResponse {
   response: {
-    payload: MLTrainingResponse;
+    intent: number;
     timestamp_ms: number;
+    data: MLTrainingResponse;
+    // Signed after `data`, complete_job passes them to verify_signature.
+    nonce: number[];
+    counter: number;
+    reference_ms: number;
   };
   signature: string;
 }
@@ -30,7 +38,7 @@ export async function callNautilusTraining(
   const nautilusUrl = process.env['NAUTILUS_URL'] || 'http://localhost:3000';
 
   const { data } = await axios.post(
-    `${nautilusUrl}/process_data`,
+    `${nautilusUrl}/mltraining/process_data`,
     { payload },
     { headers: { 'Content-Type': 'application/json' } }
   );
@@ -43,6 +43,7 @@ export async function triggerTrainingPipeline(job: Job) {
     }
 
     const request = {
+      job_id: Number(job.id),
       data_blob_ids: dataBlobIds,
       model_config_blob_id: modelConfigBlobId,
       key_id: job.creator,
@@ -56,8 +57,11 @@ export async function triggerTrainingPipeline(job: Job) {
     console.log(`✅ Training completed, submitting results to blockchain`);
     await submitCompleteJob({
       jobId: job.id,
-      response: nautilusResponse.response.payload,
+      response: nautilusResponse.response.data,
       timestampMs: nautilusResponse.response.timestamp_ms,
+      nonce: nautilusResponse.response.nonce,
+      counter: nautilusResponse.response.counter,
+      referenceMs: nautilusResponse.response.reference_ms,
       signature: nautilusResponse.signature,
     });
 
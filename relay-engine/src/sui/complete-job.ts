@@ -17,11 +17,17 @@ export async function submitCompleteJob({
   jobId,
   response,
   timestampMs,
+  nonce,
+  counter,
+  referenceMs,
   signature,
 }: {
   jobId: bigint;
   response: MLTrainingResponse;
   timestampMs: number;
+  nonce: number[];
+  counter: number;
+  referenceMs: number;
   signature: string;
 }) {
   const { client, keypair } = getAdminClient();
@@ -56,6 +62,9 @@ export async function submitCompleteJob({
       tx.object(CONFIG.addresses.enclavePackage),
       tx.pure.u64(timestampMs),
       tx.pure(responseBcsBytes), // BCS bytes for the struct
+      tx.pure.vector("u8", nonce), // signed after the struct, see enclave::IntentMessage
+      tx.pure.u64(counter),
+      tx.pure.u64(referenceMs),
       tx.pure.vector("u8", Array.from(signatureBytes)), // vector<u8> for signature
       tx.pure.u64(jobId.toString()),
     ],
@@ -0,0 +1,38 @@
+#!/bin/sh
+# Copyright (c), Mysten Labs, Inc.
+# SPDX-License-Identifier: Apache-2.0
+
+# - Starts a coordinator and WORKERS worker servers as local processes, for
+#   trying /mltraining/federated/train without enclaves
+# - Debug build with LOCAL_ATTESTATION set: the servers serve and accept
+#   unsigned local attestation documents
+# - The coordinator listens on port 3000, workers on 3001, 3002, ...
+# - MODEL_SEALING_KEY and POOL_DATA_KEY are taken from the environment, and
+#   SEAL_CONFIG names a key server config with public keys filled in
+# - Ctrl-C stops all servers
+
+set -e
+
+WORKERS=${WORKERS:-2}
+DIR="$( cd "$( dirname "$0" )" && pwd )"
+SERVER="${DIR}/../src/nautilus-server"
+
+: "${MODEL_SEALING_KEY:?MODEL_SEALING_KEY must be set}"
+: "${POOL_DATA_KEY:?POOL_DATA_KEY must be set}"
+: "${SEAL_CONFIG:?SEAL_CONFIG must name a seal config with key server public keys}"
+
+cd "$SERVER"
+cargo build --features mltraining
+export LOCAL_ATTESTATION=1
+
+trap 'kill 0' INT TERM EXIT
+URLS=""
+for i in $(seq 0 "$WORKERS"); do
+    PORT=$((3000 + i)) ./target/debug/nautilus-server &
+    if [ "$i" -gt 0 ]; then
+        URLS="${URLS:+$URLS, }\"http://127.0.0.1:$((3000 + i))\""
+    fi
+done
+
+echo "Coordinator on http://127.0.0.1:3000, add to the request: \"workers\": [$URLS]"
+wait
@@ -24,6 +24,11 @@ busybox ip link set dev lo up
 echo "127.0.0.1   localhost" > /etc/hosts
 echo "127.0.0.64   aggregator.walrus-testnet.walrus.space" >> /etc/hosts
 echo "127.0.0.65   publisher.walrus-testnet.walrus.space" >> /etc/hosts
+echo "127.0.0.66   wal-aggregator-testnet.staketab.org" >> /etc/hosts
+echo "127.0.0.67   walrus-testnet-aggregator.nodes.guru" >> /etc/hosts
+echo "127.0.0.68   seal-key-server-testnet-1.mystenlabs.com" >> /etc/hosts
+echo "127.0.0.69   seal-key-server-testnet-2.mystenlabs.com" >> /etc/hosts
+echo "127.0.0.70   fullnode.testnet.sui.io" >> /etc/hosts
 
 
 
@@ -47,6 +52,11 @@ echo "$JSON_RESPONSE" | jq -r 'to_entries[] | "\(.key)=\(.value)"' > /tmp/kvpair
 # Traffic-forwarder-block
 python3 /traffic_forwarder.py 127.0.0.64 443 3 8101 &
 python3 /traffic_forwarder.py 127.0.0.65 443 3 8102 &
+python3 /traffic_forwarder.py 127.0.0.66 443 3 8103 &
+python3 /traffic_forwarder.py 127.0.0.67 443 3 8104 &
+python3 /traffic_forwarder.py 127.0.0.68 443 3 8105 &
+python3 /traffic_forwarder.py 127.0.0.69 443 3 8106 &
+python3 /traffic_forwarder.py 127.0.0.70 443 3 8107 &
 
 
 
@@ -0,0 +1,337 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::apps::mltraining::chain::{RpcReader, SuiReader};
+use crate::apps::mltraining::crypto::DATA_KEY_SECRET;
+use crate::apps::mltraining::policy::{CohortReport, PolicyCheck};
+use crate::apps::mltraining::pool::{check_cohort, load_rows, verify_pool, PoolRows};
+use crate::apps::mltraining::quality::{BlobReport, QualityChecker, QualityConfig};
+use crate::apps::mltraining::seal::{EnclaveObject, SealedJob};
+use crate::apps::mltraining::usage;
+use crate::common::{
+    f32_bits, to_signed_response, IntentMessage, IntentScope, ProcessDataRequest,
+    ProcessedDataResponse,
+};
+use crate::registry::{parse_allowed_endpoints, EnclaveApp};
+use crate::AppState;
+use crate::EnclaveError;
+use axum::{extract::State, routing::post, Json, Router};
+use fastcrypto::hash::{HashFunction, Sha256};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+mod noise;
+mod query;
+mod sql;
+
+use noise::NoiseConfig;
+use query::{ColumnSchema, GroupResult, Query};
+use sql::{ResultSet, SqlQuery};
+
+/// Private aggregate statistics over pooled contributor data.
+pub struct AnalyticsApp;
+
+impl EnclaveApp for AnalyticsApp {
+    fn name(&self) -> &'static str {
+        "analytics"
+    }
+
+    fn routes(&self) -> Router<Arc<AppState>> {
+        Router::new()
+            .route("/query", post(run_query))
+            .route("/sql", post(run_sql))
+    }
+
+    fn intent_scopes(&self) -> &'static [IntentScope] {
+        &[
+            IntentScope::AnalyticsResult,
+            IntentScope::AnalyticsRejected,
+            IntentScope::AnalyticsSqlResult,
+        ]
+    }
+
+    fn required_secrets(&self) -> &'static [&'static str] {
+        &[DATA_KEY_SECRET]
+    }
+
+    fn allowed_endpoints(&self) -> Vec<String> {
+        parse_allowed_endpoints(include_str!("allowed_endpoints.yaml"))
+    }
+}
+
+// === REQUEST ===
+/// Pool data a query runs over.
+#[derive(Deserialize, Debug)]
+pub struct PoolSelection {
+    /// Id of the pending `cloakx::jobs::Job` paying for the query. A job pays
+    /// for one query over its pool.
+    pub job_id: u64,
+    /// Pool queried, the job's. `data_blob_ids` must be its `pool_data` entry.
+    pub pool_id: u64,
+    pub data_blob_ids: Vec<String>,
+    /// Seal key id of sealed data blobs.
+    pub key_id: String,
+    /// This enclave's registered `Enclave<JOBS>` object. Needed for sealed
+    /// data blobs.
+    pub enclave_object: Option<EnclaveObject>,
+    /// Thresholds of the per-blob data quality checks.
+    #[serde(default)]
+    pub quality: QualityConfig,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AnalyticsRequest {
+    #[serde(flatten)]
+    pub pool: PoolSelection,
+    pub query: Query,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SqlRequest {
+    #[serde(flatten)]
+    pub pool: PoolSelection,
+    pub query: SqlQuery,
+}
+
+// === RESPONSE ===
+/// Signed aggregates of a query.
+#[derive(Serialize, Clone, Debug)]
+pub struct AnalyticsResponse {
+    pub job_id: u64,
+    pub pool_id: u64,
+    /// The query run, including its columns and noise.
+    pub query: Query,
+    /// Data quality of each blob, in request order.
+    pub data_quality: Vec<BlobReport>,
+    /// Contributors and rows queried, with the pool's minimums they met.
+    pub cohort: CohortReport,
+    /// Fewest rows of a released group, the larger of the query's and the
+    /// pool's minimum.
+    pub min_group_size: u64,
+    /// Epsilon spent by the noisy outputs of the pool so far, this query's
+    /// included.
+    #[serde(with = "f32_bits")]
+    pub epsilon_spent: f64,
+    /// Groups of at least `min_group_size` rows, by ascending key.
+    pub groups: Vec<GroupResult>,
+}
+
+/// Signed result set of a SQL query.
+#[derive(Serialize, Clone, Debug)]
+pub struct SqlResponse {
+    pub job_id: u64,
+    pub pool_id: u64,
+    /// Sha256 of the SQL text.
+    pub query_hash: Vec<u8>,
+    /// The query run, including its columns and noise.
+    pub query: SqlQuery,
+    /// Data quality of each blob, in request order.
+    pub data_quality: Vec<BlobReport>,
+    /// Contributors and rows queried, with the pool's minimums they met.
+    pub cohort: CohortReport,
+    /// Fewest rows of a released group, the larger of the query's and the
+    /// pool's minimum.
+    pub min_group_size: u64,
+    /// Epsilon spent by the noisy outputs of the pool so far, this query's
+    /// included.
+    #[serde(with = "f32_bits")]
+    pub epsilon_spent: f64,
+    /// One row per group of at least `min_group_size` rows, by ascending
+    /// `GROUP BY` values.
+    pub result: ResultSet,
+}
+
+/// Signed instead of a result when a query is refused by its pool's policy.
+#[derive(Serialize, Clone, Debug)]
+pub struct QueryRejection {
+    pub pool_id: u64,
+    /// Sha256 of the pool metadata the policy was read from.
+    pub policy_hash: Vec<u8>,
+    pub reason: String,
+}
+
+pub async fn run_query(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ProcessDataRequest<AnalyticsRequest>>,
+) -> Result<Json<ProcessedDataResponse<IntentMessage<AnalyticsResponse>>>, EnclaveError> {
+    let payload = &req.payload;
+    let nonce = req.nonce()?;
+    let query = &payload.query;
+    let plan = query.plan()?;
+    let needs = QueryNeeds {
+        columns: &query.columns,
+        classes: query.classes,
+        noise: query.noise.as_ref(),
+        filtered: plan.filtered(),
+        min_group_size: query.min_group_size,
+    };
+    let pool = load_pool(&state, &nonce, &payload.pool, needs).await?;
+
+    // Aggregate, noise and suppress
+    let groups = plan.run(&pool.rows.dataset.load()?, pool.min_group_size, &mut OsRng)?;
+
+    let response = AnalyticsResponse {
+        job_id: payload.pool.job_id,
+        pool_id: payload.pool.pool_id,
+        query: query.clone(),
+        data_quality: pool.rows.data_quality,
+        cohort: pool.cohort,
+        min_group_size: pool.min_group_size,
+        epsilon_spent: pool.epsilon_spent,
+        groups: groups.into_iter().map(GroupResult::from).collect(),
+    };
+    Ok(Json(to_signed_response(
+        &state.eph_kp,
+        response,
+        &state.clock.stamp(&nonce)?,
+        IntentScope::AnalyticsResult,
+    )))
+}
+
+pub async fn run_sql(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ProcessDataRequest<SqlRequest>>,
+) -> Result<Json<ProcessedDataResponse<IntentMessage<SqlResponse>>>, EnclaveError> {
+    let payload = &req.payload;
+    let nonce = req.nonce()?;
+    let query = &payload.query;
+    let plan = query.compile()?;
+    let needs = QueryNeeds {
+        columns: &query.columns,
+        classes: query.classes,
+        noise: query.noise.as_ref(),
+        filtered: plan.filtered(),
+        min_group_size: query.min_group_size,
+    };
+    let pool = load_pool(&state, &nonce, &payload.pool, needs).await?;
+
+    let result = plan.run(&pool.rows.dataset.load()?, pool.min_group_size, &mut OsRng)?;
+
+    let response = SqlResponse {
+        job_id: payload.pool.job_id,
+        pool_id: payload.pool.pool_id,
+        query_hash: Sha256::digest(query.sql.as_bytes()).digest.to_vec(),
+        query: query.clone(),
+        data_quality: pool.rows.data_quality,
+        cohort: pool.cohort,
+        min_group_size: pool.min_group_size,
+        epsilon_spent: pool.epsilon_spent,
+        result,
+    };
+    Ok(Json(to_signed_response(
+        &state.eph_kp,
+        response,
+        &state.clock.stamp(&nonce)?,
+        IntentScope::AnalyticsSqlResult,
+    )))
+}
+
+/// What a query asks of a pool.
+struct QueryNeeds<'a> {
+    columns: &'a [ColumnSchema],
+    classes: u64,
+    noise: Option<&'a NoiseConfig>,
+    /// Whether the query aggregates only the rows that pass a filter.
+    filtered: bool,
+    min_group_size: u64,
+}
+
+/// The rows a query runs over, once it was charged to its job.
+struct LoadedPool {
+    rows: PoolRows,
+    cohort: CohortReport,
+    /// The smallest group size the query may release.
+    min_group_size: u64,
+    /// Epsilon spent by the pool's noisy outputs, this query's included.
+    epsilon_spent: f64,
+}
+
+/// Check the request against its on-chain job and the query against the
+/// pool's policy, then load the rows that pass the quality checks. The job
+/// is used up, and the query's epsilon counted against the pool's budget,
+/// only once the rows passed the cohort checks. Rejections echo `nonce`.
+async fn load_pool(
+    state: &AppState,
+    nonce: &[u8],
+    pool: &PoolSelection,
+    needs: QueryNeeds<'_>,
+) -> Result<LoadedPool, EnclaveError> {
+    let reader = RpcReader::from_config()?;
+    let job = reader.job(pool.job_id).await?;
+    if job.status != "Pending" {
+        return Err(EnclaveError::JobMismatch(format!(
+            "job {} is {}",
+            job.job_id, job.status
+        )));
+    }
+    if job.pool_id != pool.pool_id {
+        return Err(EnclaveError::JobMismatch(format!(
+            "job {} queries pool {}",
+            job.job_id, job.pool_id
+        )));
+    }
+    let (policy, contributors) = verify_pool(&reader, pool.pool_id, &pool.data_blob_ids).await?;
+    let epsilon = needs.noise.map(|n| n.epsilon());
+    let min_group_size = policy
+        .check_query(epsilon, needs.filtered, needs.min_group_size)
+        .map_err(|reason| reject(state, nonce, &policy, reason))?;
+
+    let checker = QualityChecker::new(needs.columns.len(), needs.classes as usize, &pool.quality)?;
+    let rows = load_rows(
+        state,
+        &pool.data_blob_ids,
+        SealedJob {
+            job_id: job.job_id,
+            pool_id: pool.pool_id,
+            key_id: &pool.key_id,
+            enclave: pool.enclave_object.as_ref(),
+        },
+        checker,
+    )
+    .await?;
+    let cohort = check_cohort(&policy, &contributors, &rows.data_quality)
+        .map_err(|reason| reject(state, nonce, &policy, reason))?;
+    let (now_ms, _) = state.clock.now_ms()?;
+    let epsilon_spent = usage::charge(state, |usage| {
+        if !usage.jobs_answered.insert(job.job_id) {
+            return Err(EnclaveError::JobMismatch(format!(
+                "job {} already paid for a query",
+                job.job_id
+            )));
+        }
+        policy
+            .charge_job(usage, now_ms)
+            .and_then(|()| policy.charge_epsilon(usage, epsilon))
+            .map_err(|reason| reject(state, nonce, &policy, reason))
+    })??;
+    Ok(LoadedPool {
+        rows,
+        cohort,
+        min_group_size,
+        epsilon_spent,
+    })
+}
+
+/// A signed [`QueryRejection`].
+fn reject(state: &AppState, nonce: &[u8], policy: &PolicyCheck, reason: String) -> EnclaveError {
+    let stamp = match state.clock.stamp(nonce) {
+        Ok(stamp) => stamp,
+        Err(e) => return e,
+    };
+    let rejection = QueryRejection {
+        pool_id: policy.pool_id,
+        policy_hash: policy.policy_hash.clone(),
+        reason: reason.clone(),
+    };
+    let signed = to_signed_response(
+        &state.eph_kp,
+        rejection,
+        &stamp,
+        IntentScope::AnalyticsRejected,
+    );
+    EnclaveError::Rejected {
+        reason,
+        signed: serde_json::to_value(signed).expect("should not fail"),
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Differentially private noise on released aggregates.
+//!
+//! A query releases, per group, a handful of sums over its rows. Each row
+//! lands in one group and moves each sum by at most that sum's sensitivity,
+//! so the groups compose in parallel and the sums of a group in sequence: the
+//! budget of the query is split evenly over the sums of a group, and each sum
+//! gets noise calibrated to its own sensitivity and share.
+
+use crate::apps::mltraining::sampling::{laplace, standard_normal};
+use crate::common::f32_bits;
+use crate::EnclaveError;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "mechanism", rename_all = "snake_case", deny_unknown_fields)]
+pub enum NoiseConfig {
+    /// Pure `epsilon`-differential privacy.
+    Laplace {
+        #[serde(with = "f32_bits")]
+        epsilon: f64,
+    },
+    /// `(epsilon, delta)`-differential privacy with the classic Gaussian
+    /// mechanism, which needs `epsilon < 1`.
+    Gaussian {
+        #[serde(with = "f32_bits")]
+        epsilon: f64,
+        #[serde(with = "f32_bits")]
+        delta: f64,
+    },
+}
+
+impl NoiseConfig {
+    pub fn validate(&self) -> Result<(), EnclaveError> {
+        let valid = match *self {
+            NoiseConfig::Laplace { epsilon } => epsilon > 0.0 && epsilon.is_finite(),
+            NoiseConfig::Gaussian { epsilon, delta } => {
+                epsilon > 0.0 && epsilon < 1.0 && delta > 0.0 && delta < 1.0
+            }
+        };
+        if !valid {
+            return Err(EnclaveError::InvalidInput(
+                "laplace noise needs a positive epsilon, gaussian noise an epsilon and delta in (0, 1)"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Privacy budget of the whole query.
+    pub fn epsilon(&self) -> f64 {
+        match *self {
+            NoiseConfig::Laplace { epsilon } | NoiseConfig::Gaussian { epsilon, .. } => epsilon,
+        }
+    }
+
+    /// Add noise to `value`, a sum one row moves by at most `sensitivity`,
+    /// released as one of `releases` sums sharing the budget.
+    pub fn perturb<R: Rng>(
+        &self,
+        value: f64,
+        sensitivity: f64,
+        releases: usize,
+        rng: &mut R,
+    ) -> f64 {
+        let share = releases as f64;
+        match *self {
+            NoiseConfig::Laplace { epsilon } => value + laplace(sensitivity * share / epsilon, rng),
+            NoiseConfig::Gaussian { epsilon, delta } => {
+                let (epsilon, delta) = (epsilon / share, delta / share);
+                let sigma = sensitivity * (2.0 * (1.25 / delta).ln()).sqrt() / epsilon;
+                value + sigma * standard_normal(rng)
+            }
+        }
+    }
+}
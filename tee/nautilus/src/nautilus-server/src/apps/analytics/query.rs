@@ -0,0 +1,650 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Declarative aggregate queries over the rows of a pool.
+//!
+//! A query names the feature columns of the pool rows, the statistics to
+//! compute and optionally a column to group the rows by. Rows are the
+//! `[features, label]` rows of the pool after the data quality checks, and
+//! the label is the `label` pseudo column. Missing values are skipped by every
+//! statistic but `count`. Groups with fewer rows than the minimum group size
+//! are withheld; with noise, that is decided on the noisy row count.
+//!
+//! Queries compile to a [`Plan`], which SQL queries (see `sql`) compile to as
+//! well, adding a row filter, several grouping columns and a condition on the
+//! released aggregates.
+
+use super::noise::NoiseConfig;
+use crate::apps::mltraining::dataset::Dataset;
+use crate::common::f32_bits;
+use crate::EnclaveError;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Pseudo column holding the label of a row.
+pub const LABEL_COLUMN: &str = "label";
+
+pub const MAX_STATISTICS: usize = 32;
+
+pub const MAX_HISTOGRAM_BINS: usize = 1024;
+
+/// Groups a query may split the rows into, before suppression.
+pub const MAX_GROUPS: usize = 10_000;
+
+pub const MAX_GROUP_COLUMNS: usize = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Query {
+    /// Feature columns of the pool rows, in row order.
+    pub columns: Vec<ColumnSchema>,
+    /// Labels are below this.
+    pub classes: u64,
+    pub statistics: Vec<Statistic>,
+    /// Column whose values split the rows into groups. All rows form one
+    /// group when absent.
+    pub group_by: Option<String>,
+    /// Fewest rows of a released group, raised to the pool's minimum.
+    #[serde(default)]
+    pub min_group_size: u64,
+    pub noise: Option<NoiseConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ColumnSchema {
+    pub name: String,
+    /// Values are clipped to `[min, max]`. Noisy means and correlations need
+    /// bounds to calibrate their noise.
+    pub bounds: Option<Bounds>,
+}
+
+/// `[min, max]` of a column.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Bounds(
+    #[serde(with = "f32_bits")] pub f64,
+    #[serde(with = "f32_bits")] pub f64,
+);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Statistic {
+    /// Rows of the group.
+    Count,
+    /// Sum of the present values.
+    Sum { column: String },
+    /// Mean of the present values.
+    Mean { column: String },
+    /// Present values per bin: below the first edge, from each edge up to
+    /// the next, and from the last edge up.
+    Histogram {
+        column: String,
+        #[serde(with = "f32_bits")]
+        edges: Vec<f64>,
+    },
+    /// Pearson correlation over the rows where both values are present.
+    Correlation { x: String, y: String },
+}
+
+/// Released aggregates of one group.
+#[derive(Serialize, Clone, Debug)]
+pub struct GroupResult {
+    /// Value of `group_by` in the group, `None` for the rows missing it or
+    /// when the query has no `group_by`.
+    #[serde(with = "f32_bits")]
+    pub key: Option<f64>,
+    #[serde(with = "f32_bits")]
+    pub count: f64,
+    /// One per statistic, in query order.
+    pub statistics: Vec<StatisticResult>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum StatisticResult {
+    Count {
+        #[serde(with = "f32_bits")]
+        count: f64,
+    },
+    Sum {
+        #[serde(with = "f32_bits")]
+        sum: f64,
+    },
+    /// `None` when the group has no present value.
+    Mean {
+        #[serde(with = "f32_bits")]
+        mean: Option<f64>,
+    },
+    Histogram {
+        #[serde(with = "f32_bits")]
+        counts: Vec<f64>,
+    },
+    /// `None` when either column is constant or has fewer than two values.
+    Correlation {
+        #[serde(with = "f32_bits")]
+        correlation: Option<f64>,
+    },
+}
+
+impl StatisticResult {
+    /// The statistic as one value, `None` for histograms and missing values.
+    pub fn scalar(&self) -> Option<f64> {
+        match self {
+            StatisticResult::Count { count } => Some(*count),
+            StatisticResult::Sum { sum } => Some(*sum),
+            StatisticResult::Mean { mean } => *mean,
+            StatisticResult::Histogram { .. } => None,
+            StatisticResult::Correlation { correlation } => *correlation,
+        }
+    }
+}
+
+impl From<Group> for GroupResult {
+    fn from(group: Group) -> Self {
+        GroupResult {
+            key: group.key.into_iter().next().flatten(),
+            count: group.count,
+            statistics: group.statistics,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// Condition over values that may be missing, with SQL's three valued
+/// logic: a comparison with a missing value is unknown, `None`.
+#[derive(Debug, Clone)]
+pub enum Condition<T> {
+    Compare(T, Comparison, f64),
+    IsNull(T),
+    Not(Box<Condition<T>>),
+    And(Box<Condition<T>>, Box<Condition<T>>),
+    Or(Box<Condition<T>>, Box<Condition<T>>),
+}
+
+impl<T> Condition<T> {
+    fn eval(&self, value: &impl Fn(&T) -> Option<f64>) -> Option<bool> {
+        match self {
+            Condition::Compare(operand, comparison, literal) => {
+                let v = value(operand)?;
+                Some(match comparison {
+                    Comparison::Eq => v == *literal,
+                    Comparison::Ne => v != *literal,
+                    Comparison::Lt => v < *literal,
+                    Comparison::Le => v <= *literal,
+                    Comparison::Gt => v > *literal,
+                    Comparison::Ge => v >= *literal,
+                })
+            }
+            Condition::IsNull(operand) => Some(value(operand).is_none()),
+            Condition::Not(c) => c.eval(value).map(|b| !b),
+            Condition::And(a, b) => match (a.eval(value), b.eval(value)) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Condition::Or(a, b) => match (a.eval(value), b.eval(value)) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+        }
+    }
+
+    /// Whether the condition holds, unknown counting as false.
+    fn holds(&self, value: impl Fn(&T) -> Option<f64>) -> bool {
+        self.eval(&value) == Some(true)
+    }
+
+    /// The same condition over other operands.
+    pub fn try_map<U, E>(&self, f: &mut impl FnMut(&T) -> Result<U, E>) -> Result<Condition<U>, E> {
+        Ok(match self {
+            Condition::Compare(operand, comparison, literal) => {
+                Condition::Compare(f(operand)?, *comparison, *literal)
+            }
+            Condition::IsNull(operand) => Condition::IsNull(f(operand)?),
+            Condition::Not(c) => Condition::Not(Box::new(c.try_map(f)?)),
+            Condition::And(a, b) => {
+                Condition::And(Box::new(a.try_map(f)?), Box::new(b.try_map(f)?))
+            }
+            Condition::Or(a, b) => Condition::Or(Box::new(a.try_map(f)?), Box::new(b.try_map(f)?)),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Column {
+    Feature(usize),
+    Label,
+}
+
+/// A column of a query with its clipping bounds.
+#[derive(Debug, Clone, Copy)]
+struct Resolved {
+    column: Column,
+    bounds: Option<Bounds>,
+}
+
+impl Resolved {
+    /// Clipped value of the column in a row, `NaN` if missing. Rows are
+    /// filtered and grouped on clipped values too.
+    fn value(&self, input: &[f32], label: usize) -> f64 {
+        let value = match self.column {
+            Column::Feature(i) => input[i] as f64,
+            Column::Label => label as f64,
+        };
+        match self.bounds {
+            Some(Bounds(min, max)) if !value.is_nan() => value.clamp(min, max),
+            _ => value,
+        }
+    }
+
+    /// Largest absolute value, which bounds how much one row moves a sum.
+    fn magnitude(&self) -> f64 {
+        self.bounds
+            .map_or(f64::INFINITY, |Bounds(min, max)| min.abs().max(max.abs()))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Compiled {
+    Count,
+    Sum(Resolved),
+    Mean(Resolved),
+    Histogram(Resolved, Vec<f64>),
+    Correlation(Resolved, Resolved),
+}
+
+impl Compiled {
+    /// Sums accumulated per group.
+    fn width(&self) -> usize {
+        match self {
+            Compiled::Count => 0,
+            Compiled::Sum(_) => 1,
+            Compiled::Mean(_) => 2,
+            Compiled::Histogram(_, edges) => edges.len() + 1,
+            Compiled::Correlation(..) => 6,
+        }
+    }
+
+    fn accumulate(&self, sums: &mut [f64], input: &[f32], label: usize) {
+        match self {
+            Compiled::Count => {}
+            Compiled::Sum(column) => {
+                let v = column.value(input, label);
+                if !v.is_nan() {
+                    sums[0] += v;
+                }
+            }
+            Compiled::Mean(column) => {
+                let v = column.value(input, label);
+                if !v.is_nan() {
+                    sums[0] += v;
+                    sums[1] += 1.0;
+                }
+            }
+            Compiled::Histogram(column, edges) => {
+                let v = column.value(input, label);
+                if !v.is_nan() {
+                    sums[edges.partition_point(|e| *e <= v)] += 1.0;
+                }
+            }
+            Compiled::Correlation(x, y) => {
+                let (x, y) = (x.value(input, label), y.value(input, label));
+                if !x.is_nan() && !y.is_nan() {
+                    for (sum, v) in sums.iter_mut().zip([1.0, x, y, x * x, y * y, x * y]) {
+                        *sum += v;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sums released together, each with the most one row moves it by. A
+    /// row lands in one bin of a histogram, so its bins are one release.
+    fn releases(&self) -> Vec<(Range<usize>, f64)> {
+        match self {
+            Compiled::Count => vec![],
+            Compiled::Sum(column) => vec![(0..1, column.magnitude())],
+            Compiled::Mean(column) => vec![(0..1, column.magnitude()), (1..2, 1.0)],
+            Compiled::Histogram(_, edges) => vec![(0..edges.len() + 1, 1.0)],
+            Compiled::Correlation(x, y) => {
+                let (x, y) = (x.magnitude(), y.magnitude());
+                [1.0, x, y, x * x, y * y, x * y]
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, sensitivity)| (i..i + 1, sensitivity))
+                    .collect()
+            }
+        }
+    }
+
+    fn finish(&self, sums: &[f64], count: f64) -> StatisticResult {
+        match self {
+            Compiled::Count => StatisticResult::Count { count },
+            Compiled::Sum(_) => StatisticResult::Sum { sum: sums[0] },
+            Compiled::Mean(column) => {
+                let mean = (sums[1] >= 1.0).then(|| {
+                    let mean = sums[0] / sums[1];
+                    match column.bounds {
+                        Some(Bounds(min, max)) => mean.clamp(min, max),
+                        None => mean,
+                    }
+                });
+                StatisticResult::Mean { mean }
+            }
+            Compiled::Histogram(..) => StatisticResult::Histogram {
+                counts: sums.iter().map(|c| c.max(0.0)).collect(),
+            },
+            Compiled::Correlation(..) => {
+                let n = sums[0];
+                let correlation = (n >= 2.0)
+                    .then(|| {
+                        let (mx, my) = (sums[1] / n, sums[2] / n);
+                        let vx = sums[3] / n - mx * mx;
+                        let vy = sums[4] / n - my * my;
+                        let covariance = sums[5] / n - mx * my;
+                        (vx > 0.0 && vy > 0.0)
+                            .then(|| (covariance / (vx * vy).sqrt()).clamp(-1.0, 1.0))
+                    })
+                    .flatten();
+                StatisticResult::Correlation { correlation }
+            }
+        }
+    }
+}
+
+/// Released aggregates of one group.
+#[derive(Clone, Debug)]
+pub struct Group {
+    /// Values of the grouping columns, `None` where missing.
+    pub key: Vec<Option<f64>>,
+    pub count: f64,
+    pub statistics: Vec<StatisticResult>,
+}
+
+/// What a query computes, with its columns named.
+pub struct Spec<'a> {
+    pub columns: &'a [ColumnSchema],
+    pub classes: u64,
+    /// Rows aggregated, all when absent.
+    pub filter: Option<&'a Condition<String>>,
+    pub group_by: &'a [String],
+    pub statistics: &'a [Statistic],
+    /// Groups released, on the index of a statistic, all when absent.
+    pub having: Option<&'a Condition<usize>>,
+    pub noise: Option<&'a NoiseConfig>,
+}
+
+/// A checked query with its columns resolved to row positions.
+pub struct Plan {
+    filter: Option<Condition<Resolved>>,
+    group_by: Vec<Resolved>,
+    statistics: Vec<Compiled>,
+    having: Option<Condition<usize>>,
+    noise: Option<NoiseConfig>,
+}
+
+impl Query {
+    pub fn plan(&self) -> Result<Plan, EnclaveError> {
+        if self.statistics.is_empty() {
+            return Err(EnclaveError::InvalidInput(
+                "a query computes at least one statistic".to_string(),
+            ));
+        }
+        Spec {
+            columns: &self.columns,
+            classes: self.classes,
+            filter: None,
+            group_by: self.group_by.as_slice(),
+            statistics: &self.statistics,
+            having: None,
+            noise: self.noise.as_ref(),
+        }
+        .plan()
+    }
+}
+
+impl Spec<'_> {
+    pub fn plan(&self) -> Result<Plan, EnclaveError> {
+        let invalid = |e: String| Err(EnclaveError::InvalidInput(e));
+        if self.columns.is_empty() || self.classes == 0 {
+            return invalid("a query needs columns and classes".to_string());
+        }
+        for (i, column) in self.columns.iter().enumerate() {
+            if column.name == LABEL_COLUMN
+                || self.columns[..i].iter().any(|c| c.name == column.name)
+            {
+                return invalid(format!("column name {} is taken", column.name));
+            }
+            if let Some(Bounds(min, max)) = column.bounds {
+                if !(min.is_finite() && max.is_finite() && min <= max) {
+                    return invalid(format!("column {} has invalid bounds", column.name));
+                }
+            }
+        }
+        if self.statistics.len() > MAX_STATISTICS {
+            return invalid(format!(
+                "a query computes at most {MAX_STATISTICS} statistics"
+            ));
+        }
+        if self.group_by.len() > MAX_GROUP_COLUMNS {
+            return invalid(format!(
+                "a query groups by at most {MAX_GROUP_COLUMNS} columns"
+            ));
+        }
+        if let Some(noise) = self.noise {
+            noise.validate()?;
+        }
+
+        let mut statistics = Vec::with_capacity(self.statistics.len());
+        for statistic in self.statistics {
+            statistics.push(match statistic {
+                Statistic::Count => Compiled::Count,
+                Statistic::Sum { column } => Compiled::Sum(self.bounded(column)?),
+                Statistic::Mean { column } => Compiled::Mean(self.bounded(column)?),
+                Statistic::Histogram { column, edges } => {
+                    let increasing = edges.windows(2).all(|w| w[0] < w[1]);
+                    if edges.is_empty()
+                        || edges.len() >= MAX_HISTOGRAM_BINS
+                        || !increasing
+                        || edges.iter().any(|e| !e.is_finite())
+                    {
+                        return invalid(format!(
+                            "histogram of {column} needs 1 to {} increasing finite edges",
+                            MAX_HISTOGRAM_BINS - 1
+                        ));
+                    }
+                    Compiled::Histogram(self.resolve(column)?, edges.clone())
+                }
+                Statistic::Correlation { x, y } => {
+                    Compiled::Correlation(self.bounded(x)?, self.bounded(y)?)
+                }
+            });
+        }
+        let group_by = self
+            .group_by
+            .iter()
+            .map(|column| self.resolve(column))
+            .collect::<Result<_, _>>()?;
+        let filter = self
+            .filter
+            .map(|c| c.try_map(&mut |column: &String| self.resolve(column)))
+            .transpose()?;
+        if let Some(having) = self.having {
+            having.try_map(&mut |i: &usize| match statistics.get(*i) {
+                Some(Compiled::Histogram(..)) | None => Err(EnclaveError::InvalidInput(
+                    "having refers to no scalar statistic".to_string(),
+                )),
+                Some(_) => Ok(()),
+            })?;
+        }
+        Ok(Plan {
+            filter,
+            group_by,
+            statistics,
+            having: self.having.cloned(),
+            noise: self.noise.cloned(),
+        })
+    }
+
+    fn resolve(&self, name: &str) -> Result<Resolved, EnclaveError> {
+        if name == LABEL_COLUMN {
+            return Ok(Resolved {
+                column: Column::Label,
+                bounds: Some(Bounds(0.0, (self.classes - 1) as f64)),
+            });
+        }
+        let index = self
+            .columns
+            .iter()
+            .position(|c| c.name == name)
+            .ok_or_else(|| EnclaveError::InvalidInput(format!("unknown column {name}")))?;
+        Ok(Resolved {
+            column: Column::Feature(index),
+            bounds: self.columns[index].bounds,
+        })
+    }
+
+    /// A column summed by a statistic, which needs bounds under noise.
+    fn bounded(&self, name: &str) -> Result<Resolved, EnclaveError> {
+        let column = self.resolve(name)?;
+        if self.noise.is_some() && column.bounds.is_none() {
+            return Err(EnclaveError::InvalidInput(format!(
+                "column {name} needs bounds to be summed with noise"
+            )));
+        }
+        Ok(column)
+    }
+}
+
+impl Plan {
+    /// Whether the plan aggregates only the rows that pass a filter.
+    pub fn filtered(&self) -> bool {
+        self.filter.is_some()
+    }
+
+    /// Aggregate the rows of `dataset` that pass the filter per group, and
+    /// release the groups of at least `min_group_size` rows that meet the
+    /// condition on their aggregates, ordered by key.
+    pub fn run<R: Rng>(
+        &self,
+        dataset: &Dataset,
+        min_group_size: u64,
+        rng: &mut R,
+    ) -> Result<Vec<Group>, EnclaveError> {
+        let offsets: Vec<usize> = self
+            .statistics
+            .iter()
+            .scan(0, |offset, s| {
+                let start = *offset;
+                *offset += s.width();
+                Some(start)
+            })
+            .collect();
+        let width: usize = self.statistics.iter().map(Compiled::width).sum();
+
+        // 1. Row count and sums of each group, keyed by the bits of the
+        // group values
+        let mut groups: HashMap<Vec<Option<u64>>, (f64, Vec<f64>)> = HashMap::new();
+        for (input, label) in dataset.inputs.iter().zip(&dataset.targets) {
+            let present = |column: &Resolved| {
+                let value = column.value(input, *label);
+                (!value.is_nan()).then_some(value)
+            };
+            if let Some(filter) = &self.filter {
+                if !filter.holds(present) {
+                    continue;
+                }
+            }
+            // Fold -0 into 0
+            let key: Vec<_> = self
+                .group_by
+                .iter()
+                .map(|column| present(column).map(|v| (v + 0.0).to_bits()))
+                .collect();
+            if !groups.contains_key(&key) && groups.len() == MAX_GROUPS {
+                return Err(EnclaveError::InvalidInput(format!(
+                    "the grouping splits the rows into more than {MAX_GROUPS} groups"
+                )));
+            }
+            let (count, sums) = groups.entry(key).or_insert_with(|| (0.0, vec![0.0; width]));
+            *count += 1.0;
+            for (statistic, offset) in self.statistics.iter().zip(&offsets) {
+                let sums = &mut sums[*offset..*offset + statistic.width()];
+                statistic.accumulate(sums, input, *label);
+            }
+        }
+        let mut groups: Vec<_> = groups
+            .into_iter()
+            .map(|(key, group)| {
+                let key: Vec<_> = key.into_iter().map(|v| v.map(f64::from_bits)).collect();
+                (key, group)
+            })
+            .collect();
+        groups.sort_by(|(a, _), (b, _)| {
+            a.iter()
+                .zip(b)
+                .map(|(a, b)| match (a, b) {
+                    (Some(a), Some(b)) => a.total_cmp(b),
+                    _ => a.is_some().cmp(&b.is_some()),
+                })
+                .find(|o| o.is_ne())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+
+        // 2. Noise every released sum, then withhold small groups and the
+        // groups failing the condition, which only sees released values
+        let releases = 1 + self
+            .statistics
+            .iter()
+            .map(|s| s.releases().len())
+            .sum::<usize>();
+        let mut results = vec![];
+        for (key, (count, mut sums)) in groups {
+            let count = match &self.noise {
+                Some(noise) => noise.perturb(count, 1.0, releases, rng).max(0.0),
+                None => count,
+            };
+            if count < min_group_size as f64 {
+                continue;
+            }
+            if let Some(noise) = &self.noise {
+                for (statistic, offset) in self.statistics.iter().zip(&offsets) {
+                    for (range, sensitivity) in statistic.releases() {
+                        for sum in &mut sums[offset + range.start..offset + range.end] {
+                            *sum = noise.perturb(*sum, sensitivity, releases, rng);
+                        }
+                    }
+                }
+            }
+            let statistics: Vec<_> = self
+                .statistics
+                .iter()
+                .zip(&offsets)
+                .map(|(s, offset)| s.finish(&sums[*offset..*offset + s.width()], count))
+                .collect();
+            if let Some(having) = &self.having {
+                if !having.holds(|i: &usize| statistics[*i].scalar()) {
+                    continue;
+                }
+            }
+            results.push(Group {
+                key,
+                count,
+                statistics,
+            });
+        }
+        Ok(results)
+    }
+}
@@ -0,0 +1,598 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A restricted SQL dialect over the rows of a pool, e.g.
+//!
+//! ```sql
+//! SELECT site, COUNT(*), AVG(bmi) AS mean_bmi, CORR(age, bmi)
+//! FROM pool
+//! WHERE age BETWEEN 18 AND 65 AND bmi IS NOT NULL
+//! GROUP BY site
+//! HAVING COUNT(*) > 100
+//! ```
+//!
+//! The only table is `pool`, whose columns are those of the query plus
+//! `label`. A query selects the aggregates `COUNT(*)`, `SUM`, `AVG` and
+//! `CORR`, and the columns it groups by. Conditions compare a column, or in
+//! `HAVING` an aggregate, with a number, and combine with `AND`, `OR` and
+//! `NOT`. There is no `SELECT *`, no selected column outside `GROUP BY`, no
+//! `MIN` or `MAX`, and no `ORDER BY` or `LIMIT`, so every row of a result is
+//! an aggregate over a group, which the minimum group size then applies to.
+//! Queries compile to a [`Plan`] and run like declarative queries.
+
+use super::noise::NoiseConfig;
+use super::query::{ColumnSchema, Comparison, Condition, Group, Plan, Spec, Statistic};
+use crate::apps::mltraining::dataset::Dataset;
+use crate::common::f32_bits;
+use crate::EnclaveError;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_SQL_LENGTH: usize = 4096;
+
+/// Deepest nesting of conditions.
+const MAX_DEPTH: usize = 32;
+
+/// Words that are never column names unless quoted.
+const RESERVED: &[&str] = &[
+    "select", "from", "where", "group", "by", "having", "as", "and", "or", "not", "is", "null",
+    "between", "in",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SqlQuery {
+    /// Feature columns of the pool rows, in row order.
+    pub columns: Vec<ColumnSchema>,
+    /// Labels are below this.
+    pub classes: u64,
+    pub sql: String,
+    /// Fewest rows of a released group, raised to the pool's minimum.
+    #[serde(default)]
+    pub min_group_size: u64,
+    pub noise: Option<NoiseConfig>,
+}
+
+/// Rows of a query result, one per released group.
+#[derive(Serialize, Clone, Debug)]
+pub struct ResultSet {
+    /// Alias or text of each selected expression.
+    pub columns: Vec<String>,
+    /// `None` for missing group values and undefined aggregates.
+    #[serde(with = "f32_bits")]
+    pub rows: Vec<Vec<Option<f64>>>,
+}
+
+/// What a selected expression holds.
+#[derive(Debug, Clone, Copy)]
+enum Output {
+    /// Value of the nth `GROUP BY` column.
+    Key(usize),
+    Statistic(usize),
+}
+
+/// A compiled SQL query.
+pub struct SqlPlan {
+    plan: Plan,
+    outputs: Vec<(String, Output)>,
+}
+
+impl SqlQuery {
+    pub fn compile(&self) -> Result<SqlPlan, EnclaveError> {
+        if self.sql.len() > MAX_SQL_LENGTH {
+            return Err(invalid(format!(
+                "queries are at most {MAX_SQL_LENGTH} bytes"
+            )));
+        }
+        let select = Parser::new(lex(&self.sql)?).select()?;
+        let plan = Spec {
+            columns: &self.columns,
+            classes: self.classes,
+            filter: select.filter.as_ref(),
+            group_by: &select.group_by,
+            statistics: &select.statistics,
+            having: select.having.as_ref(),
+            noise: self.noise.as_ref(),
+        }
+        .plan()?;
+        Ok(SqlPlan {
+            plan,
+            outputs: select.outputs,
+        })
+    }
+}
+
+impl SqlPlan {
+    /// Whether the query has a `WHERE` clause.
+    pub fn filtered(&self) -> bool {
+        self.plan.filtered()
+    }
+
+    pub fn run<R: Rng>(
+        &self,
+        dataset: &Dataset,
+        min_group_size: u64,
+        rng: &mut R,
+    ) -> Result<ResultSet, EnclaveError> {
+        let groups = self.plan.run(dataset, min_group_size, rng)?;
+        Ok(ResultSet {
+            columns: self.outputs.iter().map(|(name, _)| name.clone()).collect(),
+            rows: groups.iter().map(|group| self.row(group)).collect(),
+        })
+    }
+
+    fn row(&self, group: &Group) -> Vec<Option<f64>> {
+        self.outputs
+            .iter()
+            .map(|(_, output)| match output {
+                Output::Key(i) => group.key[*i],
+                Output::Statistic(i) => group.statistics[*i].scalar(),
+            })
+            .collect()
+    }
+}
+
+fn invalid(e: String) -> EnclaveError {
+    EnclaveError::InvalidInput(format!("sql: {e}"))
+}
+
+// === LEXER ===
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// Bare word: a keyword, function or column name.
+    Word(String),
+    /// Double quoted column name.
+    Quoted(String),
+    Number(f64),
+    Compare(Comparison),
+    Star,
+    Comma,
+    Open,
+    Close,
+    Semicolon,
+}
+
+impl Token {
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Token::Word(w) if w.eq_ignore_ascii_case(keyword))
+    }
+}
+
+fn lex(sql: &str) -> Result<Vec<Token>, EnclaveError> {
+    let chars: Vec<char> = sql.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let single = match c {
+            '*' => Some(Token::Star),
+            ',' => Some(Token::Comma),
+            '(' => Some(Token::Open),
+            ')' => Some(Token::Close),
+            ';' => Some(Token::Semicolon),
+            '=' => Some(Token::Compare(Comparison::Eq)),
+            _ => None,
+        };
+        if let Some(token) = single {
+            tokens.push(token);
+            i += 1;
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c == '-' && next == Some('-') {
+            // Comment to the end of the line
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+        } else if c == '<' || c == '>' || c == '!' {
+            let (comparison, width) = match (c, next) {
+                ('<', Some('=')) => (Comparison::Le, 2),
+                ('<', Some('>')) | ('!', Some('=')) => (Comparison::Ne, 2),
+                ('<', _) => (Comparison::Lt, 1),
+                ('>', Some('=')) => (Comparison::Ge, 2),
+                ('>', _) => (Comparison::Gt, 1),
+                _ => return Err(invalid("unexpected !".to_string())),
+            };
+            tokens.push(Token::Compare(comparison));
+            i += width;
+        } else if c.is_ascii_digit()
+            || ((c == '-' || c == '.') && next.is_some_and(|n| n.is_ascii_digit() || n == '.'))
+        {
+            let start = i;
+            i += 1;
+            while i < chars.len()
+                && (chars[i].is_ascii_alphanumeric()
+                    || chars[i] == '.'
+                    || (matches!(chars[i], '+' | '-') && matches!(chars[i - 1], 'e' | 'E')))
+            {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let number = text
+                .parse::<f64>()
+                .ok()
+                .filter(|n| n.is_finite())
+                .ok_or_else(|| invalid(format!("invalid number {text}")))?;
+            tokens.push(Token::Number(number));
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(Token::Word(chars[start..i].iter().collect()));
+        } else if c == '"' {
+            let mut name = String::new();
+            i += 1;
+            loop {
+                match (chars.get(i), chars.get(i + 1)) {
+                    (Some('"'), Some('"')) => {
+                        name.push('"');
+                        i += 2;
+                    }
+                    (Some('"'), _) => break,
+                    (Some(c), _) => {
+                        name.push(*c);
+                        i += 1;
+                    }
+                    (None, _) => return Err(invalid("unterminated quoted name".to_string())),
+                }
+            }
+            tokens.push(Token::Quoted(name));
+            i += 1;
+        } else if c == '\'' {
+            return Err(invalid("string literals are not supported".to_string()));
+        } else {
+            return Err(invalid(format!("unexpected character {c}")));
+        }
+    }
+    Ok(tokens)
+}
+
+// === PARSER ===
+/// What a condition compares.
+#[derive(Debug, Clone)]
+enum Operand {
+    Column(String),
+    Aggregate(Statistic),
+}
+
+struct Select {
+    outputs: Vec<(String, Output)>,
+    filter: Option<Condition<String>>,
+    group_by: Vec<String>,
+    /// Aggregates of the select list and of `HAVING`, without repeats.
+    statistics: Vec<Statistic>,
+    having: Option<Condition<usize>>,
+}
+
+impl Select {
+    fn statistic(&mut self, statistic: Statistic) -> usize {
+        match self.statistics.iter().position(|s| *s == statistic) {
+            Some(i) => i,
+            None => {
+                self.statistics.push(statistic);
+                self.statistics.len() - 1
+            }
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn new(tokens: Vec<Token>) -> Self {
+        Self {
+            tokens,
+            position: 0,
+            depth: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.peek() == Some(token);
+        self.position += found as usize;
+        found
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.peek().is_some_and(|t| t.is_keyword(keyword));
+        self.position += found as usize;
+        found
+    }
+
+    fn expect(&mut self, token: &Token, what: &str) -> Result<(), EnclaveError> {
+        if self.eat(token) {
+            return Ok(());
+        }
+        Err(self.unexpected(what))
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), EnclaveError> {
+        if self.eat_keyword(keyword) {
+            return Ok(());
+        }
+        Err(self.unexpected(&keyword.to_uppercase()))
+    }
+
+    fn unexpected(&self, expected: &str) -> EnclaveError {
+        match self.peek() {
+            Some(token) => invalid(format!("expected {expected}, found {token:?}")),
+            None => invalid(format!("expected {expected} at the end of the query")),
+        }
+    }
+
+    fn select(mut self) -> Result<Select, EnclaveError> {
+        let mut select = Select {
+            outputs: vec![],
+            filter: None,
+            group_by: vec![],
+            statistics: vec![],
+            having: None,
+        };
+
+        // SELECT list, grouped columns resolved once GROUP BY is read
+        self.expect_keyword("select")?;
+        let mut items = vec![];
+        loop {
+            if self.eat(&Token::Star) {
+                return Err(invalid(
+                    "SELECT * would release raw rows, select aggregates".to_string(),
+                ));
+            }
+            let (operand, text) = self.operand()?;
+            let name = match self.eat_keyword("as") {
+                true => self.name()?,
+                false => text,
+            };
+            items.push((name, operand));
+            if !self.eat(&Token::Comma) {
+                break;
+            }
+        }
+
+        self.expect_keyword("from")?;
+        if !self.eat_keyword("pool") {
+            return Err(self.unexpected("the pool table"));
+        }
+        if self.eat_keyword("where") {
+            let condition = self.condition()?;
+            select.filter = Some(condition.try_map(&mut |operand: &Operand| match operand {
+                Operand::Column(name) => Ok(name.clone()),
+                Operand::Aggregate(_) => Err(invalid(
+                    "WHERE compares columns, use HAVING for aggregates".to_string(),
+                )),
+            })?);
+        }
+        if self.eat_keyword("group") {
+            self.expect_keyword("by")?;
+            loop {
+                let column = self.name()?;
+                if select.group_by.contains(&column) {
+                    return Err(invalid(format!("{column} is grouped twice")));
+                }
+                select.group_by.push(column);
+                if !self.eat(&Token::Comma) {
+                    break;
+                }
+            }
+        }
+        for (name, operand) in items {
+            let output = match operand {
+                Operand::Aggregate(statistic) => Output::Statistic(select.statistic(statistic)),
+                Operand::Column(column) => {
+                    match select.group_by.iter().position(|c| *c == column) {
+                        Some(i) => Output::Key(i),
+                        None => {
+                            return Err(invalid(format!(
+                                "{column} is neither aggregated nor grouped by, \
+                             which would release raw rows"
+                            )))
+                        }
+                    }
+                }
+            };
+            select.outputs.push((name, output));
+        }
+        if self.eat_keyword("having") {
+            let condition = self.condition()?;
+            select.having = Some(condition.try_map(&mut |operand: &Operand| match operand {
+                Operand::Aggregate(statistic) => Ok(select.statistic(statistic.clone())),
+                Operand::Column(_) => Err(invalid("HAVING compares aggregates".to_string())),
+            })?);
+        }
+        self.eat(&Token::Semicolon);
+        if self.peek().is_some() {
+            return Err(self.unexpected("the end of the query"));
+        }
+        Ok(select)
+    }
+
+    /// A column or quoted name.
+    fn name(&mut self) -> Result<String, EnclaveError> {
+        match self.next() {
+            Some(Token::Quoted(name)) => Ok(name),
+            Some(Token::Word(word)) if !RESERVED.iter().any(|r| word.eq_ignore_ascii_case(r)) => {
+                Ok(word)
+            }
+            _ => {
+                self.position -= 1;
+                Err(self.unexpected("a column name"))
+            }
+        }
+    }
+
+    /// A column or aggregate, with its text as a default output name.
+    fn operand(&mut self) -> Result<(Operand, String), EnclaveError> {
+        let function = match (self.peek(), self.tokens.get(self.position + 1)) {
+            (Some(Token::Word(word)), Some(Token::Open)) => word.to_lowercase(),
+            _ => {
+                let name = self.name()?;
+                return Ok((Operand::Column(name.clone()), name));
+            }
+        };
+        self.position += 2;
+        let (statistic, text) = match function.as_str() {
+            "count" => {
+                self.expect(&Token::Star, "COUNT(*)")?;
+                (Statistic::Count, "count(*)".to_string())
+            }
+            "sum" | "avg" => {
+                let column = self.name()?;
+                let text = format!("{function}({column})");
+                match function.as_str() {
+                    "sum" => (Statistic::Sum { column }, text),
+                    _ => (Statistic::Mean { column }, text),
+                }
+            }
+            "corr" => {
+                let x = self.name()?;
+                self.expect(&Token::Comma, ",")?;
+                let y = self.name()?;
+                let text = format!("corr({x}, {y})");
+                (Statistic::Correlation { x, y }, text)
+            }
+            "min" | "max" => {
+                return Err(invalid(format!(
+                    "{} would release a single row's value",
+                    function.to_uppercase()
+                )))
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "unknown aggregate {function}, use COUNT(*), SUM, AVG or CORR"
+                )))
+            }
+        };
+        self.expect(&Token::Close, ")")?;
+        Ok((Operand::Aggregate(statistic), text))
+    }
+
+    fn condition(&mut self) -> Result<Condition<Operand>, EnclaveError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err(invalid("conditions are nested too deeply".to_string()));
+        }
+        let mut condition = self.conjunction()?;
+        while self.eat_keyword("or") {
+            condition = Condition::Or(Box::new(condition), Box::new(self.conjunction()?));
+        }
+        self.depth -= 1;
+        Ok(condition)
+    }
+
+    fn conjunction(&mut self) -> Result<Condition<Operand>, EnclaveError> {
+        let mut condition = self.negation()?;
+        while self.eat_keyword("and") {
+            condition = Condition::And(Box::new(condition), Box::new(self.negation()?));
+        }
+        Ok(condition)
+    }
+
+    fn negation(&mut self) -> Result<Condition<Operand>, EnclaveError> {
+        if self.eat_keyword("not") {
+            self.depth += 1;
+            if self.depth > MAX_DEPTH {
+                return Err(invalid("conditions are nested too deeply".to_string()));
+            }
+            let condition = Condition::Not(Box::new(self.negation()?));
+            self.depth -= 1;
+            return Ok(condition);
+        }
+        if self.eat(&Token::Open) {
+            let condition = self.condition()?;
+            self.expect(&Token::Close, ")")?;
+            return Ok(condition);
+        }
+        self.comparison()
+    }
+
+    /// `operand op number`, `number op operand`, `operand IS [NOT] NULL`,
+    /// `operand [NOT] BETWEEN a AND b` or `operand [NOT] IN (a, ...)`.
+    fn comparison(&mut self) -> Result<Condition<Operand>, EnclaveError> {
+        if let Some(Token::Number(literal)) = self.peek().cloned() {
+            self.position += 1;
+            let Some(Token::Compare(comparison)) = self.next() else {
+                self.position -= 1;
+                return Err(self.unexpected("a comparison"));
+            };
+            let (operand, _) = self.operand()?;
+            return Ok(Condition::Compare(operand, flip(comparison), literal));
+        }
+        let (operand, _) = self.operand()?;
+        if self.eat_keyword("is") {
+            let negated = self.eat_keyword("not");
+            self.expect_keyword("null")?;
+            let condition = Condition::IsNull(operand);
+            return Ok(match negated {
+                true => Condition::Not(Box::new(condition)),
+                false => condition,
+            });
+        }
+        let negated = self.eat_keyword("not");
+        let condition = if self.eat_keyword("between") {
+            let low = self.number()?;
+            self.expect_keyword("and")?;
+            let high = self.number()?;
+            Condition::And(
+                Box::new(Condition::Compare(operand.clone(), Comparison::Ge, low)),
+                Box::new(Condition::Compare(operand, Comparison::Le, high)),
+            )
+        } else if self.eat_keyword("in") {
+            self.expect(&Token::Open, "(")?;
+            let mut condition = Condition::Compare(operand.clone(), Comparison::Eq, self.number()?);
+            while self.eat(&Token::Comma) {
+                let next = Condition::Compare(operand.clone(), Comparison::Eq, self.number()?);
+                condition = Condition::Or(Box::new(condition), Box::new(next));
+            }
+            self.expect(&Token::Close, ")")?;
+            condition
+        } else if negated {
+            return Err(self.unexpected("BETWEEN or IN"));
+        } else {
+            let Some(Token::Compare(comparison)) = self.next() else {
+                self.position -= 1;
+                return Err(self.unexpected("a comparison"));
+            };
+            Condition::Compare(operand, comparison, self.number()?)
+        };
+        Ok(match negated {
+            true => Condition::Not(Box::new(condition)),
+            false => condition,
+        })
+    }
+
+    fn number(&mut self) -> Result<f64, EnclaveError> {
+        match self.next() {
+            Some(Token::Number(number)) => Ok(number),
+            _ => {
+                self.position -= 1;
+                Err(self.unexpected("a number"))
+            }
+        }
+    }
+}
+
+/// The comparison with its sides swapped, `a < x` being `x > a`.
+fn flip(comparison: Comparison) -> Comparison {
+    match comparison {
+        Comparison::Lt => Comparison::Gt,
+        Comparison::Le => Comparison::Ge,
+        Comparison::Gt => Comparison::Lt,
+        Comparison::Ge => Comparison::Le,
+        c => c,
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Versioned model container uploaded to Walrus after training.
+//!
+//! The encoding is `ARTIFACT_MAGIC || bcs(ModelArtifact)`, with floats stored
+//! as their bit patterns, so the bytes are canonical and `model_hash` in the
+//! signed response can be recomputed by anyone holding the artifact.
+//!
+//! `model_hash` covers the whole container, the training enclave included.
+//! The ephemeral key and the timestamp differ between runs, so
+//! reproducibility is checked with `training_hash`, which leaves them out but
+//! keeps the PCRs: two boots of the same enclave image training on the same
+//! inputs with the same seed report the same `training_hash`, and another
+//! image does not.
+
+use super::model::{Mlp, ModelConfig};
+use super::preprocess::Pipeline;
+use crate::common::f32_bits;
+use crate::EnclaveError;
+use fastcrypto::hash::{HashFunction, Sha256};
+use serde::{Deserialize, Serialize};
+
+/// Leading bytes of every encoded artifact.
+pub const ARTIFACT_MAGIC: &[u8; 4] = b"CLKX";
+
+/// Current container version. Bump when the layout of `ModelArtifact` changes.
+pub const ARTIFACT_VERSION: u16 = 1;
+
+/// How a raw input feature is encoded.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FeatureKind {
+    Numeric,
+    /// Category codes, one-hot encoded by the preprocessing.
+    Categorical,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FeatureSchema {
+    pub name: String,
+    pub kind: FeatureKind,
+}
+
+/// Names and kinds of the inputs the model takes. It describes no values
+/// of the training data, which only reach buyers through the fitted
+/// preprocessing the model needs anyway.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct InputSchema {
+    pub features: Vec<FeatureSchema>,
+    pub num_classes: u64,
+}
+
+impl InputSchema {
+    pub fn new(config: &ModelConfig, pipeline: &Pipeline) -> Self {
+        let features = pipeline
+            .columns
+            .iter()
+            .enumerate()
+            .map(|(i, column)| FeatureSchema {
+                name: config
+                    .feature_names
+                    .get(i)
+                    .cloned()
+                    .unwrap_or_else(|| format!("f{i}")),
+                kind: match column.categories.is_empty() {
+                    true => FeatureKind::Numeric,
+                    false => FeatureKind::Categorical,
+                },
+            })
+            .collect();
+        Self {
+            features,
+            num_classes: config.output_size as u64,
+        }
+    }
+}
+
+/// Hyperparameters the model was trained with.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hyperparameters {
+    #[serde(with = "f32_bits")]
+    pub learning_rate: f32,
+    pub epochs: u64,
+    /// Seed of every random draw made during training.
+    pub seed: u64,
+    /// Index of the search trial that produced the model, 0 without search.
+    pub trial: u64,
+}
+
+/// Which data the model was trained on.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Provenance {
+    pub data_blob_ids: Vec<String>,
+    /// See [`dataset_digest`].
+    pub dataset_digest: Vec<u8>,
+}
+
+/// Which enclave trained the model, and when.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EnclaveInfo {
+    /// PCR0..2 of the enclave that trained the model, empty outside an enclave.
+    pub pcrs: Vec<Vec<u8>>,
+    /// Ephemeral public key of the enclave that signed the training response.
+    pub public_key: Vec<u8>,
+    pub trained_at_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelArtifact {
+    pub version: u16,
+    pub config: ModelConfig,
+    pub input_schema: InputSchema,
+    /// Preprocessing fitted on the training split, applied before `network`.
+    pub preprocessing: Pipeline,
+    pub hyperparameters: Hyperparameters,
+    pub provenance: Provenance,
+    /// Rows `/predict` may answer for this model.
+    pub query_budget: u64,
+    /// Trained on a pool that only releases aggregates: `/predict` answers
+    /// with class counts instead of per-row predictions.
+    pub aggregates_only: bool,
+    /// Fewest rows `/predict` answers at once when `aggregates_only`, the
+    /// pool's `min_group_size`, so class counts never describe a few rows.
+    pub min_batch_size: u64,
+    pub network: Mlp,
+    pub enclave: EnclaveInfo,
+}
+
+impl ModelArtifact {
+    /// Canonical encoding of the artifact.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = ARTIFACT_MAGIC.to_vec();
+        bytes.extend(bcs::to_bytes(self).expect("should not fail"));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, EnclaveError> {
+        let body = bytes
+            .strip_prefix(ARTIFACT_MAGIC.as_slice())
+            .ok_or_else(|| EnclaveError::InvalidInput("not a model artifact".to_string()))?;
+        // `version` is the first field, so it can be checked before decoding the rest.
+        let version = body
+            .get(..2)
+            .map(|v| u16::from_le_bytes([v[0], v[1]]))
+            .ok_or_else(|| EnclaveError::InvalidInput("truncated model artifact".to_string()))?;
+        if version != ARTIFACT_VERSION {
+            return Err(EnclaveError::InvalidInput(format!(
+                "unsupported model artifact version {version}"
+            )));
+        }
+        bcs::from_bytes(body)
+            .map_err(|e| EnclaveError::InvalidInput(format!("invalid model artifact: {e}")))
+    }
+
+    /// Sha256 over the canonical encoding, reported as `model_hash`.
+    pub fn digest(&self) -> Vec<u8> {
+        Sha256::digest(self.to_bytes()).digest.to_vec()
+    }
+
+    /// Sha256 over the canonical encoding without the enclave's public key
+    /// and timestamp, reported as `training_hash`. bcs encodes a tuple like
+    /// the struct, so this is the artifact encoding with the trailing
+    /// `public_key` and `trained_at_ms` bytes cut off.
+    pub fn training_digest(&self) -> Vec<u8> {
+        let mut bytes = ARTIFACT_MAGIC.to_vec();
+        let fields = (
+            &self.version,
+            &self.config,
+            &self.input_schema,
+            &self.preprocessing,
+            &self.hyperparameters,
+            &self.provenance,
+            &self.query_budget,
+            &self.aggregates_only,
+            &self.min_batch_size,
+            &self.network,
+            &self.enclave.pcrs,
+        );
+        bytes.extend(bcs::to_bytes(&fields).expect("should not fail"));
+        Sha256::digest(bytes).digest.to_vec()
+    }
+}
+
+/// Sha256 of a downloaded data blob.
+pub fn blob_digest(bytes: &[u8]) -> Vec<u8> {
+    Sha256::digest(bytes).digest.to_vec()
+}
+
+/// Sha256 over the bcs encoded list of `(blob_id, blob_digest)`, in request order.
+pub fn dataset_digest(blobs: &[(String, Vec<u8>)]) -> Vec<u8> {
+    Sha256::digest(bcs::to_bytes(blobs).expect("should not fail"))
+        .digest
+        .to_vec()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apps::mltraining::preprocess::PreprocessingConfig;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+    use serde_json::json;
+
+    fn artifact() -> ModelArtifact {
+        let config: ModelConfig = serde_json::from_value(json!({
+            "input_size": 2,
+            "output_size": 2,
+            "layers": [{"neurons": 3, "activation": "relu"}],
+        }))
+        .unwrap();
+        let mut rng = ChaCha20Rng::seed_from_u64(3);
+        let network = Mlp::new(&config, 2, &mut rng).unwrap();
+        let rows = vec![vec![0.0, 1.0], vec![1.0, 0.0]];
+        let pipeline = PreprocessingConfig::default().fit(2, &rows).unwrap();
+        ModelArtifact {
+            version: ARTIFACT_VERSION,
+            input_schema: InputSchema::new(&config, &pipeline),
+            config,
+            preprocessing: pipeline,
+            hyperparameters: Hyperparameters {
+                learning_rate: 0.01,
+                epochs: 1,
+                seed: 3,
+                trial: 0,
+            },
+            provenance: Provenance {
+                data_blob_ids: vec!["blob".to_string()],
+                dataset_digest: vec![1; 32],
+            },
+            query_budget: 10,
+            aggregates_only: false,
+            min_batch_size: 1,
+            network,
+            enclave: EnclaveInfo {
+                pcrs: vec![vec![0; 48]; 3],
+                public_key: vec![7; 32],
+                trained_at_ms: 1_700_000_000_000,
+            },
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let artifact = artifact();
+        let decoded = ModelArtifact::from_bytes(&artifact.to_bytes()).unwrap();
+        assert_eq!(decoded.digest(), artifact.digest());
+    }
+
+    #[test]
+    fn test_training_digest_keeps_the_pcrs_only() {
+        let artifact = artifact();
+        let mut rebooted = artifact.clone();
+        rebooted.enclave.public_key = vec![8; 32];
+        rebooted.enclave.trained_at_ms += 1;
+        assert_eq!(rebooted.training_digest(), artifact.training_digest());
+        assert_ne!(rebooted.digest(), artifact.digest());
+
+        let mut other_image = artifact.clone();
+        other_image.enclave.pcrs[0][0] = 1;
+        assert_ne!(other_image.training_digest(), artifact.training_digest());
+    }
+
+    #[test]
+    fn test_training_digest_is_a_prefix_hash() {
+        let artifact = artifact();
+        let bytes = artifact.to_bytes();
+        let enclave = bcs::to_bytes(&(
+            &artifact.enclave.public_key,
+            &artifact.enclave.trained_at_ms,
+        ))
+        .unwrap();
+        let prefix = &bytes[..bytes.len() - enclave.len()];
+        assert_eq!(
+            Sha256::digest(prefix).digest.to_vec(),
+            artifact.training_digest()
+        );
+    }
+}
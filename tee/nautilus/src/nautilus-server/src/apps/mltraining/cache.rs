@@ -0,0 +1,298 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Content-addressed cache of verified blobs.
+//!
+//! Blobs are stored under their Walrus blob id, which commits to their
+//! content, only after the downloaded bytes were checked against it. Entries
+//! are encrypted in the chunked format under a key drawn at boot, so they are
+//! unreadable outside this enclave run. A retried job finds its blobs here
+//! instead of downloading them again.
+
+use super::integrity::{check_blob_size, verify_blob_id};
+use super::stream::{ChunkedDecryptor, ChunkedEncryptor};
+use crate::EnclaveError;
+use fastcrypto::encoding::{Encoding, Hex};
+use fastcrypto::hash::{HashFunction, Sha256};
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Environment variable naming the cache directory, a tmpfs mount in the
+/// enclave by default. Debug builds only: the directory is wiped at boot, and
+/// the host sets the environment of a release enclave.
+pub const CACHE_DIR_ENV: &str = "BLOB_CACHE_DIR";
+
+/// Cache size above which the least recently used blobs not held by a job
+/// are evicted.
+pub const MAX_CACHE_BYTES: u64 = 4 << 30;
+
+const CACHE_CHUNK_SIZE: usize = 1 << 16;
+
+lazy_static! {
+    static ref CACHE: BlobCache = BlobCache::new();
+}
+
+struct BlobCache {
+    dir: PathBuf,
+    key: [u8; 32],
+    index: Mutex<CacheIndex>,
+}
+
+#[derive(Default)]
+struct CacheIndex {
+    entries: HashMap<String, Entry>,
+    bytes: u64,
+    clock: u64,
+}
+
+struct Entry {
+    file: PathBuf,
+    size: u64,
+    digest: Vec<u8>,
+    last_used: u64,
+    /// Jobs currently holding the entry.
+    pins: usize,
+}
+
+impl BlobCache {
+    fn new() -> Self {
+        let dir = match std::env::var(CACHE_DIR_ENV) {
+            Ok(dir) if cfg!(debug_assertions) => PathBuf::from(dir),
+            _ => std::env::temp_dir().join("cloakx-blob-cache"),
+        };
+        // Entries of an earlier run are encrypted under a lost key
+        let _ = std::fs::remove_dir_all(&dir);
+        Self {
+            dir,
+            key: rand::thread_rng().gen(),
+            index: Mutex::new(CacheIndex::default()),
+        }
+    }
+}
+
+/// The cached blob `blob_id`, if present.
+pub fn lookup(blob_id: &str) -> Option<CachedBlob> {
+    let mut index = CACHE.index.lock().expect("poisoned lock");
+    index.clock += 1;
+    let clock = index.clock;
+    let entry = index.entries.get_mut(blob_id)?;
+    entry.last_used = clock;
+    entry.pins += 1;
+    Some(CachedBlob {
+        blob_id: blob_id.to_string(),
+        file: entry.file.clone(),
+        size: entry.size,
+        digest: entry.digest.clone(),
+    })
+}
+
+/// A blob pinned in the cache for as long as it is held.
+#[derive(Debug)]
+pub struct CachedBlob {
+    pub blob_id: String,
+    file: PathBuf,
+    /// Plaintext length.
+    pub size: u64,
+    /// Sha256 of the blob bytes.
+    pub digest: Vec<u8>,
+}
+
+impl CachedBlob {
+    /// Call `out` with the blob bytes, in order. An entry that fails to
+    /// authenticate was modified outside the enclave and fails the read.
+    pub fn read<F>(&self, mut out: F) -> Result<(), EnclaveError>
+    where
+        F: FnMut(&[u8]) -> Result<(), EnclaveError>,
+    {
+        read_entry(&self.file, &self.blob_id, |bytes| out(bytes).map(|()| true))
+    }
+
+    /// The first `len` bytes of the blob, or all of it if shorter.
+    pub fn head(&self, len: usize) -> Result<Vec<u8>, EnclaveError> {
+        let mut head = vec![];
+        read_entry(&self.file, &self.blob_id, |bytes| {
+            head.extend_from_slice(bytes);
+            Ok(head.len() < len)
+        })?;
+        head.truncate(len);
+        Ok(head)
+    }
+
+    /// The whole blob in memory.
+    pub fn to_vec(&self) -> Result<Vec<u8>, EnclaveError> {
+        read_entry_to_vec(&self.file, &self.blob_id, self.size)
+    }
+}
+
+/// Call `out` with the entry's bytes until it returns `false`.
+fn read_entry<F>(file: &Path, blob_id: &str, mut out: F) -> Result<(), EnclaveError>
+where
+    F: FnMut(&[u8]) -> Result<bool, EnclaveError>,
+{
+    let modified = |e| match e {
+        EnclaveError::InvalidInput(_) => {
+            EnclaveError::GenericError(format!("cache entry of {blob_id} was modified"))
+        }
+        e => e,
+    };
+    let mut file = File::open(file)?;
+    let mut decryptor = ChunkedDecryptor::new(CACHE.key, blob_id.as_bytes());
+    let mut buffer = vec![0u8; CACHE_CHUNK_SIZE];
+    let more = Cell::new(true);
+    let mut emit = |bytes: &[u8]| {
+        if more.get() {
+            more.set(out(bytes)?);
+        }
+        Ok(())
+    };
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        decryptor.push(&buffer[..n], &mut emit).map_err(modified)?;
+        if !more.get() {
+            return Ok(());
+        }
+    }
+    decryptor.finish(&mut emit).map_err(modified)
+}
+
+fn read_entry_to_vec(file: &Path, blob_id: &str, size: u64) -> Result<Vec<u8>, EnclaveError> {
+    let mut bytes = Vec::with_capacity(size as usize);
+    read_entry(file, blob_id, |chunk| {
+        bytes.extend_from_slice(chunk);
+        Ok(true)
+    })?;
+    Ok(bytes)
+}
+
+impl Drop for CachedBlob {
+    fn drop(&mut self) {
+        let mut index = CACHE.index.lock().expect("poisoned lock");
+        if let Some(entry) = index.entries.get_mut(&self.blob_id) {
+            entry.pins -= 1;
+        }
+    }
+}
+
+/// Writes a downloaded blob to the cache. Nothing is visible to lookups
+/// until [`CacheWriter::commit`].
+pub struct CacheWriter {
+    blob_id: String,
+    file: PathBuf,
+    writer: BufWriter<File>,
+    encryptor: ChunkedEncryptor,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl CacheWriter {
+    pub fn new(blob_id: &str) -> Result<Self, EnclaveError> {
+        std::fs::create_dir_all(&CACHE.dir)?;
+        let file = CACHE.dir.join(uuid::Uuid::new_v4().to_string());
+        let mut writer = BufWriter::new(File::create(&file)?);
+        let (encryptor, header) =
+            ChunkedEncryptor::new(CACHE.key, blob_id.as_bytes(), CACHE_CHUNK_SIZE);
+        writer.write_all(&header)?;
+        Ok(Self {
+            blob_id: blob_id.to_string(),
+            file,
+            writer,
+            encryptor,
+            hasher: Sha256::default(),
+            size: 0,
+        })
+    }
+
+    /// Append `bytes`, failing once the blob is over `MAX_BLOB_BYTES`.
+    pub fn write(&mut self, bytes: &[u8]) -> Result<(), EnclaveError> {
+        check_blob_size(self.size + bytes.len() as u64)?;
+        self.hasher.update(bytes);
+        self.size += bytes.len() as u64;
+        let writer = &mut self.writer;
+        self.encryptor
+            .push(bytes, &mut |sealed| Ok(writer.write_all(sealed)?))
+    }
+
+    /// Finish the blob and insert it if its bytes encode to its blob id.
+    /// They are only read back into memory for the check, under its lock.
+    pub fn commit(self) -> Result<CachedBlob, EnclaveError> {
+        let CacheWriter {
+            blob_id,
+            file,
+            mut writer,
+            encryptor,
+            hasher,
+            size,
+        } = self;
+        let checked = encryptor
+            .finish(&mut |sealed| Ok(writer.write_all(sealed)?))
+            .and_then(|()| Ok(writer.flush()?))
+            .and_then(|()| verify_blob_id(&blob_id, || read_entry_to_vec(&file, &blob_id, size)));
+        if let Err(e) = checked {
+            let _ = std::fs::remove_file(&file);
+            return Err(e);
+        }
+        let digest = hasher.finalize().digest.to_vec();
+
+        let mut index = CACHE.index.lock().expect("poisoned lock");
+        index.clock += 1;
+        let clock = index.clock;
+        if index.entries.contains_key(&blob_id) {
+            // Fetched concurrently by another job, keep the first copy
+            let _ = std::fs::remove_file(&file);
+        } else {
+            let cached = CACHE
+                .dir
+                .join(Hex::encode(Sha256::digest(blob_id.as_bytes()).digest));
+            std::fs::rename(&file, &cached)?;
+            index.bytes += size;
+            index.entries.insert(
+                blob_id.clone(),
+                Entry {
+                    file: cached,
+                    size,
+                    digest,
+                    last_used: clock,
+                    pins: 0,
+                },
+            );
+        }
+        let entry = index.entries.get_mut(&blob_id).expect("entry exists");
+        entry.last_used = clock;
+        entry.pins += 1;
+        let blob = CachedBlob {
+            blob_id,
+            file: entry.file.clone(),
+            size: entry.size,
+            digest: entry.digest.clone(),
+        };
+        evict(&mut index);
+        Ok(blob)
+    }
+}
+
+/// Drop unpinned entries, least recently used first, until the cache fits.
+fn evict(index: &mut CacheIndex) {
+    while index.bytes > MAX_CACHE_BYTES {
+        let Some(blob_id) = index
+            .entries
+            .iter()
+            .filter(|(_, e)| e.pins == 0)
+            .min_by_key(|(_, e)| e.last_used)
+            .map(|(id, _)| id.clone())
+        else {
+            return;
+        };
+        let entry = index.entries.remove(&blob_id).expect("entry exists");
+        let _ = std::fs::remove_file(&entry.file);
+        index.bytes -= entry.size;
+    }
+}
@@ -0,0 +1,452 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Read access to the CloakX objects on Sui.
+//!
+//! The enclave reads the `cloakx::jobs::Job` a request claims to run, and the
+//! pool it trains on, straight from a full node over JSON-RPC. The node and
+//! the table ids come from `sui_config.yaml`, which is compiled into the
+//! image. TLS ends inside the enclave, so the host's proxy cannot alter the
+//! answers. Checks take any [`SuiReader`], so tests can back them with fixed
+//! objects, or run them through an [`RpcReader`] pointed at a local node
+//! serving the same objects.
+
+use crate::EnclaveError;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::future::Future;
+use std::time::Duration;
+
+/// Environment variable naming a config to use instead of the measured
+/// `sui_config.yaml`, e.g. to point at a local JSON-RPC stand-in. Debug
+/// builds only: the host sets the environment of a release enclave.
+pub const SUI_CONFIG_ENV: &str = "SUI_CONFIG";
+
+const RPC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `sui_config.yaml`.
+#[derive(Deserialize, Debug)]
+pub struct SuiConfig {
+    pub rpc_url: String,
+    /// `JobRegistry.jobs`, job id to `Job`.
+    pub jobs_table_id: String,
+    /// `PoolRegistry.pools`, pool id to `Pool`.
+    pub pools_table_id: String,
+    /// `PoolRegistry.pool_users`, pool id to contributor addresses.
+    pub pool_users_table_id: String,
+    /// `PoolRegistry.pool_data`, pool id to data blob ids.
+    pub pool_data_table_id: String,
+}
+
+/// The fields of a `cloakx::jobs::Job` the enclave checks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnChainJob {
+    pub job_id: u64,
+    pub pool_id: u64,
+    /// `model_wid`, the model config blob id.
+    pub model_config_blob_id: String,
+    pub epochs: u64,
+    /// Fixed point, see `LEARNING_RATE_SCALE`.
+    pub learning_rate: u64,
+    /// Variant name of `JobStatus`, e.g. `Pending`.
+    pub status: String,
+    /// Key the buyer receives encrypted outputs under.
+    #[cfg_attr(not(feature = "synthetic"), allow(dead_code))]
+    pub buyer_public_key: Vec<u8>,
+}
+
+/// The state of a pool the enclave checks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OnChainPool {
+    pub active: bool,
+    /// `Pool.metadata`, holding its usage policy.
+    pub metadata: Vec<u8>,
+    /// `pool_data` entry, in contribution order.
+    pub data_blob_ids: Vec<String>,
+    /// Address that contributed each of `data_blob_ids`, from `pool_users`.
+    pub contributors: Vec<String>,
+}
+
+/// Source of the on-chain jobs and pools.
+pub trait SuiReader: Sync {
+    /// Job `job_id`, a `JobMismatch` if it does not exist.
+    fn job(&self, job_id: u64) -> impl Future<Output = Result<OnChainJob, EnclaveError>> + Send;
+
+    /// Pool `pool_id` with its data and contributors, a `JobMismatch` if it
+    /// does not exist.
+    fn pool(&self, pool_id: u64) -> impl Future<Output = Result<OnChainPool, EnclaveError>> + Send;
+}
+
+/// JSON-RPC client of a Sui full node.
+pub struct RpcReader {
+    client: reqwest::Client,
+    config: SuiConfig,
+}
+
+impl RpcReader {
+    pub fn from_config() -> Result<Self, EnclaveError> {
+        let text = match std::env::var(SUI_CONFIG_ENV) {
+            Ok(path) if cfg!(debug_assertions) => std::fs::read_to_string(path)?,
+            _ => include_str!("sui_config.yaml").to_string(),
+        };
+        let config = serde_yaml::from_str(&text)
+            .map_err(|e| EnclaveError::GenericError(format!("invalid sui config: {e}")))?;
+        let client = reqwest::Client::builder()
+            .timeout(RPC_TIMEOUT)
+            .build()
+            .map_err(|e| EnclaveError::Network(e.to_string()))?;
+        Ok(Self { client, config })
+    }
+
+    /// Value of the `u64` key of a `Table`, `None` if absent.
+    async fn table_entry(&self, table_id: &str, key: u64) -> Result<Option<Value>, EnclaveError> {
+        let response = self
+            .call(
+                "suix_getDynamicFieldObject",
+                json!([table_id, {"type": "u64", "value": key.to_string()}]),
+            )
+            .await?;
+        if response["error"]["code"] == "dynamicFieldNotFound" {
+            return Ok(None);
+        }
+        let value = &response["data"]["content"]["fields"]["value"];
+        // Struct values are wrapped in their own `fields`
+        Ok(Some(value.get("fields").unwrap_or(value).clone()))
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Result<Value, EnclaveError> {
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+        let response: Value = self
+            .client
+            .post(&self.config.rpc_url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| EnclaveError::Network(e.to_string()))?
+            .json()
+            .await
+            .map_err(|e| EnclaveError::Network(e.to_string()))?;
+        if let Some(error) = response.get("error") {
+            return Err(EnclaveError::Network(format!("{method} failed: {error}")));
+        }
+        Ok(response["result"].clone())
+    }
+}
+
+impl SuiReader for RpcReader {
+    async fn job(&self, job_id: u64) -> Result<OnChainJob, EnclaveError> {
+        let fields = self
+            .table_entry(&self.config.jobs_table_id, job_id)
+            .await?
+            .ok_or_else(|| EnclaveError::JobMismatch(format!("job {job_id} does not exist")))?;
+        let status = &fields["status"];
+        Ok(OnChainJob {
+            job_id,
+            pool_id: u64_field(&fields, "pool_id")?,
+            model_config_blob_id: utf8(&fields["model_wid"])?,
+            epochs: u64_field(&fields, "epochs")?,
+            learning_rate: u64_field(&fields, "learning_rate")?,
+            status: status["variant"]
+                .as_str()
+                .or_else(|| status.as_str())
+                .ok_or_else(|| unexpected("status", status))?
+                .to_string(),
+            buyer_public_key: bytes(&fields["buyer_public_key"])?,
+        })
+    }
+
+    async fn pool(&self, pool_id: u64) -> Result<OnChainPool, EnclaveError> {
+        let missing = || EnclaveError::JobMismatch(format!("pool {pool_id} does not exist"));
+        let pool = self
+            .table_entry(&self.config.pools_table_id, pool_id)
+            .await?
+            .ok_or_else(missing)?;
+        let active = pool["active"]
+            .as_bool()
+            .ok_or_else(|| unexpected("active", &pool["active"]))?;
+        let metadata = bytes(&pool["metadata"])?;
+        let data = self
+            .table_entry(&self.config.pool_data_table_id, pool_id)
+            .await?
+            .ok_or_else(missing)?;
+        let data_blob_ids = data
+            .as_array()
+            .ok_or_else(|| unexpected("pool_data", &data))?
+            .iter()
+            .map(utf8)
+            .collect::<Result<Vec<_>, _>>()?;
+        let users = self
+            .table_entry(&self.config.pool_users_table_id, pool_id)
+            .await?
+            .ok_or_else(missing)?;
+        let contributors: Vec<String> = users
+            .as_array()
+            .and_then(|users| {
+                users
+                    .iter()
+                    .map(|u| u.as_str().map(str::to_string))
+                    .collect()
+            })
+            .ok_or_else(|| unexpected("pool_users", &users))?;
+        // `register_user_data` pushes to both
+        if contributors.len() != data_blob_ids.len() {
+            return Err(unexpected("pool_users", &users));
+        }
+        Ok(OnChainPool {
+            active,
+            metadata,
+            data_blob_ids,
+            contributors,
+        })
+    }
+}
+
+fn unexpected(field: &str, value: &Value) -> EnclaveError {
+    EnclaveError::Network(format!("unexpected {field} from the full node: {value}"))
+}
+
+/// `u64`s are rendered as decimal strings.
+fn u64_field(fields: &Value, name: &str) -> Result<u64, EnclaveError> {
+    fields[name]
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| unexpected(name, &fields[name]))
+}
+
+fn bytes(value: &Value) -> Result<Vec<u8>, EnclaveError> {
+    value
+        .as_array()
+        .and_then(|bytes| {
+            bytes
+                .iter()
+                .map(|b| b.as_u64().and_then(|b| u8::try_from(b).ok()))
+                .collect()
+        })
+        .ok_or_else(|| unexpected("vector<u8>", value))
+}
+
+/// A `vector<u8>` holding UTF-8 text, such as a blob id.
+fn utf8(value: &Value) -> Result<String, EnclaveError> {
+    String::from_utf8(bytes(value)?).map_err(|_| unexpected("UTF-8 text", value))
+}
+
+/// Fixed jobs and pools standing in for a full node in tests.
+#[cfg(test)]
+pub(crate) mod stub {
+    use super::*;
+    use axum::{routing::post, Json, Router};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    pub const JOBS_TABLE: &str = "0xa0";
+    pub const POOLS_TABLE: &str = "0xa1";
+    pub const POOL_USERS_TABLE: &str = "0xa2";
+    pub const POOL_DATA_TABLE: &str = "0xa3";
+
+    /// `(table id, key)` to the `value` of the table entry, as a full node
+    /// renders it.
+    pub type Tables = HashMap<(&'static str, u64), Value>;
+
+    #[derive(Default)]
+    pub struct StubReader {
+        pub jobs: HashMap<u64, OnChainJob>,
+        pub pools: HashMap<u64, OnChainPool>,
+    }
+
+    impl SuiReader for StubReader {
+        async fn job(&self, job_id: u64) -> Result<OnChainJob, EnclaveError> {
+            self.jobs
+                .get(&job_id)
+                .cloned()
+                .ok_or_else(|| EnclaveError::JobMismatch(format!("job {job_id} does not exist")))
+        }
+
+        async fn pool(&self, pool_id: u64) -> Result<OnChainPool, EnclaveError> {
+            self.pools
+                .get(&pool_id)
+                .cloned()
+                .ok_or_else(|| EnclaveError::JobMismatch(format!("pool {pool_id} does not exist")))
+        }
+    }
+
+    impl StubReader {
+        /// The tables of a full node holding these jobs and pools.
+        pub fn tables(&self) -> Tables {
+            let mut tables = Tables::new();
+            for job in self.jobs.values() {
+                let job_fields = json!({
+                    "pool_id": job.pool_id.to_string(),
+                    "model_wid": job.model_config_blob_id.as_bytes(),
+                    "epochs": job.epochs.to_string(),
+                    "learning_rate": job.learning_rate.to_string(),
+                    "status": {"variant": job.status, "fields": {}},
+                    "buyer_public_key": job.buyer_public_key,
+                });
+                tables.insert(
+                    (JOBS_TABLE, job.job_id),
+                    move_struct("jobs::Job", job_fields),
+                );
+            }
+            for (&pool_id, pool) in &self.pools {
+                let pool_fields = json!({"active": pool.active, "metadata": pool.metadata});
+                let blob_ids: Vec<&[u8]> =
+                    pool.data_blob_ids.iter().map(|id| id.as_bytes()).collect();
+                tables.insert(
+                    (POOLS_TABLE, pool_id),
+                    move_struct("pools::Pool", pool_fields),
+                );
+                tables.insert((POOL_DATA_TABLE, pool_id), json!(blob_ids));
+                tables.insert((POOL_USERS_TABLE, pool_id), json!(pool.contributors));
+            }
+            tables
+        }
+
+        /// An [`RpcReader`] of a local full node holding these jobs and pools.
+        pub async fn serve(&self) -> RpcReader {
+            stand_in(self.tables()).await
+        }
+    }
+
+    fn move_struct(name: &str, fields: Value) -> Value {
+        json!({"type": format!("0xc0::{name}"), "fields": fields})
+    }
+
+    /// A JSON-RPC full node on a local port answering
+    /// `suix_getDynamicFieldObject` from `tables`, and an [`RpcReader`]
+    /// pointed at it.
+    pub async fn stand_in(tables: Tables) -> RpcReader {
+        let tables = Arc::new(tables);
+        let node = move |Json(request): Json<Value>| {
+            let tables = tables.clone();
+            async move {
+                let id = request["id"].clone();
+                if request["method"] != "suix_getDynamicFieldObject" {
+                    let error = json!({"code": -32601, "message": "Method not found"});
+                    return Json(json!({"jsonrpc": "2.0", "id": id, "error": error}));
+                }
+                let params = &request["params"];
+                let entry = params[0].as_str().zip(
+                    params[1]["value"]
+                        .as_str()
+                        .and_then(|key| key.parse::<u64>().ok()),
+                );
+                let value = entry.and_then(|(table, key)| {
+                    tables
+                        .iter()
+                        .find(|((t, k), _)| *t == table && *k == key)
+                        .map(|(_, value)| value)
+                });
+                let result = match value {
+                    Some(value) => json!({"data": {
+                        "objectId": "0xd0",
+                        "version": "1",
+                        "content": {
+                            "dataType": "moveObject",
+                            "type": "0x2::dynamic_field::Field<u64, _>",
+                            "fields": {"id": {"id": "0xd0"}, "name": params[1]["value"], "value": value},
+                        },
+                    }}),
+                    None => json!({"error": {
+                        "code": "dynamicFieldNotFound",
+                        "parent_object_id": params[0],
+                    }}),
+                };
+                Json(json!({"jsonrpc": "2.0", "id": id, "result": result}))
+            }
+        };
+        let app = Router::new().route("/", post(node));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let rpc_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        RpcReader {
+            client: reqwest::Client::new(),
+            config: SuiConfig {
+                rpc_url,
+                jobs_table_id: JOBS_TABLE.to_string(),
+                pools_table_id: POOLS_TABLE.to_string(),
+                pool_users_table_id: POOL_USERS_TABLE.to_string(),
+                pool_data_table_id: POOL_DATA_TABLE.to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::stub::{stand_in, StubReader, JOBS_TABLE, POOLS_TABLE, POOL_USERS_TABLE};
+    use super::*;
+
+    /// A table entry, and how to break it.
+    type Edit = (&'static str, u64, fn(&mut Value));
+
+    fn stub() -> StubReader {
+        let mut stub = StubReader::default();
+        stub.jobs.insert(
+            7,
+            OnChainJob {
+                job_id: 7,
+                pool_id: 3,
+                model_config_blob_id: "config".to_string(),
+                epochs: 10,
+                learning_rate: 100,
+                status: "Pending".to_string(),
+                buyer_public_key: vec![1, 2, 3],
+            },
+        );
+        stub.pools.insert(
+            3,
+            OnChainPool {
+                active: true,
+                metadata: b"{}".to_vec(),
+                data_blob_ids: vec!["a".to_string(), "b".to_string()],
+                contributors: vec!["0x1".to_string(), "0x2".to_string()],
+            },
+        );
+        stub
+    }
+
+    #[tokio::test]
+    async fn test_rpc_reader() {
+        let stub = stub();
+        let reader = stub.serve().await;
+        assert_eq!(reader.job(7).await.unwrap(), stub.jobs[&7]);
+        assert_eq!(reader.pool(3).await.unwrap(), stub.pools[&3]);
+        assert!(matches!(
+            reader.job(8).await,
+            Err(EnclaveError::JobMismatch(_))
+        ));
+        assert!(matches!(
+            reader.pool(4).await,
+            Err(EnclaveError::JobMismatch(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rpc_reader_rejects_unexpected_objects() {
+        let tables = stub().tables();
+        let edits: [Edit; 4] = [
+            (JOBS_TABLE, 7, |job| job["fields"]["epochs"] = json!(10)),
+            (JOBS_TABLE, 7, |job| {
+                job["fields"]["model_wid"] = json!([256])
+            }),
+            (POOLS_TABLE, 3, |pool| {
+                pool["fields"]["active"] = json!("true")
+            }),
+            // A contributor without its blob
+            (POOL_USERS_TABLE, 3, |users| {
+                users.as_array_mut().unwrap().push(json!("0x3"))
+            }),
+        ];
+        for (table, key, edit) in edits {
+            let mut tables = tables.clone();
+            edit(tables.get_mut(&(table, key)).unwrap());
+            let reader = stand_in(tables).await;
+            let read = match table {
+                JOBS_TABLE => reader.job(key).await.map(drop),
+                _ => reader.pool(key).await.map(drop),
+            };
+            assert!(matches!(read, Err(EnclaveError::Network(_))), "{table}");
+        }
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! K-fold cross-validation, for pools too small for a stable validation split.
+//!
+//! Folds are assigned from the job seed. Each fold fits its own preprocessing
+//! on the other folds, so no statistic of the held-out rows leaks into the
+//! model scored on them.
+
+use super::dataset::SpilledDataset;
+use super::model::{Mlp, ModelConfig};
+use super::train::{fold_rng, train};
+use super::METRIC_SCALE;
+use crate::EnclaveError;
+use rand::Rng;
+use serde::Serialize;
+use tracing::info;
+
+/// Upper bound on the folds of one job.
+pub const MAX_FOLDS: u64 = 20;
+
+/// Metrics across folds, signed in `TrainingReport`.
+#[derive(Serialize, Clone, Debug)]
+pub struct CrossValidation {
+    pub folds: u64,
+    /// Accuracy as a fraction, scaled by `METRIC_SCALE`.
+    pub accuracy_mean: u64,
+    pub accuracy_std: u64,
+    /// Cross-entropy loss, scaled by `METRIC_SCALE`.
+    pub loss_mean: u64,
+    pub loss_std: u64,
+}
+
+/// Checks of a cross validation request that need no data.
+pub fn check_folds(config: &ModelConfig, folds: u64) -> Result<(), EnclaveError> {
+    if config.search.is_some() || config.training.early_stopping.is_some() {
+        return Err(EnclaveError::InvalidInput(
+            "cross validation cannot be combined with search or early stopping".to_string(),
+        ));
+    }
+    if !(2..=MAX_FOLDS).contains(&folds) {
+        return Err(EnclaveError::InvalidInput(format!(
+            "cross validation needs between 2 and {MAX_FOLDS} folds"
+        )));
+    }
+    Ok(())
+}
+
+/// Train and score one model per fold of `dataset`.
+pub fn cross_validate<R: Rng>(
+    config: &ModelConfig,
+    learning_rate: f32,
+    dataset: &SpilledDataset,
+    folds: u64,
+    epochs: u64,
+    seed: u64,
+    rng: &mut R,
+) -> Result<CrossValidation, EnclaveError> {
+    check_folds(config, folds)?;
+
+    let mut accuracies = vec![];
+    let mut losses = vec![];
+    for (fold, (training, validation)) in dataset.folds(folds as usize, rng)?.iter().enumerate() {
+        let training = dataset.rows(training);
+        let pipeline = training.fit(&config.preprocessing, config.input_size)?;
+        let validation = dataset.rows(validation).transformed(&pipeline);
+        let mut rng = fold_rng(seed, fold as u64);
+        let mut network = Mlp::new(config, pipeline.output_width(), &mut rng)?;
+        train(
+            &mut network,
+            training.transformed(&pipeline),
+            dataset.rows(&[]),
+            &config.training,
+            learning_rate,
+            epochs,
+            &mut rng,
+        )?;
+        let (accuracy, loss) = validation.evaluate(&network)?;
+        info!("Fold {fold}: Accuracy = {accuracy:.2}%, Loss = {loss:.4}");
+        accuracies.push(accuracy as f64 / 100.0);
+        losses.push(loss as f64);
+    }
+
+    let (accuracy_mean, accuracy_std) = mean_std(&accuracies);
+    let (loss_mean, loss_std) = mean_std(&losses);
+    let scaled = |v: f64| (v * METRIC_SCALE as f64) as u64;
+    Ok(CrossValidation {
+        folds,
+        accuracy_mean: scaled(accuracy_mean),
+        accuracy_std: scaled(accuracy_std),
+        loss_mean: scaled(loss_mean),
+        loss_std: scaled(loss_std),
+    })
+}
+
+/// Mean and population standard deviation.
+fn mean_std(values: &[f64]) -> (f64, f64) {
+    let n = values.len().max(1) as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let var = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+    (mean, var.sqrt())
+}
@@ -0,0 +1,110 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sealing of enclave-only artifacts (e.g. models whose buyers may only query
+//! predictions). Sealed blobs are `iv || AES-256-GCM(ciphertext || tag)` under
+//! the `MODEL_SEALING_KEY` secret, which never leaves enclaves built from the
+//! same image.
+//!
+//! Also holds the raw-key AES-256-GCM primitives used by the chunked data
+//! blob format, the spill buffer and the usage file.
+
+use crate::AppState;
+use crate::EnclaveError;
+use fastcrypto::aes::{Aes256Gcm, AesKey, AuthenticatedCipher, InitializationVector};
+use fastcrypto::encoding::{Encoding, Hex};
+use fastcrypto::traits::{Generate, ToFromBytes};
+use typenum::U12;
+
+/// Name of the secret holding the hex encoded 32 byte sealing key.
+pub const SEALING_KEY_SECRET: &str = "MODEL_SEALING_KEY";
+
+/// Name of the secret holding the hex encoded 32 byte key contributors
+/// encrypt pool data blobs with.
+pub const DATA_KEY_SECRET: &str = "POOL_DATA_KEY";
+
+const IV_LENGTH: usize = 12;
+
+/// Length of the AES-256-GCM tag appended to every ciphertext.
+pub const TAG_LENGTH: usize = 16;
+
+fn secret_key(state: &AppState, name: &str) -> Result<[u8; 32], EnclaveError> {
+    let key_hex = state
+        .secrets
+        .get(name)
+        .ok_or_else(|| EnclaveError::GenericError(format!("{name} is not configured")))?;
+    Hex::decode(key_hex)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| EnclaveError::GenericError(format!("{name} is not a 32 byte hex key")))
+}
+
+fn cipher(key: &[u8; 32]) -> Aes256Gcm {
+    Aes256Gcm::new(AesKey::from_bytes(key).expect("32 byte key"))
+}
+
+fn sealing_cipher(state: &AppState, aad_label: &str) -> Result<(Aes256Gcm, Vec<u8>), EnclaveError> {
+    let key = secret_key(state, SEALING_KEY_SECRET)?;
+    Ok((cipher(&key), aad_label.as_bytes().to_vec()))
+}
+
+/// Key of the encrypted pool data blobs.
+pub fn data_key(state: &AppState) -> Result<[u8; 32], EnclaveError> {
+    secret_key(state, DATA_KEY_SECRET)
+}
+
+/// Key of enclave-only files other than sealed blobs, e.g. the usage file.
+pub fn sealing_key(state: &AppState) -> Result<[u8; 32], EnclaveError> {
+    secret_key(state, SEALING_KEY_SECRET)
+}
+
+/// AES-256-GCM under a raw key. The caller guarantees `nonce` is never reused
+/// with the same key.
+pub fn encrypt_with_key(
+    key: &[u8; 32],
+    nonce: &[u8; IV_LENGTH],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Vec<u8> {
+    let iv = InitializationVector::<U12>::from_bytes(nonce).expect("12 byte nonce");
+    cipher(key).encrypt_authenticated(&iv, aad, plaintext)
+}
+
+/// Decrypt and authenticate a ciphertext of [`encrypt_with_key`].
+pub fn decrypt_with_key(
+    key: &[u8; 32],
+    nonce: &[u8; IV_LENGTH],
+    aad: &[u8],
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, EnclaveError> {
+    let iv = InitializationVector::<U12>::from_bytes(nonce).expect("12 byte nonce");
+    cipher(key)
+        .decrypt_authenticated(&iv, aad, ciphertext)
+        .map_err(|_| EnclaveError::InvalidInput("failed to decrypt chunk".to_string()))
+}
+
+/// Encrypt `plaintext` so that only an enclave holding the sealing key can read it.
+/// `aad_label` binds the ciphertext to its purpose (e.g. `"model"`).
+pub fn seal(state: &AppState, aad_label: &str, plaintext: &[u8]) -> Result<Vec<u8>, EnclaveError> {
+    let (cipher, aad) = sealing_cipher(state, aad_label)?;
+    let iv = InitializationVector::<U12>::generate(&mut rand::thread_rng());
+    let mut out = iv.as_bytes().to_vec();
+    out.extend(cipher.encrypt_authenticated(&iv, &aad, plaintext));
+    Ok(out)
+}
+
+/// Decrypt a blob produced by [`seal`] with the same `aad_label`.
+pub fn unseal(state: &AppState, aad_label: &str, sealed: &[u8]) -> Result<Vec<u8>, EnclaveError> {
+    if sealed.len() < IV_LENGTH {
+        return Err(EnclaveError::InvalidInput(
+            "sealed blob too short".to_string(),
+        ));
+    }
+    let (cipher, aad) = sealing_cipher(state, aad_label)?;
+    let (iv, ciphertext) = sealed.split_at(IV_LENGTH);
+    let iv = InitializationVector::<U12>::from_bytes(iv)
+        .map_err(|e| EnclaveError::InvalidInput(e.to_string()))?;
+    cipher
+        .decrypt_authenticated(&iv, &aad, ciphertext)
+        .map_err(|_| EnclaveError::InvalidInput("failed to unseal blob".to_string()))
+}
@@ -0,0 +1,484 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rows of a job.
+//!
+//! The rows that pass the quality checks stay in the encrypted spill they
+//! were checked in, see [`super::spill`], with only their labels and shards
+//! in memory. Splits and folds are sets of row indices, and training,
+//! evaluation and preprocessing read the inputs through [`Rows`], a window of
+//! segments at a time, so a pool is never decrypted in full.
+
+use super::model::Mlp;
+use super::preprocess::{ColumnStats, Pipeline, PreprocessingConfig};
+use super::spill::{SpillBuffer, SEGMENT_ROWS};
+use crate::EnclaveError;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Segments in the clear at once while training.
+pub const WINDOW_SEGMENTS: usize = 16;
+
+/// Training and validation row indices, both ascending.
+pub type Split = (Vec<usize>, Vec<usize>);
+
+/// Labelled rows in memory. Missing feature values are `NaN` until
+/// preprocessing imputes them.
+#[derive(Debug, Clone, Default)]
+pub struct Dataset {
+    pub inputs: Vec<Vec<f32>>,
+    pub targets: Vec<usize>,
+    /// Index of the data blob each row comes from.
+    pub shards: Vec<usize>,
+}
+
+impl Dataset {
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inputs.is_empty()
+    }
+
+    pub fn push(&mut self, input: Vec<f32>, target: usize, shard: usize) {
+        self.inputs.push(input);
+        self.targets.push(target);
+        self.shards.push(shard);
+    }
+}
+
+/// Labelled rows decoded from the contributor blobs, their inputs encrypted
+/// in a spill buffer.
+pub struct SpilledDataset {
+    spill: SpillBuffer,
+    targets: Vec<usize>,
+    shards: Vec<usize>,
+}
+
+impl SpilledDataset {
+    /// An empty dataset of rows with `width` features.
+    pub fn new(width: usize) -> Result<Self, EnclaveError> {
+        Ok(Self {
+            spill: SpillBuffer::new(width)?,
+            targets: vec![],
+            shards: vec![],
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.targets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    pub fn push(&mut self, input: &[f32], target: usize, shard: usize) -> Result<(), EnclaveError> {
+        self.spill.push(shard, target, input)?;
+        self.targets.push(target);
+        self.shards.push(shard);
+        Ok(())
+    }
+
+    /// Every row in memory, for jobs that need them all at once.
+    pub fn load(&self) -> Result<Dataset, EnclaveError> {
+        let mut dataset = Dataset::default();
+        self.spill.scan(|row| {
+            dataset.push(row.input, row.label, row.shard);
+            Ok(())
+        })?;
+        Ok(dataset)
+    }
+
+    /// Indices of every row.
+    pub fn all(&self) -> Vec<usize> {
+        (0..self.len()).collect()
+    }
+
+    /// The rows at ascending `indices`, their inputs as stored.
+    pub fn rows<'a>(&'a self, indices: &'a [usize]) -> Rows<'a> {
+        debug_assert!(indices.windows(2).all(|w| w[0] < w[1]));
+        Rows {
+            data: self,
+            indices,
+            pipeline: None,
+        }
+    }
+
+    /// Randomly hold out `validation_fraction` of the rows.
+    /// Returns the ascending indices of `(training, validation)`.
+    pub fn split<R: Rng>(
+        &self,
+        validation_fraction: f32,
+        rng: &mut R,
+    ) -> Result<Split, EnclaveError> {
+        check_validation_split(validation_fraction)?;
+        if validation_fraction == 0.0 {
+            return Ok((self.all(), vec![]));
+        }
+        let mut indices = self.all();
+        indices.shuffle(rng);
+        let num_validation = (self.len() as f32 * validation_fraction).round() as usize;
+        let (validation, training) = indices.split_at_mut(num_validation);
+        if training.is_empty() {
+            return Err(EnclaveError::InvalidInput(
+                "no training rows left after the validation split".to_string(),
+            ));
+        }
+        training.sort_unstable();
+        validation.sort_unstable();
+        Ok((training.to_vec(), validation.to_vec()))
+    }
+
+    /// Randomly assign rows to `k` folds. Returns the ascending indices of
+    /// `(training, validation)` per fold, each fold being the validation set
+    /// once.
+    pub fn folds<R: Rng>(&self, k: usize, rng: &mut R) -> Result<Vec<Split>, EnclaveError> {
+        if k < 2 || k > self.len() {
+            return Err(EnclaveError::InvalidInput(format!(
+                "cross validation needs between 2 and {} folds",
+                self.len()
+            )));
+        }
+        let mut fold_of = vec![0; self.len()];
+        let mut indices = self.all();
+        indices.shuffle(rng);
+        for (position, i) in indices.iter().enumerate() {
+            fold_of[*i] = position % k;
+        }
+        Ok((0..k)
+            .map(|fold| {
+                let (mut training, mut validation) = (vec![], vec![]);
+                for (i, f) in fold_of.iter().enumerate() {
+                    if *f == fold {
+                        validation.push(i);
+                    } else {
+                        training.push(i);
+                    }
+                }
+                (training, validation)
+            })
+            .collect())
+    }
+}
+
+/// `validation_split` must be in `[0, 1)`.
+pub fn check_validation_split(validation_fraction: f32) -> Result<(), EnclaveError> {
+    if !(0.0..1.0).contains(&validation_fraction) {
+        return Err(EnclaveError::InvalidInput(
+            "validation_split must be in [0, 1)".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Some rows of a [`SpilledDataset`], read a segment at a time, with a
+/// pipeline applied to their inputs as they are read.
+#[derive(Clone, Copy)]
+pub struct Rows<'a> {
+    data: &'a SpilledDataset,
+    /// Ascending.
+    indices: &'a [usize],
+    pipeline: Option<&'a Pipeline>,
+}
+
+impl<'a> Rows<'a> {
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// The same rows with `pipeline` applied to their inputs.
+    pub fn transformed(self, pipeline: &'a Pipeline) -> Self {
+        Self {
+            pipeline: Some(pipeline),
+            ..self
+        }
+    }
+
+    /// Shards present, ascending.
+    pub fn shards(&self) -> Vec<usize> {
+        let mut shards: Vec<usize> = self.indices.iter().map(|i| self.data.shards[*i]).collect();
+        shards.sort_unstable();
+        shards.dedup();
+        shards
+    }
+
+    /// Segments holding any of the rows, ascending.
+    pub fn segments(&self) -> Vec<usize> {
+        let mut segments: Vec<usize> = self.indices.iter().map(|i| i / SEGMENT_ROWS).collect();
+        segments.dedup();
+        segments
+    }
+
+    /// Segments holding any row of each shard, by ascending shard.
+    pub fn shard_segments(&self) -> Vec<Vec<usize>> {
+        let mut by_shard: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for i in self.indices {
+            let segments = by_shard.entry(self.data.shards[*i]).or_default();
+            if segments.last() != Some(&(i / SEGMENT_ROWS)) {
+                segments.push(i / SEGMENT_ROWS);
+            }
+        }
+        by_shard.into_values().collect()
+    }
+
+    /// Positions in `indices` of the rows in `segment`.
+    fn positions(&self, segment: usize) -> Range<usize> {
+        let start = self
+            .indices
+            .partition_point(|i| *i < segment * SEGMENT_ROWS);
+        let end = self
+            .indices
+            .partition_point(|i| *i < (segment + 1) * SEGMENT_ROWS);
+        start..end
+    }
+
+    /// Call `f` with the input and target of each row in `segment`, in order.
+    fn read_segment<F>(&self, segment: usize, f: &mut F) -> Result<(), EnclaveError>
+    where
+        F: FnMut(Vec<f32>, usize) -> Result<(), EnclaveError>,
+    {
+        let mut wanted = self.indices[self.positions(segment)].iter().peekable();
+        if wanted.peek().is_none() {
+            return Ok(());
+        }
+        let mut index = segment * SEGMENT_ROWS;
+        self.data.spill.read_segment(segment, |row| {
+            if wanted.next_if_eq(&&index).is_some() {
+                let input = match self.pipeline {
+                    Some(pipeline) => pipeline.transform(&row.input)?,
+                    None => row.input,
+                };
+                f(input, row.label)?;
+            }
+            index += 1;
+            Ok(())
+        })
+    }
+
+    /// Call `f` with the input and target of every row, in order.
+    pub fn scan<F>(&self, mut f: F) -> Result<(), EnclaveError>
+    where
+        F: FnMut(Vec<f32>, usize) -> Result<(), EnclaveError>,
+    {
+        for segment in self.segments() {
+            self.read_segment(segment, &mut f)?;
+        }
+        Ok(())
+    }
+
+    /// The rows in `segments`, in memory.
+    pub fn window(&self, segments: &[usize]) -> Result<Dataset, EnclaveError> {
+        let mut window = Dataset::default();
+        for segment in segments {
+            let shards = &self.indices[self.positions(*segment)];
+            let mut shards = shards.iter().map(|i| self.data.shards[*i]);
+            self.read_segment(*segment, &mut |input, target| {
+                window.push(input, target, shards.next().expect("one per row"));
+                Ok(())
+            })?;
+        }
+        Ok(window)
+    }
+
+    /// Inputs of the first `n` rows.
+    pub fn head(&self, n: usize) -> Result<Vec<Vec<f32>>, EnclaveError> {
+        let mut inputs = vec![];
+        for segment in self.segments() {
+            if inputs.len() >= n {
+                break;
+            }
+            self.read_segment(segment, &mut |input, _| {
+                inputs.push(input);
+                Ok(())
+            })?;
+        }
+        inputs.truncate(n);
+        Ok(inputs)
+    }
+
+    /// Values of raw column `index`.
+    pub fn column(&self, index: usize) -> Result<Vec<f32>, EnclaveError> {
+        let mut values = Vec::with_capacity(self.len());
+        self.scan(|input, _| {
+            values.push(input[index]);
+            Ok(())
+        })?;
+        Ok(values)
+    }
+
+    /// Fit `config` on the rows, one column at a time.
+    pub fn fit(
+        &self,
+        config: &PreprocessingConfig,
+        input_size: usize,
+    ) -> Result<Pipeline, EnclaveError> {
+        config.fit_columns(input_size, |index| self.column(index))
+    }
+
+    /// Statistics of each raw column, merged over the segments.
+    pub fn stats(
+        &self,
+        config: &PreprocessingConfig,
+        input_size: usize,
+    ) -> Result<Vec<ColumnStats>, EnclaveError> {
+        let mut stats = vec![ColumnStats::default(); input_size];
+        for segment in self.segments() {
+            let window = self.window(&[segment])?;
+            let segment_stats = config.shard_stats(input_size, &window.inputs)?;
+            for (merged, column) in stats.iter_mut().zip(&segment_stats) {
+                merged.merge(column);
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Accuracy in percent and mean cross-entropy loss of `network`, as
+    /// [`Mlp::evaluate`] over every row.
+    pub fn evaluate(&self, network: &Mlp) -> Result<(f32, f32), EnclaveError> {
+        let (mut accuracy, mut loss) = (0.0f64, 0.0f64);
+        for segment in self.segments() {
+            let window = self.window(&[segment])?;
+            let (a, l) = network.evaluate(&window.inputs, &window.targets);
+            accuracy += a as f64 * window.len() as f64;
+            loss += l as f64 * window.len() as f64;
+        }
+        let rows = self.len().max(1) as f64;
+        Ok(((accuracy / rows) as f32, (loss / rows) as f32))
+    }
+}
+
+/// Orders the rows of a training window into mini-batches.
+#[derive(Debug, Clone, Copy)]
+pub struct DataLoader {
+    pub batch_size: usize,
+    /// Draw rows so that every class is equally likely, instead of taking
+    /// each row once per epoch.
+    pub class_balanced: bool,
+}
+
+impl DataLoader {
+    /// Order in which the rows labelled `targets` are fed, drawn from `rng`:
+    /// as many rows as `targets`.
+    pub fn order<R: Rng>(&self, targets: &[usize], rng: &mut R) -> Vec<usize> {
+        if self.class_balanced {
+            let mut by_class: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+            for (i, target) in targets.iter().enumerate() {
+                by_class.entry(*target).or_default().push(i);
+            }
+            let classes: Vec<&Vec<usize>> = by_class.values().collect();
+            (0..targets.len())
+                .map(|_| {
+                    let rows = classes[rng.gen_range(0..classes.len())];
+                    rows[rng.gen_range(0..rows.len())]
+                })
+                .collect()
+        } else {
+            let mut order: Vec<usize> = (0..targets.len()).collect();
+            order.shuffle(rng);
+            order
+        }
+    }
+
+    /// `steps` steps of sharded training: each step has one batch per shard,
+    /// drawn with replacement from that shard's `rows`.
+    pub fn sharded_steps<R: Rng>(
+        &self,
+        shards: &[Vec<usize>],
+        steps: usize,
+        rng: &mut R,
+    ) -> Vec<Vec<Vec<usize>>> {
+        (0..steps)
+            .map(|_| {
+                shards
+                    .iter()
+                    .map(|rows| {
+                        (0..self.batch_size.max(1))
+                            .map(|_| rows[rng.gen_range(0..rows.len())])
+                            .collect()
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+    use serde_json::json;
+
+    /// Rows over two segments: input `[i, i % 5]`, target `i % 3`, shard `i % 2`.
+    fn spilled() -> SpilledDataset {
+        let mut dataset = SpilledDataset::new(2).unwrap();
+        for i in 0..SEGMENT_ROWS + 10 {
+            dataset
+                .push(&[i as f32, (i % 5) as f32], i % 3, i % 2)
+                .unwrap();
+        }
+        dataset
+    }
+
+    #[test]
+    fn test_rows_read_across_segments() {
+        let dataset = spilled();
+        let indices = [1, 2, SEGMENT_ROWS - 1, SEGMENT_ROWS, SEGMENT_ROWS + 9];
+        let rows = dataset.rows(&indices);
+        assert_eq!(rows.segments(), vec![0, 1]);
+        assert_eq!(rows.shards(), vec![0, 1]);
+        assert_eq!(rows.shard_segments(), vec![vec![0, 1], vec![0, 1]]);
+
+        let window = rows.window(&[1]).unwrap();
+        assert_eq!(window.inputs, vec![vec![4096.0, 1.0], vec![4105.0, 0.0]]);
+        assert_eq!(window.targets, vec![4096 % 3, 4105 % 3]);
+        assert_eq!(window.shards, vec![0, 1]);
+        assert_eq!(rows.head(3).unwrap().len(), 3);
+        assert_eq!(
+            rows.column(0).unwrap(),
+            indices.iter().map(|i| *i as f32).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_fit_and_stats_match_in_memory() {
+        let dataset = spilled();
+        let (training, validation) = dataset
+            .split(0.25, &mut ChaCha20Rng::seed_from_u64(3))
+            .unwrap();
+        assert_eq!(training.len() + validation.len(), dataset.len());
+        assert!(training.windows(2).all(|w| w[0] < w[1]));
+
+        let config: PreprocessingConfig = serde_json::from_value(json!({"columns": [
+            {"index": 0, "scale": "standard"},
+            {"index": 1, "one_hot": true},
+        ]}))
+        .unwrap();
+        let rows = dataset.rows(&training);
+        let inputs = rows.window(&rows.segments()).unwrap().inputs;
+        let streamed = rows.fit(&config, 2).unwrap();
+        let in_memory = config.fit(2, &inputs).unwrap();
+        assert_eq!(streamed.output_width(), in_memory.output_width());
+        let stats = rows.stats(&config, 2).unwrap();
+        let merged = config.fit_stats(2, &stats).unwrap();
+        for input in inputs.iter().take(50) {
+            let expected = in_memory.transform(input).unwrap();
+            for actual in [streamed.transform(input), merged.transform(input)] {
+                for (a, e) in actual.unwrap().iter().zip(&expected) {
+                    assert!((a - e).abs() < 1e-3, "{a} != {e}");
+                }
+            }
+        }
+
+        let transformed = rows.transformed(&streamed).head(1).unwrap();
+        assert_eq!(transformed, vec![streamed.transform(&inputs[0]).unwrap()]);
+    }
+}
@@ -0,0 +1,597 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Exporters that let buyers deploy trained models outside CloakX.
+//!
+//! - safetensors: `layers.{i}.weight` (`[out, in]`) and `layers.{i}.bias`
+//!   tensors, activations recorded in the `__metadata__` header.
+//! - ONNX: one `Gemm` (`transB = 1`) per layer followed by its activation,
+//!   input `input` of shape `[batch, input_width]`, output `logits`.
+//!
+//! Both take preprocessed rows. The fitted preprocessing pipeline is attached
+//! as JSON, under the `preprocessing` metadata key.
+
+use super::model::{Activation, Dense, Mlp};
+use super::preprocess::Pipeline;
+use crate::EnclaveError;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/// Export formats a job can request in addition to the CloakX artifact.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Onnx,
+    Safetensors,
+}
+
+impl ExportFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExportFormat::Onnx => "onnx",
+            ExportFormat::Safetensors => "safetensors",
+        }
+    }
+
+    pub fn export(&self, network: &Mlp, preprocessing: &Pipeline) -> Vec<u8> {
+        let preprocessing = serde_json::to_string(preprocessing).expect("should not fail");
+        match self {
+            ExportFormat::Onnx => to_onnx(network, &preprocessing),
+            ExportFormat::Safetensors => to_safetensors(network, &preprocessing),
+        }
+    }
+}
+
+fn activation_name(activation: Activation) -> &'static str {
+    match activation {
+        Activation::Identity => "identity",
+        Activation::Relu => "relu",
+        Activation::Sigmoid => "sigmoid",
+        Activation::Tanh => "tanh",
+        Activation::LeakyRelu => "leaky_relu",
+    }
+}
+
+// === SAFETENSORS ===
+
+/// Serialize the network weights as a safetensors file.
+pub fn to_safetensors(network: &Mlp, preprocessing: &str) -> Vec<u8> {
+    let mut header = Map::new();
+    let mut data = Vec::new();
+    for (i, layer) in network.layers.iter().enumerate() {
+        for (name, values, shape) in [
+            (
+                format!("layers.{i}.weight"),
+                &layer.weight,
+                vec![layer.out_features, layer.in_features],
+            ),
+            (
+                format!("layers.{i}.bias"),
+                &layer.bias,
+                vec![layer.out_features],
+            ),
+        ] {
+            let begin = data.len();
+            values
+                .iter()
+                .for_each(|v| data.extend_from_slice(&v.to_le_bytes()));
+            header.insert(
+                name,
+                json!({ "dtype": "F32", "shape": shape, "data_offsets": [begin, data.len()] }),
+            );
+        }
+    }
+    let activations: Vec<&str> = network
+        .layers
+        .iter()
+        .map(|l| activation_name(l.activation))
+        .collect();
+    header.insert(
+        "__metadata__".to_string(),
+        json!({
+            "format": "cloakx-mlp",
+            "activations": activations.join(","),
+            "preprocessing": preprocessing,
+        }),
+    );
+
+    let mut header_bytes = serde_json::to_vec(&Value::Object(header)).expect("should not fail");
+    // Pad the header with spaces so the data section is 8 byte aligned.
+    while header_bytes.len() % 8 != 0 {
+        header_bytes.push(b' ');
+    }
+    let mut out = (header_bytes.len() as u64).to_le_bytes().to_vec();
+    out.extend(header_bytes);
+    out.extend(data);
+    out
+}
+
+/// Rebuild a network from a safetensors file produced by [`to_safetensors`].
+pub fn from_safetensors(bytes: &[u8]) -> Result<Mlp, EnclaveError> {
+    let invalid = |msg: &str| EnclaveError::InvalidInput(format!("invalid safetensors: {msg}"));
+    let header_len = bytes
+        .get(..8)
+        .map(|b| u64::from_le_bytes(b.try_into().expect("8 bytes")) as usize)
+        .ok_or_else(|| invalid("truncated"))?;
+    let data_start = header_len
+        .checked_add(8)
+        .filter(|end| *end <= bytes.len())
+        .ok_or_else(|| invalid("truncated header"))?;
+    let header_bytes = &bytes[8..data_start];
+    let data = &bytes[data_start..];
+    let header: Map<String, Value> =
+        serde_json::from_slice(header_bytes).map_err(|e| invalid(&e.to_string()))?;
+
+    let activations: Vec<Activation> = header
+        .get("__metadata__")
+        .and_then(|m| m.get("activations"))
+        .and_then(|a| a.as_str())
+        .ok_or_else(|| invalid("missing activations metadata"))?
+        .split(',')
+        .map(Activation::parse)
+        .collect::<Result<_, _>>()?;
+
+    let tensor = |name: String| -> Result<(Vec<usize>, Vec<f32>), EnclaveError> {
+        let entry = header.get(&name).ok_or_else(|| invalid(&name))?;
+        let shape: Vec<usize> =
+            serde_json::from_value(entry["shape"].clone()).map_err(|e| invalid(&e.to_string()))?;
+        let offsets: [usize; 2] = serde_json::from_value(entry["data_offsets"].clone())
+            .map_err(|e| invalid(&e.to_string()))?;
+        let raw = data
+            .get(offsets[0]..offsets[1])
+            .ok_or_else(|| invalid("data offsets out of range"))?;
+        let values = raw
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes(c.try_into().expect("4 bytes")))
+            .collect();
+        Ok((shape, values))
+    };
+
+    let mut layers = Vec::with_capacity(activations.len());
+    for (i, activation) in activations.into_iter().enumerate() {
+        let (shape, weight) = tensor(format!("layers.{i}.weight"))?;
+        let (_, bias) = tensor(format!("layers.{i}.bias"))?;
+        if shape.len() != 2 || weight.len() != shape[0] * shape[1] || bias.len() != shape[0] {
+            return Err(invalid("inconsistent tensor shapes"));
+        }
+        layers.push(Dense {
+            in_features: shape[1],
+            out_features: shape[0],
+            weight,
+            bias,
+            activation,
+            dropout: 0.0,
+        });
+    }
+    Ok(Mlp { layers })
+}
+
+/// Check that re-importing the safetensors export reproduces the in-enclave
+/// forward pass bit for bit on `rows`.
+pub fn verify_safetensors_round_trip(
+    network: &Mlp,
+    exported: &[u8],
+    rows: &[Vec<f32>],
+) -> Result<(), EnclaveError> {
+    let imported = from_safetensors(exported)?;
+    for row in rows {
+        let expected = network.forward(row);
+        let actual = imported.forward(row);
+        if expected.len() != actual.len()
+            || expected
+                .iter()
+                .zip(&actual)
+                .any(|(a, b)| a.to_bits() != b.to_bits())
+        {
+            return Err(EnclaveError::GenericError(
+                "safetensors export does not reproduce the trained model".to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// === ONNX ===
+
+/// Minimal protobuf writer, enough to encode the ONNX messages used here.
+#[derive(Default)]
+struct Proto(Vec<u8>);
+
+impl Proto {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8);
+    }
+
+    fn key(&mut self, field: u64, wire_type: u64) {
+        self.varint((field << 3) | wire_type);
+    }
+
+    fn int(mut self, field: u64, v: i64) -> Self {
+        self.key(field, 0);
+        self.varint(v as u64);
+        self
+    }
+
+    fn float(mut self, field: u64, v: f32) -> Self {
+        self.key(field, 5);
+        self.0.extend_from_slice(&v.to_le_bytes());
+        self
+    }
+
+    fn bytes(mut self, field: u64, v: &[u8]) -> Self {
+        self.key(field, 2);
+        self.varint(v.len() as u64);
+        self.0.extend_from_slice(v);
+        self
+    }
+
+    fn string(self, field: u64, v: &str) -> Self {
+        self.bytes(field, v.as_bytes())
+    }
+
+    fn message(self, field: u64, v: Proto) -> Self {
+        self.bytes(field, &v.0)
+    }
+}
+
+// ONNX enum values.
+const TENSOR_FLOAT: i64 = 1;
+const ATTRIBUTE_FLOAT: i64 = 1;
+const ATTRIBUTE_INT: i64 = 2;
+
+/// `TensorProto` holding a float initializer.
+fn onnx_tensor(name: &str, dims: &[usize], values: &[f32]) -> Proto {
+    let raw: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+    dims.iter()
+        .fold(Proto::default(), |p, d| p.int(1, *d as i64))
+        .int(2, TENSOR_FLOAT)
+        .string(8, name)
+        .bytes(9, &raw)
+}
+
+/// `ValueInfoProto` of a float tensor `[batch, width]`.
+fn onnx_value_info(name: &str, width: usize) -> Proto {
+    let shape = Proto::default()
+        .message(1, Proto::default().string(2, "batch"))
+        .message(1, Proto::default().int(1, width as i64));
+    let tensor_type = Proto::default().int(1, TENSOR_FLOAT).message(2, shape);
+    Proto::default()
+        .string(1, name)
+        .message(2, Proto::default().message(1, tensor_type))
+}
+
+/// `NodeProto` with optional attributes.
+fn onnx_node(op_type: &str, name: &str, inputs: &[&str], output: &str, attrs: Vec<Proto>) -> Proto {
+    let node = inputs
+        .iter()
+        .fold(Proto::default(), |p, i| p.string(1, i))
+        .string(2, output)
+        .string(3, name)
+        .string(4, op_type);
+    attrs.into_iter().fold(node, |p, a| p.message(5, a))
+}
+
+/// Serialize the network as an ONNX model (IR version 8, opset 13).
+pub fn to_onnx(network: &Mlp, preprocessing: &str) -> Vec<u8> {
+    let mut graph = Proto::default();
+    let mut current = "input".to_string();
+    let last = network.layers.len().saturating_sub(1);
+    for (i, layer) in network.layers.iter().enumerate() {
+        let weight = format!("layers.{i}.weight");
+        let bias = format!("layers.{i}.bias");
+        let gemm_out = if i == last && layer.activation == Activation::Identity {
+            "logits".to_string()
+        } else {
+            format!("layers.{i}.linear")
+        };
+        let trans_b = Proto::default()
+            .string(1, "transB")
+            .int(3, 1)
+            .int(20, ATTRIBUTE_INT);
+        graph = graph.message(
+            1,
+            onnx_node(
+                "Gemm",
+                &format!("gemm_{i}"),
+                &[&current, &weight, &bias],
+                &gemm_out,
+                vec![trans_b],
+            ),
+        );
+        current = gemm_out;
+
+        let activation = match layer.activation {
+            Activation::Identity => None,
+            Activation::Relu => Some(("Relu", vec![])),
+            Activation::Sigmoid => Some(("Sigmoid", vec![])),
+            Activation::Tanh => Some(("Tanh", vec![])),
+            Activation::LeakyRelu => Some((
+                "LeakyRelu",
+                vec![Proto::default()
+                    .string(1, "alpha")
+                    .float(2, 0.01)
+                    .int(20, ATTRIBUTE_FLOAT)],
+            )),
+        };
+        if let Some((op, attrs)) = activation {
+            let out = if i == last {
+                "logits".to_string()
+            } else {
+                format!("layers.{i}.out")
+            };
+            graph = graph.message(
+                1,
+                onnx_node(op, &format!("act_{i}"), &[&current], &out, attrs),
+            );
+            current = out;
+        }
+    }
+
+    graph = graph.string(2, "cloakx_mlp");
+    for (i, layer) in network.layers.iter().enumerate() {
+        graph = graph
+            .message(
+                5,
+                onnx_tensor(
+                    &format!("layers.{i}.weight"),
+                    &[layer.out_features, layer.in_features],
+                    &layer.weight,
+                ),
+            )
+            .message(
+                5,
+                onnx_tensor(
+                    &format!("layers.{i}.bias"),
+                    &[layer.out_features],
+                    &layer.bias,
+                ),
+            );
+    }
+    graph = graph
+        .message(11, onnx_value_info("input", network.input_size()))
+        .message(12, onnx_value_info("logits", network.output_size()));
+
+    Proto::default()
+        .int(1, 8)
+        .string(2, "cloakx")
+        .message(7, graph)
+        .message(8, Proto::default().string(1, "").int(2, 13))
+        .message(
+            14,
+            Proto::default()
+                .string(1, "preprocessing")
+                .string(2, preprocessing),
+        )
+        .0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apps::mltraining::model::ModelConfig;
+    use crate::apps::mltraining::preprocess::PreprocessingConfig;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+    use std::collections::HashMap;
+
+    /// A network with every activation and non zero biases, and rows to run it on.
+    fn network() -> (Mlp, Pipeline, Vec<Vec<f32>>) {
+        let config: ModelConfig = serde_json::from_value(json!({
+            "input_size": 3,
+            "output_size": 3,
+            "layers": [
+                {"neurons": 5, "activation": "relu"},
+                {"neurons": 4, "activation": "leaky_relu"},
+                {"neurons": 4, "activation": "sigmoid"},
+                {"neurons": 3, "activation": "tanh"},
+            ],
+        }))
+        .unwrap();
+        let mut rng = ChaCha20Rng::seed_from_u64(29);
+        let mut network = Mlp::new(&config, 3, &mut rng).unwrap();
+        let parameters: Vec<f32> = network
+            .parameters()
+            .iter()
+            .map(|_| rng.gen_range(-1.0..1.0))
+            .collect();
+        network.set_parameters(&parameters).unwrap();
+        let rows: Vec<Vec<f32>> = (0..16)
+            .map(|_| (0..3).map(|_| rng.gen_range(-3.0..3.0)).collect())
+            .collect();
+        let pipeline = PreprocessingConfig::default().fit(3, &rows).unwrap();
+        (network, pipeline, rows)
+    }
+
+    #[test]
+    fn test_safetensors_round_trip() {
+        let (network, pipeline, rows) = network();
+        let exported = ExportFormat::Safetensors.export(&network, &pipeline);
+        let imported = from_safetensors(&exported).unwrap();
+        for row in &rows {
+            let expected: Vec<u32> = network.forward(row).iter().map(|v| v.to_bits()).collect();
+            let actual: Vec<u32> = imported.forward(row).iter().map(|v| v.to_bits()).collect();
+            assert_eq!(expected, actual);
+        }
+        verify_safetensors_round_trip(&network, &exported, &rows).unwrap();
+    }
+
+    #[test]
+    fn test_safetensors_rejects_bad_header_length() {
+        let (network, pipeline, _) = network();
+        let mut exported = ExportFormat::Safetensors.export(&network, &pipeline);
+        exported[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(from_safetensors(&exported).is_err());
+        assert!(from_safetensors(&exported[..4]).is_err());
+    }
+
+    #[test]
+    fn test_onnx_round_trip() {
+        let (network, pipeline, rows) = network();
+        let exported = ExportFormat::Onnx.export(&network, &pipeline);
+        let model = fields(&exported);
+        assert_eq!(varint_field(&model, 1), Some(8));
+        let opset = fields(bytes_field(&model, 8).unwrap());
+        assert_eq!(varint_field(&opset, 2), Some(13));
+        let metadata = fields(bytes_field(&model, 14).unwrap());
+        let preprocessing: Pipeline =
+            serde_json::from_slice(bytes_field(&metadata, 2).unwrap()).unwrap();
+        assert_eq!(preprocessing.output_width(), pipeline.output_width());
+
+        for row in &rows {
+            let expected = network.forward(row);
+            let actual = run_onnx(&exported, row);
+            assert_eq!(expected.len(), actual.len());
+            for (e, a) in expected.iter().zip(&actual) {
+                assert!((e - a).abs() <= 1e-6 * e.abs().max(1.0), "{e} != {a}");
+            }
+        }
+    }
+
+    // === ONNX READER ===
+    enum Field<'a> {
+        Varint(u64),
+        Fixed32(u32),
+        Bytes(&'a [u8]),
+    }
+
+    fn varint(bytes: &mut &[u8]) -> u64 {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let (byte, rest) = bytes.split_first().expect("truncated varint");
+            *bytes = rest;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    /// Fields of a protobuf message, in order.
+    fn fields(mut bytes: &[u8]) -> Vec<(u64, Field<'_>)> {
+        let mut out = vec![];
+        while !bytes.is_empty() {
+            let key = varint(&mut bytes);
+            let field = match key & 7 {
+                0 => Field::Varint(varint(&mut bytes)),
+                2 => {
+                    let len = varint(&mut bytes) as usize;
+                    let (value, rest) = bytes.split_at(len);
+                    bytes = rest;
+                    Field::Bytes(value)
+                }
+                5 => {
+                    let (value, rest) = bytes.split_at(4);
+                    bytes = rest;
+                    Field::Fixed32(u32::from_le_bytes(value.try_into().unwrap()))
+                }
+                wire_type => panic!("unexpected wire type {wire_type}"),
+            };
+            out.push((key >> 3, field));
+        }
+        out
+    }
+
+    fn varint_field(message: &[(u64, Field<'_>)], number: u64) -> Option<u64> {
+        message.iter().find_map(|(n, f)| match f {
+            Field::Varint(v) if *n == number => Some(*v),
+            _ => None,
+        })
+    }
+
+    fn bytes_fields<'a>(message: &[(u64, Field<'a>)], number: u64) -> Vec<&'a [u8]> {
+        message
+            .iter()
+            .filter_map(|(n, f)| match f {
+                Field::Bytes(v) if *n == number => Some(*v),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn bytes_field<'a>(message: &[(u64, Field<'a>)], number: u64) -> Option<&'a [u8]> {
+        bytes_fields(message, number).into_iter().next()
+    }
+
+    fn string_field(message: &[(u64, Field<'_>)], number: u64) -> String {
+        String::from_utf8(bytes_field(message, number).unwrap().to_vec()).unwrap()
+    }
+
+    /// Run the graph of an ONNX model on one row, for the operators
+    /// [`to_onnx`] emits.
+    fn run_onnx(model: &[u8], row: &[f32]) -> Vec<f32> {
+        let graph = fields(bytes_field(&fields(model), 7).unwrap());
+        let mut tensors: HashMap<String, (Vec<usize>, Vec<f32>)> = HashMap::new();
+        for initializer in bytes_fields(&graph, 5) {
+            let tensor = fields(initializer);
+            assert_eq!(varint_field(&tensor, 2), Some(TENSOR_FLOAT as u64));
+            let dims = tensor
+                .iter()
+                .filter_map(|(n, f)| match f {
+                    Field::Varint(d) if *n == 1 => Some(*d as usize),
+                    _ => None,
+                })
+                .collect();
+            let values = bytes_field(&tensor, 9)
+                .unwrap()
+                .chunks_exact(4)
+                .map(|c| f32::from_le_bytes(c.try_into().unwrap()))
+                .collect();
+            tensors.insert(string_field(&tensor, 8), (dims, values));
+        }
+        let input = string_field(&fields(bytes_field(&graph, 11).unwrap()), 1);
+        tensors.insert(input, (vec![1, row.len()], row.to_vec()));
+
+        for node in bytes_fields(&graph, 1) {
+            let node = fields(node);
+            let inputs: Vec<String> = bytes_fields(&node, 1)
+                .into_iter()
+                .map(|i| String::from_utf8(i.to_vec()).unwrap())
+                .collect();
+            let attributes: HashMap<String, Vec<(u64, Field<'_>)>> = bytes_fields(&node, 5)
+                .into_iter()
+                .map(|a| {
+                    let attribute = fields(a);
+                    (string_field(&attribute, 1), attribute)
+                })
+                .collect();
+            let x = &tensors[&inputs[0]].1;
+            let y: Vec<f32> = match string_field(&node, 4).as_str() {
+                "Gemm" => {
+                    assert_eq!(varint_field(&attributes["transB"], 3), Some(1));
+                    let (dims, weight) = &tensors[&inputs[1]];
+                    let bias = &tensors[&inputs[2]].1;
+                    (0..dims[0])
+                        .map(|o| {
+                            let w = &weight[o * dims[1]..(o + 1) * dims[1]];
+                            bias[o] + w.iter().zip(x).map(|(w, v)| w * v).sum::<f32>()
+                        })
+                        .collect()
+                }
+                "Relu" => x.iter().map(|v| v.max(0.0)).collect(),
+                "Sigmoid" => x.iter().map(|v| 1.0 / (1.0 + (-v).exp())).collect(),
+                "Tanh" => x.iter().map(|v| v.tanh()).collect(),
+                "LeakyRelu" => {
+                    let alpha = attributes["alpha"]
+                        .iter()
+                        .find_map(|(n, f)| match f {
+                            Field::Fixed32(bits) if *n == 2 => Some(f32::from_bits(*bits)),
+                            _ => None,
+                        })
+                        .unwrap();
+                    x.iter()
+                        .map(|v| if *v > 0.0 { *v } else { alpha * v })
+                        .collect()
+                }
+                op => panic!("unexpected operator {op}"),
+            };
+            tensors.insert(string_field(&node, 2), (vec![1, y.len()], y));
+        }
+        let output = string_field(&fields(bytes_field(&graph, 12).unwrap()), 1);
+        tensors.remove(&output).unwrap().1
+    }
+}
@@ -0,0 +1,1449 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Federated training across several enclaves.
+//!
+//! A pool can outgrow the memory and CPU of a single enclave. A coordinator
+//! enclave then deals the pool's data blobs out to worker enclaves in
+//! contiguous shards, and each worker loads and checks only its own shard.
+//! Training runs in FedAvg rounds: every worker trains `local_epochs` on its
+//! shard from the global weights, and the coordinator averages the weights it
+//! gets back, weighted by the training rows of each shard. Rows never leave
+//! their worker, only per-column statistics (to fit the preprocessing),
+//! weights and evaluation metrics do.
+//!
+//! Coordinator and workers authenticate each other with attestation
+//! documents, see [`crate::attestation`]: each side checks that the other
+//! runs the same image and learns the key its messages are signed with.
+//! Coordinator messages are signed under `MlFederatedControl` and worker
+//! replies under `MlFederatedUpdate`.
+//!
+//! With `secure_aggregation_threshold`, the coordinator does not see the
+//! weights of any one worker either: the workers mask them pairwise, see
+//! [`super::secagg`], and a round only reveals their weighted sum. A worker
+//! that stops answering mid-round then drops out of the job instead of
+//! failing it, as long as `threshold` workers remain. The signed result
+//! lists whose weights went into every round.
+//!
+//! The job's `epochs` are the number of rounds. Optimizer state and the
+//! learning rate schedule restart every round.
+
+use super::artifact::{
+    dataset_digest, EnclaveInfo, Hyperparameters, InputSchema, ModelArtifact, Provenance,
+    ARTIFACT_VERSION,
+};
+use super::chain::RpcReader;
+use super::crypto::seal;
+use super::dataset::{check_validation_split, SpilledDataset};
+use super::model::{Mlp, ModelConfig};
+use super::policy::{CohortReport, OutputPolicy};
+use super::pool::{check_cohort, load_rows};
+use super::preprocess::{ColumnStats, Pipeline};
+use super::quality::{BlobReport, QualityChecker, QualityConfig};
+use super::seal::{EnclaveObject, SealedJob};
+use super::secagg::{
+    check_threshold, decode, encode, unmask_sum, AdvertisedKeys, Client, EncryptedShares, Unmasking,
+};
+use super::train::{train, trial_rng, worker_rng};
+use super::usage;
+use super::{
+    download_blob, upload_blob, verify_job, MLTrainingRequest, DEFAULT_QUERY_BUDGET,
+    LEARNING_RATE_SCALE, METRIC_SCALE, MODEL_AAD,
+};
+use crate::attestation::authenticate_peer;
+use crate::common::{
+    current_timestamp_ms, f32_bits, get_attestation, read_pcrs, to_signed_response,
+    GetAttestationResponse, IntentMessage, IntentScope, ProcessDataRequest, ProcessedDataResponse,
+    Stamp,
+};
+use crate::AppState;
+use crate::EnclaveError;
+use axum::{extract::State, Json};
+use fastcrypto::ed25519::{Ed25519PublicKey, Ed25519Signature};
+use fastcrypto::encoding::{Encoding, Hex};
+use fastcrypto::traits::{KeyPair, ToFromBytes, VerifyingKey};
+use lazy_static::lazy_static;
+use rand::rngs::OsRng;
+use rand_chacha::ChaCha20Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tracing::info;
+
+/// Most workers a job may be spread over.
+pub const MAX_WORKERS: usize = 16;
+
+/// Idle time after which a worker drops a session, e.g. of a coordinator
+/// that failed mid-job.
+const SESSION_TIMEOUT_MS: u64 = 30 * 60 * 1000;
+
+/// Longest wait for a worker's reply to one message.
+const WORKER_TIMEOUT: Duration = Duration::from_secs(600);
+
+lazy_static! {
+    /// Open sessions of this enclave as a worker, by session id.
+    static ref SESSIONS: Mutex<HashMap<String, Session>> = Mutex::new(HashMap::new());
+}
+
+/// A protocol message, signed by the enclave that sent it.
+pub type Signed<T> = ProcessedDataResponse<IntentMessage<T>>;
+
+// === REQUEST ===
+#[derive(Deserialize, Debug)]
+pub struct FederatedRequest {
+    /// The job, as for `/process_data`. Cross validation, hyperparameter
+    /// search, early stopping, robust aggregation and exports are not
+    /// supported.
+    #[serde(flatten)]
+    pub job: MLTrainingRequest,
+    /// Base URLs of the worker enclaves, e.g. `http://10.0.1.7:3000`. The
+    /// data blobs are dealt out to them in order.
+    pub workers: Vec<String>,
+    /// Epochs each worker trains per round.
+    #[serde(default = "default_local_epochs")]
+    pub local_epochs: u64,
+    /// Aggregate the workers' weights under secure aggregation, tolerating
+    /// dropouts down to this many workers. Must be above half the workers.
+    pub secure_aggregation_threshold: Option<u64>,
+}
+
+fn default_local_epochs() -> u64 {
+    1
+}
+
+// === RESPONSE ===
+/// Signed result of a federated training job.
+#[derive(Serialize, Clone, Debug)]
+pub struct FederatedTrainingResponse {
+    /// The on-chain job the request was checked against.
+    pub job_id: u64,
+    pub model_blob_id: String,
+    /// Accuracy as a fraction, scaled by `METRIC_SCALE`, over the validation
+    /// splits of all shards, or their training rows without a split.
+    pub accuracy: u64,
+    /// Mean cross-entropy loss on the same rows, scaled by `METRIC_SCALE`.
+    pub final_loss: u64,
+    /// Rows that passed the data quality checks, over all shards.
+    pub num_samples: u64,
+    /// Data quality of each blob, in request order.
+    pub data_quality: Vec<BlobReport>,
+    /// Contributors and rows used, with the pool's minimums they met.
+    pub cohort: CohortReport,
+    pub seed: u64,
+    /// FedAvg rounds run, the job's `epochs`.
+    pub rounds: u64,
+    pub local_epochs: u64,
+    /// One per worker, in request order.
+    pub workers: Vec<WorkerReport>,
+    /// `None` without secure aggregation.
+    pub secure_aggregation: Option<SecureAggregationReport>,
+    /// Sha256 of the canonical `ModelArtifact` encoding (before sealing).
+    pub model_hash: Vec<u8>,
+    /// Sha256 of the encoding without the coordinator's public key and
+    /// timestamp. Rerunning the job on the same enclave image and inputs with
+    /// `seed` reproduces it.
+    pub training_hash: Vec<u8>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct WorkerReport {
+    /// Key bound by the worker's attestation document.
+    pub public_key: Vec<u8>,
+    pub data_blob_ids: Vec<String>,
+    /// Training rows of the shard, the worker's weight in every average.
+    pub training_rows: u64,
+    /// Round in which the worker stopped answering, under secure
+    /// aggregation.
+    pub dropped_at_round: Option<u64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SecureAggregationReport {
+    pub threshold: u64,
+    /// Per round, the indices of the workers whose weights were summed.
+    pub contributors: Vec<Vec<u64>>,
+}
+
+// === PROTOCOL ===
+/// Body of `/federated/open`, from the coordinator.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OpenRequest {
+    /// Hex encoded attestation document of the coordinator.
+    pub attestation: String,
+    pub message: Signed<OpenShard>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct OpenShard {
+    pub session_id: String,
+    pub job_id: u64,
+    /// Pool of the job, checked on chain by the coordinator.
+    pub pool_id: u64,
+    /// Index of the worker, which picks its random stream.
+    pub worker: u64,
+    /// Number of workers of the job.
+    pub workers: u64,
+    pub data_blob_ids: Vec<String>,
+    pub model_config_blob_id: String,
+    pub key_id: String,
+    pub quality: QualityConfig,
+    pub enclave_object: Option<EnclaveObject>,
+    #[serde(with = "f32_bits")]
+    pub validation_split: f32,
+    pub seed: u64,
+    /// In fixed point, see `LEARNING_RATE_SCALE`.
+    pub learning_rate: u64,
+    pub local_epochs: u64,
+    pub secure_aggregation_threshold: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShardOpened {
+    pub session_id: String,
+    /// Data quality of each blob of the shard, in order.
+    pub data_quality: Vec<BlobReport>,
+    /// Blob id and Sha256 of each blob of the shard, in order.
+    pub blob_digests: Vec<(String, Vec<u8>)>,
+    pub training_rows: u64,
+    pub validation_rows: u64,
+    /// Statistics of each raw column over the training rows.
+    pub stats: Vec<ColumnStats>,
+}
+
+/// Body of `/federated/round`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoundRequest {
+    pub session_id: String,
+    /// From 0.
+    pub round: u64,
+    /// The preprocessing, fitted on all shards. Sent with round 0 only.
+    pub pipeline: Option<Pipeline>,
+    /// Global weights, in the order of `Mlp::parameters`.
+    #[serde(with = "f32_bits")]
+    pub weights: Vec<f32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RoundUpdate {
+    pub session_id: String,
+    pub round: u64,
+    /// Weights after local training on the shard, empty under secure
+    /// aggregation.
+    #[serde(with = "f32_bits")]
+    pub weights: Vec<f32>,
+    /// Under secure aggregation, the keys the weights will be masked with.
+    pub keys: Option<AdvertisedKeys>,
+}
+
+/// Body of `/federated/share`: the keys of the workers that trained.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShareRequest {
+    pub session_id: String,
+    pub round: u64,
+    /// By worker index.
+    pub keys: BTreeMap<u64, AdvertisedKeys>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SharesSent {
+    pub session_id: String,
+    pub round: u64,
+    /// For every other worker of the `ShareRequest`.
+    pub shares: Vec<EncryptedShares>,
+}
+
+/// Body of `/federated/mask`: the shares other workers sent this one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MaskRequest {
+    pub session_id: String,
+    pub round: u64,
+    pub shares: Vec<EncryptedShares>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MaskedUpdate {
+    pub session_id: String,
+    pub round: u64,
+    /// Weights scaled by the training rows, in fixed point and masked.
+    pub masked: Vec<u64>,
+}
+
+/// Body of `/federated/unmask`: the workers whose masked weights are summed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UnmaskRequest {
+    pub session_id: String,
+    pub round: u64,
+    pub survivors: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UnmaskReply {
+    pub session_id: String,
+    pub round: u64,
+    pub unmasking: Unmasking,
+}
+
+/// Body of `/federated/close`, which also ends the session.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CloseRequest {
+    pub session_id: String,
+    /// Final global weights to evaluate.
+    #[serde(with = "f32_bits")]
+    pub weights: Vec<f32>,
+    /// Evaluate on the validation split rather than the training rows.
+    pub on_validation: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShardEvaluation {
+    pub session_id: String,
+    /// Rows evaluated.
+    pub rows: u64,
+    /// In percent, as returned by `Mlp::evaluate`.
+    #[serde(with = "f32_bits")]
+    pub accuracy: f32,
+    #[serde(with = "f32_bits")]
+    pub loss: f32,
+}
+
+/// A shard held by this enclave as a worker.
+struct Session {
+    /// Key the coordinator's messages must be signed with.
+    coordinator: Ed25519PublicKey,
+    config: ModelConfig,
+    learning_rate: f32,
+    local_epochs: u64,
+    /// The shard's rows, of which `training` and `validation` are indices.
+    rows: SpilledDataset,
+    training: Vec<usize>,
+    validation: Vec<usize>,
+    /// Applied to the rows from round 0 on, which brings it.
+    pipeline: Option<Pipeline>,
+    /// Next round expected.
+    round: u64,
+    /// Created in round 0.
+    network: Option<Mlp>,
+    rng: ChaCha20Rng,
+    index: u64,
+    workers: u64,
+    secure_aggregation_threshold: Option<usize>,
+    /// Under secure aggregation, the masking of the last round's weights.
+    masking: Option<Client>,
+    last_used_ms: u64,
+}
+
+/// A worker, as the coordinator knows it.
+struct Peer {
+    url: String,
+    key: Ed25519PublicKey,
+    session_id: String,
+}
+
+// === COORDINATOR ===
+pub async fn coordinate(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ProcessDataRequest<FederatedRequest>>,
+) -> Result<Json<Signed<FederatedTrainingResponse>>, EnclaveError> {
+    let request = &req.payload;
+    let payload = &request.job;
+    let nonce = req.nonce()?;
+    let unsupported = |what: &str| {
+        Err(EnclaveError::InvalidInput(format!(
+            "federated training does not support {what}"
+        )))
+    };
+    if !payload.export_formats.is_empty() {
+        return unsupported("exports");
+    }
+    if payload.cross_validation_folds.is_some() {
+        return unsupported("cross validation");
+    }
+    if request.workers.is_empty()
+        || request.workers.len() > MAX_WORKERS.min(payload.data_blob_ids.len())
+    {
+        return Err(EnclaveError::InvalidInput(format!(
+            "expected between 1 and {MAX_WORKERS} workers, and no more than data blobs"
+        )));
+    }
+    if request.local_epochs == 0 || payload.learning_rate == 0 {
+        return Err(EnclaveError::InvalidInput(
+            "local_epochs and learning_rate must be non zero".to_string(),
+        ));
+    }
+    check_validation_split(payload.validation_split)?;
+    if let Some(threshold) = request.secure_aggregation_threshold {
+        check_threshold(threshold as usize, request.workers.len())?;
+    }
+
+    // 0. Check the request against the on-chain job before touching any data
+    let (policy, contributors) = verify_job(&RpcReader::from_config()?, payload).await?;
+    let reject = |reason: String| {
+        let rejection = policy.rejection(payload.job_id, reason.clone());
+        let stamp = match state.clock.stamp(&nonce) {
+            Ok(stamp) => stamp,
+            Err(e) => return e,
+        };
+        let signed =
+            to_signed_response(&state.eph_kp, rejection, &stamp, IntentScope::MlJobRejected);
+        EnclaveError::Rejected {
+            reason,
+            signed: serde_json::to_value(signed).expect("should not fail"),
+        }
+    };
+
+    // 1. Download model config and check the job against the pool's policy
+    let config_bytes = download_blob(&payload.model_config_blob_id).await?;
+    let config: ModelConfig = serde_json::from_slice(&config_bytes)
+        .map_err(|e| EnclaveError::InvalidInput(e.to_string()))?;
+    config.validate()?;
+    if config.search.is_some() {
+        return unsupported("hyperparameter search");
+    }
+    if config.training.early_stopping.is_some() || config.training.robust.is_some() {
+        return unsupported("early stopping or robust aggregation");
+    }
+    policy
+        .check_training(&config, payload.seal_model, false)
+        .map_err(reject)?;
+
+    // 2. Authenticate every worker from its attestation document
+    let client = reqwest::Client::builder()
+        .timeout(WORKER_TIMEOUT)
+        .build()
+        .map_err(|e| EnclaveError::Network(e.to_string()))?;
+    let mut workers: Vec<Peer> = Vec::with_capacity(request.workers.len());
+    for url in &request.workers {
+        let url = url.trim_end_matches('/').to_string();
+        let attestation: GetAttestationResponse = client
+            .get(format!("{url}/get_attestation"))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| EnclaveError::Network(format!("worker {url}: {e}")))?
+            .json()
+            .await
+            .map_err(|e| EnclaveError::Network(format!("worker {url}: {e}")))?;
+        let key = authenticate_peer(&attestation.attestation, current_timestamp_ms())?;
+        if key == *state.eph_kp.public() || workers.iter().any(|w| w.key == key) {
+            return Err(EnclaveError::InvalidInput(format!(
+                "worker {url} is listed twice or is the coordinator"
+            )));
+        }
+        workers.push(Peer {
+            url,
+            key,
+            session_id: uuid::Uuid::new_v4().to_string(),
+        });
+    }
+    let Json(own) = get_attestation(State(state.clone())).await?;
+
+    // 3. Open a session per shard, each worker loading and checking its blobs
+    let seed = payload.seed.unwrap_or_else(rand::random);
+    let shards = deal(&payload.data_blob_ids, workers.len());
+    let opens = workers
+        .iter()
+        .zip(&shards)
+        .enumerate()
+        .map(|(index, (worker, shard))| OpenRequest {
+            attestation: own.attestation.clone(),
+            message: sign(
+                &state,
+                OpenShard {
+                    session_id: worker.session_id.clone(),
+                    job_id: payload.job_id,
+                    pool_id: policy.pool_id,
+                    worker: index as u64,
+                    workers: workers.len() as u64,
+                    data_blob_ids: shard.clone(),
+                    model_config_blob_id: payload.model_config_blob_id.clone(),
+                    key_id: payload.key_id.clone(),
+                    quality: payload.quality.clone(),
+                    enclave_object: payload.enclave_object.clone(),
+                    validation_split: payload.validation_split,
+                    seed,
+                    learning_rate: payload.learning_rate,
+                    local_epochs: request.local_epochs,
+                    secure_aggregation_threshold: request.secure_aggregation_threshold,
+                },
+            ),
+        })
+        .collect();
+    let everyone: Vec<usize> = (0..workers.len()).collect();
+    let replies: Vec<Signed<ShardOpened>> =
+        broadcast(&client, &workers, &everyone, "open", opens).await?;
+    let mut opened = Vec::with_capacity(workers.len());
+    for ((worker, shard), reply) in workers.iter().zip(&shards).zip(&replies) {
+        let reply = verify_reply(worker, reply)?;
+        if reply.data_quality.len() != shard.len() || reply.stats.len() != config.input_size {
+            return Err(EnclaveError::PeerAuthentication(format!(
+                "worker {} opened a different shard",
+                worker.url
+            )));
+        }
+        opened.push(reply);
+    }
+
+    // 4. Check the cohort over all shards and fit the preprocessing on their
+    // merged statistics
+    let data_quality: Vec<BlobReport> = opened
+        .iter()
+        .flat_map(|o| o.data_quality.iter().cloned())
+        .collect();
+    let blob_digests: Vec<(String, Vec<u8>)> = opened
+        .iter()
+        .flat_map(|o| o.blob_digests.iter().cloned())
+        .collect();
+    let cohort = check_cohort(&policy, &contributors, &data_quality).map_err(reject)?;
+    let training_rows: Vec<u64> = opened.iter().map(|o| o.training_rows).collect();
+    let validation_rows: u64 = opened.iter().map(|o| o.validation_rows).sum();
+    let num_samples = training_rows.iter().sum::<u64>() + validation_rows;
+    if training_rows.iter().all(|rows| *rows == 0) {
+        return Err(EnclaveError::InvalidInput(
+            "no training data passed the quality checks".to_string(),
+        ));
+    }
+    let (now_ms, _) = state.clock.now_ms()?;
+    usage::charge(&state, |usage| policy.charge_job(usage, now_ms))?.map_err(reject)?;
+    let mut stats = vec![ColumnStats::default(); config.input_size];
+    for shard in &opened {
+        for (merged, column) in stats.iter_mut().zip(&shard.stats) {
+            merged.merge(column);
+        }
+    }
+    let pipeline = config.preprocessing.fit_stats(config.input_size, &stats)?;
+    let mut network = Mlp::new(&config, pipeline.output_width(), &mut trial_rng(seed, 0))?;
+
+    // 5. FedAvg rounds, the workers' weights masked under secure aggregation
+    let mut dropped_at: Vec<Option<u64>> = vec![None; workers.len()];
+    let mut contributors_by_round = vec![];
+    for round in 0..payload.epochs {
+        let weights = network.parameters();
+        let fitted = (round == 0).then_some(&pipeline);
+        let average = match request.secure_aggregation_threshold {
+            None => {
+                clear_round(
+                    &state,
+                    &client,
+                    &workers,
+                    &training_rows,
+                    round,
+                    fitted,
+                    &weights,
+                )
+                .await?
+            }
+            Some(threshold) => {
+                let (average, summed) = secure_round(
+                    &state,
+                    &client,
+                    &workers,
+                    &training_rows,
+                    threshold as usize,
+                    &mut dropped_at,
+                    round,
+                    fitted,
+                    &weights,
+                )
+                .await?;
+                contributors_by_round.push(summed);
+                average
+            }
+        };
+        network.set_parameters(&average)?;
+        info!("federated round {} of {} done", round + 1, payload.epochs);
+    }
+    // The shards of workers that dropped out only trained the early rounds,
+    // the cohort must hold without them
+    let cohort = match dropped_at.iter().any(Option::is_some) {
+        false => cohort,
+        true => {
+            let kept: Vec<BlobReport> = opened
+                .iter()
+                .zip(&dropped_at)
+                .flat_map(|(o, dropped)| {
+                    o.data_quality.iter().map(move |report| BlobReport {
+                        rows_used: if dropped.is_some() {
+                            0
+                        } else {
+                            report.rows_used
+                        },
+                        ..report.clone()
+                    })
+                })
+                .collect();
+            check_cohort(&policy, &contributors, &kept).map_err(reject)?
+        }
+    };
+    let active: Vec<usize> = (0..workers.len())
+        .filter(|i| dropped_at[*i].is_none())
+        .collect();
+
+    // 6. Evaluate the final weights on every remaining shard, which also
+    // closes the sessions
+    let on_validation = active.iter().any(|i| opened[*i].validation_rows > 0);
+    let weights = network.parameters();
+    let messages = active
+        .iter()
+        .map(|i| {
+            sign(
+                &state,
+                CloseRequest {
+                    session_id: workers[*i].session_id.clone(),
+                    weights: weights.clone(),
+                    on_validation,
+                },
+            )
+        })
+        .collect();
+    let replies: Vec<Signed<ShardEvaluation>> =
+        broadcast(&client, &workers, &active, "close", messages).await?;
+    let (mut rows, mut accuracy, mut loss) = (0u64, 0.0f64, 0.0f64);
+    for (i, reply) in active.iter().zip(&replies) {
+        let evaluation = verify_reply(&workers[*i], reply)?;
+        rows += evaluation.rows;
+        accuracy += evaluation.accuracy as f64 * evaluation.rows as f64;
+        loss += evaluation.loss as f64 * evaluation.rows as f64;
+    }
+    let rows = rows.max(1) as f64;
+    let accuracy = (accuracy / rows / 100.0 * METRIC_SCALE as f64) as u64;
+    let final_loss = (loss / rows * METRIC_SCALE as f64) as u64;
+
+    // 7. Package the model with its config, schema and provenance
+    let stamp = state.clock.stamp(&nonce)?;
+    let pcrs = read_pcrs().unwrap_or_else(|e| {
+        info!("artifact will carry no PCRs: {e}");
+        vec![]
+    });
+    let artifact = ModelArtifact {
+        version: ARTIFACT_VERSION,
+        input_schema: InputSchema::new(&config, &pipeline),
+        config,
+        preprocessing: pipeline,
+        hyperparameters: Hyperparameters {
+            learning_rate: (payload.learning_rate as f64 / LEARNING_RATE_SCALE) as f32,
+            epochs: payload.epochs,
+            seed,
+            trial: 0,
+        },
+        provenance: Provenance {
+            data_blob_ids: payload.data_blob_ids.clone(),
+            dataset_digest: dataset_digest(&blob_digests),
+        },
+        query_budget: payload.query_budget.unwrap_or(DEFAULT_QUERY_BUDGET),
+        aggregates_only: policy.outputs() == OutputPolicy::Aggregates,
+        min_batch_size: policy.min_group_size(),
+        network,
+        enclave: EnclaveInfo {
+            pcrs,
+            public_key: state.eph_kp.public().as_bytes().to_vec(),
+            trained_at_ms: stamp.timestamp_ms,
+        },
+    };
+    let model_hash = artifact.digest();
+    let training_hash = artifact.training_digest();
+    let model_bytes = artifact.to_bytes();
+    let model_bytes = if payload.seal_model {
+        seal(&state, MODEL_AAD, &model_bytes)?
+    } else {
+        model_bytes
+    };
+    let model_blob_id = upload_blob(&model_bytes).await?;
+
+    // 8. Return signed result
+    let response = FederatedTrainingResponse {
+        job_id: payload.job_id,
+        model_blob_id,
+        accuracy,
+        final_loss,
+        num_samples,
+        data_quality,
+        cohort,
+        seed,
+        rounds: payload.epochs,
+        local_epochs: request.local_epochs,
+        workers: workers
+            .iter()
+            .zip(shards)
+            .zip(training_rows)
+            .zip(dropped_at)
+            .map(
+                |(((worker, data_blob_ids), training_rows), dropped_at_round)| WorkerReport {
+                    public_key: worker.key.as_bytes().to_vec(),
+                    data_blob_ids,
+                    training_rows,
+                    dropped_at_round,
+                },
+            )
+            .collect(),
+        secure_aggregation: request.secure_aggregation_threshold.map(|threshold| {
+            SecureAggregationReport {
+                threshold,
+                contributors: contributors_by_round,
+            }
+        }),
+        model_hash,
+        training_hash,
+    };
+    Ok(Json(to_signed_response(
+        &state.eph_kp,
+        response,
+        &stamp,
+        IntentScope::MlFederatedTraining,
+    )))
+}
+
+/// One FedAvg round with the weights of every worker in the clear. Returns
+/// their average.
+async fn clear_round(
+    state: &AppState,
+    client: &reqwest::Client,
+    workers: &[Peer],
+    training_rows: &[u64],
+    round: u64,
+    pipeline: Option<&Pipeline>,
+    weights: &[f32],
+) -> Result<Vec<f32>, EnclaveError> {
+    let everyone: Vec<usize> = (0..workers.len()).collect();
+    let messages = workers
+        .iter()
+        .map(|worker| sign(state, round_request(worker, round, pipeline, weights)))
+        .collect();
+    let replies: Vec<Signed<RoundUpdate>> =
+        broadcast(client, workers, &everyone, "round", messages).await?;
+    let mut updates = Vec::with_capacity(workers.len());
+    for ((worker, rows), reply) in workers.iter().zip(training_rows).zip(&replies) {
+        let update = verify_reply(worker, reply)?;
+        if update.round != round || update.weights.len() != weights.len() {
+            return Err(unexpected_reply(worker));
+        }
+        updates.push((*rows, update.weights.as_slice()));
+    }
+    Ok(average(&updates))
+}
+
+/// One FedAvg round under secure aggregation, over the workers that have
+/// not dropped out. Workers that stop answering are marked in `dropped_at`
+/// and left out of the rest of the job. Returns the average of the weights
+/// summed, and the indices of the workers they came from.
+#[allow(clippy::too_many_arguments)]
+async fn secure_round(
+    state: &AppState,
+    client: &reqwest::Client,
+    workers: &[Peer],
+    training_rows: &[u64],
+    threshold: usize,
+    dropped_at: &mut [Option<u64>],
+    round: u64,
+    pipeline: Option<&Pipeline>,
+    weights: &[f32],
+) -> Result<(Vec<f32>, Vec<u64>), EnclaveError> {
+    let mut active: Vec<usize> = (0..workers.len())
+        .filter(|i| dropped_at[*i].is_none())
+        .collect();
+    let session = |i: &usize| workers[*i].session_id.clone();
+
+    // 1. Local training, each worker answering with the keys of its masks
+    let messages = active
+        .iter()
+        .map(|i| sign(state, round_request(&workers[*i], round, pipeline, weights)))
+        .collect();
+    let replies: Vec<Option<Signed<RoundUpdate>>> =
+        gather(client, workers, &active, "round", messages).await;
+    let replies = keep_answered(&mut active, replies, dropped_at, round, threshold)?;
+    let mut keys = BTreeMap::new();
+    for (i, reply) in active.iter().zip(&replies) {
+        let update = verify_reply(&workers[*i], reply)?;
+        match &update.keys {
+            Some(advertised) if update.round == round => keys.insert(*i as u64, advertised.clone()),
+            _ => return Err(unexpected_reply(&workers[*i])),
+        };
+    }
+
+    // 2. Every worker shares its mask secrets with the others, encrypted to
+    // them, which lets the survivors unmask the sum if it drops out
+    let messages = active
+        .iter()
+        .map(|i| {
+            sign(
+                state,
+                ShareRequest {
+                    session_id: session(i),
+                    round,
+                    keys: keys.clone(),
+                },
+            )
+        })
+        .collect();
+    let replies: Vec<Option<Signed<SharesSent>>> =
+        gather(client, workers, &active, "share", messages).await;
+    let replies = keep_answered(&mut active, replies, dropped_at, round, threshold)?;
+    let mut inboxes: BTreeMap<u64, Vec<EncryptedShares>> = BTreeMap::new();
+    for (i, reply) in active.iter().zip(&replies) {
+        let sent = verify_reply(&workers[*i], reply)?;
+        let recipients: Vec<u64> = sent.shares.iter().map(|s| s.to).collect();
+        let expected: Vec<u64> = keys.keys().copied().filter(|v| *v != *i as u64).collect();
+        if sent.round != round
+            || recipients != expected
+            || sent.shares.iter().any(|s| s.from != *i as u64)
+        {
+            return Err(unexpected_reply(&workers[*i]));
+        }
+        for shares in &sent.shares {
+            inboxes.entry(shares.to).or_default().push(shares.clone());
+        }
+    }
+    let shared: Vec<u64> = active.iter().map(|i| *i as u64).collect();
+
+    // 3. Workers mask their weights, pairwise with every worker that shared
+    let messages = active
+        .iter()
+        .map(|i| {
+            sign(
+                state,
+                MaskRequest {
+                    session_id: session(i),
+                    round,
+                    shares: inboxes.remove(&(*i as u64)).unwrap_or_default(),
+                },
+            )
+        })
+        .collect();
+    let replies: Vec<Option<Signed<MaskedUpdate>>> =
+        gather(client, workers, &active, "mask", messages).await;
+    let replies = keep_answered(&mut active, replies, dropped_at, round, threshold)?;
+    let mut masked = BTreeMap::new();
+    for (i, reply) in active.iter().zip(&replies) {
+        let update = verify_reply(&workers[*i], reply)?;
+        if update.round != round || update.masked.len() != weights.len() {
+            return Err(unexpected_reply(&workers[*i]));
+        }
+        masked.insert(*i as u64, update.masked.clone());
+    }
+    let survivors: Vec<u64> = masked.keys().copied().collect();
+    let dropped: Vec<u64> = shared
+        .into_iter()
+        .filter(|v| !masked.contains_key(v))
+        .collect();
+
+    // 4. The survivors reveal the shares that unmask the sum
+    let messages = active
+        .iter()
+        .map(|i| {
+            sign(
+                state,
+                UnmaskRequest {
+                    session_id: session(i),
+                    round,
+                    survivors: survivors.clone(),
+                },
+            )
+        })
+        .collect();
+    let replies: Vec<Option<Signed<UnmaskReply>>> =
+        gather(client, workers, &active, "unmask", messages).await;
+    let replies = keep_answered(&mut active, replies, dropped_at, round, threshold)?;
+    let mut unmaskings = Vec::with_capacity(replies.len());
+    for (i, reply) in active.iter().zip(&replies) {
+        let reply = verify_reply(&workers[*i], reply)?;
+        if reply.round != round {
+            return Err(unexpected_reply(&workers[*i]));
+        }
+        unmaskings.push(reply.unmasking.clone());
+    }
+    let sum = unmask_sum(threshold, &keys, &masked, &dropped, &unmaskings)?;
+    let rows: u64 = survivors.iter().map(|u| training_rows[*u as usize]).sum();
+    if rows == 0 {
+        return Err(EnclaveError::InvalidInput(
+            "the surviving workers hold no training rows".to_string(),
+        ));
+    }
+    Ok((decode(&sum, rows), survivors))
+}
+
+fn round_request(
+    worker: &Peer,
+    round: u64,
+    pipeline: Option<&Pipeline>,
+    weights: &[f32],
+) -> RoundRequest {
+    RoundRequest {
+        session_id: worker.session_id.clone(),
+        round,
+        pipeline: pipeline.cloned(),
+        weights: weights.to_vec(),
+    }
+}
+
+/// Keep the workers of `active` that answered, in order, and mark the
+/// others as dropped out at `round`. Fails below `threshold` workers.
+fn keep_answered<R>(
+    active: &mut Vec<usize>,
+    replies: Vec<Option<R>>,
+    dropped_at: &mut [Option<u64>],
+    round: u64,
+    threshold: usize,
+) -> Result<Vec<R>, EnclaveError> {
+    let mut kept = Vec::with_capacity(active.len());
+    let mut answered = Vec::with_capacity(active.len());
+    for (i, reply) in active.iter().zip(replies) {
+        match reply {
+            Some(reply) => {
+                kept.push(*i);
+                answered.push(reply);
+            }
+            None => dropped_at[*i] = Some(round),
+        }
+    }
+    if kept.len() < threshold {
+        return Err(EnclaveError::Network(format!(
+            "{} workers answered in round {round}, below the secure aggregation threshold \
+             {threshold}",
+            kept.len()
+        )));
+    }
+    *active = kept;
+    Ok(answered)
+}
+
+fn unexpected_reply(worker: &Peer) -> EnclaveError {
+    EnclaveError::PeerAuthentication(format!("worker {} sent an unexpected reply", worker.url))
+}
+
+/// Split `blob_ids` into `workers` contiguous shards of nearly equal size.
+fn deal(blob_ids: &[String], workers: usize) -> Vec<Vec<String>> {
+    let n = blob_ids.len();
+    (0..workers)
+        .map(|k| blob_ids[k * n / workers..(k + 1) * n / workers].to_vec())
+        .collect()
+}
+
+/// FedAvg: the mean of the workers' weights, weighted by their training
+/// rows. Accumulated in worker order, so the result is deterministic.
+fn average(updates: &[(u64, &[f32])]) -> Vec<f32> {
+    let total: u64 = updates.iter().map(|(rows, _)| rows).sum();
+    let mut sum = vec![0.0f64; updates.first().map_or(0, |(_, w)| w.len())];
+    for (rows, weights) in updates {
+        let share = *rows as f64 / total as f64;
+        for (s, w) in sum.iter_mut().zip(*weights) {
+            *s += share * *w as f64;
+        }
+    }
+    sum.into_iter().map(|s| s as f32).collect()
+}
+
+/// Send `messages[k]` to worker `targets[k]` at
+/// `/mltraining/federated/<path>`, all at once. Returns the replies in the
+/// same order, failing when any worker does not answer.
+async fn broadcast<M, R>(
+    client: &reqwest::Client,
+    workers: &[Peer],
+    targets: &[usize],
+    path: &'static str,
+    messages: Vec<M>,
+) -> Result<Vec<R>, EnclaveError>
+where
+    M: Serialize + Send + 'static,
+    R: DeserializeOwned + Send + 'static,
+{
+    let mut tasks = JoinSet::new();
+    for (k, (i, message)) in targets.iter().zip(messages).enumerate() {
+        let url = format!("{}/mltraining/federated/{path}", workers[*i].url);
+        let reply = post(client.clone(), url, message);
+        tasks.spawn(async move { (k, reply.await) });
+    }
+    let mut replies: Vec<Option<R>> = targets.iter().map(|_| None).collect();
+    // Returning early drops `tasks`, which aborts the other calls
+    while let Some(joined) = tasks.join_next().await {
+        let (k, reply) = joined.map_err(|e| EnclaveError::GenericError(e.to_string()))?;
+        replies[k] = Some(reply.map_err(EnclaveError::Network)?);
+    }
+    Ok(replies
+        .into_iter()
+        .map(|r| r.expect("every task joined"))
+        .collect())
+}
+
+/// As [`broadcast`], but waits for every worker and returns `None` for
+/// those that did not answer.
+async fn gather<M, R>(
+    client: &reqwest::Client,
+    workers: &[Peer],
+    targets: &[usize],
+    path: &'static str,
+    messages: Vec<M>,
+) -> Vec<Option<R>>
+where
+    M: Serialize + Send + 'static,
+    R: DeserializeOwned + Send + 'static,
+{
+    let mut tasks = JoinSet::new();
+    for (k, (i, message)) in targets.iter().zip(messages).enumerate() {
+        let url = format!("{}/mltraining/federated/{path}", workers[*i].url);
+        let reply = post(client.clone(), url, message);
+        tasks.spawn(async move { (k, reply.await) });
+    }
+    let mut replies: Vec<Option<R>> = targets.iter().map(|_| None).collect();
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok((k, Ok(reply))) => replies[k] = Some(reply),
+            Ok((_, Err(e))) => info!("worker dropped out: {e}"),
+            Err(e) => info!("worker call failed: {e}"),
+        }
+    }
+    replies
+}
+
+async fn post<M: Serialize, R: DeserializeOwned>(
+    client: reqwest::Client,
+    url: String,
+    message: M,
+) -> Result<R, String> {
+    let request = client.post(&url).json(&message);
+    let reply = async {
+        let resp = request.send().await.map_err(|e| e.to_string())?;
+        let status = resp.status();
+        if !status.is_success() {
+            let body = resp.text().await.unwrap_or_default();
+            return Err(format!("{status} {body}"));
+        }
+        resp.json::<R>().await.map_err(|e| e.to_string())
+    };
+    reply.await.map_err(|e| format!("{url}: {e}"))
+}
+
+/// The data of a worker's reply, once checked to be signed by the worker
+/// for its own session.
+fn verify_reply<'a, T: Message>(
+    worker: &Peer,
+    reply: &'a Signed<T>,
+) -> Result<&'a T, EnclaveError> {
+    let data = verify_signed(&worker.key, reply, T::INTENT)?;
+    if data.session_id() != worker.session_id {
+        return Err(EnclaveError::PeerAuthentication(format!(
+            "worker {} answered for another session",
+            worker.url
+        )));
+    }
+    Ok(data)
+}
+
+// === WORKER ===
+pub async fn open_shard(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<OpenRequest>,
+) -> Result<Json<Signed<ShardOpened>>, EnclaveError> {
+    // 1. Authenticate the coordinator
+    let coordinator = authenticate_peer(&req.attestation, current_timestamp_ms())?;
+    let shard = verify_signed(&coordinator, &req.message, OpenShard::INTENT)?;
+    if SESSIONS
+        .lock()
+        .expect("not poisoned")
+        .contains_key(&shard.session_id)
+    {
+        return Err(EnclaveError::InvalidInput(format!(
+            "session {} is already open",
+            shard.session_id
+        )));
+    }
+
+    // 2. Load the rows of the shard that pass the quality checks
+    let config_bytes = download_blob(&shard.model_config_blob_id).await?;
+    let config: ModelConfig = serde_json::from_slice(&config_bytes)
+        .map_err(|e| EnclaveError::InvalidInput(e.to_string()))?;
+    let checker = QualityChecker::new(config.input_size, config.output_size, &shard.quality)?;
+    let rows = load_rows(
+        &state,
+        &shard.data_blob_ids,
+        SealedJob {
+            job_id: shard.job_id,
+            pool_id: shard.pool_id,
+            key_id: &shard.key_id,
+            enclave: shard.enclave_object.as_ref(),
+        },
+        checker,
+    )
+    .await?;
+
+    // 3. Hold out the shard's validation split and summarize its training rows
+    let mut rng = worker_rng(shard.seed, shard.worker);
+    let (training, validation) = match rows.dataset.is_empty() {
+        true => (vec![], vec![]),
+        false => rows.dataset.split(shard.validation_split, &mut rng)?,
+    };
+    let stats = rows
+        .dataset
+        .rows(&training)
+        .stats(&config.preprocessing, config.input_size)?;
+    let opened = ShardOpened {
+        session_id: shard.session_id.clone(),
+        data_quality: rows.data_quality,
+        blob_digests: rows.blob_digests,
+        training_rows: training.len() as u64,
+        validation_rows: validation.len() as u64,
+        stats,
+    };
+
+    let now = current_timestamp_ms();
+    let mut sessions = SESSIONS.lock().expect("not poisoned");
+    sessions.retain(|_, s| now.saturating_sub(s.last_used_ms) < SESSION_TIMEOUT_MS);
+    sessions.insert(
+        shard.session_id.clone(),
+        Session {
+            coordinator,
+            config,
+            learning_rate: (shard.learning_rate as f64 / LEARNING_RATE_SCALE) as f32,
+            local_epochs: shard.local_epochs,
+            rows: rows.dataset,
+            training,
+            validation,
+            pipeline: None,
+            round: 0,
+            network: None,
+            rng,
+            index: shard.worker,
+            workers: shard.workers,
+            secure_aggregation_threshold: shard.secure_aggregation_threshold.map(|t| t as usize),
+            masking: None,
+            last_used_ms: now,
+        },
+    );
+    Ok(Json(sign(&state, opened)))
+}
+
+pub async fn train_round(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<Signed<RoundRequest>>,
+) -> Result<Json<Signed<RoundUpdate>>, EnclaveError> {
+    let mut session = take_session(&req)?;
+    let request = &req.response.data;
+    if request.round != session.round {
+        return Err(EnclaveError::InvalidInput(format!(
+            "expected round {}",
+            session.round
+        )));
+    }
+
+    // Round 0 brings the preprocessing fitted on all shards
+    match (&request.pipeline, request.round) {
+        (Some(pipeline), 0) => {
+            let network = Mlp::new(&session.config, pipeline.output_width(), &mut session.rng)?;
+            session.network = Some(network);
+            session.pipeline = Some(pipeline.clone());
+        }
+        (None, round) if round > 0 => {}
+        _ => {
+            return Err(EnclaveError::InvalidInput(
+                "the preprocessing comes with round 0 only".to_string(),
+            ));
+        }
+    }
+    let mut network = session.network.take().expect("created in round 0");
+    network.set_parameters(&request.weights)?;
+    if !session.training.is_empty() {
+        let pipeline = session.pipeline.as_ref().expect("set in round 0");
+        train(
+            &mut network,
+            session.rows.rows(&session.training).transformed(pipeline),
+            session.rows.rows(&[]),
+            &session.config.training,
+            session.learning_rate,
+            session.local_epochs,
+            &mut session.rng,
+        )?;
+    }
+    // Under secure aggregation the weights stay here until masked
+    let update = match session.secure_aggregation_threshold {
+        None => RoundUpdate {
+            session_id: request.session_id.clone(),
+            round: request.round,
+            weights: network.parameters(),
+            keys: None,
+        },
+        Some(threshold) => {
+            let input = encode(&network.parameters(), session.training.len() as u64)?;
+            let (masking, keys) = Client::new(
+                session.index,
+                session.workers as usize,
+                threshold,
+                input,
+                &mut OsRng,
+            )?;
+            session.masking = Some(masking);
+            RoundUpdate {
+                session_id: request.session_id.clone(),
+                round: request.round,
+                weights: vec![],
+                keys: Some(keys),
+            }
+        }
+    };
+    session.network = Some(network);
+    session.round += 1;
+    restore_session(&request.session_id, session);
+    Ok(Json(sign(&state, update)))
+}
+
+pub async fn share_keys(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<Signed<ShareRequest>>,
+) -> Result<Json<Signed<SharesSent>>, EnclaveError> {
+    let mut session = take_session(&req)?;
+    let request = &req.response.data;
+    let shares = masking_of(&mut session, request.round)?.share_keys(&request.keys, &mut OsRng)?;
+    restore_session(&request.session_id, session);
+    Ok(Json(sign(
+        &state,
+        SharesSent {
+            session_id: request.session_id.clone(),
+            round: request.round,
+            shares,
+        },
+    )))
+}
+
+pub async fn mask_update(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<Signed<MaskRequest>>,
+) -> Result<Json<Signed<MaskedUpdate>>, EnclaveError> {
+    let mut session = take_session(&req)?;
+    let request = &req.response.data;
+    let masked = masking_of(&mut session, request.round)?.mask(&request.shares)?;
+    restore_session(&request.session_id, session);
+    Ok(Json(sign(
+        &state,
+        MaskedUpdate {
+            session_id: request.session_id.clone(),
+            round: request.round,
+            masked,
+        },
+    )))
+}
+
+pub async fn unmask(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<Signed<UnmaskRequest>>,
+) -> Result<Json<Signed<UnmaskReply>>, EnclaveError> {
+    let mut session = take_session(&req)?;
+    let request = &req.response.data;
+    masking_of(&mut session, request.round)?;
+    let masking = session.masking.take().expect("checked above");
+    let unmasking = masking.unmask(&request.survivors)?;
+    restore_session(&request.session_id, session);
+    Ok(Json(sign(
+        &state,
+        UnmaskReply {
+            session_id: request.session_id.clone(),
+            round: request.round,
+            unmasking,
+        },
+    )))
+}
+
+pub async fn close_shard(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<Signed<CloseRequest>>,
+) -> Result<Json<Signed<ShardEvaluation>>, EnclaveError> {
+    let session = take_session(&req)?;
+    let request = &req.response.data;
+    let mut network = session
+        .network
+        .ok_or_else(|| EnclaveError::InvalidInput("the session ran no round".to_string()))?;
+    network.set_parameters(&request.weights)?;
+    let pipeline = session.pipeline.as_ref().expect("set with the network");
+    let rows = match request.on_validation {
+        true => session.rows.rows(&session.validation),
+        false => session.rows.rows(&session.training),
+    };
+    let (accuracy, loss) = rows.transformed(pipeline).evaluate(&network)?;
+    let evaluation = ShardEvaluation {
+        session_id: request.session_id.clone(),
+        rows: rows.len() as u64,
+        accuracy,
+        loss,
+    };
+    Ok(Json(sign(&state, evaluation)))
+}
+
+/// Remove the session `message` is for, once checked to come from its
+/// coordinator. A failed round leaves the session closed.
+fn take_session<T: Message>(message: &Signed<T>) -> Result<Session, EnclaveError> {
+    let mut sessions = SESSIONS.lock().expect("not poisoned");
+    let session_id = message.response.data.session_id();
+    let session = sessions
+        .get(session_id)
+        .ok_or_else(|| EnclaveError::InvalidInput(format!("session {session_id} is not open")))?;
+    verify_signed(&session.coordinator, message, T::INTENT)?;
+    Ok(sessions.remove(session_id).expect("checked above"))
+}
+
+/// The masking of `round`, the last round the session trained.
+fn masking_of(session: &mut Session, round: u64) -> Result<&mut Client, EnclaveError> {
+    match &mut session.masking {
+        Some(masking) if round + 1 == session.round => Ok(masking),
+        _ => Err(EnclaveError::InvalidInput(format!(
+            "no secure aggregation in progress for round {round}"
+        ))),
+    }
+}
+
+/// Put back a session taken with [`take_session`].
+fn restore_session(session_id: &str, mut session: Session) {
+    session.last_used_ms = current_timestamp_ms();
+    SESSIONS
+        .lock()
+        .expect("not poisoned")
+        .insert(session_id.to_string(), session);
+}
+
+// === SIGNATURES ===
+/// A protocol message. Coordinator messages are signed under
+/// `MlFederatedControl`, worker replies under `MlFederatedUpdate`.
+trait Message: Serialize + Clone {
+    const INTENT: IntentScope;
+    fn session_id(&self) -> &str;
+}
+
+impl Message for OpenShard {
+    const INTENT: IntentScope = IntentScope::MlFederatedControl;
+    fn session_id(&self) -> &str {
+        &self.session_id
+    }
+}
+
+impl Message for ShardOpened {
+    const INTENT: IntentScope = IntentScope::MlFederatedUpdate;
+    fn session_id(&self) -> &str {
+        &self.session_id
+    }
+}
+
+impl Message for RoundRequest {
+    const INTENT: IntentScope = IntentScope::MlFederatedControl;
+    fn session_id(&self) -> &str {
+        &self.session_id
+    }
+}
+
+impl Message for RoundUpdate {
+    const INTENT: IntentScope = IntentScope::MlFederatedUpdate;
+    fn session_id(&self) -> &str {
+        &self.session_id
+    }
+}
+
+impl Message for ShareRequest {
+    const INTENT: IntentScope = IntentScope::MlFederatedControl;
+    fn session_id(&self) -> &str {
+        &self.session_id
+    }
+}
+
+impl Message for SharesSent {
+    const INTENT: IntentScope = IntentScope::MlFederatedUpdate;
+    fn session_id(&self) -> &str {
+        &self.session_id
+    }
+}
+
+impl Message for MaskRequest {
+    const INTENT: IntentScope = IntentScope::MlFederatedControl;
+    fn session_id(&self) -> &str {
+        &self.session_id
+    }
+}
+
+impl Message for MaskedUpdate {
+    const INTENT: IntentScope = IntentScope::MlFederatedUpdate;
+    fn session_id(&self) -> &str {
+        &self.session_id
+    }
+}
+
+impl Message for UnmaskRequest {
+    const INTENT: IntentScope = IntentScope::MlFederatedControl;
+    fn session_id(&self) -> &str {
+        &self.session_id
+    }
+}
+
+impl Message for UnmaskReply {
+    const INTENT: IntentScope = IntentScope::MlFederatedUpdate;
+    fn session_id(&self) -> &str {
+        &self.session_id
+    }
+}
+
+impl Message for CloseRequest {
+    const INTENT: IntentScope = IntentScope::MlFederatedControl;
+    fn session_id(&self) -> &str {
+        &self.session_id
+    }
+}
+
+impl Message for ShardEvaluation {
+    const INTENT: IntentScope = IntentScope::MlFederatedUpdate;
+    fn session_id(&self) -> &str {
+        &self.session_id
+    }
+}
+
+/// Sign a protocol message of this enclave. Messages are bound to their
+/// session rather than to a request nonce, and stamped with the host time,
+/// which peers do not rely on.
+fn sign<T: Message>(state: &AppState, data: T) -> Signed<T> {
+    let stamp = Stamp {
+        timestamp_ms: current_timestamp_ms(),
+        ..Stamp::default()
+    };
+    to_signed_response(&state.eph_kp, data, &stamp, T::INTENT)
+}
+
+/// The data of `message`, once checked to be signed by `key` under `intent`.
+fn verify_signed<'a, T: Serialize>(
+    key: &Ed25519PublicKey,
+    message: &'a Signed<T>,
+    intent: IntentScope,
+) -> Result<&'a T, EnclaveError> {
+    let rejected = |reason: &str| EnclaveError::PeerAuthentication(reason.to_string());
+    if message.response.intent != intent {
+        return Err(rejected("message signed under another intent"));
+    }
+    let signature = Hex::decode(&message.signature)
+        .ok()
+        .and_then(|bytes| Ed25519Signature::from_bytes(&bytes).ok())
+        .ok_or_else(|| rejected("malformed signature"))?;
+    let bytes = bcs::to_bytes(&message.response).expect("should not fail");
+    key.verify(&bytes, &signature)
+        .map_err(|_| rejected("signature does not verify"))?;
+    Ok(&message.response.data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_deal() {
+        let ids: Vec<String> = (0..7).map(|i| i.to_string()).collect();
+        let shards = deal(&ids, 3);
+        assert_eq!(
+            shards,
+            [vec!["0", "1"], vec!["2", "3"], vec!["4", "5", "6"]]
+        );
+        // Every blob once, in order
+        assert_eq!(shards.concat(), ids);
+        assert_eq!(deal(&ids[..2], 2), [vec!["0"], vec!["1"]]);
+    }
+
+    #[test]
+    fn test_average() {
+        let a = [1.0, -2.0, 4.0];
+        let b = [3.0, 2.0, 0.0];
+        assert_eq!(average(&[(10, &a), (30, &b)]), [2.5, 1.0, 1.0]);
+        assert_eq!(average(&[(5, &a)]), a);
+        assert!(average(&[]).is_empty());
+    }
+}
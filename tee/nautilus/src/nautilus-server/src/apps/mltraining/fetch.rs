@@ -0,0 +1,142 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Blob downloads with retries across aggregators.
+//!
+//! Blobs are fetched concurrently, at most `MAX_CONCURRENT_FETCHES` at a time.
+//! A failed attempt is retried on the next aggregator after an exponential
+//! backoff with jitter. Every blob is checked against its blob id before it
+//! enters the cache, so a wrong answer from an aggregator, or from the host's
+//! proxy in front of it, only costs a retry. If every attempt returns wrong
+//! bytes the fetch fails with [`EnclaveError::BlobIdMismatch`]. Blobs over
+//! `MAX_BLOB_BYTES` fail without a retry, as soon as their length is known.
+
+use super::cache::{self, CacheWriter, CachedBlob};
+use super::integrity::check_blob_size;
+use crate::EnclaveError;
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tracing::info;
+
+/// Aggregators tried in turn, all listed in `allowed_endpoints.yaml`.
+pub const AGGREGATORS: &[&str] = &[
+    "https://aggregator.walrus-testnet.walrus.space",
+    "https://wal-aggregator-testnet.staketab.org",
+    "https://walrus-testnet-aggregator.nodes.guru",
+];
+
+/// Environment variable naming an aggregator to use instead of
+/// `AGGREGATORS`, e.g. a local stand-in. Debug builds only: the host sets the
+/// environment of a release enclave.
+pub const AGGREGATOR_ENV: &str = "WALRUS_AGGREGATOR";
+
+pub const MAX_CONCURRENT_FETCHES: usize = 4;
+
+/// Attempts per blob, across all aggregators.
+pub const MAX_ATTEMPTS: u32 = 6;
+
+const BASE_BACKOFF: Duration = Duration::from_millis(250);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(16);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest wait for the next bytes of a response before the attempt fails.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fetch `blob_ids` through the cache, in request order.
+pub async fn fetch_blobs(blob_ids: &[String]) -> Result<Vec<CachedBlob>, EnclaveError> {
+    let permits = Arc::new(Semaphore::new(MAX_CONCURRENT_FETCHES));
+    let mut tasks = JoinSet::new();
+    for (index, blob_id) in blob_ids.iter().enumerate() {
+        let permits = permits.clone();
+        let blob_id = blob_id.clone();
+        tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await.expect("never closed");
+            (index, fetch_blob(&blob_id).await)
+        });
+    }
+    let mut blobs: Vec<Option<CachedBlob>> = blob_ids.iter().map(|_| None).collect();
+    // Returning early drops `tasks`, which aborts the other fetches
+    while let Some(joined) = tasks.join_next().await {
+        let (index, blob) = joined.map_err(|e| EnclaveError::GenericError(e.to_string()))?;
+        blobs[index] = Some(blob?);
+    }
+    Ok(blobs
+        .into_iter()
+        .map(|b| b.expect("every fetch joined"))
+        .collect())
+}
+
+/// Fetch one blob through the cache.
+pub async fn fetch_blob(blob_id: &str) -> Result<CachedBlob, EnclaveError> {
+    if let Some(blob) = cache::lookup(blob_id) {
+        return Ok(blob);
+    }
+    let aggregators = aggregators();
+    let mut attempt = 0;
+    loop {
+        let aggregator = &aggregators[attempt as usize % aggregators.len()];
+        match fetch_from(aggregator, blob_id).await {
+            Ok(blob) => return Ok(blob),
+            // A malformed blob id fails the same way everywhere
+            Err(e) if attempt + 1 < MAX_ATTEMPTS && !matches!(e, EnclaveError::InvalidInput(_)) => {
+                info!("fetching {blob_id} from {aggregator} failed, retrying: {e}");
+                tokio::time::sleep(backoff(attempt)).await;
+            }
+            Err(e) => return Err(e),
+        }
+        attempt += 1;
+    }
+}
+
+fn aggregators() -> Vec<String> {
+    match std::env::var(AGGREGATOR_ENV) {
+        Ok(url) if cfg!(debug_assertions) => vec![url],
+        _ => AGGREGATORS.iter().map(|a| a.to_string()).collect(),
+    }
+}
+
+async fn fetch_from(aggregator: &str, blob_id: &str) -> Result<CachedBlob, EnclaveError> {
+    let network = |e: reqwest::Error| EnclaveError::Network(e.to_string());
+    let client = reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .build()
+        .map_err(network)?;
+    let mut response = client
+        .get(format!("{aggregator}/v1/{blob_id}"))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .map_err(network)?;
+    if let Some(length) = response.content_length() {
+        check_blob_size(length)?;
+    }
+
+    let mut writer = CacheWriter::new(blob_id)?;
+    loop {
+        let chunk = tokio::time::timeout(READ_TIMEOUT, response.chunk())
+            .await
+            .map_err(|_| EnclaveError::Network(format!("{aggregator} stalled")))?
+            .map_err(network)?;
+        match chunk {
+            Some(bytes) => writer.write(&bytes)?,
+            None => break,
+        }
+    }
+    tokio::task::spawn_blocking(move || writer.commit())
+        .await
+        .map_err(|e| EnclaveError::GenericError(e.to_string()))?
+}
+
+/// Delay before retry `attempt + 1`: doubling from `BASE_BACKOFF` up to
+/// `MAX_BACKOFF`, plus up to half of it again at random.
+fn backoff(attempt: u32) -> Duration {
+    let delay = BASE_BACKOFF
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_BACKOFF);
+    delay + delay.mul_f64(rand::thread_rng().gen_range(0.0..0.5))
+}
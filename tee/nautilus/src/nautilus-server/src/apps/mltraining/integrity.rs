@@ -0,0 +1,81 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Checks that downloaded bytes are the blob their Walrus blob id commits to.
+//!
+//! A blob id is a hash of the blob's encoding metadata, so it can be
+//! recomputed from the bytes alone by re-encoding them as the uploader did.
+//! This needs the whole blob in memory along with its RS2 encoding, several
+//! times its size. Checks run one at a time, with the blob only read into
+//! memory once the check holds the lock, and blobs over `MAX_BLOB_BYTES` are
+//! refused before they are buffered. The memory of blob checks thus peaks at
+//! one blob of at most `MAX_BLOB_BYTES` plus its encoding, however many blobs
+//! are fetched at once.
+//!
+//! Every blob the enclave reads (data, model configs, and models loaded by
+//! `/predict`) is checked before it enters the blob cache. Blob ids returned
+//! by the publisher are checked the same way after an upload.
+
+use crate::EnclaveError;
+use lazy_static::lazy_static;
+use std::num::NonZeroU16;
+use std::str::FromStr;
+use std::sync::Mutex;
+use walrus_core::encoding::{EncodingConfig, EncodingConfigTrait as _};
+use walrus_core::{BlobId, EncodingType};
+
+/// Shards of the Walrus network the blobs are stored on.
+pub const WALRUS_N_SHARDS: u16 = 1000;
+
+/// Largest blob the enclave reads or writes. Larger pools are split across
+/// more blobs.
+pub const MAX_BLOB_BYTES: u64 = 128 << 20;
+
+lazy_static! {
+    static ref CHECK: Mutex<()> = Mutex::new(());
+}
+
+/// Fails unless a blob of `size` bytes may be read or written.
+pub fn check_blob_size(size: u64) -> Result<(), EnclaveError> {
+    if size > MAX_BLOB_BYTES {
+        return Err(EnclaveError::InvalidInput(format!(
+            "blob of {size} bytes, at most {MAX_BLOB_BYTES} are allowed"
+        )));
+    }
+    Ok(())
+}
+
+/// The blob id `bytes` are stored under.
+pub fn blob_id_of(bytes: &[u8]) -> Result<BlobId, EnclaveError> {
+    let _check = CHECK.lock().expect("poisoned lock");
+    encode(bytes)
+}
+
+fn encode(bytes: &[u8]) -> Result<BlobId, EnclaveError> {
+    check_blob_size(bytes.len() as u64)?;
+    let config = EncodingConfig::new(NonZeroU16::new(WALRUS_N_SHARDS).expect("non zero"));
+    let metadata = config
+        .get_for_type(EncodingType::RS2)
+        .compute_metadata(bytes)
+        .map_err(|e| EnclaveError::InvalidInput(e.to_string()))?;
+    Ok(*metadata.blob_id())
+}
+
+/// Fails with [`EnclaveError::BlobIdMismatch`] unless the bytes `read`
+/// returns encode to `blob_id`. `read` runs under the check lock.
+pub fn verify_blob_id<F>(blob_id: &str, read: F) -> Result<(), EnclaveError>
+where
+    F: FnOnce() -> Result<Vec<u8>, EnclaveError>,
+{
+    let expected = BlobId::from_str(blob_id)
+        .map_err(|e| EnclaveError::InvalidInput(format!("invalid blob id {blob_id}: {e}")))?;
+    let _check = CHECK.lock().expect("poisoned lock");
+    let computed = encode(&read()?)?;
+    if computed != expected {
+        return Err(EnclaveError::BlobIdMismatch {
+            blob_id: blob_id.to_string(),
+            computed: computed.to_string(),
+        });
+    }
+    Ok(())
+}
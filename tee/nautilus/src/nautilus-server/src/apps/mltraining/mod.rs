@@ -1,269 +1,708 @@
-// src/apps/ml_training/mod.rs
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
 use crate::common::{
-    IntentMessage, to_signed_response, IntentScope, ProcessDataRequest, ProcessedDataResponse,
+    read_pcrs, to_signed_response, IntentMessage, IntentScope, ProcessDataRequest,
+    ProcessedDataResponse,
 };
+use crate::registry::{parse_allowed_endpoints, EnclaveApp};
 use crate::AppState;
 use crate::EnclaveError;
-use axum::{extract::State, Json};
+use axum::{extract::State, routing::post, Json, Router};
+use fastcrypto::traits::{KeyPair, ToFromBytes};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
-use std::fs;
+use tracing::info;
+
+mod artifact;
+mod cache;
+pub(crate) mod chain;
+mod crossval;
+pub(crate) mod crypto;
+pub(crate) mod dataset;
+mod export;
+mod federated;
+mod fetch;
+mod integrity;
+mod model;
+mod optim;
+pub(crate) mod policy;
+pub(crate) mod pool;
+mod predict;
+mod preprocess;
+pub(crate) mod quality;
+mod robust;
+#[cfg(any(feature = "analytics", feature = "synthetic"))]
+pub(crate) mod sampling;
+pub(crate) mod seal;
+mod search;
+mod secagg;
+mod spill;
+mod stream;
+mod train;
+pub(crate) mod usage;
+
+use artifact::{
+    blob_digest, dataset_digest, EnclaveInfo, Hyperparameters, InputSchema, ModelArtifact,
+    Provenance, ARTIFACT_VERSION,
+};
+use chain::{RpcReader, SuiReader};
+use crossval::{check_folds, cross_validate, CrossValidation};
+use crypto::{seal, DATA_KEY_SECRET, SEALING_KEY_SECRET};
+use dataset::check_validation_split;
+use export::{verify_safetensors_round_trip, ExportFormat};
+use fetch::fetch_blob;
+use integrity::blob_id_of;
+use model::{Mlp, ModelConfig};
+use policy::{CohortReport, OutputPolicy, PolicyCheck};
+use pool::{check_cohort, load_rows, verify_pool, PoolRows};
+use quality::{BlobReport, QualityChecker, QualityConfig};
+use robust::{ShardReport, DOWN_WEIGHT_THRESHOLD};
+use seal::{EnclaveObject, SealedJob};
+use search::{Trial, TrialSummary, MAX_SEARCH_ROW_EPOCHS};
+use train::{job_rng, train, trial_rng};
+
+/// Private model training over pooled contributor data.
+pub struct MlTrainingApp;
+
+impl EnclaveApp for MlTrainingApp {
+    fn name(&self) -> &'static str {
+        "mltraining"
+    }
 
-// === REAL KEYPAIR (generated once at startup) ===
-use once_cell::sync::Lazy;
-use ed25519_dalek::{Keypair, PublicKey};
-use rand::rngs::OsRng;
+    fn routes(&self) -> Router<Arc<AppState>> {
+        Router::new()
+            .route("/process_data", post(process_data))
+            .route("/predict", post(predict::predict))
+            .route("/federated/train", post(federated::coordinate))
+            .route("/federated/open", post(federated::open_shard))
+            .route("/federated/round", post(federated::train_round))
+            .route("/federated/share", post(federated::share_keys))
+            .route("/federated/mask", post(federated::mask_update))
+            .route("/federated/unmask", post(federated::unmask))
+            .route("/federated/close", post(federated::close_shard))
+    }
 
-static KEYPAIR: Lazy<Keypair> = Lazy::new(|| {
-    let mut csprng = OsRng;
-    Keypair::generate(&mut csprng)
-});
+    fn intent_scopes(&self) -> &'static [IntentScope] {
+        &[
+            IntentScope::MlTraining,
+            IntentScope::MlPredict,
+            IntentScope::MlJobRejected,
+            IntentScope::MlFederatedTraining,
+            IntentScope::MlFederatedControl,
+            IntentScope::MlFederatedUpdate,
+            IntentScope::MlTrainingReport,
+        ]
+    }
 
-static PUBLIC_KEY_B64: Lazy<String> = Lazy::new(|| {
-    base64::encode(KEYPAIR.public.as_bytes())
-});
+    fn required_secrets(&self) -> &'static [&'static str] {
+        &[SEALING_KEY_SECRET, DATA_KEY_SECRET]
+    }
 
-// === PUBLIC KEY ENDPOINT===
-#[derive(Serialize)]
-pub struct PublicKeyResponse {
-    pub public_key: String,
-}
+    fn allowed_endpoints(&self) -> Vec<String> {
+        parse_allowed_endpoints(include_str!("allowed_endpoints.yaml"))
+    }
 
-pub async fn get_public_key() -> Json<PublicKeyResponse> {
-    Json(PublicKeyResponse {
-        public_key: PUBLIC_KEY_B64.clone(),
-    })
+    fn check_config(&self) -> Result<(), EnclaveError> {
+        seal::check_config()
+    }
 }
 
+/// Publisher models are stored through.
+const PUBLISHER: &str = "https://publisher.walrus-testnet.walrus.space";
+
+/// Environment variable naming a publisher to use instead of `PUBLISHER`,
+/// e.g. a local stand-in. Debug builds only: the host sets the environment
+/// of a release enclave.
+pub const PUBLISHER_ENV: &str = "WALRUS_PUBLISHER";
+
+/// Associated data label of sealed model blobs.
+const MODEL_AAD: &str = "cloakx-model";
+
+/// Rows `/predict` answers for a model when the job sets no budget.
+const DEFAULT_QUERY_BUDGET: u64 = 10_000;
+
+/// Fixed-point scale of the float metrics in `MLTrainingResponse` and `TrainingReport`.
+pub const METRIC_SCALE: f32 = 10_000.0;
+
+/// Fixed-point scale of `learning_rate`, as stored in `cloakx::jobs::Job`:
+/// the rate used is `learning_rate / LEARNING_RATE_SCALE`, so `100` is `0.01`.
+pub const LEARNING_RATE_SCALE: f64 = 10_000.0;
+
+/// Preprocessed training rows replayed through re-imported exports to check they match.
+const EXPORT_CHECK_ROWS: usize = 64;
+
 // === REQUEST ===
 #[derive(Deserialize, Debug)]
 pub struct MLTrainingRequest {
+    /// Id of the `cloakx::jobs::Job` run. The request must match it, and
+    /// `data_blob_ids` must be its pool's `pool_data` entry.
+    pub job_id: u64,
     pub data_blob_ids: Vec<String>,
     pub model_config_blob_id: String,
     pub key_id: String,
-    pub learning_rate: f64,
+    /// Base learning rate in fixed point, see `LEARNING_RATE_SCALE`.
+    pub learning_rate: u64,
     pub epochs: u64,
-}
-
-// === MODEL CONFIG ===
-#[derive(Deserialize, Debug)]
-pub struct LayerConfig {
-    pub neurons: usize,
-    pub activation: String,
-    pub dropout: Option<f32>,
-}
-
-#[derive(Deserialize, Debug)]
-pub struct ModelConfig {
-    pub input_size: usize,
-    pub output_size: usize,
-    pub layers: Vec<LayerConfig>,
+    /// Seal the trained model to the enclave so the buyer only gets predictions.
+    #[serde(default)]
+    pub seal_model: bool,
+    /// Rows `/predict` may answer for the trained model.
+    pub query_budget: Option<u64>,
+    /// Portable formats to export the trained model to, e.g. `["onnx", "safetensors"]`.
+    #[serde(default)]
+    pub export_formats: Vec<ExportFormat>,
+    /// Fraction of the rows held out for evaluation, in `[0, 1)`.
+    #[serde(default)]
+    pub validation_split: f32,
+    /// Seed of the training randomness. Drawn by the enclave when absent.
+    pub seed: Option<u64>,
+    /// Score the config with k-fold cross validation, then train the
+    /// returned model on all rows. Excludes `validation_split`.
+    pub cross_validation_folds: Option<u64>,
+    /// Thresholds of the per-blob data quality checks.
+    #[serde(default)]
+    pub quality: QualityConfig,
+    /// This enclave's registered `Enclave<JOBS>` object, which the key
+    /// servers check the Seal policy against. Needed for sealed data blobs.
+    pub enclave_object: Option<EnclaveObject>,
 }
 
 // === RESPONSE ===
+/// Signed training result, byte-identical to `cloakx::jobs::MLTrainingResponse`
+/// so that `complete_job` can verify it. The rest of the run is reported in
+/// the `TrainingReport` signed next to it.
 #[derive(Serialize, Clone, Debug)]
 pub struct MLTrainingResponse {
     pub model_blob_id: String,
-    pub accuracy: String,
-    pub final_loss: String,
-    pub num_samples: String,
+    /// Accuracy as a fraction, scaled by `METRIC_SCALE`. Measured on the
+    /// validation split, averaged over the folds in cross validation, or on
+    /// the training rows otherwise.
+    pub accuracy: u64,
+    /// Mean cross-entropy loss on the same rows, scaled by `METRIC_SCALE`.
+    pub final_loss: u64,
+    /// Rows used for training, after the data quality checks.
+    pub num_samples: u64,
+    /// Sha256 of the canonical `ModelArtifact` encoding (before sealing).
+    pub model_hash: Vec<u8>,
 }
 
-// === BURN IMPORTS ===
-use burn::{
-    module::{Module, Param},
-    tensor::{backend::AutodiffBackend, Tensor, Device},
-    nn::{
-        Linear, LinearConfig, ReLU, Sigmoid, Tanh, LeakyReLU,
-        Dropout, DropoutConfig, Initializer,
-    },
-    data::dataloader::DataLoaderBuilder,
-    train::{TrainerBuilder, LearnerBuilder, TrainStep},
-    record::BinBytesRecorder,
-};
-use burn::backend::{NdArray, NdArrayDevice};
-use burn::tensor::backend::Backend;
-
-type Backend = NdArray;
-type Autodiff = AutodiffBackend<Backend>;
-type Device = NdArrayDevice;
-
-// === DYNAMIC MODEL ===
-#[derive(Module, Debug)]
-pub struct DynamicNet<B: Backend> {
-    layers: Vec<Box<dyn Module<B>>>,
-    output: Linear<B>,
+/// How a model was trained, signed with intent `MlTrainingReport`. Not
+/// verified on chain: `model_hash` ties it to its `MLTrainingResponse`.
+#[derive(Serialize, Clone, Debug)]
+pub struct TrainingReport {
+    /// The on-chain job the request was checked against.
+    pub job_id: u64,
+    /// `model_hash` of the `MLTrainingResponse` this reports on.
+    pub model_hash: Vec<u8>,
+    /// Data quality of each blob, in request order.
+    pub data_quality: Vec<BlobReport>,
+    /// Contributors and rows used, with the pool's minimums they met.
+    pub cohort: CohortReport,
+    /// Sha256 of the `ModelArtifact` encoding without the training enclave's
+    /// public key and timestamp. Rerunning the job on the same enclave image
+    /// and inputs with `seed` reproduces it.
+    pub training_hash: Vec<u8>,
+    /// Seed the model was trained with.
+    pub seed: u64,
+    /// Epochs actually run, fewer than requested if training stopped early.
+    pub epochs_run: u64,
+    /// Epoch whose weights were kept.
+    pub best_epoch: u64,
+    /// One row per hyperparameter search trial, empty without search.
+    pub trials: Vec<TrialSummary>,
+    /// Index in `trials` of the returned model.
+    pub best_trial: u64,
+    /// Per-fold metric spread, when cross validation was requested.
+    pub cross_validation: Option<CrossValidation>,
+    /// Influence of each data blob under robust aggregation, empty otherwise.
+    pub shard_influence: Vec<ShardReport>,
+    /// Portable exports of the model, in request order.
+    pub exports: Vec<ModelExport>,
 }
 
-impl<B: Backend> DynamicNet<B> {
-    pub fn forward(&self, x: Tensor<B, 2>) -> Tensor<B, 2> {
-        let mut x = x;
-        for layer in &self.layers {
-            x = layer.forward(x);
-        }
-        self.output.forward(x)
-    }
+/// Response of `/process_data`: the signed result `complete_job` takes, with
+/// the signed report of the run under `report`.
+#[derive(Serialize)]
+pub struct MLTrainingResult {
+    #[serde(flatten)]
+    pub result: ProcessedDataResponse<IntentMessage<MLTrainingResponse>>,
+    pub report: ProcessedDataResponse<IntentMessage<TrainingReport>>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ModelExport {
+    pub format: String,
+    pub blob_id: String,
+    /// Sha256 of the exported file.
+    pub hash: Vec<u8>,
 }
 
 // === MAIN TRAINING  ===
 pub async fn process_data(
-    State(_state): State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
     Json(req): Json<ProcessDataRequest<MLTrainingRequest>>,
-) -> Result<Json<ProcessedDataResponse<IntentMessage<MLTrainingResponse>>>, EnclaveError> {
+) -> Result<Json<MLTrainingResult>, EnclaveError> {
     let payload = &req.payload;
-    let device = Device::Cpu;
+    let nonce = req.nonce()?;
+    if payload.seal_model && !payload.export_formats.is_empty() {
+        return Err(EnclaveError::InvalidInput(
+            "sealed models cannot be exported".to_string(),
+        ));
+    }
 
-    // 1. Download and save model config
+    // 0. Check the request against the on-chain job before touching any data
+    let (policy, contributors) = verify_job(&RpcReader::from_config()?, payload).await?;
+    let reject = |reason: String| {
+        let rejection = policy.rejection(payload.job_id, reason.clone());
+        let stamp = match state.clock.stamp(&nonce) {
+            Ok(stamp) => stamp,
+            Err(e) => return e,
+        };
+        let signed =
+            to_signed_response(&state.eph_kp, rejection, &stamp, IntentScope::MlJobRejected);
+        EnclaveError::Rejected {
+            reason,
+            signed: serde_json::to_value(signed).expect("should not fail"),
+        }
+    };
+
+    // 1. Download and check the model config, then check the job against the
+    // pool's policy
     let config_bytes = download_blob(&payload.model_config_blob_id).await?;
-    fs::create_dir_all("assets")?;
-    fs::write("assets/model_config.json", &config_bytes)?;
     let config: ModelConfig = serde_json::from_slice(&config_bytes)
         .map_err(|e| EnclaveError::InvalidInput(e.to_string()))?;
-
-    // 2. Download and parse all data
-    let mut inputs = vec![];
-    let mut targets = vec![];
-    for blob_id in &payload.data_blob_ids {
-        let data = download_blob(blob_id).await?;
-        let batch: Vec<(Vec<f32>, usize)> = serde_json::from_slice(&data)
-            .map_err(|e| EnclaveError::InvalidInput(e.to_string()))?;
-        for (input, label) in batch {
-            inputs.push(input);
-            targets.push(label);
-        }
+    validate_job(payload, &config)?;
+    policy
+        .check_training(
+            &config,
+            payload.seal_model,
+            !payload.export_formats.is_empty(),
+        )
+        .map_err(reject)?;
+
+    // 2. Fetch all data into the verified blob cache and unseal the keys of
+    // sealed blobs from the Seal key servers. Then stream each blob through
+    // decryption and the quality checks, keeping the rows that pass. Missing
+    // values are `null`.
+    let checker = QualityChecker::new(config.input_size, config.output_size, &payload.quality)?;
+    let rows = load_rows(
+        &state,
+        &payload.data_blob_ids,
+        SealedJob {
+            job_id: payload.job_id,
+            pool_id: policy.pool_id,
+            key_id: &payload.key_id,
+            enclave: payload.enclave_object.as_ref(),
+        },
+        checker,
+    )
+    .await?;
+    let cohort = check_cohort(&policy, &contributors, &rows.data_quality).map_err(reject)?;
+    let PoolRows {
+        dataset,
+        data_quality,
+        blob_digests,
+    } = rows;
+    let num_samples = dataset.len();
+    if num_samples == 0 {
+        return Err(EnclaveError::InvalidInput(
+            "no training data passed the quality checks".to_string(),
+        ));
     }
-    let num_samples = inputs.len();
-
-    // 3. Build REAL dynamic network
-    let mut layers = vec![];
-    let mut in_features = config.input_size;
-
-    for layer in config.layers {
-        let linear = LinearConfig::new(in_features, layer.neurons)
-            .with_initializer(Initializer::XavierUniform { gain: 1.0 })
-            .init(&device);
-        layers.push(Box::new(linear) as Box<dyn Module<Backend>>);
-
-        let activation: Box<dyn Module<Backend>> = match layer.activation.as_str() {
-            "relu" => Box::new(ReLU::new()),
-            "sigmoid" => Box::new(Sigmoid::new()),
-            "tanh" => Box::new(Tanh::new()),
-            "leaky_relu" => Box::new(LeakyReLU::new(0.01)),
-            _ => Box::new(ReLU::new()),
-        };
-        layers.push(activation);
 
-        if let Some(p) = layer.dropout {
-            if p > 0.0 {
-                let dropout = DropoutConfig::new(p).init();
-                layers.push(Box::new(dropout));
+    // Count the job against the pool's rate only once it passed the checks
+    let (now_ms, _) = state.clock.now_ms()?;
+    usage::charge(&state, |usage| policy.charge_job(usage, now_ms))?.map_err(reject)?;
+
+    // 3. Cross validate if requested, then hold out a validation split and fit
+    // preprocessing on the training rows only. All randomness from here on
+    // derives from the seed.
+    let seed = payload.seed.unwrap_or_else(rand::random);
+    let mut rng = job_rng(seed);
+    let learning_rate = (payload.learning_rate as f64 / LEARNING_RATE_SCALE) as f32;
+    let cross_validation = match payload.cross_validation_folds {
+        Some(folds) => Some(cross_validate(
+            &config,
+            learning_rate,
+            &dataset,
+            folds,
+            payload.epochs,
+            seed,
+            &mut rng,
+        )?),
+        None => None,
+    };
+    let (training, validation) = dataset.split(payload.validation_split, &mut rng)?;
+    let pipeline = dataset
+        .rows(&training)
+        .fit(&config.preprocessing, config.input_size)?;
+    let train_set = dataset.rows(&training).transformed(&pipeline);
+    let validation_set = dataset.rows(&validation).transformed(&pipeline);
+
+    // 4. Expand the hyperparameter search, if any, into trials
+    let searched = config.search.is_some();
+    let trials = match &config.search {
+        Some(search) => {
+            if validation_set.is_empty() {
+                return Err(EnclaveError::InvalidInput(
+                    "hyperparameter search needs a validation_split".to_string(),
+                ));
             }
+            let trials = search.trials(&config, learning_rate, &mut rng)?;
+            let work = trials.len() as u64 * payload.epochs * train_set.len() as u64;
+            if work > MAX_SEARCH_ROW_EPOCHS {
+                return Err(EnclaveError::InvalidInput(
+                    "hyperparameter search exceeds the enclave training budget".to_string(),
+                ));
+            }
+            trials
         }
-        in_features = layer.neurons;
-    }
+        None => vec![Trial {
+            config: config.clone(),
+            learning_rate,
+        }],
+    };
+    let evaluation_set = if validation_set.is_empty() {
+        train_set
+    } else {
+        validation_set
+    };
 
-    let output = LinearConfig::new(in_features, config.output_size)
-        .with_initializer(Initializer::XavierUniform { gain: 1.0 })
-        .init(&device);
-
-    let model = DynamicNet { layers, output };
-
-    // 4. Trai model
-    let mut trainer = LearnerBuilder::new()
-        .devices(vec![device.clone()])
-        .num_epochs(payload.epochs as usize)
-        .build(model, payload.learning_rate as f32, device);
-
-    for epoch in 1..=payload.epochs {
-        let mut correct = 0;
-        let mut total = 0;
-        for (input, target) in inputs.iter().zip(targets.iter()) {
-            let input_tensor = Tensor::<Autodiff, 2>::from_data(
-                input.as_slice().into(),
-                &device,
-            ).reshape([1, config.input_size]);
-
-            let target_tensor = Tensor::<Autodiff, 1>::from_data(
-                vec![*target as f32],
-                &device,
-            ).reshape([1]);
-
-            let output = trainer.forward(input_tensor.clone());
-            let pred = output.argmax(1).int().to_data().value[0] as usize;
-            if pred == *target { correct += 1; }
-            total += 1;
-
-            trainer.backward_step(&output, &target_tensor);
-            trainer.update();
-        }
-        if epoch % 10 == 0 || epoch == payload.epochs {
-            println!("Epoch {}: Accuracy = {:.2}%", epoch, (correct as f32 / total as f32) * 100.0);
+    // 5. Train a model per trial and keep the one with the lowest evaluation loss
+    let mut summaries = Vec::with_capacity(trials.len());
+    let mut best = None;
+    for (index, trial) in trials.iter().enumerate() {
+        trial.config.training.validate()?;
+        let mut rng = trial_rng(seed, index as u64);
+        let mut network = Mlp::new(&trial.config, pipeline.output_width(), &mut rng)?;
+        let report = train(
+            &mut network,
+            train_set,
+            validation_set,
+            &trial.config.training,
+            trial.learning_rate,
+            payload.epochs,
+            &mut rng,
+        )?;
+        let (accuracy, loss) = evaluation_set.evaluate(&network)?;
+        summaries.push(TrialSummary::new(
+            trial,
+            report.epochs_run,
+            (accuracy / 100.0 * METRIC_SCALE) as u64,
+            (loss * METRIC_SCALE) as u64,
+        ));
+        // A diverged trial, with a non finite loss, is never kept
+        let better = match &best {
+            Some((_, _, _, best_loss)) => loss.total_cmp(best_loss).is_lt(),
+            None => true,
+        };
+        if loss.is_finite() && better {
+            best = Some((index, network, report, loss));
         }
     }
+    let (best_trial, network, report, _) = best.ok_or_else(|| {
+        EnclaveError::InvalidInput("training diverged in every trial".to_string())
+    })?;
+    let Trial {
+        config,
+        learning_rate,
+    } = trials
+        .into_iter()
+        .nth(best_trial)
+        .expect("best trial exists");
+    if !searched {
+        summaries.clear();
+    }
 
-    // 5. Save trained model
-    let recorder = BinBytesRecorder::<burn::record::FullPrecisionSettings>::new();
-    let model_bytes = trainer.model()
-        .save_with_recorder(recorder)
-        .expect("Failed to serialize model");
-    fs::write("assets/trained_model.pkl", &model_bytes)?;
+    // 6. Final evaluation
+    let (accuracy, final_loss) = match &cross_validation {
+        Some(cv) => (cv.accuracy_mean, cv.loss_mean),
+        None => {
+            let (accuracy, loss) = evaluation_set.evaluate(&network)?;
+            (
+                (accuracy / 100.0 * METRIC_SCALE) as u64,
+                (loss * METRIC_SCALE) as u64,
+            )
+        }
+    };
 
-    // 6. Upload to Walrus
-    let model_blob_id = upload_blob(&model_bytes).await?;
+    let shard_influence = report
+        .shard_influence
+        .iter()
+        .map(|(shard, influence)| ShardReport {
+            blob_id: payload.data_blob_ids[*shard].clone(),
+            influence: (influence * METRIC_SCALE) as u64,
+            down_weighted: *influence < DOWN_WEIGHT_THRESHOLD,
+        })
+        .collect();
+
+    // 7. Package trained model with its config, schema and provenance
+    let stamp = state.clock.stamp(&nonce)?;
+    let pcrs = read_pcrs().unwrap_or_else(|e| {
+        info!("artifact will carry no PCRs: {e}");
+        vec![]
+    });
+    let artifact = ModelArtifact {
+        version: ARTIFACT_VERSION,
+        input_schema: InputSchema::new(&config, &pipeline),
+        config,
+        preprocessing: pipeline,
+        hyperparameters: Hyperparameters {
+            learning_rate,
+            epochs: payload.epochs,
+            seed,
+            trial: best_trial as u64,
+        },
+        provenance: Provenance {
+            data_blob_ids: payload.data_blob_ids.clone(),
+            dataset_digest: dataset_digest(&blob_digests),
+        },
+        query_budget: payload.query_budget.unwrap_or(DEFAULT_QUERY_BUDGET),
+        aggregates_only: policy.outputs() == OutputPolicy::Aggregates,
+        min_batch_size: policy.min_group_size(),
+        network,
+        enclave: EnclaveInfo {
+            pcrs,
+            public_key: state.eph_kp.public().as_bytes().to_vec(),
+            trained_at_ms: stamp.timestamp_ms,
+        },
+    };
+    let model_hash = artifact.digest();
+    let training_hash = artifact.training_digest();
+    let model_bytes = artifact.to_bytes();
+    let model_bytes = if payload.seal_model {
+        seal(&state, MODEL_AAD, &model_bytes)?
+    } else {
+        model_bytes
+    };
 
-    // 7. Final evaluation 
-    let final_model = trainer.model();
-    let mut correct = 0;
-    let mut total_loss = 0.0;
-    for (input, target) in inputs.iter().zip(targets.iter()) {
-        let input_tensor = Tensor::<Backend, 2>::from_data(input.as_slice().into(), &device)
-            .reshape([1, config.input_size]);
-        let output = final_model.forward(input_tensor);
-        let pred = output.argmax(1).int().to_data().value[0] as usize;
-        if pred == *target { correct += 1; }
-        total_loss += (pred as f32 - *target as f32).powi(2);
+    // 8. Upload to Walrus, along with the requested exports
+    let model_blob_id = upload_blob(&model_bytes).await?;
+    let mut exports = Vec::with_capacity(payload.export_formats.len());
+    for format in &payload.export_formats {
+        let bytes = format.export(&artifact.network, &artifact.preprocessing);
+        if *format == ExportFormat::Safetensors {
+            let check_rows = train_set.head(EXPORT_CHECK_ROWS)?;
+            verify_safetensors_round_trip(&artifact.network, &bytes, &check_rows)?;
+        }
+        exports.push(ModelExport {
+            format: format.name().to_string(),
+            blob_id: upload_blob(&bytes).await?,
+            hash: blob_digest(&bytes),
+        });
     }
 
-    let accuracy = (correct as f32 / inputs.len() as f32) * 100.0;
-    let final_loss = total_loss / inputs.len() as f32;
-
-    // 8. Return signed result
+    // 9. Return signed result and report
     let response = MLTrainingResponse {
         model_blob_id,
-        accuracy: format!("{:.6}", accuracy),
-        final_loss: format!("{:.6}", final_loss),
-        num_samples: num_samples.to_string(),
+        accuracy,
+        final_loss,
+        num_samples: num_samples as u64,
+        model_hash: model_hash.clone(),
+    };
+    let training_report = TrainingReport {
+        job_id: payload.job_id,
+        model_hash,
+        training_hash,
+        data_quality,
+        cohort,
+        seed,
+        epochs_run: report.epochs_run,
+        best_epoch: report.best_epoch,
+        trials: summaries,
+        best_trial: best_trial as u64,
+        cross_validation,
+        shard_influence,
+        exports,
     };
 
-    let timestamp_ms = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64;
-
-    Ok(Json(to_signed_response(
-        &KEYPAIR,
-        response,
-        timestamp_ms,
-        IntentScope::ProcessData,
-    )))
+    Ok(Json(MLTrainingResult {
+        result: to_signed_response(&state.eph_kp, response, &stamp, IntentScope::MlTraining),
+        report: to_signed_response(
+            &state.eph_kp,
+            training_report,
+            &stamp,
+            IntentScope::MlTrainingReport,
+        ),
+    }))
+}
+
+/// Checks of the request and its model config that need no data, so a bad
+/// job fails before any blob is downloaded.
+fn validate_job(payload: &MLTrainingRequest, config: &ModelConfig) -> Result<(), EnclaveError> {
+    config.validate()?;
+    if payload.learning_rate == 0 {
+        return Err(EnclaveError::InvalidInput(
+            "learning_rate must be non zero".to_string(),
+        ));
+    }
+    check_validation_split(payload.validation_split)?;
+    let validation_split = payload.validation_split != 0.0;
+    match payload.cross_validation_folds {
+        Some(_) if validation_split => {
+            return Err(EnclaveError::InvalidInput(
+                "cross validation cannot be combined with a validation_split".to_string(),
+            ));
+        }
+        Some(folds) => check_folds(config, folds)?,
+        None => {}
+    }
+    if config.search.is_some() && !validation_split {
+        return Err(EnclaveError::InvalidInput(
+            "hyperparameter search needs a validation_split".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Check that `payload` runs its on-chain job: still pending, with the same
+/// model config and hyperparameters, over exactly the data of an active pool.
+/// Returns the pool's policy and the contributor of each data blob.
+async fn verify_job<R: SuiReader>(
+    reader: &R,
+    payload: &MLTrainingRequest,
+) -> Result<(PolicyCheck, Vec<String>), EnclaveError> {
+    let job = reader.job(payload.job_id).await?;
+    let mismatch = |e: String| Err(EnclaveError::JobMismatch(e));
+    if job.status != "Pending" {
+        return mismatch(format!("job {} is {}", job.job_id, job.status));
+    }
+    if job.model_config_blob_id != payload.model_config_blob_id {
+        return mismatch(format!(
+            "job {} trains model config {}",
+            job.job_id, job.model_config_blob_id
+        ));
+    }
+    if job.epochs != payload.epochs || job.learning_rate != payload.learning_rate {
+        return mismatch(format!(
+            "job {} trains {} epochs at learning rate {}",
+            job.job_id, job.epochs, job.learning_rate
+        ));
+    }
+    verify_pool(reader, job.pool_id, &payload.data_blob_ids).await
 }
 
 // === WALRUS HELPERS  ===
-async fn download_blob(blob_id: &str) -> Result<Vec<u8>, EnclaveError> {
-    let url = format!("https://aggregator.walrus-testnet.walrus.space/v1/{}", blob_id);
-    reqwest::get(&url).await.map_err(|e| EnclaveError::Network(e.to_string()))?
-        .bytes().await.map(|b| b.to_vec()).map_err(|e| EnclaveError::Network(e.to_string()))
+/// Fetch a whole blob, checked against its blob id.
+pub(crate) async fn download_blob(blob_id: &str) -> Result<Vec<u8>, EnclaveError> {
+    fetch_blob(blob_id).await?.to_vec()
 }
 
-async fn upload_blob(data: &[u8]) -> Result<String, EnclaveError> {
+/// Store `data` on Walrus and return its blob id, computed in the enclave
+/// and checked against the one the publisher reports.
+pub(crate) async fn upload_blob(data: &[u8]) -> Result<String, EnclaveError> {
+    let blob_id = blob_id_of(data)?.to_string();
+    let publisher = match std::env::var(PUBLISHER_ENV) {
+        Ok(url) if cfg!(debug_assertions) => url,
+        _ => PUBLISHER.to_string(),
+    };
     let resp = reqwest::Client::new()
-        .put("https://publisher.walrus-testnet.walrus.space/v1/store")
+        .put(format!("{publisher}/v1/store"))
         .body(data.to_vec())
         .header("Content-Type", "application/octet-stream")
         .send()
         .await
         .map_err(|e| EnclaveError::Network(e.to_string()))?;
 
-    let json: serde_json::Value = resp.json().await.map_err(|e| EnclaveError::Network(e.to_string()))?;
-    Ok(json["newlyCreated"]["blobObject"]["blobId"].as_str().unwrap_or("unknown").to_string())
-}
\ No newline at end of file
+    let json: serde_json::Value = resp
+        .json()
+        .await
+        .map_err(|e| EnclaveError::Network(e.to_string()))?;
+    let stored = json["newlyCreated"]["blobObject"]["blobId"]
+        .as_str()
+        .or_else(|| json["alreadyCertified"]["blobId"].as_str())
+        .ok_or_else(|| EnclaveError::Network(format!("unexpected publisher response: {json}")))?;
+    if stored != blob_id {
+        return Err(EnclaveError::BlobIdMismatch {
+            blob_id: stored.to_string(),
+            computed: blob_id,
+        });
+    }
+    Ok(blob_id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chain::stub::StubReader;
+    use chain::{OnChainJob, OnChainPool};
+    use serde_json::json;
+
+    fn reader(status: &str) -> StubReader {
+        let mut reader = StubReader::default();
+        reader.jobs.insert(
+            7,
+            OnChainJob {
+                job_id: 7,
+                pool_id: 3,
+                model_config_blob_id: "config".to_string(),
+                epochs: 10,
+                learning_rate: 100,
+                status: status.to_string(),
+                buyer_public_key: vec![],
+            },
+        );
+        reader.pools.insert(
+            3,
+            OnChainPool {
+                active: true,
+                metadata: b"{}".to_vec(),
+                data_blob_ids: vec!["a".to_string()],
+                contributors: vec!["0x1".to_string()],
+            },
+        );
+        reader
+    }
+
+    fn request(fields: serde_json::Value) -> MLTrainingRequest {
+        let mut request = json!({
+            "job_id": 7,
+            "data_blob_ids": ["a"],
+            "model_config_blob_id": "config",
+            "key_id": "",
+            "learning_rate": 100,
+            "epochs": 10,
+        });
+        request
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        serde_json::from_value(request).unwrap()
+    }
+
+    async fn check_verify_job<R: SuiReader>(pending: &R, completed: &R) {
+        let (_, contributors) = verify_job(pending, &request(json!({}))).await.unwrap();
+        assert_eq!(contributors, ["0x1"]);
+
+        let mismatched = [
+            request(json!({"model_config_blob_id": "other"})),
+            request(json!({"epochs": 11})),
+            request(json!({"learning_rate": 10})),
+            request(json!({"data_blob_ids": ["a", "b"]})),
+            request(json!({"job_id": 8})),
+        ];
+        for request in &mismatched {
+            assert!(matches!(
+                verify_job(pending, request).await,
+                Err(EnclaveError::JobMismatch(_))
+            ));
+        }
+        assert!(matches!(
+            verify_job(completed, &request(json!({}))).await,
+            Err(EnclaveError::JobMismatch(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_verify_job() {
+        check_verify_job(&reader("Pending"), &reader("Completed")).await;
+    }
+
+    #[tokio::test]
+    async fn test_verify_job_over_rpc() {
+        let pending = reader("Pending").serve().await;
+        let completed = reader("Completed").serve().await;
+        check_verify_job(&pending, &completed).await;
+    }
+}
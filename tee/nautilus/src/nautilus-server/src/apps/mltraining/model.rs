@@ -0,0 +1,496 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Dense feed-forward network trained and evaluated inside the enclave.
+//!
+//! The network is plain `f32` arithmetic with a fixed summation order, so it
+//! can be rebuilt from its serialized weights for inference and produces the
+//! same outputs on every enclave.
+
+use super::preprocess::PreprocessingConfig;
+use super::search::SearchConfig;
+use super::train::TrainingConfig;
+use crate::common::f32_bits;
+use crate::EnclaveError;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// === MODEL CONFIG ===
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LayerConfig {
+    pub neurons: usize,
+    pub activation: String,
+    #[serde(default, with = "f32_bits")]
+    pub dropout: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelConfig {
+    /// Number of raw input features, before preprocessing.
+    pub input_size: usize,
+    pub output_size: usize,
+    pub layers: Vec<LayerConfig>,
+    /// Optional names of the input features, in column order.
+    #[serde(default)]
+    pub feature_names: Vec<String>,
+    #[serde(default)]
+    pub preprocessing: PreprocessingConfig,
+    #[serde(default)]
+    pub training: TrainingConfig,
+    /// Hyperparameter search, cleared in the config stored with the model.
+    #[serde(default)]
+    pub search: Option<SearchConfig>,
+}
+
+impl LayerConfig {
+    /// Checked activation and dropout of the layer.
+    fn parse(&self) -> Result<(Activation, f32), EnclaveError> {
+        if self.neurons == 0 {
+            return Err(EnclaveError::InvalidInput(
+                "layer with zero neurons".to_string(),
+            ));
+        }
+        let dropout = self.dropout.unwrap_or(0.0);
+        if !(0.0..1.0).contains(&dropout) {
+            return Err(EnclaveError::InvalidInput(format!(
+                "dropout {dropout} must be in [0, 1)"
+            )));
+        }
+        Ok((Activation::parse(&self.activation)?, dropout))
+    }
+}
+
+impl ModelConfig {
+    /// Checks that need no data, so a bad config fails a job before any blob
+    /// is downloaded.
+    pub fn validate(&self) -> Result<(), EnclaveError> {
+        if self.output_size == 0 {
+            return Err(EnclaveError::InvalidInput(
+                "output size must be non zero".to_string(),
+            ));
+        }
+        for layer in &self.layers {
+            layer.parse()?;
+        }
+        if let Some(search) = &self.search {
+            search.validate(self)?;
+        }
+        self.preprocessing.validate(self.input_size)?;
+        self.training.validate()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activation {
+    Identity,
+    Relu,
+    Sigmoid,
+    Tanh,
+    LeakyRelu,
+}
+
+impl Activation {
+    /// Parse the activation name used in `LayerConfig`.
+    pub fn parse(name: &str) -> Result<Self, EnclaveError> {
+        match name {
+            "relu" => Ok(Activation::Relu),
+            "sigmoid" => Ok(Activation::Sigmoid),
+            "tanh" => Ok(Activation::Tanh),
+            "leaky_relu" => Ok(Activation::LeakyRelu),
+            "linear" | "identity" => Ok(Activation::Identity),
+            _ => Err(EnclaveError::InvalidInput(format!(
+                "unknown activation {name}"
+            ))),
+        }
+    }
+
+    fn apply(&self, z: f32) -> f32 {
+        match self {
+            Activation::Identity => z,
+            Activation::Relu => z.max(0.0),
+            Activation::Sigmoid => 1.0 / (1.0 + (-z).exp()),
+            Activation::Tanh => z.tanh(),
+            Activation::LeakyRelu => {
+                if z > 0.0 {
+                    z
+                } else {
+                    0.01 * z
+                }
+            }
+        }
+    }
+
+    /// Derivative expressed in terms of the pre-activation `z` and output `a`.
+    fn derivative(&self, z: f32, a: f32) -> f32 {
+        match self {
+            Activation::Identity => 1.0,
+            Activation::Relu => {
+                if z > 0.0 {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Activation::Sigmoid => a * (1.0 - a),
+            Activation::Tanh => 1.0 - a * a,
+            Activation::LeakyRelu => {
+                if z > 0.0 {
+                    1.0
+                } else {
+                    0.01
+                }
+            }
+        }
+    }
+}
+
+/// Fully connected layer, `weight` is row-major `[out_features, in_features]`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Dense {
+    pub in_features: usize,
+    pub out_features: usize,
+    #[serde(with = "f32_bits")]
+    pub weight: Vec<f32>,
+    #[serde(with = "f32_bits")]
+    pub bias: Vec<f32>,
+    pub activation: Activation,
+    #[serde(with = "f32_bits")]
+    pub dropout: f32,
+}
+
+impl Dense {
+    /// Xavier uniform initialization.
+    fn new<R: Rng>(
+        in_features: usize,
+        out_features: usize,
+        activation: Activation,
+        dropout: f32,
+        rng: &mut R,
+    ) -> Self {
+        let limit = (6.0 / (in_features + out_features) as f32).sqrt();
+        let weight = (0..in_features * out_features)
+            .map(|_| rng.gen_range(-limit..=limit))
+            .collect();
+        Self {
+            in_features,
+            out_features,
+            weight,
+            bias: vec![0.0; out_features],
+            activation,
+            dropout,
+        }
+    }
+
+    /// Pre-activation output `W x + b`.
+    fn linear(&self, x: &[f32]) -> Vec<f32> {
+        (0..self.out_features)
+            .map(|o| {
+                let row = &self.weight[o * self.in_features..(o + 1) * self.in_features];
+                self.bias[o] + row.iter().zip(x).map(|(w, v)| w * v).sum::<f32>()
+            })
+            .collect()
+    }
+}
+
+/// Gradients of a single `Dense` layer.
+#[derive(Debug, Clone)]
+pub struct DenseGrad {
+    pub weight: Vec<f32>,
+    pub bias: Vec<f32>,
+}
+
+/// Gradients of every layer of an `Mlp`, in layer order.
+#[derive(Debug, Clone)]
+pub struct Gradients {
+    pub layers: Vec<DenseGrad>,
+}
+
+impl Gradients {
+    pub fn zeros_like(model: &Mlp) -> Self {
+        Self {
+            layers: model
+                .layers
+                .iter()
+                .map(|l| DenseGrad {
+                    weight: vec![0.0; l.weight.len()],
+                    bias: vec![0.0; l.bias.len()],
+                })
+                .collect(),
+        }
+    }
+
+    pub fn add_assign(&mut self, other: &Gradients) {
+        for (a, b) in self.layers.iter_mut().zip(&other.layers) {
+            a.weight
+                .iter_mut()
+                .zip(&b.weight)
+                .for_each(|(x, y)| *x += y);
+            a.bias.iter_mut().zip(&b.bias).for_each(|(x, y)| *x += y);
+        }
+    }
+
+    pub fn scale(&mut self, factor: f32) {
+        for l in &mut self.layers {
+            l.weight.iter_mut().for_each(|x| *x *= factor);
+            l.bias.iter_mut().for_each(|x| *x *= factor);
+        }
+    }
+
+    /// All values in layer order, weights before biases.
+    pub fn flatten(&self) -> Vec<f32> {
+        self.layers
+            .iter()
+            .flat_map(|l| l.weight.iter().chain(&l.bias))
+            .copied()
+            .collect()
+    }
+
+    /// Overwrite all values from `values`, in the order of [`Gradients::flatten`].
+    pub fn set_flat(&mut self, values: &[f32]) {
+        let mut values = values.iter();
+        for l in &mut self.layers {
+            for x in l.weight.iter_mut().chain(l.bias.iter_mut()) {
+                *x = *values.next().expect("as many values as gradients");
+            }
+        }
+    }
+
+    /// Global L2 norm over all layers.
+    pub fn norm(&self) -> f32 {
+        self.layers
+            .iter()
+            .flat_map(|l| l.weight.iter().chain(&l.bias))
+            .map(|x| (*x as f64).powi(2))
+            .sum::<f64>()
+            .sqrt() as f32
+    }
+}
+
+/// Multi-layer perceptron classifier producing one logit per class.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mlp {
+    pub layers: Vec<Dense>,
+}
+
+impl Mlp {
+    /// Build a freshly initialized network from a `ModelConfig`, taking
+    /// `input_width` preprocessed features.
+    pub fn new<R: Rng>(
+        config: &ModelConfig,
+        input_width: usize,
+        rng: &mut R,
+    ) -> Result<Self, EnclaveError> {
+        if input_width == 0 || config.output_size == 0 {
+            return Err(EnclaveError::InvalidInput(
+                "input and output sizes must be non zero".to_string(),
+            ));
+        }
+        let mut layers = Vec::with_capacity(config.layers.len() + 1);
+        let mut in_features = input_width;
+        for layer in &config.layers {
+            let (activation, dropout) = layer.parse()?;
+            layers.push(Dense::new(
+                in_features,
+                layer.neurons,
+                activation,
+                dropout,
+                rng,
+            ));
+            in_features = layer.neurons;
+        }
+        layers.push(Dense::new(
+            in_features,
+            config.output_size,
+            Activation::Identity,
+            0.0,
+            rng,
+        ));
+        Ok(Self { layers })
+    }
+
+    pub fn input_size(&self) -> usize {
+        self.layers.first().map(|l| l.in_features).unwrap_or(0)
+    }
+
+    pub fn output_size(&self) -> usize {
+        self.layers.last().map(|l| l.out_features).unwrap_or(0)
+    }
+
+    /// All weights and biases, in the order of [`Gradients::flatten`].
+    pub fn parameters(&self) -> Vec<f32> {
+        self.layers
+            .iter()
+            .flat_map(|l| l.weight.iter().chain(&l.bias))
+            .copied()
+            .collect()
+    }
+
+    /// Overwrite all weights and biases from `values`, in the order of
+    /// [`Mlp::parameters`].
+    pub fn set_parameters(&mut self, values: &[f32]) -> Result<(), EnclaveError> {
+        let count: usize = self
+            .layers
+            .iter()
+            .map(|l| l.weight.len() + l.bias.len())
+            .sum();
+        if values.len() != count || values.iter().any(|v| !v.is_finite()) {
+            return Err(EnclaveError::InvalidInput(format!(
+                "expected {count} finite parameters, got {}",
+                values.len()
+            )));
+        }
+        let mut values = values.iter();
+        for l in &mut self.layers {
+            for x in l.weight.iter_mut().chain(l.bias.iter_mut()) {
+                *x = *values.next().expect("as many values as parameters");
+            }
+        }
+        Ok(())
+    }
+
+    /// Logits for a single row, dropout disabled.
+    pub fn forward(&self, x: &[f32]) -> Vec<f32> {
+        self.layers.iter().fold(x.to_vec(), |a, layer| {
+            layer
+                .linear(&a)
+                .into_iter()
+                .map(|z| layer.activation.apply(z))
+                .collect()
+        })
+    }
+
+    /// Logits for a batch of rows.
+    pub fn forward_batch(&self, rows: &[Vec<f32>]) -> Vec<Vec<f32>> {
+        rows.iter().map(|r| self.forward(r)).collect()
+    }
+
+    /// Predicted class of a single row.
+    pub fn predict(&self, x: &[f32]) -> usize {
+        argmax(&self.forward(x))
+    }
+
+    /// Cross-entropy loss and gradients for a single sample, with dropout
+    /// applied to hidden layers.
+    pub fn gradients<R: Rng>(&self, x: &[f32], target: usize, rng: &mut R) -> (f32, Gradients) {
+        // Forward pass keeping pre-activations and (dropped out) activations.
+        let mut inputs = Vec::with_capacity(self.layers.len());
+        let mut pre = Vec::with_capacity(self.layers.len());
+        let mut masks = Vec::with_capacity(self.layers.len());
+        let mut a = x.to_vec();
+        for layer in &self.layers {
+            let z = layer.linear(&a);
+            let mut out: Vec<f32> = z.iter().map(|v| layer.activation.apply(*v)).collect();
+            let mask: Vec<f32> = if layer.dropout > 0.0 {
+                let keep = 1.0 - layer.dropout;
+                (0..out.len())
+                    .map(|_| {
+                        if rng.gen::<f32>() < keep {
+                            1.0 / keep
+                        } else {
+                            0.0
+                        }
+                    })
+                    .collect()
+            } else {
+                vec![1.0; out.len()]
+            };
+            out.iter_mut().zip(&mask).for_each(|(o, m)| *o *= m);
+            inputs.push(a);
+            pre.push(z);
+            masks.push(mask);
+            a = out;
+        }
+
+        let probs = softmax(&a);
+        let loss = -probs[target].max(f32::MIN_POSITIVE).ln();
+
+        // Backward pass, `delta` is dL/dz of the current layer.
+        let mut delta: Vec<f32> = probs
+            .iter()
+            .enumerate()
+            .map(|(i, p)| if i == target { p - 1.0 } else { *p })
+            .collect();
+        let mut grads = Gradients::zeros_like(self);
+        for (idx, layer) in self.layers.iter().enumerate().rev() {
+            let input = &inputs[idx];
+            let g = &mut grads.layers[idx];
+            for (o, d) in delta.iter().enumerate() {
+                g.bias[o] = *d;
+                let row = &mut g.weight[o * layer.in_features..(o + 1) * layer.in_features];
+                row.iter_mut().zip(input).for_each(|(w, v)| *w = d * v);
+            }
+            if idx == 0 {
+                break;
+            }
+            let below = &self.layers[idx - 1];
+            delta = (0..layer.in_features)
+                .map(|i| {
+                    let back: f32 = (0..layer.out_features)
+                        .map(|o| layer.weight[o * layer.in_features + i] * delta[o])
+                        .sum();
+                    let z = pre[idx - 1][i];
+                    let a = below.activation.apply(z);
+                    back * masks[idx - 1][i] * below.activation.derivative(z, a)
+                })
+                .collect();
+        }
+        (loss, grads)
+    }
+
+    /// Mean loss and mean gradients over a batch of samples.
+    pub fn batch_gradients<R: Rng>(
+        &self,
+        batch: &[(&[f32], usize)],
+        rng: &mut R,
+    ) -> (f32, Gradients) {
+        let mut total = Gradients::zeros_like(self);
+        let mut loss = 0.0;
+        for (x, y) in batch {
+            let (l, g) = self.gradients(x, *y, rng);
+            loss += l;
+            total.add_assign(&g);
+        }
+        let n = batch.len().max(1) as f32;
+        total.scale(1.0 / n);
+        (loss / n, total)
+    }
+
+    /// Accuracy in percent and mean cross-entropy loss over a dataset.
+    pub fn evaluate(&self, inputs: &[Vec<f32>], targets: &[usize]) -> (f32, f32) {
+        if inputs.is_empty() {
+            return (0.0, 0.0);
+        }
+        let mut correct = 0;
+        let mut loss = 0.0;
+        for (x, y) in inputs.iter().zip(targets) {
+            let logits = self.forward(x);
+            if argmax(&logits) == *y {
+                correct += 1;
+            }
+            loss += -softmax(&logits)[*y].max(f32::MIN_POSITIVE).ln();
+        }
+        let n = inputs.len() as f32;
+        (correct as f32 / n * 100.0, loss / n)
+    }
+}
+
+pub fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = logits.iter().map(|l| (l - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+pub fn argmax(values: &[f32]) -> usize {
+    values
+        .iter()
+        .enumerate()
+        .fold((0, f32::NEG_INFINITY), |(bi, bv), (i, v)| {
+            if *v > bv {
+                (i, *v)
+            } else {
+                (bi, bv)
+            }
+        })
+        .0
+}
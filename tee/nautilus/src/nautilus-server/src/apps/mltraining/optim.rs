@@ -0,0 +1,324 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Optimizers and learning-rate schedules.
+//!
+//! Weight decay applies to weights only, never to biases. It is added to the
+//! gradient (L2 regularization) for SGD, Adam and RMSProp, and decoupled from
+//! it for AdamW.
+
+use super::model::{Gradients, Mlp};
+use crate::common::f32_bits;
+use crate::EnclaveError;
+use serde::{Deserialize, Serialize};
+
+/// `optimizer` of the training config, e.g. `"optimizer": {"adam": {}}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum OptimizerConfig {
+    Sgd {
+        #[serde(default, with = "f32_bits")]
+        momentum: f32,
+    },
+    Adam {
+        #[serde(default = "default_beta1", with = "f32_bits")]
+        beta1: f32,
+        #[serde(default = "default_beta2", with = "f32_bits")]
+        beta2: f32,
+        #[serde(default = "default_epsilon", with = "f32_bits")]
+        epsilon: f32,
+    },
+    #[serde(rename = "adamw")]
+    AdamW {
+        #[serde(default = "default_beta1", with = "f32_bits")]
+        beta1: f32,
+        #[serde(default = "default_beta2", with = "f32_bits")]
+        beta2: f32,
+        #[serde(default = "default_epsilon", with = "f32_bits")]
+        epsilon: f32,
+    },
+    #[serde(rename = "rmsprop")]
+    RmsProp {
+        /// Decay of the squared gradient average.
+        #[serde(default = "default_rms_decay", with = "f32_bits")]
+        decay: f32,
+        #[serde(default, with = "f32_bits")]
+        momentum: f32,
+        #[serde(default = "default_epsilon", with = "f32_bits")]
+        epsilon: f32,
+    },
+}
+
+fn default_beta1() -> f32 {
+    0.9
+}
+
+fn default_beta2() -> f32 {
+    0.999
+}
+
+fn default_epsilon() -> f32 {
+    1e-8
+}
+
+fn default_rms_decay() -> f32 {
+    0.99
+}
+
+impl Default for OptimizerConfig {
+    fn default() -> Self {
+        OptimizerConfig::Sgd { momentum: 0.0 }
+    }
+}
+
+impl OptimizerConfig {
+    pub fn validate(&self) -> Result<(), EnclaveError> {
+        let unit = |name: &str, v: f32| {
+            if (0.0..1.0).contains(&v) {
+                Ok(())
+            } else {
+                Err(EnclaveError::InvalidInput(format!(
+                    "optimizer {name} must be in [0, 1)"
+                )))
+            }
+        };
+        let positive = |v: f32| {
+            if v > 0.0 {
+                Ok(())
+            } else {
+                Err(EnclaveError::InvalidInput(
+                    "optimizer epsilon must be positive".to_string(),
+                ))
+            }
+        };
+        match *self {
+            OptimizerConfig::Sgd { momentum } => unit("momentum", momentum),
+            OptimizerConfig::Adam {
+                beta1,
+                beta2,
+                epsilon,
+            }
+            | OptimizerConfig::AdamW {
+                beta1,
+                beta2,
+                epsilon,
+            } => {
+                unit("beta1", beta1)?;
+                unit("beta2", beta2)?;
+                positive(epsilon)
+            }
+            OptimizerConfig::RmsProp {
+                decay,
+                momentum,
+                epsilon,
+            } => {
+                unit("decay", decay)?;
+                unit("momentum", momentum)?;
+                positive(epsilon)
+            }
+        }
+    }
+}
+
+/// `schedule` of the training config, scaling the job learning rate over time.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LrSchedule {
+    #[default]
+    Constant,
+    /// Multiply by `gamma` every `every_epochs` epochs.
+    Step {
+        every_epochs: u64,
+        #[serde(with = "f32_bits")]
+        gamma: f32,
+    },
+    /// Cosine decay from the base rate down to `min_factor` times it, over
+    /// the steps following the warmup.
+    Cosine {
+        #[serde(default, with = "f32_bits")]
+        min_factor: f32,
+    },
+}
+
+impl LrSchedule {
+    pub fn validate(&self) -> Result<(), EnclaveError> {
+        match *self {
+            LrSchedule::Step {
+                every_epochs,
+                gamma,
+            } if every_epochs == 0 || !(gamma.is_finite() && gamma > 0.0) => Err(
+                EnclaveError::InvalidInput("invalid step schedule".to_string()),
+            ),
+            LrSchedule::Cosine { min_factor } if !(0.0..=1.0).contains(&min_factor) => Err(
+                EnclaveError::InvalidInput("cosine min_factor must be in [0, 1]".to_string()),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Learning rate of optimizer step `step` (from 0), in epoch `epoch`
+    /// (from 0), out of `total_steps`, after `warmup_steps` of linear warmup.
+    pub fn learning_rate(
+        &self,
+        base: f32,
+        step: u64,
+        epoch: u64,
+        total_steps: u64,
+        warmup_steps: u64,
+    ) -> f32 {
+        if step < warmup_steps {
+            return base * (step + 1) as f32 / warmup_steps as f32;
+        }
+        match *self {
+            LrSchedule::Constant => base,
+            LrSchedule::Step {
+                every_epochs,
+                gamma,
+            } => base * gamma.powi((epoch / every_epochs) as i32),
+            LrSchedule::Cosine { min_factor } => {
+                let span = total_steps.saturating_sub(warmup_steps).max(1);
+                let t = (step - warmup_steps) as f64 / span as f64;
+                let cosine = 0.5 * (1.0 + (std::f64::consts::PI * t).cos());
+                base * (min_factor as f64 + (1.0 - min_factor as f64) * cosine) as f32
+            }
+        }
+    }
+}
+
+/// Scale `grads` down so their global L2 norm is at most `max_norm`.
+pub fn clip_grad_norm(grads: &mut Gradients, max_norm: f32) {
+    let norm = grads.norm();
+    if norm > max_norm {
+        grads.scale(max_norm / norm);
+    }
+}
+
+/// Optimizer state: moment estimates shaped like the network.
+pub struct Optimizer {
+    config: OptimizerConfig,
+    weight_decay: f32,
+    first: Gradients,
+    second: Gradients,
+    steps: i32,
+}
+
+impl Optimizer {
+    pub fn new(config: OptimizerConfig, weight_decay: f32, network: &Mlp) -> Self {
+        Self {
+            config,
+            weight_decay,
+            first: Gradients::zeros_like(network),
+            second: Gradients::zeros_like(network),
+            steps: 0,
+        }
+    }
+
+    /// Apply one update of `grads` to `network`.
+    pub fn step(&mut self, network: &mut Mlp, grads: &Gradients, learning_rate: f32) {
+        self.steps += 1;
+        let layers = network
+            .layers
+            .iter_mut()
+            .zip(&grads.layers)
+            .zip(self.first.layers.iter_mut().zip(&mut self.second.layers));
+        for ((layer, g), (m, v)) in layers {
+            let mut update = Update {
+                config: self.config,
+                learning_rate,
+                t: self.steps,
+                weight_decay: self.weight_decay,
+            };
+            update.apply(&mut layer.weight, &g.weight, &mut m.weight, &mut v.weight);
+            update.weight_decay = 0.0;
+            update.apply(&mut layer.bias, &g.bias, &mut m.bias, &mut v.bias);
+        }
+    }
+}
+
+/// Parameters of one optimizer step over a tensor.
+struct Update {
+    config: OptimizerConfig,
+    learning_rate: f32,
+    t: i32,
+    weight_decay: f32,
+}
+
+impl Update {
+    fn apply(&self, params: &mut [f32], grads: &[f32], m: &mut [f32], v: &mut [f32]) {
+        let (lr, wd) = (self.learning_rate, self.weight_decay);
+        let slots = params
+            .iter_mut()
+            .zip(grads)
+            .zip(m.iter_mut().zip(v.iter_mut()));
+        match self.config {
+            OptimizerConfig::Sgd { momentum } => {
+                for ((p, g), (m, _)) in slots {
+                    *m = momentum * *m + g + wd * *p;
+                    *p -= lr * *m;
+                }
+            }
+            OptimizerConfig::Adam {
+                beta1,
+                beta2,
+                epsilon,
+            }
+            | OptimizerConfig::AdamW {
+                beta1,
+                beta2,
+                epsilon,
+            } => {
+                let decoupled = matches!(self.config, OptimizerConfig::AdamW { .. });
+                let c1 = 1.0 - beta1.powi(self.t);
+                let c2 = 1.0 - beta2.powi(self.t);
+                for ((p, g), (m, v)) in slots {
+                    let g = if decoupled { *g } else { g + wd * *p };
+                    *m = beta1 * *m + (1.0 - beta1) * g;
+                    *v = beta2 * *v + (1.0 - beta2) * g * g;
+                    let mut delta = (*m / c1) / ((*v / c2).sqrt() + epsilon);
+                    if decoupled {
+                        delta += wd * *p;
+                    }
+                    *p -= lr * delta;
+                }
+            }
+            OptimizerConfig::RmsProp {
+                decay,
+                momentum,
+                epsilon,
+            } => {
+                for ((p, g), (m, v)) in slots {
+                    let g = g + wd * *p;
+                    *v = decay * *v + (1.0 - decay) * g * g;
+                    *m = momentum * *m + g / (v.sqrt() + epsilon);
+                    *p -= lr * *m;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_step_schedule_needs_a_positive_finite_gamma() {
+        let step = |every_epochs, gamma| LrSchedule::Step {
+            every_epochs,
+            gamma,
+        };
+        assert!(step(2, 0.5).validate().is_ok());
+        for schedule in [
+            step(0, 0.5),
+            step(2, 0.0),
+            step(2, -0.5),
+            step(2, f32::NAN),
+            step(2, f32::INFINITY),
+        ] {
+            assert!(matches!(
+                schedule.validate(),
+                Err(EnclaveError::InvalidInput(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,453 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Data usage policy of a pool.
+//!
+//! Contributors agree to a pool's policy when they join it. It is the
+//! `policy` member of the pool's `metadata`, read as a JSON object, e.g.
+//!
+//! ```json
+//! {"name": "clinic visits", "policy": {"allowed_model_families": ["linear"],
+//!  "min_samples_per_job": 500, "min_contributors": 5, "min_group_size": 20,
+//!  "outputs": "aggregates",
+//!  "max_jobs_per_period": {"jobs": 10, "period_ms": 86400000}}}
+//! ```
+//!
+//! Pools whose metadata has no policy accept every job that meets the default
+//! cohort thresholds. A policy that does not parse refuses every job, so a typo
+//! never loosens it. A refused job gets a signed [`JobRejection`].
+
+use super::model::ModelConfig;
+use super::usage::Usage;
+use fastcrypto::hash::{HashFunction, Sha256};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+/// Fewest rows a job may use when the pool's policy sets no minimum.
+pub const DEFAULT_MIN_SAMPLES: u64 = 100;
+
+/// Fewest distinct contributors a job may use when the pool's policy sets no
+/// minimum.
+pub const DEFAULT_MIN_CONTRIBUTORS: u64 = 3;
+
+/// Fewest rows of a group an analytics query may release when the pool's
+/// policy sets no minimum.
+pub const DEFAULT_MIN_GROUP_SIZE: u64 = 10;
+
+/// Queries at `max_epsilon` the epsilon budget of a pool covers when its
+/// policy sets no budget.
+pub const DEFAULT_EPSILON_BUDGET_QUERIES: f64 = 10.0;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct PoolPolicy {
+    /// Model families jobs may train, any when absent.
+    pub allowed_model_families: Option<Vec<ModelFamily>>,
+    /// Fewest rows a job may use, after the data quality checks.
+    #[serde(default = "default_min_samples")]
+    pub min_samples_per_job: u64,
+    /// Fewest distinct contributors whose rows a job may use, so no output
+    /// stems from a single person's data.
+    #[serde(default = "default_min_contributors")]
+    pub min_contributors: u64,
+    /// Fewest rows of a group an analytics query may release, and fewest
+    /// rows `/predict` answers with class counts. Queries may ask for more.
+    #[serde(default = "default_min_group_size")]
+    pub min_group_size: u64,
+    /// Jobs must be differentially private with at most this epsilon.
+    pub max_epsilon: Option<f64>,
+    /// Total epsilon the noisy outputs of the pool may spend, so repeated
+    /// queries and synthetic jobs cannot average the noise away. Counted in
+    /// the usage file, which a host restarting the enclave resets, see
+    /// [`super::usage`]. Defaults to
+    /// `DEFAULT_EPSILON_BUDGET_QUERIES` times `max_epsilon`, and to no limit
+    /// without one.
+    #[cfg_attr(
+        not(any(feature = "analytics", feature = "synthetic")),
+        allow(dead_code)
+    )]
+    pub epsilon_budget: Option<f64>,
+    /// Answer analytics queries that filter rows without noise. Two exact
+    /// queries whose filters differ by one row give away that row, so
+    /// filtered queries need noise unless the pool opts in.
+    #[serde(default)]
+    #[cfg_attr(not(feature = "analytics"), allow(dead_code))]
+    pub exact_filtered_queries: bool,
+    #[serde(default)]
+    pub outputs: OutputPolicy,
+    pub max_jobs_per_period: Option<JobRate>,
+}
+
+impl Default for PoolPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_model_families: None,
+            min_samples_per_job: DEFAULT_MIN_SAMPLES,
+            min_contributors: DEFAULT_MIN_CONTRIBUTORS,
+            min_group_size: DEFAULT_MIN_GROUP_SIZE,
+            max_epsilon: None,
+            epsilon_budget: None,
+            exact_filtered_queries: false,
+            outputs: OutputPolicy::default(),
+            max_jobs_per_period: None,
+        }
+    }
+}
+
+impl PoolPolicy {
+    /// Refuse outputs without noise, or with more than `max_epsilon`.
+    #[cfg_attr(
+        not(any(feature = "analytics", feature = "synthetic")),
+        allow(dead_code)
+    )]
+    fn check_epsilon(&self, epsilon: Option<f64>) -> Result<(), String> {
+        let Some(max) = self.max_epsilon else {
+            return Ok(());
+        };
+        match epsilon {
+            None => Err(format!(
+                "the pool requires noise with epsilon at most {max}"
+            )),
+            Some(epsilon) if epsilon > max => Err(format!(
+                "epsilon {epsilon} exceeds the pool's maximum of {max}"
+            )),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+fn default_min_samples() -> u64 {
+    DEFAULT_MIN_SAMPLES
+}
+
+fn default_min_contributors() -> u64 {
+    DEFAULT_MIN_CONTRIBUTORS
+}
+
+fn default_min_group_size() -> u64 {
+    DEFAULT_MIN_GROUP_SIZE
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModelFamily {
+    /// No hidden layers, i.e. softmax regression.
+    Linear,
+    /// One or more hidden layers.
+    Mlp,
+}
+
+impl ModelFamily {
+    fn name(&self) -> &'static str {
+        match self {
+            ModelFamily::Linear => "linear",
+            ModelFamily::Mlp => "mlp",
+        }
+    }
+
+    fn of(hidden_layers: usize) -> Self {
+        if hidden_layers == 0 {
+            ModelFamily::Linear
+        } else {
+            ModelFamily::Mlp
+        }
+    }
+}
+
+/// What a job may release about the pool.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputPolicy {
+    /// Models, including per-row predictions of `/predict`.
+    #[default]
+    Predictions,
+    /// Aggregates only: models stay sealed to the enclave, and `/predict`
+    /// answers with class counts instead of per-row predictions.
+    Aggregates,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct JobRate {
+    pub jobs: u64,
+    pub period_ms: u64,
+}
+
+/// Signed instead of a result when a job is refused.
+#[derive(Serialize, Clone, Debug)]
+pub struct JobRejection {
+    pub job_id: u64,
+    pub pool_id: u64,
+    /// Sha256 of the pool metadata the policy was read from.
+    pub policy_hash: Vec<u8>,
+    pub reason: String,
+}
+
+/// Cohort a job used and the thresholds it was checked against, signed in
+/// the job's response.
+#[derive(Serialize, Clone, Debug)]
+pub struct CohortReport {
+    /// Distinct contributors with at least one row used.
+    pub contributors: u64,
+    pub min_contributors: u64,
+    /// Rows used, after the data quality checks.
+    pub rows: u64,
+    pub min_rows: u64,
+}
+
+/// A policy and the metadata it was read from.
+#[derive(Debug, Clone)]
+pub struct PolicyCheck {
+    pub pool_id: u64,
+    pub policy_hash: Vec<u8>,
+    policy: Result<PoolPolicy, String>,
+}
+
+impl PolicyCheck {
+    pub fn new(pool_id: u64, metadata: &[u8]) -> Self {
+        Self {
+            pool_id,
+            policy_hash: Sha256::digest(metadata).digest.to_vec(),
+            policy: parse_policy(metadata),
+        }
+    }
+
+    fn policy(&self) -> Result<&PoolPolicy, String> {
+        self.policy.as_ref().map_err(Clone::clone)
+    }
+
+    pub fn outputs(&self) -> OutputPolicy {
+        self.policy.as_ref().map(|p| p.outputs).unwrap_or_default()
+    }
+
+    pub fn min_group_size(&self) -> u64 {
+        self.policy
+            .as_ref()
+            .map(|p| p.min_group_size)
+            .unwrap_or(DEFAULT_MIN_GROUP_SIZE)
+    }
+
+    /// Checks of a training job that need no data. Training is not
+    /// differentially private, so pools with an epsilon ceiling refuse it.
+    pub fn check_training(
+        &self,
+        config: &ModelConfig,
+        sealed: bool,
+        exports: bool,
+    ) -> Result<(), String> {
+        let policy = self.policy()?;
+        if let Some(allowed) = &policy.allowed_model_families {
+            let mut families = vec![ModelFamily::of(config.layers.len())];
+            if let Some(search) = &config.search {
+                families.extend(search.layer_widths.iter().map(|w| ModelFamily::of(w.len())));
+            }
+            if let Some(family) = families.iter().find(|f| !allowed.contains(f)) {
+                return Err(format!(
+                    "model family {} is not allowed by the pool",
+                    family.name()
+                ));
+            }
+        }
+        if let Some(epsilon) = policy.max_epsilon {
+            return Err(format!(
+                "the pool requires differential privacy with epsilon at most {epsilon}, \
+                 which training does not provide"
+            ));
+        }
+        if policy.outputs == OutputPolicy::Aggregates && (!sealed || exports) {
+            return Err(
+                "the pool only releases aggregates, models must be sealed and not exported"
+                    .to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks of an analytics query that need no data, where `epsilon` is
+    /// the privacy budget of its noise, if any, and `filtered` whether it
+    /// aggregates a subset of the rows. Returns the smallest group size the
+    /// query may release: its own minimum or the pool's, whichever is larger.
+    #[cfg_attr(not(feature = "analytics"), allow(dead_code))]
+    pub fn check_query(
+        &self,
+        epsilon: Option<f64>,
+        filtered: bool,
+        min_group_size: u64,
+    ) -> Result<u64, String> {
+        let policy = self.policy()?;
+        policy.check_epsilon(epsilon)?;
+        if filtered && epsilon.is_none() && !policy.exact_filtered_queries {
+            return Err(
+                "queries that filter rows need noise unless the pool allows exact ones".to_string(),
+            );
+        }
+        Ok(min_group_size.max(policy.min_group_size))
+    }
+
+    /// Count `epsilon`, the privacy budget of a noisy output, against the
+    /// pool's `epsilon_budget` in `usage`, refusing it once the budget would
+    /// be exceeded. Returns the epsilon the pool spent, this output included.
+    #[cfg_attr(
+        not(any(feature = "analytics", feature = "synthetic")),
+        allow(dead_code)
+    )]
+    pub fn charge_epsilon(&self, usage: &mut Usage, epsilon: Option<f64>) -> Result<f64, String> {
+        let policy = self.policy()?;
+        let spent = usage.epsilon_spent.entry(self.pool_id).or_insert(0.0);
+        let Some(epsilon) = epsilon else {
+            return Ok(*spent);
+        };
+        let budget = policy.epsilon_budget.or(policy
+            .max_epsilon
+            .map(|max| max * DEFAULT_EPSILON_BUDGET_QUERIES));
+        if let Some(budget) = budget {
+            if *spent + epsilon > budget {
+                return Err(format!(
+                    "the pool spent epsilon {spent} of its budget of {budget}"
+                ));
+            }
+        }
+        *spent += epsilon;
+        Ok(*spent)
+    }
+
+    /// Checks of a synthetic data job that need no data, where `epsilon` is
+    /// the privacy budget of its generator, if any. Synthetic rows are row
+    /// level outputs, so pools that only release aggregates refuse them.
+    #[cfg_attr(not(feature = "synthetic"), allow(dead_code))]
+    pub fn check_synthesis(&self, epsilon: Option<f64>) -> Result<(), String> {
+        let policy = self.policy()?;
+        policy.check_epsilon(epsilon)?;
+        if policy.outputs == OutputPolicy::Aggregates {
+            return Err("the pool only releases aggregates, not synthetic rows".to_string());
+        }
+        Ok(())
+    }
+
+    /// Refuse jobs whose rows come from too few contributors or are too few,
+    /// where `contributors` holds the contributor of each row used.
+    pub fn check_cohort<'a, I>(&self, contributors: I) -> Result<CohortReport, String>
+    where
+        I: IntoIterator<Item = (&'a str, u64)>,
+    {
+        let policy = self.policy()?;
+        let mut distinct = HashSet::new();
+        let mut rows = 0;
+        for (contributor, used) in contributors {
+            if used > 0 {
+                distinct.insert(contributor);
+                rows += used;
+            }
+        }
+        let report = CohortReport {
+            contributors: distinct.len() as u64,
+            min_contributors: policy.min_contributors,
+            rows,
+            min_rows: policy.min_samples_per_job,
+        };
+        if report.contributors < report.min_contributors {
+            return Err(format!(
+                "rows of {} contributors passed the quality checks, the pool requires {}",
+                report.contributors, report.min_contributors
+            ));
+        }
+        if report.rows < report.min_rows {
+            return Err(format!(
+                "{} rows passed the quality checks, the pool requires {}",
+                report.rows, report.min_rows
+            ));
+        }
+        Ok(report)
+    }
+
+    /// Count a job against `max_jobs_per_period` in `usage`, refusing it if
+    /// the pool already ran as many in the period ending at `now_ms`. Called
+    /// once the job passed every other check, see [`super::usage::charge`].
+    pub fn charge_job(&self, usage: &mut Usage, now_ms: u64) -> Result<(), String> {
+        let Some(rate) = self.policy()?.max_jobs_per_period else {
+            return Ok(());
+        };
+        let times = usage.jobs_accepted.entry(self.pool_id).or_default();
+        while times
+            .front()
+            .is_some_and(|t| t.saturating_add(rate.period_ms) <= now_ms)
+        {
+            times.pop_front();
+        }
+        if times.len() as u64 >= rate.jobs {
+            return Err(format!(
+                "the pool allows {} jobs per {} ms",
+                rate.jobs, rate.period_ms
+            ));
+        }
+        times.push_back(now_ms);
+        Ok(())
+    }
+
+    pub fn rejection(&self, job_id: u64, reason: String) -> JobRejection {
+        JobRejection {
+            job_id,
+            pool_id: self.pool_id,
+            policy_hash: self.policy_hash.clone(),
+            reason,
+        }
+    }
+}
+
+fn parse_policy(metadata: &[u8]) -> Result<PoolPolicy, String> {
+    let Ok(serde_json::Value::Object(mut metadata)) = serde_json::from_slice(metadata) else {
+        // Plain text metadata, e.g. a pool name
+        return Ok(PoolPolicy::default());
+    };
+    match metadata.remove("policy") {
+        None => Ok(PoolPolicy::default()),
+        Some(policy) => {
+            serde_json::from_value(policy).map_err(|e| format!("the pool policy is malformed: {e}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_filtered_queries_need_noise() {
+        let check = |metadata: &str| PolicyCheck::new(1, metadata.as_bytes());
+
+        let default = check("clinic visits");
+        assert!(default.check_query(None, false, 0).is_ok());
+        assert!(default.check_query(None, true, 0).is_err());
+        assert_eq!(
+            default.check_query(Some(1.0), true, 0),
+            Ok(DEFAULT_MIN_GROUP_SIZE)
+        );
+
+        let exact = check(r#"{"policy": {"exact_filtered_queries": true, "min_group_size": 20}}"#);
+        assert_eq!(exact.check_query(None, true, 5), Ok(20));
+
+        // A ceiling on epsilon still applies
+        let private = check(r#"{"policy": {"exact_filtered_queries": true, "max_epsilon": 1.0}}"#);
+        assert!(private.check_query(None, true, 0).is_err());
+        assert!(private.check_query(Some(2.0), true, 0).is_err());
+    }
+
+    #[test]
+    fn test_epsilon_budget() {
+        let mut usage = Usage::default();
+        let check = PolicyCheck::new(1, br#"{"policy": {"max_epsilon": 1.0}}"#);
+        for spent in 1..=10 {
+            assert_eq!(
+                check.charge_epsilon(&mut usage, Some(1.0)),
+                Ok(spent as f64)
+            );
+        }
+        assert!(check.charge_epsilon(&mut usage, Some(0.5)).is_err());
+        // Exact outputs spend nothing
+        assert_eq!(check.charge_epsilon(&mut usage, None), Ok(10.0));
+
+        // Budgets are per pool
+        let other = PolicyCheck::new(2, br#"{"policy": {"epsilon_budget": 0.5}}"#);
+        assert_eq!(other.charge_epsilon(&mut usage, Some(0.5)), Ok(0.5));
+        assert!(other.charge_epsilon(&mut usage, Some(0.1)).is_err());
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The data of a pool, as every job over it sees it.
+//!
+//! A job names the data blobs of a pool, which must be exactly the pool's
+//! on-chain `pool_data` entry. The blobs are fetched into the verified cache,
+//! their keys unsealed, and their rows streamed through the quality checks.
+//! Training, analytics and synthetic data jobs share this path, so all are
+//! held to the same pool policy and cohort thresholds.
+
+use super::cache::CachedBlob;
+use super::chain::SuiReader;
+use super::crypto::data_key;
+use super::dataset::SpilledDataset;
+use super::fetch::fetch_blobs;
+use super::policy::{CohortReport, PolicyCheck};
+use super::quality::{BlobReport, QualityChecker};
+use super::seal::{read_envelope, unseal_data_keys, SealedJob};
+use super::stream::BlobDecoder;
+use crate::AppState;
+use crate::EnclaveError;
+use tracing::info;
+
+/// Rows of a pool that passed the quality checks.
+pub struct PoolRows {
+    pub dataset: SpilledDataset,
+    /// Quality of each blob, in request order.
+    pub data_quality: Vec<BlobReport>,
+    /// Blob id and Sha256 of each blob, in request order.
+    pub blob_digests: Vec<(String, Vec<u8>)>,
+}
+
+/// Check that `data_blob_ids` is exactly the data of active pool `pool_id`.
+/// Returns the pool's policy and the contributor of each data blob.
+pub async fn verify_pool<R: SuiReader>(
+    reader: &R,
+    pool_id: u64,
+    data_blob_ids: &[String],
+) -> Result<(PolicyCheck, Vec<String>), EnclaveError> {
+    let pool = reader.pool(pool_id).await?;
+    if !pool.active {
+        return Err(EnclaveError::JobMismatch(format!(
+            "pool {pool_id} is not active"
+        )));
+    }
+    if pool.data_blob_ids != data_blob_ids {
+        return Err(EnclaveError::JobMismatch(format!(
+            "data_blob_ids are not the {} blobs of pool {pool_id}",
+            pool.data_blob_ids.len()
+        )));
+    }
+    Ok((PolicyCheck::new(pool_id, &pool.metadata), pool.contributors))
+}
+
+/// Fetch the blobs of a pool and stream each one through decryption into
+/// `checker`, keeping the rows that pass. The keys of sealed blobs are
+/// unsealed from the Seal key servers for `job`.
+pub async fn load_rows(
+    state: &AppState,
+    data_blob_ids: &[String],
+    job: SealedJob<'_>,
+    mut checker: QualityChecker<'_>,
+) -> Result<PoolRows, EnclaveError> {
+    let blobs = fetch_blobs(data_blob_ids).await?;
+    let keys = data_keys(state, &job, &blobs).await?;
+    let mut blob_digests = vec![];
+    for (blob, key) in blobs.iter().zip(keys) {
+        checker.begin_blob(&blob.blob_id);
+        match key {
+            Some(key) => decode_data_blob(blob, key, &mut checker)?,
+            None => checker.malformed(),
+        }
+        checker.end_blob();
+        blob_digests.push((blob.blob_id.clone(), blob.digest.clone()));
+    }
+    drop(blobs);
+    let (dataset, data_quality) = checker.finish()?;
+    Ok(PoolRows {
+        dataset,
+        data_quality,
+        blob_digests,
+    })
+}
+
+/// Check the cohort of the rows used against the pool's thresholds, from
+/// the quality report of each blob. Blobs are in pool order, so each lines
+/// up with its entry in `contributors`.
+pub fn check_cohort(
+    policy: &PolicyCheck,
+    contributors: &[String],
+    data_quality: &[BlobReport],
+) -> Result<CohortReport, String> {
+    policy.check_cohort(
+        contributors
+            .iter()
+            .map(String::as_str)
+            .zip(data_quality.iter().map(|r| r.rows_used)),
+    )
+}
+
+/// Key of each data blob: unsealed from its envelope for sealed blobs, the
+/// `POOL_DATA_KEY` otherwise. `None` for blobs whose envelope is malformed,
+/// not for the job's key id, or does not open.
+async fn data_keys(
+    state: &AppState,
+    job: &SealedJob<'_>,
+    blobs: &[CachedBlob],
+) -> Result<Vec<Option<[u8; 32]>>, EnclaveError> {
+    let pool_key = data_key(state)?;
+    let mut keys = vec![];
+    let mut sealed = vec![];
+    for blob in blobs {
+        match read_envelope(blob) {
+            Ok(None) => keys.push(Some(pool_key)),
+            Ok(Some(envelope)) => {
+                keys.push(None);
+                sealed.push((keys.len() - 1, envelope));
+            }
+            Err(EnclaveError::InvalidInput(e)) => {
+                info!("data blob {} is malformed: {e}", blob.blob_id);
+                keys.push(None);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let envelopes: Vec<Vec<u8>> = sealed.iter().map(|(_, e)| e.clone()).collect();
+    let unsealed = unseal_data_keys(state, job, &envelopes).await?;
+    for ((index, _), key) in sealed.iter().zip(unsealed) {
+        keys[*index] = key;
+    }
+    Ok(keys)
+}
+
+/// Stream a cached data blob through decryption into `checker`. Blobs that
+/// fail to decode are reported malformed.
+fn decode_data_blob(
+    blob: &CachedBlob,
+    key: [u8; 32],
+    checker: &mut QualityChecker<'_>,
+) -> Result<(), EnclaveError> {
+    let mut decoder = Some(BlobDecoder::new(key));
+    blob.read(|bytes| {
+        if let Some(d) = decoder.as_mut() {
+            let decoded = d.push(bytes, &mut |row: &[u8]| checker.row(row));
+            if !decode_ok(&blob.blob_id, decoded, checker)? {
+                decoder = None;
+            }
+        }
+        Ok(())
+    })?;
+    if let Some(d) = decoder {
+        let decoded = d.finish(&mut |row: &[u8]| checker.row(row));
+        decode_ok(&blob.blob_id, decoded, checker)?;
+    }
+    Ok(())
+}
+
+/// Whether decoding may go on. Invalid content marks the blob malformed,
+/// other errors fail the job.
+fn decode_ok(
+    blob_id: &str,
+    decoded: Result<(), EnclaveError>,
+    checker: &mut QualityChecker<'_>,
+) -> Result<bool, EnclaveError> {
+    match decoded {
+        Ok(()) => Ok(true),
+        Err(EnclaveError::InvalidInput(e)) => {
+            info!("data blob {blob_id} is malformed: {e}");
+            checker.malformed();
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apps::mltraining::chain::stub::StubReader;
+    use crate::apps::mltraining::chain::OnChainPool;
+
+    fn reader(active: bool) -> StubReader {
+        let mut reader = StubReader::default();
+        reader.pools.insert(
+            3,
+            OnChainPool {
+                active,
+                metadata: br#"{"policy": {"min_contributors": 2}}"#.to_vec(),
+                data_blob_ids: vec!["a".to_string(), "b".to_string()],
+                contributors: vec!["0x1".to_string(), "0x2".to_string()],
+            },
+        );
+        reader
+    }
+
+    #[tokio::test]
+    async fn test_verify_pool() {
+        let blobs = ["a".to_string(), "b".to_string()];
+        let (_, contributors) = verify_pool(&reader(true), 3, &blobs).await.unwrap();
+        assert_eq!(contributors, ["0x1", "0x2"]);
+
+        // Every blob of the pool, in order, and nothing else
+        for other in [&blobs[..1], &[blobs[1].clone(), blobs[0].clone()]] {
+            assert!(matches!(
+                verify_pool(&reader(true), 3, other).await,
+                Err(EnclaveError::JobMismatch(_))
+            ));
+        }
+        for (reader, pool_id) in [(reader(false), 3), (reader(true), 4)] {
+            assert!(matches!(
+                verify_pool(&reader, pool_id, &blobs).await,
+                Err(EnclaveError::JobMismatch(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use super::artifact::ModelArtifact;
+use super::crypto::unseal;
+use super::download_blob;
+use super::model::argmax;
+use super::sampling::laplace;
+use super::usage;
+use super::MODEL_AAD;
+use crate::common::{
+    to_signed_response, IntentMessage, IntentScope, ProcessDataRequest, ProcessedDataResponse,
+};
+use crate::AppState;
+use crate::EnclaveError;
+use axum::{extract::State, Json};
+use fastcrypto::hash::{HashFunction, Sha256};
+use rand::rngs::OsRng;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Maximum number of rows answered by a single `/predict` call.
+const MAX_ROWS_PER_REQUEST: usize = 1024;
+
+/// Privacy budget the class counts of an aggregates-only model spend on each
+/// row of a batch. The batch is chosen by the caller, so a row's prediction
+/// could otherwise be read off the exact counts of two batches that differ
+/// in that row alone. Repeated batches compose, up to the query budget.
+pub const AGGREGATE_EPSILON: f64 = 1.0;
+
+// === REQUEST ===
+#[derive(Deserialize, Debug)]
+pub struct PredictRequest {
+    pub model_blob_id: String,
+    /// Whether the model blob is sealed to the enclave.
+    #[serde(default)]
+    pub sealed: bool,
+    /// Raw feature rows, each of the model's input size. Missing values are
+    /// `null` and imputed by the model's preprocessing.
+    pub rows: Vec<Vec<Option<f32>>>,
+}
+
+// === RESPONSE ===
+#[derive(Serialize, Clone, Debug)]
+pub struct PredictResponse {
+    pub model_blob_id: String,
+    /// Sha256 of the bcs encoded input rows, floats as IEEE-754 bits and
+    /// missing values as the bits of `NaN`.
+    pub inputs_hash: Vec<u8>,
+    /// Predicted class per row, empty for models of aggregates-only pools.
+    pub predictions: Vec<u64>,
+    /// Rows predicted as each class. Models of aggregates-only pools answer
+    /// batches of at least the pool's `min_group_size` rows only, with
+    /// `AGGREGATE_EPSILON`-differentially private counts.
+    pub class_counts: Vec<u64>,
+    /// Rows the model may still answer.
+    pub queries_remaining: u64,
+}
+
+/// Run batched inference of a trained model without releasing its weights.
+pub async fn predict(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ProcessDataRequest<PredictRequest>>,
+) -> Result<Json<ProcessedDataResponse<IntentMessage<PredictResponse>>>, EnclaveError> {
+    let nonce = req.nonce()?;
+    let payload = req.payload;
+    if payload.rows.is_empty() || payload.rows.len() > MAX_ROWS_PER_REQUEST {
+        return Err(EnclaveError::InvalidInput(format!(
+            "expected between 1 and {MAX_ROWS_PER_REQUEST} rows"
+        )));
+    }
+
+    // 1. Load the model
+    let bytes = download_blob(&payload.model_blob_id).await?;
+    let bytes = if payload.sealed {
+        unseal(&state, MODEL_AAD, &bytes)?
+    } else {
+        bytes
+    };
+    let artifact = ModelArtifact::from_bytes(&bytes)?;
+    let requested = payload.rows.len() as u64;
+    if artifact.aggregates_only && requested < artifact.min_batch_size {
+        return Err(EnclaveError::InvalidInput(format!(
+            "the model only answers class counts of at least {} rows",
+            artifact.min_batch_size
+        )));
+    }
+
+    let input_size = artifact.preprocessing.input_size();
+    if let Some(row) = payload
+        .rows
+        .iter()
+        .find(|r| r.len() != input_size || r.iter().flatten().any(|v| !v.is_finite()))
+    {
+        return Err(EnclaveError::InvalidInput(format!(
+            "rows must have {input_size} finite or missing features, got {}",
+            row.len()
+        )));
+    }
+    let rows: Vec<Vec<f32>> = payload
+        .rows
+        .iter()
+        .map(|r| r.iter().map(|v| v.unwrap_or(f32::NAN)).collect())
+        .collect();
+    let transformed = artifact.preprocessing.transform_all(&rows)?;
+
+    // 2. Charge the query budget before answering, once the clock is known
+    // to be trusted
+    let stamp = state.clock.stamp(&nonce)?;
+    let queries_remaining = usage::charge(&state, |usage| {
+        let used = usage
+            .queries_used
+            .entry(payload.model_blob_id.clone())
+            .or_insert(0);
+        if *used + requested > artifact.query_budget {
+            return Err(EnclaveError::InvalidInput(format!(
+                "query budget exhausted for model {}",
+                payload.model_blob_id
+            )));
+        }
+        *used += requested;
+        Ok(artifact.query_budget - *used)
+    })??;
+
+    // 3. Batched forward pass
+    let mut predictions: Vec<u64> = artifact
+        .network
+        .forward_batch(&transformed)
+        .iter()
+        .map(|logits| argmax(logits) as u64)
+        .collect();
+    let mut class_counts = vec![0; artifact.config.output_size];
+    predictions
+        .iter()
+        .for_each(|c| class_counts[*c as usize] += 1);
+    if artifact.aggregates_only {
+        predictions.clear();
+        class_counts = noisy_counts(&class_counts, AGGREGATE_EPSILON, &mut OsRng);
+    }
+
+    let rows_bits: Vec<Vec<u32>> = rows
+        .iter()
+        .map(|r| r.iter().map(|v| v.to_bits()).collect())
+        .collect();
+    let rows_bytes = bcs::to_bytes(&rows_bits).expect("should not fail");
+    let response = PredictResponse {
+        model_blob_id: payload.model_blob_id,
+        inputs_hash: Sha256::digest(&rows_bytes).digest.to_vec(),
+        predictions,
+        class_counts,
+        queries_remaining,
+    };
+
+    Ok(Json(to_signed_response(
+        &state.eph_kp,
+        response,
+        &stamp,
+        IntentScope::MlPredict,
+    )))
+}
+
+/// `counts` with Laplace noise, rounded and clamped at zero. A row moves two
+/// counts by one, so their L1 sensitivity is 2.
+fn noisy_counts<R: Rng>(counts: &[u64], epsilon: f64, rng: &mut R) -> Vec<u64> {
+    counts
+        .iter()
+        .map(|c| (*c as f64 + laplace(2.0 / epsilon, rng)).round().max(0.0) as u64)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn test_noisy_counts() {
+        let mut rng = ChaCha20Rng::seed_from_u64(11);
+        let counts = [0, 5, 500];
+        assert_eq!(noisy_counts(&counts, 1e9, &mut rng), counts);
+        // The counts of a batch and of the batch with one row changed are
+        // no longer told apart by a single answer
+        let draws: Vec<Vec<u64>> = (0..200)
+            .map(|_| noisy_counts(&counts, AGGREGATE_EPSILON, &mut rng))
+            .collect();
+        assert!(draws.iter().any(|d| d[1] != 5));
+        let mean = draws.iter().map(|d| d[2] as f64).sum::<f64>() / draws.len() as f64;
+        assert!((mean - 500.0).abs() < 1.0, "{mean}");
+    }
+}
@@ -0,0 +1,605 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Declarative feature preprocessing, fitted inside the enclave on the
+//! training split and persisted with the model so inference applies the same
+//! transforms.
+//!
+//! Each configured column goes through, in order: imputation of missing
+//! values, clipping, `ln(1 + x)`, then either scaling or one-hot encoding.
+//! Columns that are not configured pass through unchanged. Missing values are
+//! `null` in the data blobs and `NaN` once parsed.
+
+use crate::common::f32_bits;
+use crate::EnclaveError;
+use serde::{Deserialize, Serialize};
+
+/// `preprocessing` section of `ModelConfig`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PreprocessingConfig {
+    #[serde(default)]
+    pub columns: Vec<ColumnConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ColumnConfig {
+    /// Index of the raw input column.
+    pub index: usize,
+    #[serde(default)]
+    pub impute: Option<Imputation>,
+    #[serde(default)]
+    pub clip: Option<ClipRange>,
+    /// Apply `ln(1 + x)`, after clipping.
+    #[serde(default)]
+    pub log: bool,
+    #[serde(default)]
+    pub scale: Option<Scaling>,
+    /// Treat the column as categorical and one-hot encode the categories seen
+    /// in the training split. Cannot be combined with `log` or `scale`.
+    #[serde(default)]
+    pub one_hot: bool,
+    /// Most categories a one-hot encoded column may have. Fitting fails on
+    /// columns with more, e.g. continuous ones. At most `MAX_CATEGORIES`.
+    #[serde(default = "default_max_categories")]
+    pub max_categories: usize,
+}
+
+/// Upper bound of `ColumnConfig::max_categories`.
+pub const MAX_CATEGORIES: usize = 256;
+
+fn default_max_categories() -> usize {
+    32
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Imputation {
+    Mean,
+    Median,
+    MostFrequent,
+    Constant(#[serde(with = "f32_bits")] f32),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ClipRange {
+    #[serde(with = "f32_bits")]
+    pub min: f32,
+    #[serde(with = "f32_bits")]
+    pub max: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Scaling {
+    /// Zero mean, unit variance.
+    Standard,
+    /// Rescale to `[0, 1]`.
+    MinMax,
+}
+
+/// Transform of one raw column with its fitted parameters.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FittedColumn {
+    #[serde(with = "f32_bits")]
+    pub impute: Option<f32>,
+    pub clip: Option<ClipRange>,
+    pub log: bool,
+    pub scale: Option<Affine>,
+    /// Categories of a one-hot encoded column, in ascending order.
+    #[serde(with = "f32_bits")]
+    pub categories: Vec<f32>,
+}
+
+/// Fitted scaling, applied as `(x - offset) * factor`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Affine {
+    #[serde(with = "f32_bits")]
+    pub offset: f32,
+    #[serde(with = "f32_bits")]
+    pub factor: f32,
+}
+
+impl FittedColumn {
+    fn passthrough() -> Self {
+        Self {
+            impute: None,
+            clip: None,
+            log: false,
+            scale: None,
+            categories: vec![],
+        }
+    }
+
+    fn width(&self) -> usize {
+        if self.categories.is_empty() {
+            1
+        } else {
+            self.categories.len()
+        }
+    }
+
+    /// Value after imputation, clipping and log, before scaling or encoding.
+    fn prepare(&self, index: usize, v: f32) -> Result<f32, EnclaveError> {
+        let mut v = if v.is_nan() {
+            self.impute.ok_or_else(|| {
+                EnclaveError::InvalidInput(format!("missing value in column {index}"))
+            })?
+        } else {
+            v
+        };
+        if let Some(clip) = self.clip {
+            v = v.clamp(clip.min, clip.max);
+        }
+        if self.log {
+            v = v.ln_1p();
+        }
+        if !v.is_finite() {
+            return Err(EnclaveError::InvalidInput(format!(
+                "non finite value in column {index} after preprocessing"
+            )));
+        }
+        Ok(v)
+    }
+}
+
+impl ColumnConfig {
+    fn validate(&self, input_size: usize) -> Result<(), EnclaveError> {
+        if self.index >= input_size {
+            return Err(EnclaveError::InvalidInput(format!(
+                "preprocessing column {} out of range",
+                self.index
+            )));
+        }
+        if self.one_hot && (self.log || self.scale.is_some()) {
+            return Err(EnclaveError::InvalidInput(format!(
+                "column {} cannot be both one-hot encoded and scaled",
+                self.index
+            )));
+        }
+        if let Some(clip) = self.clip {
+            if !clip.min.is_finite() || !clip.max.is_finite() || clip.min > clip.max {
+                return Err(EnclaveError::InvalidInput(format!(
+                    "column {} clips to [{}, {}], which must be finite and ordered",
+                    self.index, clip.min, clip.max
+                )));
+            }
+        }
+        if self.max_categories == 0 || self.max_categories > MAX_CATEGORIES {
+            return Err(EnclaveError::InvalidInput(format!(
+                "column {} max_categories must be in [1, {MAX_CATEGORIES}]",
+                self.index
+            )));
+        }
+        Ok(())
+    }
+
+    /// Fail if a one-hot encoded column has more than `max_categories`.
+    fn check_categories(&self, categories: &[f32]) -> Result<(), EnclaveError> {
+        if categories.len() > self.max_categories {
+            return Err(EnclaveError::InvalidInput(format!(
+                "column {} has more than {} categories to one-hot encode",
+                self.index, self.max_categories
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Preprocessing fitted on the training split.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pipeline {
+    /// One entry per raw input column.
+    pub columns: Vec<FittedColumn>,
+}
+
+impl PreprocessingConfig {
+    /// Check every column against `input_size` raw features.
+    pub fn validate(&self, input_size: usize) -> Result<(), EnclaveError> {
+        self.columns
+            .iter()
+            .try_for_each(|spec| spec.validate(input_size))
+    }
+
+    /// Fit the configured transforms on the training rows.
+    pub fn fit(&self, input_size: usize, rows: &[Vec<f32>]) -> Result<Pipeline, EnclaveError> {
+        self.fit_columns(input_size, |index| {
+            Ok(rows.iter().map(|r| r[index]).collect())
+        })
+    }
+
+    /// Like [`PreprocessingConfig::fit`], where `column` reads the training
+    /// values of one raw column, so only configured columns are held.
+    pub fn fit_columns<F>(&self, input_size: usize, mut column: F) -> Result<Pipeline, EnclaveError>
+    where
+        F: FnMut(usize) -> Result<Vec<f32>, EnclaveError>,
+    {
+        self.validate(input_size)?;
+        let mut columns: Vec<FittedColumn> = (0..input_size)
+            .map(|_| FittedColumn::passthrough())
+            .collect();
+        for spec in &self.columns {
+            let values = column(spec.index)?;
+            let observed: Vec<f32> = values.iter().copied().filter(|v| !v.is_nan()).collect();
+            let mut fitted = FittedColumn {
+                impute: spec.impute.map(|i| impute_value(i, &observed)),
+                clip: spec.clip,
+                log: spec.log,
+                scale: None,
+                categories: vec![],
+            };
+
+            let prepared = values
+                .iter()
+                .map(|v| fitted.prepare(spec.index, *v))
+                .collect::<Result<Vec<f32>, _>>()?;
+            if spec.one_hot {
+                let mut categories = prepared;
+                categories.sort_by(f32::total_cmp);
+                categories.dedup_by(|a, b| a.to_bits() == b.to_bits());
+                spec.check_categories(&categories)?;
+                fitted.categories = categories;
+            } else if let Some(scaling) = spec.scale {
+                fitted.scale = Some(fit_scaling(scaling, &prepared));
+            }
+            columns[spec.index] = fitted;
+        }
+        Ok(Pipeline { columns })
+    }
+}
+
+/// Sums of a set of values, which merge across shards.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct Moments {
+    pub count: u64,
+    #[serde(with = "f32_bits")]
+    pub sum: f64,
+    #[serde(with = "f32_bits")]
+    pub sum_sq: f64,
+    /// Smallest and largest value, `0` while `count` is `0`.
+    #[serde(with = "f32_bits")]
+    pub min: f64,
+    #[serde(with = "f32_bits")]
+    pub max: f64,
+}
+
+/// Sufficient statistics of one raw column over a shard of the training
+/// split. Federated jobs fit the preprocessing on the merged statistics of
+/// all shards, so no row leaves its worker.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ColumnStats {
+    /// The present values.
+    pub observed: Moments,
+    pub missing: u64,
+    /// The present values after clipping and log, for configured columns.
+    pub prepared: Moments,
+    /// Distinct prepared values of a one-hot encoded column, in ascending order.
+    #[serde(with = "f32_bits")]
+    pub categories: Vec<f32>,
+}
+
+impl Moments {
+    fn push(&mut self, v: f64) {
+        if self.count == 0 {
+            (self.min, self.max) = (v, v);
+        }
+        self.count += 1;
+        self.sum += v;
+        self.sum_sq += v * v;
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+    }
+
+    /// Add `count` copies of `v`.
+    fn push_n(&mut self, v: f64, count: u64) {
+        self.merge(&Moments {
+            count,
+            sum: v * count as f64,
+            sum_sq: v * v * count as f64,
+            min: v,
+            max: v,
+        });
+    }
+
+    pub fn merge(&mut self, other: &Moments) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            (self.min, self.max) = (other.min, other.max);
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.sum_sq += other.sum_sq;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+
+    /// Mean and population standard deviation, `(0, 0)` when empty.
+    pub fn mean_std(&self) -> (f64, f64) {
+        if self.count == 0 {
+            return (0.0, 0.0);
+        }
+        let n = self.count as f64;
+        let mean = self.sum / n;
+        (mean, (self.sum_sq / n - mean * mean).max(0.0).sqrt())
+    }
+}
+
+impl ColumnStats {
+    pub fn merge(&mut self, other: &ColumnStats) {
+        self.observed.merge(&other.observed);
+        self.missing += other.missing;
+        self.prepared.merge(&other.prepared);
+        self.categories.extend(&other.categories);
+        self.categories.sort_by(f32::total_cmp);
+        self.categories.dedup_by(|a, b| a.to_bits() == b.to_bits());
+    }
+}
+
+impl PreprocessingConfig {
+    /// Statistics of each raw column of a shard's training rows.
+    pub fn shard_stats(
+        &self,
+        input_size: usize,
+        rows: &[Vec<f32>],
+    ) -> Result<Vec<ColumnStats>, EnclaveError> {
+        let mut stats = vec![ColumnStats::default(); input_size];
+        for row in rows {
+            for (column, v) in stats.iter_mut().zip(row) {
+                match v.is_nan() {
+                    true => column.missing += 1,
+                    false => column.observed.push(*v as f64),
+                }
+            }
+        }
+        for spec in &self.columns {
+            spec.validate(input_size)?;
+            let unimputed = FittedColumn {
+                clip: spec.clip,
+                log: spec.log,
+                ..FittedColumn::passthrough()
+            };
+            let column = &mut stats[spec.index];
+            for v in rows.iter().map(|r| r[spec.index]).filter(|v| !v.is_nan()) {
+                let v = unimputed.prepare(spec.index, v)?;
+                column.prepared.push(v as f64);
+                if spec.one_hot {
+                    column.categories.push(v);
+                }
+            }
+            column.categories.sort_by(f32::total_cmp);
+            column
+                .categories
+                .dedup_by(|a, b| a.to_bits() == b.to_bits());
+            spec.check_categories(&column.categories)?;
+        }
+        Ok(stats)
+    }
+
+    /// Fit the configured transforms on the merged `stats` of every shard.
+    /// Median and most frequent imputation need the rows themselves, and are
+    /// refused.
+    pub fn fit_stats(
+        &self,
+        input_size: usize,
+        stats: &[ColumnStats],
+    ) -> Result<Pipeline, EnclaveError> {
+        let mut columns: Vec<FittedColumn> = (0..input_size)
+            .map(|_| FittedColumn::passthrough())
+            .collect();
+        for spec in &self.columns {
+            spec.validate(input_size)?;
+            let column = &stats[spec.index];
+            let impute = match spec.impute {
+                None => None,
+                Some(Imputation::Constant(c)) => Some(c),
+                Some(Imputation::Mean) => Some(column.observed.mean_std().0 as f32),
+                Some(Imputation::Median | Imputation::MostFrequent) => {
+                    return Err(EnclaveError::InvalidInput(format!(
+                        "column {} imputes the median or most frequent value, which needs \
+                         every row in one enclave",
+                        spec.index
+                    )));
+                }
+            };
+            let mut fitted = FittedColumn {
+                impute,
+                clip: spec.clip,
+                log: spec.log,
+                scale: None,
+                categories: column.categories.clone(),
+            };
+            // Every missing value prepares to the same imputed value.
+            let mut prepared = column.prepared;
+            if column.missing > 0 {
+                let imputed = fitted.prepare(spec.index, f32::NAN)?;
+                prepared.push_n(imputed as f64, column.missing);
+                if spec.one_hot {
+                    fitted.categories.push(imputed);
+                    fitted.categories.sort_by(f32::total_cmp);
+                    fitted
+                        .categories
+                        .dedup_by(|a, b| a.to_bits() == b.to_bits());
+                }
+            }
+            spec.check_categories(&fitted.categories)?;
+            if let Some(scaling) = spec.scale {
+                fitted.scale = Some(match (scaling, prepared.count) {
+                    (_, 0) => affine(0.0, 0.0),
+                    (Scaling::Standard, _) => {
+                        let (mean, std) = prepared.mean_std();
+                        affine(mean, std)
+                    }
+                    (Scaling::MinMax, _) => affine(prepared.min, prepared.max - prepared.min),
+                });
+            }
+            columns[spec.index] = fitted;
+        }
+        Ok(Pipeline { columns })
+    }
+}
+
+fn impute_value(imputation: Imputation, observed: &[f32]) -> f32 {
+    if observed.is_empty() {
+        return match imputation {
+            Imputation::Constant(c) => c,
+            _ => 0.0,
+        };
+    }
+    match imputation {
+        Imputation::Constant(c) => c,
+        Imputation::Mean => {
+            (observed.iter().map(|v| *v as f64).sum::<f64>() / observed.len() as f64) as f32
+        }
+        Imputation::Median => {
+            let mut sorted = observed.to_vec();
+            sorted.sort_by(f32::total_cmp);
+            sorted[sorted.len() / 2]
+        }
+        Imputation::MostFrequent => {
+            let mut sorted = observed.to_vec();
+            sorted.sort_by(f32::total_cmp);
+            // Longest run in sorted order, ties resolved to the smallest value.
+            let (mut best, mut best_len, mut run) = (sorted[0], 0, 0);
+            for (i, v) in sorted.iter().enumerate() {
+                run = if i > 0 && sorted[i - 1] == *v {
+                    run + 1
+                } else {
+                    1
+                };
+                if run > best_len {
+                    best = *v;
+                    best_len = run;
+                }
+            }
+            best
+        }
+    }
+}
+
+fn fit_scaling(scaling: Scaling, values: &[f32]) -> Affine {
+    if values.is_empty() {
+        return Affine {
+            offset: 0.0,
+            factor: 1.0,
+        };
+    }
+    let (offset, spread) = match scaling {
+        Scaling::Standard => {
+            let n = values.len() as f64;
+            let mean = values.iter().map(|v| *v as f64).sum::<f64>() / n;
+            let var = values
+                .iter()
+                .map(|v| (*v as f64 - mean).powi(2))
+                .sum::<f64>()
+                / n;
+            (mean, var.sqrt())
+        }
+        Scaling::MinMax => {
+            let min = values.iter().cloned().fold(f32::INFINITY, f32::min) as f64;
+            let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max) as f64;
+            (min, max - min)
+        }
+    };
+    affine(offset, spread)
+}
+
+fn affine(offset: f64, spread: f64) -> Affine {
+    // Constant columns are only centered.
+    let factor = if spread > f64::EPSILON {
+        1.0 / spread
+    } else {
+        1.0
+    };
+    Affine {
+        offset: offset as f32,
+        factor: factor as f32,
+    }
+}
+
+impl Pipeline {
+    pub fn input_size(&self) -> usize {
+        self.columns.len()
+    }
+
+    /// Width of the transformed rows fed to the network.
+    pub fn output_width(&self) -> usize {
+        self.columns.iter().map(FittedColumn::width).sum()
+    }
+
+    pub fn transform(&self, row: &[f32]) -> Result<Vec<f32>, EnclaveError> {
+        if row.len() != self.columns.len() {
+            return Err(EnclaveError::InvalidInput(format!(
+                "expected {} features, got {}",
+                self.columns.len(),
+                row.len()
+            )));
+        }
+        let mut out = Vec::with_capacity(self.output_width());
+        for (index, (column, v)) in self.columns.iter().zip(row).enumerate() {
+            let v = column.prepare(index, *v)?;
+            if !column.categories.is_empty() {
+                // Categories unseen during fitting encode as all zeros.
+                out.extend(column.categories.iter().map(|c| {
+                    if c.to_bits() == v.to_bits() {
+                        1.0
+                    } else {
+                        0.0
+                    }
+                }));
+            } else if let Some(scale) = column.scale {
+                out.push((v - scale.offset) * scale.factor);
+            } else {
+                out.push(v);
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn transform_all(&self, rows: &[Vec<f32>]) -> Result<Vec<Vec<f32>>, EnclaveError> {
+        rows.iter().map(|r| self.transform(r)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn config(column: serde_json::Value) -> PreprocessingConfig {
+        serde_json::from_value(json!({ "columns": [column] })).unwrap()
+    }
+
+    #[test]
+    fn test_clip_must_be_finite_and_ordered() {
+        let rows = vec![vec![1.0], vec![5.0]];
+        let reversed = config(json!({"index": 0, "clip": {"min": 3.0, "max": 2.0}}));
+        assert!(reversed.fit(1, &rows).is_err());
+        let mut unbounded = config(json!({"index": 0, "clip": {"min": 0.0, "max": 2.0}}));
+        unbounded.columns[0].clip = Some(ClipRange {
+            min: f32::NAN,
+            max: 2.0,
+        });
+        assert!(unbounded.fit(1, &rows).is_err());
+        assert!(unbounded.shard_stats(1, &rows).is_err());
+
+        let clipped = config(json!({"index": 0, "clip": {"min": 2.0, "max": 2.0}}));
+        let pipeline = clipped.fit(1, &rows).unwrap();
+        assert_eq!(pipeline.transform(&[5.0]).unwrap(), vec![2.0]);
+    }
+
+    #[test]
+    fn test_one_hot_category_limit() {
+        let rows: Vec<Vec<f32>> = (0..10).map(|i| vec![(i % 4) as f32]).collect();
+        let capped = config(json!({"index": 0, "one_hot": true, "max_categories": 4}));
+        assert_eq!(capped.fit(1, &rows).unwrap().output_width(), 4);
+
+        let continuous: Vec<Vec<f32>> = (0..100).map(|i| vec![i as f32 / 7.0]).collect();
+        let default = config(json!({"index": 0, "one_hot": true}));
+        assert!(default.fit(1, &continuous).is_err());
+        assert!(default.shard_stats(1, &continuous).is_err());
+
+        let too_many = config(json!({"index": 0, "one_hot": true, "max_categories": 1000}));
+        assert!(too_many.fit(1, &rows).is_err());
+    }
+}
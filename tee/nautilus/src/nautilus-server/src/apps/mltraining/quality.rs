@@ -0,0 +1,266 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Per-blob data quality checks.
+//!
+//! Each contributor blob is checked on its own, so one malformed or
+//! low-quality upload is excluded instead of failing the whole job. Rows with
+//! the wrong dimension, non finite features or an out of range label are
+//! dropped, and a blob with too many of them is rejected. Rows that repeat a
+//! row of another contributor are dropped as duplicates, and rows far from the
+//! pooled feature means are counted as outliers.
+//!
+//! Rows are checked as they are decoded and spilled to a [`SpillBuffer`], so a
+//! pool is never held as JSON text or as parsed rows of every blob at once.
+
+use super::dataset::SpilledDataset;
+use super::spill::SpillBuffer;
+use crate::common::f32_bits;
+use crate::EnclaveError;
+use fastcrypto::hash::{HashFunction, Sha256};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QualityConfig {
+    /// Largest fraction of invalid rows a blob may have and still be used.
+    #[serde(default = "default_max_invalid_fraction", with = "f32_bits")]
+    pub max_invalid_fraction: f32,
+    /// A row is an outlier if a feature is more than this many standard
+    /// deviations from its pooled mean.
+    #[serde(default = "default_outlier_z", with = "f32_bits")]
+    pub outlier_z: f32,
+    /// Drop outliers instead of only counting them.
+    #[serde(default)]
+    pub drop_outliers: bool,
+}
+
+fn default_max_invalid_fraction() -> f32 {
+    0.05
+}
+
+fn default_outlier_z() -> f32 {
+    6.0
+}
+
+impl Default for QualityConfig {
+    fn default() -> Self {
+        Self {
+            max_invalid_fraction: default_max_invalid_fraction(),
+            outlier_z: default_outlier_z(),
+            drop_outliers: false,
+        }
+    }
+}
+
+/// Quality of one data blob, signed in the response of the job.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BlobReport {
+    pub blob_id: String,
+    /// The blob could not be decrypted or is not a JSON list of
+    /// `[features, label]` rows.
+    pub malformed: bool,
+    pub rows: u64,
+    pub wrong_dimension: u64,
+    pub non_finite: u64,
+    pub label_out_of_range: u64,
+    /// Missing feature values in the valid rows.
+    pub missing_values: u64,
+    /// Valid rows already contributed by an earlier blob.
+    pub duplicates: u64,
+    pub outliers: u64,
+    /// Whether the blob's rows were used by the job.
+    pub accepted: bool,
+    /// Rows of the blob used by the job.
+    pub rows_used: u64,
+}
+
+impl QualityConfig {
+    pub fn validate(&self) -> Result<(), EnclaveError> {
+        if !(0.0..=1.0).contains(&self.max_invalid_fraction)
+            || self.outlier_z.is_nan()
+            || self.outlier_z <= 0.0
+        {
+            return Err(EnclaveError::InvalidInput(
+                "max_invalid_fraction must be in [0, 1] and outlier_z positive".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Streaming quality checks over the blobs of one job. Blobs are fed one at a
+/// time, row by row; valid rows are spilled until every blob has been seen.
+pub struct QualityChecker<'a> {
+    /// Features per row.
+    input_size: usize,
+    /// Labels are below this.
+    output_size: usize,
+    quality: &'a QualityConfig,
+    spill: SpillBuffer,
+    /// Whether each spilled row is used; false for rows of rejected blobs
+    /// and for duplicates.
+    keep: Vec<bool>,
+    /// Digests of the rows of accepted blobs.
+    seen: HashSet<[u8; 16]>,
+    reports: Vec<BlobReport>,
+    /// First spilled row and row digests of the blob being checked.
+    blob_start: usize,
+    blob_digests: Vec<[u8; 16]>,
+}
+
+impl<'a> QualityChecker<'a> {
+    pub fn new(
+        input_size: usize,
+        output_size: usize,
+        quality: &'a QualityConfig,
+    ) -> Result<Self, EnclaveError> {
+        quality.validate()?;
+        Ok(Self {
+            input_size,
+            output_size,
+            quality,
+            spill: SpillBuffer::new(input_size)?,
+            keep: vec![],
+            seen: HashSet::new(),
+            reports: vec![],
+            blob_start: 0,
+            blob_digests: vec![],
+        })
+    }
+
+    /// Start checking the next blob.
+    pub fn begin_blob(&mut self, blob_id: &str) {
+        self.reports.push(BlobReport {
+            blob_id: blob_id.to_string(),
+            ..Default::default()
+        });
+        self.blob_start = self.spill.len();
+        self.blob_digests.clear();
+    }
+
+    /// Check the JSON text of one `[features, label]` row of the current blob.
+    pub fn row(&mut self, json: &[u8]) -> Result<(), EnclaveError> {
+        let shard = self.reports.len() - 1;
+        let report = self.reports.last_mut().expect("blob begun");
+        if report.malformed {
+            return Ok(());
+        }
+        let Ok((input, label)) = serde_json::from_slice::<(Vec<Option<f32>>, usize)>(json) else {
+            report.malformed = true;
+            return Ok(());
+        };
+        report.rows += 1;
+        if input.len() != self.input_size {
+            report.wrong_dimension += 1;
+        } else if input.iter().flatten().any(|v| !v.is_finite()) {
+            report.non_finite += 1;
+        } else if label >= self.output_size {
+            report.label_out_of_range += 1;
+        } else {
+            report.missing_values += input.iter().filter(|v| v.is_none()).count() as u64;
+            let input: Vec<f32> = input.into_iter().map(|v| v.unwrap_or(f32::NAN)).collect();
+            self.blob_digests.push(row_digest(&input, label));
+            self.spill.push(shard, label, &input)?;
+            self.keep.push(true);
+        }
+        Ok(())
+    }
+
+    /// The current blob could not be decoded as a list of rows.
+    pub fn malformed(&mut self) {
+        self.reports.last_mut().expect("blob begun").malformed = true;
+    }
+
+    /// Accept or reject the current blob, and drop its rows already
+    /// contributed by an earlier blob.
+    pub fn end_blob(&mut self) {
+        let report = self.reports.last_mut().expect("blob begun");
+        let invalid = report.wrong_dimension + report.non_finite + report.label_out_of_range;
+        report.accepted = !report.malformed
+            && report.rows > 0
+            && invalid as f64 <= self.quality.max_invalid_fraction as f64 * report.rows as f64;
+        let keep = &mut self.keep[self.blob_start..];
+        if !report.accepted {
+            keep.fill(false);
+            return;
+        }
+        // Insert only once the whole blob is seen, so rows repeated within a
+        // blob are kept
+        for (keep, digest) in keep.iter_mut().zip(&self.blob_digests) {
+            if self.seen.contains(digest) {
+                *keep = false;
+                report.duplicates += 1;
+            }
+        }
+        self.seen.extend(self.blob_digests.drain(..));
+    }
+
+    /// Count, and optionally drop, outliers against the pooled statistics,
+    /// then pool the rows that pass. They move to a spill of their own, one
+    /// segment at a time, so they are never all in the clear.
+    pub fn finish(mut self) -> Result<(SpilledDataset, Vec<BlobReport>), EnclaveError> {
+        self.seen = HashSet::new();
+
+        // 1. Pooled mean and standard deviation of each feature, ignoring
+        // missing values (Welford)
+        let mut moments = vec![(0u64, 0f64, 0f64); self.input_size];
+        let mut index = 0;
+        self.spill.scan(|row| {
+            if self.keep[index] {
+                for ((n, mean, m2), v) in moments.iter_mut().zip(&row.input) {
+                    if !v.is_nan() {
+                        let v = *v as f64;
+                        *n += 1;
+                        let delta = v - *mean;
+                        *mean += delta / *n as f64;
+                        *m2 += delta * (v - *mean);
+                    }
+                }
+            }
+            index += 1;
+            Ok(())
+        })?;
+        let stats: Vec<(f64, f64)> = moments
+            .into_iter()
+            .map(|(n, mean, m2)| (mean, (m2 / n.max(1) as f64).sqrt()))
+            .collect();
+        let outlier_z = self.quality.outlier_z as f64;
+        let is_outlier = |input: &[f32]| {
+            input
+                .iter()
+                .zip(&stats)
+                .any(|(v, (mean, std))| *std > 0.0 && (*v as f64 - mean).abs() > outlier_z * std)
+        };
+
+        // 2. Pool the kept rows, releasing the spill as it is read
+        let (keep, mut reports, drop_outliers) =
+            (self.keep, self.reports, self.quality.drop_outliers);
+        let mut dataset = SpilledDataset::new(self.input_size)?;
+        let mut index = 0;
+        self.spill.drain(|row| {
+            if keep[index] {
+                let report = &mut reports[row.shard];
+                let outlier = is_outlier(&row.input);
+                report.outliers += outlier as u64;
+                if !(outlier && drop_outliers) {
+                    report.rows_used += 1;
+                    dataset.push(&row.input, row.label, row.shard)?;
+                }
+            }
+            index += 1;
+            Ok(())
+        })?;
+        Ok((dataset, reports))
+    }
+}
+
+/// Identity of a valid row for duplicate detection.
+fn row_digest(input: &[f32], label: usize) -> [u8; 16] {
+    let mut hasher = Sha256::default();
+    for v in input {
+        hasher.update(v.to_bits().to_le_bytes());
+    }
+    hasher.update((label as u64).to_le_bytes());
+    hasher.finalize().digest[..16].try_into().expect("16 bytes")
+}
@@ -0,0 +1,159 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Robust aggregation of per-contributor gradients.
+//!
+//! In robust mode each data blob is a shard. Every step computes one gradient
+//! per shard and combines them with an aggregator that bounds the influence of
+//! a minority of poisoned shards. The aggregator also tracks how much each
+//! shard contributed, so shards that were consistently discarded can be
+//! reported.
+
+use super::model::Gradients;
+use crate::common::f32_bits;
+use crate::EnclaveError;
+use serde::{Deserialize, Serialize};
+
+/// Shards whose influence falls below this fraction of an even share are
+/// reported as down-weighted.
+pub const DOWN_WEIGHT_THRESHOLD: f32 = 0.5;
+
+/// `robust` of the training config, e.g. `"robust": "median"`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum RobustAggregation {
+    /// Per coordinate, drop the `trim_fraction` largest and smallest shard
+    /// values and average the rest.
+    TrimmedMean {
+        #[serde(with = "f32_bits")]
+        trim_fraction: f32,
+    },
+    /// Per coordinate, the median of the shard values.
+    Median,
+    /// The gradient of the shard closest to its `shards - byzantine - 2`
+    /// nearest neighbours.
+    Krum { byzantine: u64 },
+}
+
+impl RobustAggregation {
+    pub fn validate(&self, shards: usize) -> Result<(), EnclaveError> {
+        if shards < 3 {
+            return Err(EnclaveError::InvalidInput(
+                "robust aggregation needs at least 3 data blobs".to_string(),
+            ));
+        }
+        match *self {
+            RobustAggregation::TrimmedMean { trim_fraction }
+                if !(0.0..0.5).contains(&trim_fraction) =>
+            {
+                Err(EnclaveError::InvalidInput(
+                    "trim_fraction must be in [0, 0.5)".to_string(),
+                ))
+            }
+            RobustAggregation::Krum { byzantine } if shards < 2 * byzantine as usize + 3 => {
+                Err(EnclaveError::InvalidInput(format!(
+                    "krum with {byzantine} byzantine shards needs at least {} data blobs",
+                    2 * byzantine + 3
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Aggregator state of a training run.
+pub struct Aggregator {
+    method: RobustAggregation,
+    /// Aggregated units (coordinates, or steps for Krum) each shard was kept in.
+    kept: Vec<u64>,
+}
+
+impl Aggregator {
+    pub fn new(method: RobustAggregation, shards: usize) -> Self {
+        Self {
+            method,
+            kept: vec![0; shards],
+        }
+    }
+
+    /// Combine one gradient per shard, in shard order.
+    pub fn aggregate(&mut self, grads: &[Gradients]) -> Gradients {
+        let n = grads.len();
+        let flat: Vec<Vec<f32>> = grads.iter().map(Gradients::flatten).collect();
+        let mut out = grads[0].clone();
+        match self.method {
+            RobustAggregation::Krum { byzantine } => {
+                let neighbours = n - byzantine as usize - 2;
+                let scores: Vec<f64> = (0..n)
+                    .map(|i| {
+                        let mut distances: Vec<f64> = (0..n)
+                            .filter(|j| *j != i)
+                            .map(|j| {
+                                flat[i]
+                                    .iter()
+                                    .zip(&flat[j])
+                                    .map(|(a, b)| (*a as f64 - *b as f64).powi(2))
+                                    .sum()
+                            })
+                            .collect();
+                        distances.sort_by(f64::total_cmp);
+                        distances[..neighbours].iter().sum()
+                    })
+                    .collect();
+                let selected = (0..n)
+                    .min_by(|a, b| scores[*a].total_cmp(&scores[*b]))
+                    .expect("at least one shard");
+                self.kept[selected] += 1;
+                out.set_flat(&flat[selected]);
+            }
+            RobustAggregation::TrimmedMean { .. } | RobustAggregation::Median => {
+                let (lo, hi) = match self.method {
+                    RobustAggregation::TrimmedMean { trim_fraction } => {
+                        let t = (n as f32 * trim_fraction) as usize;
+                        (t, n - t)
+                    }
+                    _ => ((n - 1) / 2, n / 2 + 1),
+                };
+                let mut column: Vec<(f32, usize)> = Vec::with_capacity(n);
+                let values: Vec<f32> = (0..flat[0].len())
+                    .map(|k| {
+                        column.clear();
+                        column.extend(flat.iter().enumerate().map(|(s, g)| (g[k], s)));
+                        column.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+                        let kept = &column[lo..hi];
+                        kept.iter().for_each(|(_, s)| self.kept[*s] += 1);
+                        kept.iter().map(|(v, _)| *v as f64).sum::<f64>() as f32 / kept.len() as f32
+                    })
+                    .collect();
+                out.set_flat(&values);
+            }
+        }
+        out
+    }
+
+    /// Share of the aggregate each shard contributed to, relative to an even
+    /// share: 1 means average influence, 0 means always discarded.
+    pub fn influence(&self) -> Vec<f32> {
+        let total: u64 = self.kept.iter().sum();
+        let n = self.kept.len() as f64;
+        self.kept
+            .iter()
+            .map(|k| {
+                if total == 0 {
+                    1.0
+                } else {
+                    (*k as f64 * n / total as f64) as f32
+                }
+            })
+            .collect()
+    }
+}
+
+/// Influence of one data blob in robust training, signed in `TrainingReport`.
+#[derive(Serialize, Clone, Debug)]
+pub struct ShardReport {
+    pub blob_id: String,
+    /// Influence relative to an even share, scaled by `METRIC_SCALE`.
+    pub influence: u64,
+    pub down_weighted: bool,
+}
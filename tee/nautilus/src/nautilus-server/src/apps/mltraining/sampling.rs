@@ -0,0 +1,20 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Draws from the distributions the privacy mechanisms of the apps add,
+//! e.g. the noise of analytics queries and of synthetic data generators.
+
+use rand::Rng;
+
+/// Laplace noise of scale `b`, as the difference of two exponentials.
+pub fn laplace<R: Rng>(b: f64, rng: &mut R) -> f64 {
+    // `1 - u` is in (0, 1], so the logarithms are finite
+    let exponential = |rng: &mut R| -(1.0 - rng.gen::<f64>()).ln();
+    b * (exponential(rng) - exponential(rng))
+}
+
+/// Box-Muller transform.
+pub fn standard_normal<R: Rng>(rng: &mut R) -> f64 {
+    let radius = (-2.0 * (1.0 - rng.gen::<f64>()).ln()).sqrt();
+    radius * (std::f64::consts::TAU * rng.gen::<f64>()).cos()
+}
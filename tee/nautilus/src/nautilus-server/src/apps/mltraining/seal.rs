@@ -0,0 +1,563 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Seal threshold decryption of contributor data keys.
+//!
+//! A sealed data blob (see `stream`) carries the key of its chunked body in a
+//! Seal envelope, encrypted to the id `key_id` under the CloakX package. Key
+//! ids start with the little endian id of the pool the blob is for. The
+//! enclave asks the key servers of `seal_config.yaml` for their shares of that
+//! id, signing as its ephemeral key: `cloakx::seal_policy::seal_approve` only
+//! passes a sender whose address is derived from the public key of a
+//! registered `Enclave<JOBS>`, naming a pending job on that pool. Shares come back encrypted to an ElGamal key
+//! drawn per job, so the host's proxy never sees them, and any `threshold` of
+//! them open the envelopes.
+
+use super::cache::CachedBlob;
+use super::stream::{envelope_length, SEALED_HEADER_LENGTH, SEALED_MAGIC};
+use crate::common::current_timestamp_ms;
+use crate::AppState;
+use crate::EnclaveError;
+use fastcrypto::encoding::{Base64, Encoding, Hex};
+use fastcrypto::traits::{KeyPair, ToFromByteArray, ToFromBytes};
+use seal_sdk::types::{FetchKeyRequest, FetchKeyResponse};
+use seal_sdk::{
+    genkey, seal_decrypt_all_objects, signed_message, signed_request, Certificate,
+    ElGamalSecretKey, EncryptedObject, IBEPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use sui_crypto::ed25519::Ed25519PrivateKey;
+use sui_crypto::{Signer, SuiSigner};
+use sui_sdk_types::{
+    Argument, Command, Identifier, Input, MoveCall, ObjectId, PersonalMessage,
+    ProgrammableTransaction,
+};
+use tokio::task::JoinSet;
+use tracing::info;
+
+/// Environment variable naming a key server config to use instead of the
+/// measured `seal_config.yaml`, e.g. to point at local stand-in key servers.
+/// Debug builds only: the host sets the environment of a release enclave.
+pub const SEAL_CONFIG_ENV: &str = "SEAL_CONFIG";
+
+/// Lifetime of the session key certified by the enclave's ephemeral key.
+const SESSION_TTL_MIN: u16 = 10;
+
+const KEY_SERVER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// `seal_config.yaml`.
+#[derive(Deserialize, Debug)]
+pub struct SealConfig {
+    /// Package of `seal_policy::seal_approve`, the namespace of every key id.
+    pub package_id: String,
+    /// Shares needed to open an envelope.
+    pub threshold: usize,
+    pub key_servers: Vec<KeyServerConfig>,
+    /// The shared `JobRegistry` and `PoolRegistry` `seal_approve` reads.
+    /// Needed once public keys are set.
+    #[serde(default)]
+    pub job_registry: Option<SharedObject>,
+    #[serde(default)]
+    pub pool_registry: Option<SharedObject>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KeyServerConfig {
+    /// `KeyServer` object id, as listed in the envelopes.
+    pub object_id: String,
+    pub url: String,
+    /// Hex encoded IBE public key, the `pk` of the `KeyServer` object.
+    pub public_key: String,
+}
+
+/// A shared object passed to `seal_approve`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SharedObject {
+    pub object_id: String,
+    pub initial_shared_version: u64,
+}
+
+/// The shared `Enclave<JOBS>` object this enclave was registered as. It only
+/// exists once the enclave is running, so jobs with sealed data name it.
+pub type EnclaveObject = SharedObject;
+
+/// The job sealed blobs are opened for, checked on chain by the caller.
+pub struct SealedJob<'a> {
+    pub job_id: u64,
+    pub pool_id: u64,
+    /// Hex encoded id the pool's contributors sealed their keys to.
+    pub key_id: &'a str,
+    pub enclave: Option<&'a EnclaveObject>,
+}
+
+impl SealConfig {
+    fn parse(text: &str) -> Result<Self, EnclaveError> {
+        let config: SealConfig = serde_yaml::from_str(text)
+            .map_err(|e| EnclaveError::GenericError(format!("invalid seal config: {e}")))?;
+        if !config.configured() {
+            return Ok(config);
+        }
+        if config.threshold == 0 || config.threshold > config.key_servers.len() {
+            return Err(EnclaveError::GenericError(format!(
+                "seal threshold {} with {} key servers",
+                config.threshold,
+                config.key_servers.len()
+            )));
+        }
+        config.public_keys()?;
+        config.registries()?;
+        Ok(config)
+    }
+
+    fn registries(&self) -> Result<(&SharedObject, &SharedObject), EnclaveError> {
+        match (&self.job_registry, &self.pool_registry) {
+            (Some(jobs), Some(pools)) => Ok((jobs, pools)),
+            _ => Err(EnclaveError::GenericError(
+                "the seal config needs job_registry and pool_registry".to_string(),
+            )),
+        }
+    }
+
+    /// Whether the image was built with the key servers' public keys. Without
+    /// any, sealed blobs are refused one by one and the other blobs are used.
+    fn configured(&self) -> bool {
+        self.key_servers.iter().any(|s| !s.public_key.is_empty())
+    }
+
+    /// IBE public key of each key server.
+    fn public_keys(&self) -> Result<HashMap<ObjectId, IBEPublicKey>, EnclaveError> {
+        let mut public_keys = HashMap::new();
+        for server in &self.key_servers {
+            let public_key = Hex::decode(&server.public_key)
+                .ok()
+                .and_then(|bytes| IBEPublicKey::from_byte_array(&bytes.try_into().ok()?).ok())
+                .ok_or_else(|| {
+                    EnclaveError::GenericError(format!(
+                        "key server {} has no valid public key in the seal config, \
+                         set it to the `pk` of its `KeyServer` object",
+                        server.object_id
+                    ))
+                })?;
+            public_keys.insert(object_id(&server.object_id)?, public_key);
+        }
+        Ok(public_keys)
+    }
+}
+
+fn load_config() -> Result<SealConfig, EnclaveError> {
+    let text = match std::env::var(SEAL_CONFIG_ENV) {
+        Ok(path) if cfg!(debug_assertions) => std::fs::read_to_string(path)?,
+        _ => include_str!("seal_config.yaml").to_string(),
+    };
+    SealConfig::parse(&text)
+}
+
+/// Check the key server config at boot, so an image with a broken config
+/// does not start. One built without public keys starts, refusing sealed
+/// blobs.
+pub fn check_config() -> Result<(), EnclaveError> {
+    if !load_config()?.configured() {
+        info!("no seal key server public keys configured, sealed data blobs are refused");
+    }
+    Ok(())
+}
+
+fn object_id(id: &str) -> Result<ObjectId, EnclaveError> {
+    id.parse()
+        .map_err(|_| EnclaveError::InvalidInput(format!("invalid object id {id}")))
+}
+
+/// The bytes of `key_id`, which must be under `pool_id`.
+fn pool_key_id(key_id: &str, pool_id: u64) -> Result<Vec<u8>, EnclaveError> {
+    let id = Hex::decode(key_id)
+        .map_err(|_| EnclaveError::InvalidInput("key_id must be hex".to_string()))?;
+    if !id.starts_with(&pool_id.to_le_bytes()) {
+        return Err(EnclaveError::InvalidInput(format!(
+            "key_id must start with pool id {pool_id}, little endian"
+        )));
+    }
+    Ok(id)
+}
+
+/// The envelope of a sealed blob, `None` for other blobs.
+pub fn read_envelope(blob: &CachedBlob) -> Result<Option<Vec<u8>>, EnclaveError> {
+    let header = blob.head(SEALED_HEADER_LENGTH)?;
+    if !header.starts_with(SEALED_MAGIC) {
+        return Ok(None);
+    }
+    let length = envelope_length(&header)?;
+    let head = blob.head(SEALED_HEADER_LENGTH + length)?;
+    if head.len() < SEALED_HEADER_LENGTH + length {
+        return Err(EnclaveError::InvalidInput(
+            "truncated sealed blob envelope".to_string(),
+        ));
+    }
+    Ok(Some(head[SEALED_HEADER_LENGTH..].to_vec()))
+}
+
+/// Data keys sealed in `envelopes` for `job`, in order. An envelope that is
+/// not for its key id under the configured package and key servers, or does
+/// not open, yields `None` and is never sent anywhere. Without configured key
+/// servers every envelope yields `None`.
+pub async fn unseal_data_keys(
+    state: &AppState,
+    job: &SealedJob<'_>,
+    envelopes: &[Vec<u8>],
+) -> Result<Vec<Option<[u8; 32]>>, EnclaveError> {
+    if envelopes.is_empty() {
+        return Ok(vec![]);
+    }
+    let config = load_config()?;
+    if !config.configured() {
+        info!("refusing sealed data blobs, no seal key servers are configured");
+        return Ok(vec![None; envelopes.len()]);
+    }
+    let enclave = job.enclave.ok_or_else(|| {
+        EnclaveError::InvalidInput("sealed data blobs need enclave_object".to_string())
+    })?;
+    let package_id = object_id(&config.package_id)?;
+    let id = pool_key_id(job.key_id, job.pool_id)?;
+
+    let server_pks = config.public_keys()?;
+
+    let objects: Vec<Option<EncryptedObject>> = envelopes
+        .iter()
+        .map(|envelope| {
+            let object: EncryptedObject = bcs::from_bytes(envelope).ok()?;
+            let ours = object.package_id == package_id
+                && object.id == id
+                && object
+                    .services
+                    .iter()
+                    .all(|(s, _)| server_pks.contains_key(s));
+            if !ours {
+                info!("skipping a seal envelope that is not for this job's key id");
+            }
+            ours.then_some(object)
+        })
+        .collect();
+    if objects.iter().all(Option::is_none) {
+        return Ok(objects.iter().map(|_| None).collect());
+    }
+
+    let (enc_secret, request) = fetch_key_request(state, &config, &id, enclave, job.job_id)?;
+    let responses = fetch_shares(&config, &request).await?;
+    Ok(objects
+        .iter()
+        .map(|object| {
+            let opened = seal_decrypt_all_objects(
+                &enc_secret,
+                &responses,
+                std::slice::from_ref(object.as_ref()?),
+                &server_pks,
+            );
+            match opened {
+                Ok(mut keys) => keys.pop()?.try_into().ok(),
+                Err(e) => {
+                    info!("seal envelope does not open: {e}");
+                    None
+                }
+            }
+        })
+        .collect())
+}
+
+/// A key request for `id` on behalf of job `job_id`, authorized by a session
+/// key the enclave's ephemeral key certifies, along with the ElGamal key the
+/// shares come back encrypted to.
+fn fetch_key_request(
+    state: &AppState,
+    config: &SealConfig,
+    id: &[u8],
+    enclave: &EnclaveObject,
+    job_id: u64,
+) -> Result<(ElGamalSecretKey, FetchKeyRequest), EnclaveError> {
+    let package_id = object_id(&config.package_id)?;
+    let mut rng = rand::thread_rng();
+    let (enc_secret, enc_key, enc_verification_key) = genkey(&mut rng);
+    let session = Ed25519PrivateKey::generate(&mut rng);
+    let eph_key = Ed25519PrivateKey::new(
+        state
+            .eph_kp
+            .copy()
+            .private()
+            .as_bytes()
+            .try_into()
+            .expect("32 byte key"),
+    );
+
+    let creation_time = current_timestamp_ms();
+    let message = signed_message(
+        package_id.to_string(),
+        session.public_key(),
+        creation_time,
+        SESSION_TTL_MIN,
+    );
+    let signature = eph_key
+        .sign_personal_message(&PersonalMessage(message.as_bytes().into()))
+        .map_err(|e| EnclaveError::GenericError(format!("failed to sign certificate: {e}")))?;
+    let certificate = Certificate {
+        user: eph_key.public_key().derive_address(),
+        session_vk: session.public_key(),
+        creation_time,
+        ttl_min: SESSION_TTL_MIN,
+        signature,
+        mvr_name: None,
+    };
+
+    let ptb = seal_approve_call(config, id, enclave, job_id)?;
+    let request_signature = session.sign(&signed_request(&ptb, &enc_key, &enc_verification_key));
+    let ptb = bcs::to_bytes(&ptb).map_err(|e| EnclaveError::GenericError(e.to_string()))?;
+    Ok((
+        enc_secret,
+        FetchKeyRequest {
+            ptb: Base64::encode(ptb),
+            enc_key,
+            enc_verification_key,
+            request_signature,
+            certificate,
+        },
+    ))
+}
+
+/// `seal_policy::seal_approve(id, enclave, jobs, job_id, pools)`, dry run by
+/// each key server.
+fn seal_approve_call(
+    config: &SealConfig,
+    id: &[u8],
+    enclave: &EnclaveObject,
+    job_id: u64,
+) -> Result<ProgrammableTransaction, EnclaveError> {
+    let (jobs, pools) = config.registries()?;
+    let pure = |value: Vec<u8>| Input::Pure { value };
+    let id = bcs::to_bytes(id).map_err(|e| EnclaveError::GenericError(e.to_string()))?;
+    let inputs = vec![
+        pure(id),
+        shared(enclave)?,
+        shared(jobs)?,
+        pure(job_id.to_le_bytes().to_vec()),
+        shared(pools)?,
+    ];
+    Ok(ProgrammableTransaction {
+        commands: vec![Command::MoveCall(MoveCall {
+            package: object_id(&config.package_id)?,
+            module: Identifier::new("seal_policy").expect("valid identifier"),
+            function: Identifier::new("seal_approve").expect("valid identifier"),
+            type_arguments: vec![],
+            arguments: (0..inputs.len() as u16).map(Argument::Input).collect(),
+        })],
+        inputs,
+    })
+}
+
+fn shared(object: &SharedObject) -> Result<Input, EnclaveError> {
+    Ok(Input::Shared {
+        object_id: object_id(&object.object_id)?,
+        initial_shared_version: object.initial_shared_version,
+        mutable: false,
+    })
+}
+
+/// Ask every key server at once, failing unless `threshold` of them answer.
+async fn fetch_shares(
+    config: &SealConfig,
+    request: &FetchKeyRequest,
+) -> Result<Vec<(ObjectId, FetchKeyResponse)>, EnclaveError> {
+    let client = reqwest::Client::builder()
+        .timeout(KEY_SERVER_TIMEOUT)
+        .build()
+        .map_err(|e| EnclaveError::Network(e.to_string()))?;
+    let body =
+        serde_json::to_vec(request).map_err(|e| EnclaveError::GenericError(e.to_string()))?;
+    let mut tasks = JoinSet::new();
+    for server in &config.key_servers {
+        let client = client.clone();
+        let body = body.clone();
+        let url = format!("{}/v1/fetch_key", server.url.trim_end_matches('/'));
+        let server_id = object_id(&server.object_id)?;
+        tasks.spawn(async move {
+            let response = client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .header("Client-Sdk-Type", "rust")
+                .header("Client-Sdk-Version", "1.0.0")
+                .body(body)
+                .send()
+                .await
+                .and_then(|r| r.error_for_status());
+            let shares = match response {
+                Ok(r) => r.json::<FetchKeyResponse>().await,
+                Err(e) => Err(e),
+            };
+            (url, server_id, shares)
+        });
+    }
+
+    let mut responses = vec![];
+    while let Some(joined) = tasks.join_next().await {
+        let (url, server_id, shares) =
+            joined.map_err(|e| EnclaveError::GenericError(e.to_string()))?;
+        match shares {
+            Ok(shares) => responses.push((server_id, shares)),
+            Err(e) => info!("key server {url} failed: {e}"),
+        }
+    }
+    if responses.len() < config.threshold {
+        return Err(EnclaveError::Network(format!(
+            "{} of {} key servers answered, {} needed",
+            responses.len(),
+            config.key_servers.len(),
+            config.threshold
+        )));
+    }
+    Ok(responses)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{Clock, HostClock};
+    use axum::{body::Bytes, http::StatusCode, routing::post, Router};
+    use fastcrypto::ed25519::Ed25519KeyPair;
+    use std::sync::{Arc, Mutex};
+
+    /// The generator of G2, a valid IBE public key.
+    const PUBLIC_KEY: &str = "93e02b6052719f607dacd3a088274f65596bd0d09920b61ab5da61bbdc7f5049\
+        334cf11213945d57e5ac7d055d042b7e024aa2b2f08f0a91260805272dc51051c6e47ad4fa403b02b4510b\
+        647ae3d1770bac0326a805bbefd48056c8c121bdb8";
+
+    fn server_id(i: usize) -> String {
+        format!("0x{i:064x}")
+    }
+
+    fn config(urls: &[String], public_key: &str, threshold: usize) -> String {
+        let servers: String = urls
+            .iter()
+            .enumerate()
+            .map(|(i, url)| {
+                format!(
+                    "  - object_id: \"{}\"\n    url: \"{url}\"\n    public_key: \"{public_key}\"\n",
+                    server_id(i + 1)
+                )
+            })
+            .collect();
+        format!(
+            "package_id: \"{}\"\nthreshold: {threshold}\nkey_servers:\n{servers}\
+             job_registry: {{object_id: \"{}\", initial_shared_version: 1}}\n\
+             pool_registry: {{object_id: \"{}\", initial_shared_version: 1}}\n",
+            server_id(0xc0),
+            server_id(0xa0),
+            server_id(0xa1)
+        )
+    }
+
+    /// A key server on a local port, recording the requests it gets. It
+    /// answers with no keys, or fails.
+    async fn stand_in(answers: bool, requests: Arc<Mutex<Vec<Vec<u8>>>>) -> String {
+        let fetch_key = move |body: Bytes| {
+            requests.lock().unwrap().push(body.to_vec());
+            async move {
+                match answers {
+                    true => (StatusCode::OK, r#"{"decryption_keys": []}"#),
+                    false => (StatusCode::SERVICE_UNAVAILABLE, ""),
+                }
+            }
+        };
+        let app = Router::new().route("/v1/fetch_key", post(fetch_key));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        url
+    }
+
+    fn state() -> AppState {
+        AppState {
+            eph_kp: Ed25519KeyPair::generate(&mut rand::thread_rng()),
+            secrets: HashMap::new(),
+            allowed_endpoints: vec![],
+            clock: Clock::new(Box::new(HostClock)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shipped_config_refuses_sealed_blobs() {
+        check_config().unwrap();
+        let job = SealedJob {
+            job_id: 7,
+            pool_id: 3,
+            key_id: "03000000000000",
+            enclave: None,
+        };
+        let keys = unseal_data_keys(&state(), &job, &[vec![1], vec![2]]).await;
+        assert_eq!(keys.unwrap(), vec![None, None]);
+    }
+
+    #[test]
+    fn test_config_needs_public_keys() {
+        let urls = ["http://a".to_string(), "http://b".to_string()];
+        assert!(SealConfig::parse(&config(&urls, PUBLIC_KEY, 2)).is_ok());
+        assert!(SealConfig::parse(&config(&urls, PUBLIC_KEY, 3)).is_err());
+        // As shipped before the keys are filled in: boots, opening no envelope
+        let unconfigured = SealConfig::parse(&config(&urls, "", 1)).unwrap();
+        assert!(!unconfigured.configured());
+        // Some keys but not all
+        let partial = config(&urls, PUBLIC_KEY, 1).replacen(PUBLIC_KEY, "", 1);
+        let Err(EnclaveError::GenericError(e)) = SealConfig::parse(&partial) else {
+            panic!("a key server without a public key was accepted");
+        };
+        assert!(e.contains(&server_id(1)));
+        assert!(SealConfig::parse(&config(&urls, &PUBLIC_KEY[2..], 1)).is_err());
+        // Keys but no registries for seal_approve
+        let text = config(&urls, PUBLIC_KEY, 1);
+        let no_registries = &text[..text.find("job_registry").unwrap()];
+        assert!(SealConfig::parse(no_registries).is_err());
+    }
+
+    #[test]
+    fn test_key_id_is_under_the_pool() {
+        let key_id = "0300000000000000abcd";
+        assert_eq!(pool_key_id(key_id, 3).unwrap()[8..], [0xab, 0xcd]);
+        for (key_id, pool_id) in [(key_id, 4), ("03", 3), ("zz", 3)] {
+            assert!(matches!(
+                pool_key_id(key_id, pool_id),
+                Err(EnclaveError::InvalidInput(_))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_shares_from_stand_in_servers() {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let mut urls = vec![];
+        for answers in [true, false, true] {
+            urls.push(stand_in(answers, requests.clone()).await);
+        }
+        let state = state();
+        let enclave = EnclaveObject {
+            object_id: server_id(0xe0),
+            initial_shared_version: 1,
+        };
+        let config = SealConfig::parse(&config(&urls, PUBLIC_KEY, 2)).unwrap();
+        let (_, request) = fetch_key_request(&state, &config, &[1, 2, 3], &enclave, 7).unwrap();
+
+        let responses = fetch_shares(&config, &request).await.unwrap();
+        let mut answered: Vec<String> = responses.iter().map(|(id, _)| id.to_string()).collect();
+        answered.sort();
+        let expected: Vec<String> = [1, 3]
+            .map(|i| object_id(&server_id(i)).unwrap().to_string())
+            .to_vec();
+        assert_eq!(answered, expected);
+        // Every server got the same signed request
+        let body = serde_json::to_vec(&request).unwrap();
+        assert_eq!(*requests.lock().unwrap(), vec![body; 3]);
+
+        // Two answers do not meet a threshold of three
+        let config = SealConfig::parse(&config(&urls, PUBLIC_KEY, 3)).unwrap();
+        assert!(matches!(
+            fetch_shares(&config, &request).await,
+            Err(EnclaveError::Network(_))
+        ));
+    }
+}
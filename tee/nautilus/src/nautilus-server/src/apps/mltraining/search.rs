@@ -0,0 +1,201 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Hyperparameter search over a small declared space.
+//!
+//! Each trial trains a full model on the training split and is scored on the
+//! validation split. Only the best model leaves the enclave; the other trials
+//! are reported as rows of the signed summary table.
+
+use super::model::ModelConfig;
+use crate::common::f32_bits;
+use crate::EnclaveError;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Upper bound on the trials of one job.
+pub const MAX_SEARCH_TRIALS: u64 = 16;
+
+/// Upper bound on `trials * epochs * training rows` of one job.
+pub const MAX_SEARCH_ROW_EPOCHS: u64 = 200_000_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchStrategy {
+    /// Every combination, in declaration order.
+    #[default]
+    Grid,
+    /// `max_trials` combinations drawn with replacement from the job seed.
+    Random,
+}
+
+/// `search` section of `ModelConfig`. An empty list keeps the base value.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SearchConfig {
+    #[serde(default)]
+    pub strategy: SearchStrategy,
+    #[serde(default = "default_max_trials")]
+    pub max_trials: u64,
+    /// Learning rates, as plain floats.
+    #[serde(default, with = "f32_bits")]
+    pub learning_rates: Vec<f32>,
+    /// Hidden layer widths, one entry per configured layer.
+    #[serde(default)]
+    pub layer_widths: Vec<Vec<usize>>,
+    /// Dropout applied to every hidden layer.
+    #[serde(default, with = "f32_bits")]
+    pub dropouts: Vec<f32>,
+    #[serde(default, with = "f32_bits")]
+    pub weight_decays: Vec<f32>,
+}
+
+fn default_max_trials() -> u64 {
+    MAX_SEARCH_TRIALS
+}
+
+/// One point of the search space.
+#[derive(Debug, Clone)]
+pub struct Trial {
+    /// Base config with the trial's values filled in and no `search`.
+    pub config: ModelConfig,
+    pub learning_rate: f32,
+}
+
+/// Row of the signed summary table.
+#[derive(Serialize, Clone, Debug)]
+pub struct TrialSummary {
+    #[serde(with = "f32_bits")]
+    pub learning_rate: f32,
+    pub layer_widths: Vec<u64>,
+    #[serde(with = "f32_bits")]
+    pub dropout: Option<f32>,
+    #[serde(with = "f32_bits")]
+    pub weight_decay: f32,
+    pub epochs_run: u64,
+    /// Validation accuracy as a fraction, scaled by `METRIC_SCALE`.
+    pub accuracy: u64,
+    /// Validation loss, scaled by `METRIC_SCALE`.
+    pub loss: u64,
+}
+
+impl TrialSummary {
+    pub fn new(trial: &Trial, epochs_run: u64, accuracy: u64, loss: u64) -> Self {
+        let hidden = &trial.config.layers;
+        Self {
+            learning_rate: trial.learning_rate,
+            layer_widths: hidden.iter().map(|l| l.neurons as u64).collect(),
+            dropout: hidden.first().and_then(|l| l.dropout),
+            weight_decay: trial.config.training.weight_decay,
+            epochs_run,
+            accuracy,
+            loss,
+        }
+    }
+}
+
+impl SearchConfig {
+    /// Check the search against the config it starts from.
+    pub fn validate(&self, base: &ModelConfig) -> Result<(), EnclaveError> {
+        if self.max_trials == 0 || self.max_trials > MAX_SEARCH_TRIALS {
+            return Err(EnclaveError::InvalidInput(format!(
+                "max_trials must be between 1 and {MAX_SEARCH_TRIALS}"
+            )));
+        }
+        if self
+            .learning_rates
+            .iter()
+            .any(|lr| !(*lr > 0.0 && lr.is_finite()))
+        {
+            return Err(EnclaveError::InvalidInput(
+                "search learning_rates must be positive".to_string(),
+            ));
+        }
+        if let Some(widths) = self
+            .layer_widths
+            .iter()
+            .find(|w| w.len() != base.layers.len())
+        {
+            return Err(EnclaveError::InvalidInput(format!(
+                "layer_widths entry {widths:?} does not have one width per layer"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Trials to run from `base`, whose job learning rate is `learning_rate`.
+    pub fn trials<R: Rng>(
+        &self,
+        base: &ModelConfig,
+        learning_rate: f32,
+        rng: &mut R,
+    ) -> Result<Vec<Trial>, EnclaveError> {
+        self.validate(base)?;
+
+        let learning_rates = or_base(&self.learning_rates, learning_rate);
+        let widths = or_base(
+            &self.layer_widths,
+            base.layers.iter().map(|l| l.neurons).collect(),
+        );
+        let dropouts = self.dropouts.iter().map(|d| Some(*d)).collect::<Vec<_>>();
+        let dropouts = or_base(&dropouts, None);
+        let weight_decays = or_base(&self.weight_decays, base.training.weight_decay);
+
+        let dims = [
+            learning_rates.len(),
+            widths.len(),
+            dropouts.len(),
+            weight_decays.len(),
+        ];
+        let points: Vec<[usize; 4]> = match self.strategy {
+            SearchStrategy::Grid => {
+                let size: usize = dims.iter().product();
+                if size as u64 > self.max_trials {
+                    return Err(EnclaveError::InvalidInput(format!(
+                        "grid has {size} points, more than max_trials; use random search"
+                    )));
+                }
+                (0..size)
+                    .map(|mut i| {
+                        let mut point = [0; 4];
+                        for d in (0..4).rev() {
+                            point[d] = i % dims[d];
+                            i /= dims[d];
+                        }
+                        point
+                    })
+                    .collect()
+            }
+            SearchStrategy::Random => (0..self.max_trials)
+                .map(|_| dims.map(|n| rng.gen_range(0..n)))
+                .collect(),
+        };
+
+        Ok(points
+            .into_iter()
+            .map(|[lr, w, d, wd]| {
+                let mut config = base.clone();
+                config.search = None;
+                for (layer, neurons) in config.layers.iter_mut().zip(&widths[w]) {
+                    layer.neurons = *neurons;
+                    if dropouts[d].is_some() {
+                        layer.dropout = dropouts[d];
+                    }
+                }
+                config.training.weight_decay = weight_decays[wd];
+                Trial {
+                    config,
+                    learning_rate: learning_rates[lr],
+                }
+            })
+            .collect())
+    }
+}
+
+/// `values`, or only `base` when none are given.
+fn or_base<T: Clone>(values: &[T], base: T) -> Vec<T> {
+    if values.is_empty() {
+        vec![base]
+    } else {
+        values.to_vec()
+    }
+}
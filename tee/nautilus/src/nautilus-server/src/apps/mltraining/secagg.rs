@@ -0,0 +1,613 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Secure aggregation of model updates, after Bonawitz et al., "Practical
+//! Secure Aggregation for Privacy-Preserving Machine Learning" (CCS 2017).
+//!
+//! Every client adds two kinds of masks to its input. The self mask comes
+//! from a random seed `b_u`. There is one pairwise mask per other client
+//! `v`, from their X25519 agreement `s_uv`, added when `u < v` and
+//! subtracted otherwise. Pairwise masks cancel out in the sum, so the server
+//! only learns the sum of the inputs.
+//!
+//! Each client Shamir shares `b_u` and its mask key among all clients, the
+//! shares encrypted pairwise and routed by the server. Once the masked
+//! inputs are in, any `threshold` surviving clients let the server remove
+//! the self masks of the survivors and the pairwise masks left behind by
+//! clients that dropped out after sharing. Per client, a survivor reveals
+//! shares of the self seed or of the mask key, never both. With `threshold`
+//! above half the clients, a server lying about who dropped out cannot
+//! gather both for the same client.
+//!
+//! Keys and seeds are fresh for every aggregation and drawn from the OS,
+//! never from the job's seeded stream. Inputs are vectors over the integers
+//! modulo 2^64, see [`encode`] and [`decode`]. Shares are over the prime
+//! field of order 2^61 - 1, one element per 32-bit limb of a secret.
+
+use super::crypto::{decrypt_with_key, encrypt_with_key};
+use crate::EnclaveError;
+use fastcrypto::hash::{HashFunction, Sha256};
+use rand::{CryptoRng, Rng, RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// Fractional bits of the fixed point inputs.
+pub const FIXED_POINT_BITS: i32 = 16;
+
+/// Largest magnitude of an encoded input value, so that the sum of up to 32
+/// inputs cannot wrap.
+const MAX_ENCODED: f64 = (1u64 << 58) as f64;
+
+/// The field shares are taken in.
+const PRIME: u64 = (1 << 61) - 1;
+
+/// 32-bit limbs of a 32 byte secret.
+const LIMBS: usize = 8;
+
+const CIPHER_LABEL: &[u8] = b"secagg-cipher";
+const PAIR_MASK_LABEL: &[u8] = b"secagg-pair-mask";
+const SELF_MASK_LABEL: &[u8] = b"secagg-self-mask";
+
+/// X25519 public keys a client advertises for one aggregation.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AdvertisedKeys {
+    /// Encrypts the shares sent to the client.
+    pub cipher_key: Vec<u8>,
+    /// Agrees the client's pairwise masks.
+    pub mask_key: Vec<u8>,
+}
+
+/// A client's shares for one other client, encrypted to it.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EncryptedShares {
+    pub from: u64,
+    pub to: u64,
+    pub ciphertext: Vec<u8>,
+}
+
+/// A Shamir share of a 32 byte secret.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Share {
+    /// Evaluation point, the holder's index plus one.
+    pub x: u64,
+    /// One field element per limb of the secret.
+    pub limbs: Vec<u64>,
+}
+
+/// What a survivor reveals to unmask the sum, by owner of the secret.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Unmasking {
+    /// Shares of the self seeds of the survivors.
+    pub self_seeds: BTreeMap<u64, Share>,
+    /// Shares of the mask keys of the clients that dropped out.
+    pub mask_keys: BTreeMap<u64, Share>,
+}
+
+/// Fail unless `threshold` is above half of `clients` and lets at least two
+/// clients hide among each other.
+pub fn check_threshold(threshold: usize, clients: usize) -> Result<(), EnclaveError> {
+    if threshold < 2 || threshold * 2 <= clients || threshold > clients {
+        return Err(EnclaveError::InvalidInput(format!(
+            "the secure aggregation threshold must be at least 2, above half of the {clients} \
+             clients and no more than them"
+        )));
+    }
+    Ok(())
+}
+
+/// `weights` scaled by `rows`, so that the sum of the inputs divided by the
+/// sum of the rows is the weighted mean.
+pub fn encode(weights: &[f32], rows: u64) -> Result<Vec<u64>, EnclaveError> {
+    let scale = rows as f64 * 2f64.powi(FIXED_POINT_BITS);
+    weights
+        .iter()
+        .map(|w| {
+            let x = (*w as f64 * scale).round();
+            match x.abs() <= MAX_ENCODED {
+                true => Ok(x as i64 as u64),
+                false => Err(EnclaveError::InvalidInput(
+                    "weights too large for secure aggregation".to_string(),
+                )),
+            }
+        })
+        .collect()
+}
+
+/// The weighted mean of the inputs summed into `sum`, over `rows` in all.
+pub fn decode(sum: &[u64], rows: u64) -> Vec<f32> {
+    let scale = rows as f64 * 2f64.powi(FIXED_POINT_BITS);
+    sum.iter()
+        .map(|s| (*s as i64 as f64 / scale) as f32)
+        .collect()
+}
+
+/// One client of an aggregation, from advertising its keys to unmasking.
+pub struct Client {
+    index: u64,
+    clients: usize,
+    threshold: usize,
+    input: Vec<u64>,
+    cipher_secret: StaticSecret,
+    mask_secret: StaticSecret,
+    self_seed: [u8; 32],
+    /// Keys of the clients that advertised them, by index.
+    keys: BTreeMap<u64, AdvertisedKeys>,
+    /// Shares of the self seed and mask key of each client that sent them,
+    /// this one included.
+    held: BTreeMap<u64, (Share, Share)>,
+}
+
+impl Client {
+    /// Client `index` of `clients`, to aggregate `input`. Returns the keys to
+    /// advertise.
+    pub fn new<R: RngCore + CryptoRng>(
+        index: u64,
+        clients: usize,
+        threshold: usize,
+        input: Vec<u64>,
+        rng: &mut R,
+    ) -> Result<(Self, AdvertisedKeys), EnclaveError> {
+        check_threshold(threshold, clients)?;
+        let client = Self {
+            index,
+            clients,
+            threshold,
+            input,
+            cipher_secret: StaticSecret::random_from_rng(&mut *rng),
+            mask_secret: StaticSecret::random_from_rng(&mut *rng),
+            self_seed: rng.gen(),
+            keys: BTreeMap::new(),
+            held: BTreeMap::new(),
+        };
+        let keys = client.advertised();
+        Ok((client, keys))
+    }
+
+    fn advertised(&self) -> AdvertisedKeys {
+        AdvertisedKeys {
+            cipher_key: PublicKey::from(&self.cipher_secret).as_bytes().to_vec(),
+            mask_key: PublicKey::from(&self.mask_secret).as_bytes().to_vec(),
+        }
+    }
+
+    /// Share the self seed and mask key among the clients of `keys`, which
+    /// must include this one. Returns the shares of every other client.
+    pub fn share_keys<R: RngCore + CryptoRng>(
+        &mut self,
+        keys: &BTreeMap<u64, AdvertisedKeys>,
+        rng: &mut R,
+    ) -> Result<Vec<EncryptedShares>, EnclaveError> {
+        if !self.keys.is_empty() {
+            return Err(invalid("the keys are already shared"));
+        }
+        if keys.len() < self.threshold || keys.keys().any(|v| *v as usize >= self.clients) {
+            return Err(invalid(
+                "fewer clients than the threshold advertised keys, or unknown ones",
+            ));
+        }
+        if keys.get(&self.index) != Some(&self.advertised()) {
+            return Err(invalid("this client's keys are missing"));
+        }
+        let distinct: BTreeSet<&Vec<u8>> = keys
+            .values()
+            .flat_map(|k| [&k.cipher_key, &k.mask_key])
+            .collect();
+        if distinct.len() != 2 * keys.len() {
+            return Err(invalid("clients advertised the same key"));
+        }
+        let points: Vec<u64> = keys.keys().map(|v| v + 1).collect();
+        let self_seeds = split(&self.self_seed, &points, self.threshold, rng);
+        let mask_keys = split(&self.mask_secret.to_bytes(), &points, self.threshold, rng);
+
+        let mut sent = vec![];
+        for ((v, peer), shares) in keys.iter().zip(self_seeds.into_iter().zip(mask_keys)) {
+            if *v == self.index {
+                self.held.insert(*v, shares);
+                continue;
+            }
+            let key = cipher_key(&self.cipher_secret, &peer.cipher_key)?;
+            let plaintext = bcs::to_bytes(&(self.index, *v, &shares)).expect("should not fail");
+            sent.push(EncryptedShares {
+                from: self.index,
+                to: *v,
+                ciphertext: encrypt_with_key(
+                    &key,
+                    &nonce(self.index, *v),
+                    CIPHER_LABEL,
+                    &plaintext,
+                ),
+            });
+        }
+        self.keys = keys.clone();
+        Ok(sent)
+    }
+
+    /// Decrypt the shares other clients sent this one and mask the input.
+    /// The clients that sent shares, and this one, are the ones the masked
+    /// inputs are summed over.
+    pub fn mask(&mut self, shares: &[EncryptedShares]) -> Result<Vec<u64>, EnclaveError> {
+        if self.keys.is_empty() || self.held.len() != 1 {
+            return Err(invalid(
+                "the input is already masked or the keys not shared",
+            ));
+        }
+        for received in shares {
+            let from = received.from;
+            let peer = match self.keys.get(&from) {
+                Some(peer) if received.to == self.index && !self.held.contains_key(&from) => peer,
+                _ => return Err(invalid(&format!("unexpected shares from client {from}"))),
+            };
+            let key = cipher_key(&self.cipher_secret, &peer.cipher_key)?;
+            let plaintext = decrypt_with_key(
+                &key,
+                &nonce(from, self.index),
+                CIPHER_LABEL,
+                &received.ciphertext,
+            )
+            .map_err(|_| invalid(&format!("shares from client {from} do not decrypt")))?;
+            let (sender, recipient, shares): (u64, u64, (Share, Share)) =
+                bcs::from_bytes(&plaintext)
+                    .map_err(|_| invalid(&format!("malformed shares from client {from}")))?;
+            if (sender, recipient) != (from, self.index) {
+                return Err(invalid(&format!("shares from client {from} are misrouted")));
+            }
+            self.held.insert(from, shares);
+        }
+        if self.held.len() < self.threshold {
+            return Err(invalid("fewer clients than the threshold sent shares"));
+        }
+
+        let mut masked = self.input.clone();
+        add_mask(&mut masked, &self.self_seed, SELF_MASK_LABEL, false);
+        for v in self.held.keys().filter(|v| **v != self.index) {
+            let seed = agree(&self.mask_secret, &self.keys[v].mask_key)?;
+            add_mask(&mut masked, &seed, PAIR_MASK_LABEL, *v < self.index);
+        }
+        Ok(masked)
+    }
+
+    /// Reveal, for each client whose input is masked with this one's, the
+    /// share of its self seed if it is among `survivors`, or else the share
+    /// of its mask key.
+    pub fn unmask(self, survivors: &[u64]) -> Result<Unmasking, EnclaveError> {
+        let survivors: BTreeSet<u64> = survivors.iter().copied().collect();
+        if !survivors.contains(&self.index)
+            || survivors.len() < self.threshold
+            || survivors.iter().any(|u| !self.held.contains_key(u))
+        {
+            return Err(invalid(
+                "survivors must include this client, be at least the threshold, and have sent shares",
+            ));
+        }
+        let mut unmasking = Unmasking::default();
+        for (v, (self_seed, mask_key)) in self.held {
+            match survivors.contains(&v) {
+                true => unmasking.self_seeds.insert(v, self_seed),
+                false => unmasking.mask_keys.insert(v, mask_key),
+            };
+        }
+        Ok(unmasking)
+    }
+}
+
+/// The sum of the inputs of `masked`, by client, once unmasked with the
+/// `unmaskings` of at least `threshold` of them. `dropped` are the clients
+/// that sent shares but no masked input.
+pub fn unmask_sum(
+    threshold: usize,
+    keys: &BTreeMap<u64, AdvertisedKeys>,
+    masked: &BTreeMap<u64, Vec<u64>>,
+    dropped: &[u64],
+    unmaskings: &[Unmasking],
+) -> Result<Vec<u64>, EnclaveError> {
+    if unmaskings.len() < threshold {
+        return Err(invalid("fewer survivors than the threshold unmasked"));
+    }
+    let mut sum = vec![0u64; masked.values().next().map_or(0, Vec::len)];
+    for input in masked.values() {
+        if input.len() != sum.len() {
+            return Err(invalid("masked inputs differ in length"));
+        }
+        for (s, x) in sum.iter_mut().zip(input) {
+            *s = s.wrapping_add(*x);
+        }
+    }
+    for u in masked.keys() {
+        let shares: Vec<&Share> = unmaskings
+            .iter()
+            .filter_map(|m| m.self_seeds.get(u))
+            .collect();
+        let self_seed = combine(&shares, threshold)?;
+        add_mask(&mut sum, &self_seed, SELF_MASK_LABEL, true);
+    }
+    for v in dropped {
+        let shares: Vec<&Share> = unmaskings
+            .iter()
+            .filter_map(|m| m.mask_keys.get(v))
+            .collect();
+        let mask_secret = StaticSecret::from(combine(&shares, threshold)?);
+        let advertised = keys.get(v).map(|k| k.mask_key.as_slice());
+        if advertised != Some(PublicKey::from(&mask_secret).as_bytes().as_slice()) {
+            return Err(invalid(&format!(
+                "shares of client {v}'s mask key do not reconstruct it"
+            )));
+        }
+        // Client `u` added the mask when `u < v`, so it is removed then
+        for u in masked.keys() {
+            let seed = agree(&mask_secret, &keys[u].mask_key)?;
+            add_mask(&mut sum, &seed, PAIR_MASK_LABEL, *u < *v);
+        }
+    }
+    Ok(sum)
+}
+
+/// Add the mask expanded from `seed` to `values`, or subtract it.
+fn add_mask(values: &mut [u64], seed: &[u8; 32], label: &[u8], subtract: bool) {
+    let mut rng = ChaCha20Rng::from_seed(derive(label, seed));
+    for value in values {
+        let mask = rng.next_u64();
+        *value = match subtract {
+            true => value.wrapping_sub(mask),
+            false => value.wrapping_add(mask),
+        };
+    }
+}
+
+/// Key of the shares exchanged between the owner of `secret` and `public`.
+fn cipher_key(secret: &StaticSecret, public: &[u8]) -> Result<[u8; 32], EnclaveError> {
+    Ok(derive(CIPHER_LABEL, &agree(secret, public)?))
+}
+
+/// Both directions of a pair share the key, so the nonce tells them apart.
+fn nonce(from: u64, to: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..4].copy_from_slice(&(from as u32).to_le_bytes());
+    nonce[4..8].copy_from_slice(&(to as u32).to_le_bytes());
+    nonce
+}
+
+fn agree(secret: &StaticSecret, public: &[u8]) -> Result<[u8; 32], EnclaveError> {
+    let public: [u8; 32] = public
+        .try_into()
+        .map_err(|_| invalid("advertised key is not an X25519 key"))?;
+    let shared = secret.diffie_hellman(&PublicKey::from(public));
+    match shared.was_contributory() {
+        true => Ok(shared.to_bytes()),
+        false => Err(invalid("advertised key has a small order")),
+    }
+}
+
+fn derive(label: &[u8], secret: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::default();
+    hasher.update(label);
+    hasher.update(secret);
+    hasher.finalize().digest
+}
+
+// === SHAMIR ===
+/// Shares of `secret` at each of `points`, any `threshold` of which
+/// reconstruct it.
+fn split<R: RngCore + CryptoRng>(
+    secret: &[u8; 32],
+    points: &[u64],
+    threshold: usize,
+    rng: &mut R,
+) -> Vec<Share> {
+    let polynomials: Vec<Vec<u64>> = secret
+        .chunks(4)
+        .map(|limb| {
+            let mut coefficients =
+                vec![u32::from_le_bytes(limb.try_into().expect("4 bytes")) as u64];
+            coefficients.extend((1..threshold).map(|_| rng.gen_range(0..PRIME)));
+            coefficients
+        })
+        .collect();
+    points
+        .iter()
+        .map(|x| Share {
+            x: *x,
+            limbs: polynomials
+                .iter()
+                .map(|p| p.iter().rev().fold(0, |acc, c| add(mul(acc, *x), *c)))
+                .collect(),
+        })
+        .collect()
+}
+
+/// The secret of `shares`, interpolated at 0 from the first `threshold`.
+fn combine(shares: &[&Share], threshold: usize) -> Result<[u8; 32], EnclaveError> {
+    let shares = shares
+        .get(..threshold)
+        .ok_or_else(|| invalid("too few shares"))?;
+    let points: BTreeSet<u64> = shares.iter().map(|s| s.x).collect();
+    if points.len() != threshold
+        || points.iter().any(|x| *x == 0 || *x >= PRIME)
+        || shares
+            .iter()
+            .any(|s| s.limbs.len() != LIMBS || s.limbs.iter().any(|y| *y >= PRIME))
+    {
+        return Err(invalid("malformed shares"));
+    }
+    let mut secret = [0u8; 32];
+    for (l, limb) in secret.chunks_mut(4).enumerate() {
+        let mut value = 0;
+        for share in shares {
+            // Lagrange basis of `share` at 0
+            let (mut numerator, mut denominator) = (1, 1);
+            for other in shares.iter().filter(|o| o.x != share.x) {
+                numerator = mul(numerator, other.x);
+                denominator = mul(denominator, sub(other.x, share.x));
+            }
+            let basis = mul(numerator, inverse(denominator));
+            value = add(value, mul(share.limbs[l], basis));
+        }
+        let value = u32::try_from(value).map_err(|_| invalid("shares do not reconstruct"))?;
+        limb.copy_from_slice(&value.to_le_bytes());
+    }
+    Ok(secret)
+}
+
+fn add(a: u64, b: u64) -> u64 {
+    (a + b) % PRIME
+}
+
+fn sub(a: u64, b: u64) -> u64 {
+    (a + PRIME - b) % PRIME
+}
+
+fn mul(a: u64, b: u64) -> u64 {
+    (a as u128 * b as u128 % PRIME as u128) as u64
+}
+
+/// By Fermat's little theorem.
+fn inverse(a: u64) -> u64 {
+    let (mut result, mut base, mut exponent) = (1, a, PRIME - 2);
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = mul(result, base);
+        }
+        base = mul(base, base);
+        exponent >>= 1;
+    }
+    result
+}
+
+fn invalid(reason: &str) -> EnclaveError {
+    EnclaveError::InvalidInput(reason.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rng() -> ChaCha20Rng {
+        ChaCha20Rng::seed_from_u64(7)
+    }
+
+    type Shared = (
+        Vec<Client>,
+        BTreeMap<u64, AdvertisedKeys>,
+        BTreeMap<u64, Vec<EncryptedShares>>,
+    );
+
+    /// Clients `0..inputs.len()` with their keys shared, and the shares each
+    /// was sent, by recipient.
+    fn shared(threshold: usize, inputs: &[Vec<u64>]) -> Shared {
+        let mut rng = rng();
+        let mut clients = vec![];
+        let mut keys = BTreeMap::new();
+        for (index, input) in inputs.iter().enumerate() {
+            let (client, advertised) = Client::new(
+                index as u64,
+                inputs.len(),
+                threshold,
+                input.clone(),
+                &mut rng,
+            )
+            .unwrap();
+            clients.push(client);
+            keys.insert(index as u64, advertised);
+        }
+        let mut inboxes: BTreeMap<u64, Vec<EncryptedShares>> = BTreeMap::new();
+        for client in &mut clients {
+            for shares in client.share_keys(&keys, &mut rng).unwrap() {
+                inboxes.entry(shares.to).or_default().push(shares);
+            }
+        }
+        (clients, keys, inboxes)
+    }
+
+    #[test]
+    fn test_split_combine_round_trip() {
+        let mut rng = rng();
+        let secret: [u8; 32] = rng.gen();
+        let shares = split(&secret, &[1, 2, 3, 4, 5], 3, &mut rng);
+        for subset in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let picked: Vec<&Share> = subset.iter().map(|i| &shares[*i]).collect();
+            assert_eq!(combine(&picked, 3).unwrap(), secret);
+        }
+        let too_few: Vec<&Share> = shares.iter().take(2).collect();
+        assert!(combine(&too_few, 3).is_err());
+        let repeated = vec![&shares[0], &shares[0], &shares[1]];
+        assert!(combine(&repeated, 3).is_err());
+    }
+
+    #[test]
+    fn test_pairwise_masks_cancel() {
+        let inputs = vec![vec![1, 2, 3], vec![10, 20, 30], vec![100, 200, 300]];
+        let (clients, keys, inboxes) = shared(2, &inputs);
+        let mut masked = BTreeMap::new();
+        let mut survivors = vec![];
+        for mut client in clients {
+            let index = client.index;
+            let input = client.mask(&inboxes[&index]).unwrap();
+            assert_ne!(input, inputs[index as usize]);
+            masked.insert(index, input);
+            survivors.push(client);
+        }
+        let all: Vec<u64> = masked.keys().copied().collect();
+        let unmaskings: Vec<Unmasking> = survivors
+            .into_iter()
+            .map(|c| c.unmask(&all).unwrap())
+            .collect();
+        let sum = unmask_sum(2, &keys, &masked, &[], &unmaskings).unwrap();
+        assert_eq!(sum, vec![111, 222, 333]);
+    }
+
+    #[test]
+    fn test_sum_recovered_after_dropout() {
+        let inputs = vec![vec![1, 2], vec![3, 4], vec![5, 6], vec![7, 8]];
+        let (clients, keys, inboxes) = shared(3, &inputs);
+        // Client 3 shared its keys, then dropped before sending its input
+        let mut masked = BTreeMap::new();
+        let mut survivors = vec![];
+        for mut client in clients.into_iter().take(3) {
+            let index = client.index;
+            masked.insert(index, client.mask(&inboxes[&index]).unwrap());
+            survivors.push(client);
+        }
+        let unmaskings: Vec<Unmasking> = survivors
+            .into_iter()
+            .map(|c| c.unmask(&[0, 1, 2]).unwrap())
+            .collect();
+        let sum = unmask_sum(3, &keys, &masked, &[3], &unmaskings).unwrap();
+        assert_eq!(sum, vec![9, 12]);
+        // Without the dropped client's mask key its pairwise masks remain
+        let sum = unmask_sum(3, &keys, &masked, &[], &unmaskings).unwrap();
+        assert_ne!(sum, vec![9, 12]);
+    }
+
+    #[test]
+    fn test_unmask_reveals_one_share_per_client() {
+        let inputs = vec![vec![1], vec![2], vec![3], vec![4]];
+        let (mut clients, _, inboxes) = shared(3, &inputs);
+        for client in &mut clients {
+            client.mask(&inboxes[&client.index]).unwrap();
+        }
+        let mut clients = clients.into_iter();
+        let unmasking = clients.next().unwrap().unmask(&[0, 1, 2]).unwrap();
+        for v in 0..4 {
+            assert!(
+                !(unmasking.self_seeds.contains_key(&v) && unmasking.mask_keys.contains_key(&v))
+            );
+        }
+        assert_eq!(
+            unmasking.self_seeds.keys().copied().collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(
+            unmasking.mask_keys.keys().copied().collect::<Vec<_>>(),
+            vec![3]
+        );
+
+        // `unmask` consumes the client, so it cannot be asked again with
+        // survivors that drop client 3 the first time round. Survivors must
+        // include the client, reach the threshold and have sent shares.
+        assert!(clients.next().unwrap().unmask(&[0, 2, 3]).is_err());
+        assert!(clients.next().unwrap().unmask(&[0, 2]).is_err());
+        let (fresh, _) = Client::new(3, 4, 3, vec![4], &mut rng()).unwrap();
+        assert!(fresh.unmask(&[1, 2, 3]).is_err());
+    }
+}
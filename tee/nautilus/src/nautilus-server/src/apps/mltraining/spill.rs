@@ -0,0 +1,191 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encrypted spill buffer of decoded rows.
+//!
+//! Rows are packed as raw `f32`s into segments of `SEGMENT_ROWS` rows. Each
+//! full segment is encrypted under a key drawn per buffer and kept in memory,
+//! or written to `$SPILL_DIR` (e.g. a tmpfs mount) when it is set in a debug
+//! build. Only the segment being filled or read is ever in the clear.
+
+use super::crypto::{decrypt_with_key, encrypt_with_key};
+use crate::EnclaveError;
+use rand::Rng;
+use std::path::PathBuf;
+
+/// Environment variable naming the directory segments are spilled to. Debug
+/// builds only: the host sets the environment of a release enclave.
+pub const SPILL_DIR_ENV: &str = "SPILL_DIR";
+
+/// Rows per encrypted segment.
+pub const SEGMENT_ROWS: usize = 4096;
+
+/// One decoded row.
+#[derive(Debug, Clone)]
+pub struct SpillRow {
+    pub shard: usize,
+    pub label: usize,
+    pub input: Vec<f32>,
+}
+
+pub struct SpillBuffer {
+    width: usize,
+    key: [u8; 32],
+    dir: Option<PathBuf>,
+    /// Encrypted segments, empty for segments spilled to `dir`.
+    segments: Vec<Vec<u8>>,
+    /// Packed rows of the segment being filled.
+    current: Vec<u8>,
+    rows: usize,
+}
+
+impl SpillBuffer {
+    /// An empty buffer of rows with `width` features.
+    pub fn new(width: usize) -> Result<Self, EnclaveError> {
+        let dir = match std::env::var(SPILL_DIR_ENV) {
+            Ok(root) if cfg!(debug_assertions) => {
+                let dir = PathBuf::from(root).join(uuid::Uuid::new_v4().to_string());
+                std::fs::create_dir_all(&dir)?;
+                Some(dir)
+            }
+            _ => None,
+        };
+        Ok(Self {
+            width,
+            key: rand::thread_rng().gen(),
+            dir,
+            segments: vec![],
+            current: vec![],
+            rows: 0,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0
+    }
+
+    fn row_bytes(&self) -> usize {
+        8 + 4 * self.width
+    }
+
+    pub fn push(&mut self, shard: usize, label: usize, input: &[f32]) -> Result<(), EnclaveError> {
+        debug_assert_eq!(input.len(), self.width);
+        self.current
+            .extend_from_slice(&(shard as u32).to_le_bytes());
+        self.current
+            .extend_from_slice(&(label as u32).to_le_bytes());
+        for v in input {
+            self.current.extend_from_slice(&v.to_le_bytes());
+        }
+        self.rows += 1;
+        if self.current.len() == SEGMENT_ROWS * self.row_bytes() {
+            let plaintext = std::mem::take(&mut self.current);
+            let index = self.segments.len();
+            let sealed = encrypt_with_key(&self.key, &nonce(index), &[], &plaintext);
+            match &self.dir {
+                Some(dir) => {
+                    std::fs::write(dir.join(index.to_string()), sealed)?;
+                    self.segments.push(vec![]);
+                }
+                None => self.segments.push(sealed),
+            }
+        }
+        Ok(())
+    }
+
+    /// Segments holding rows, the one being filled included. Segment `i`
+    /// holds rows `i * SEGMENT_ROWS` onwards.
+    pub fn segments(&self) -> usize {
+        self.rows.div_ceil(SEGMENT_ROWS)
+    }
+
+    /// Call `f` with every row of segment `index`, in insertion order.
+    pub fn read_segment<F>(&self, index: usize, mut f: F) -> Result<(), EnclaveError>
+    where
+        F: FnMut(SpillRow) -> Result<(), EnclaveError>,
+    {
+        match index < self.segments.len() {
+            true => self.decode(&self.open(index, false)?, &mut f),
+            false => self.decode(&self.current, &mut f),
+        }
+    }
+
+    /// Call `f` with every row, in insertion order.
+    pub fn scan<F>(&self, mut f: F) -> Result<(), EnclaveError>
+    where
+        F: FnMut(SpillRow) -> Result<(), EnclaveError>,
+    {
+        for index in 0..self.segments() {
+            self.read_segment(index, &mut f)?;
+        }
+        Ok(())
+    }
+
+    /// Like [`SpillBuffer::scan`], releasing each segment once read.
+    pub fn drain<F>(mut self, mut f: F) -> Result<(), EnclaveError>
+    where
+        F: FnMut(SpillRow) -> Result<(), EnclaveError>,
+    {
+        for index in 0..self.segments.len() {
+            let plaintext = self.open(index, true)?;
+            self.segments[index] = vec![];
+            self.decode(&plaintext, &mut f)?;
+        }
+        let current = std::mem::take(&mut self.current);
+        self.decode(&current, &mut f)
+    }
+
+    fn open(&self, index: usize, release: bool) -> Result<Vec<u8>, EnclaveError> {
+        let sealed = match &self.dir {
+            Some(dir) => {
+                let path = dir.join(index.to_string());
+                let sealed = std::fs::read(&path)?;
+                if release {
+                    std::fs::remove_file(&path)?;
+                }
+                sealed
+            }
+            None => self.segments[index].clone(),
+        };
+        decrypt_with_key(&self.key, &nonce(index), &[], &sealed).map_err(|_| {
+            EnclaveError::GenericError(format!("spilled segment {index} was modified"))
+        })
+    }
+
+    fn decode<F>(&self, plaintext: &[u8], f: &mut F) -> Result<(), EnclaveError>
+    where
+        F: FnMut(SpillRow) -> Result<(), EnclaveError>,
+    {
+        for row in plaintext.chunks_exact(self.row_bytes()) {
+            let word = |i: usize| <[u8; 4]>::try_from(&row[4 * i..4 * i + 4]).expect("4 bytes");
+            f(SpillRow {
+                shard: u32::from_le_bytes(word(0)) as usize,
+                label: u32::from_le_bytes(word(1)) as usize,
+                input: (0..self.width)
+                    .map(|i| f32::from_le_bytes(word(i + 2)))
+                    .collect(),
+            })?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SpillBuffer {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.dir {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+    }
+}
+
+/// Segments are encrypted once under a key of their own buffer, so their
+/// index is a unique nonce.
+fn nonce(index: usize) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&(index as u64).to_le_bytes());
+    nonce
+}
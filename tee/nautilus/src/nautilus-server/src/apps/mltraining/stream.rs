@@ -0,0 +1,421 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Streaming decoding of contributor data blobs.
+//!
+//! A data blob is a JSON list of `[features, label]` rows, either in the clear
+//! or encrypted in the chunked format below. Bytes are decrypted and split into
+//! rows as they arrive, so neither the blob nor its JSON text is ever held in
+//! full.
+//!
+//! Chunked format, under the `POOL_DATA_KEY` secret or, in a sealed blob, a
+//! key of the contributor's own:
+//!
+//! ```text
+//! header = "CLKS" || version (1 byte, 1) || chunk_size (u32 LE) || nonce_prefix (7 bytes)
+//! blob   = header || chunk_0 || ... || chunk_n
+//! chunk_i = AES-256-GCM(nonce_prefix || i (u32 BE) || last (1 byte), aad = header, plaintext_i)
+//! ```
+//!
+//! Every plaintext chunk is `chunk_size` bytes except the last, which may be
+//! shorter (or empty) and is the only one with `last = 1`. Reordered, dropped
+//! or truncated chunks therefore fail to authenticate. The blob cache stores
+//! its entries in the same format, with the blob id appended to the aad.
+//!
+//! A sealed blob prefixes a chunked blob with the Seal encryption of its key:
+//!
+//! ```text
+//! sealed = "CLKE" || version (1 byte, 1) || length (u32 LE) || envelope || chunked blob
+//! ```
+//!
+//! where `envelope` is the BCS `EncryptedObject` of the 32 byte key, see `seal`.
+
+use super::crypto::{decrypt_with_key, encrypt_with_key, TAG_LENGTH};
+use crate::EnclaveError;
+use rand::Rng;
+
+/// Magic bytes of the chunked format.
+pub const CHUNKED_MAGIC: &[u8; 4] = b"CLKS";
+
+const CHUNKED_VERSION: u8 = 1;
+
+const HEADER_LENGTH: usize = 16;
+
+/// Magic bytes of a sealed blob.
+pub const SEALED_MAGIC: &[u8; 4] = b"CLKE";
+
+const SEALED_VERSION: u8 = 1;
+
+/// Bytes before the envelope of a sealed blob.
+pub const SEALED_HEADER_LENGTH: usize = 9;
+
+/// Largest envelope accepted.
+pub const MAX_ENVELOPE_BYTES: usize = 1 << 16;
+
+/// Largest plaintext chunk accepted, bounding the bytes buffered per blob.
+pub const MAX_CHUNK_SIZE: usize = 1 << 20;
+
+/// Largest JSON text of a single row.
+pub const MAX_ROW_BYTES: usize = 1 << 20;
+
+/// Incremental decoder of one data blob, fed with the bytes as downloaded.
+pub struct BlobDecoder {
+    key: [u8; 32],
+    /// Past the envelope of a sealed blob, only a chunked body may follow.
+    sealed: bool,
+    state: DecoderState,
+    parser: RowParser,
+}
+
+enum DecoderState {
+    /// Fewer bytes than the magic (or sealed header) seen so far.
+    Start(Vec<u8>),
+    /// Envelope bytes left to skip, the key was unsealed from them beforehand.
+    Envelope(usize),
+    Plain,
+    Chunked(ChunkedDecryptor),
+}
+
+impl BlobDecoder {
+    /// `key` decrypts the chunked body, for a sealed blob the one unsealed from
+    /// its envelope.
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            sealed: false,
+            state: DecoderState::Start(vec![]),
+            parser: RowParser::default(),
+        }
+    }
+
+    /// Feed the next downloaded bytes, calling `on_row` with the JSON text of
+    /// every row completed by them.
+    pub fn push<F>(&mut self, bytes: &[u8], on_row: &mut F) -> Result<(), EnclaveError>
+    where
+        F: FnMut(&[u8]) -> Result<(), EnclaveError>,
+    {
+        let parser = &mut self.parser;
+        match &mut self.state {
+            DecoderState::Plain => parser.push(bytes, on_row),
+            DecoderState::Chunked(decryptor) => {
+                decryptor.push(bytes, &mut |plaintext| parser.push(plaintext, on_row))
+            }
+            DecoderState::Envelope(remaining) => {
+                let skipped = (*remaining).min(bytes.len());
+                *remaining -= skipped;
+                if *remaining == 0 {
+                    self.sealed = true;
+                    self.state = DecoderState::Start(vec![]);
+                    return self.push(&bytes[skipped..], on_row);
+                }
+                Ok(())
+            }
+            DecoderState::Start(start) => {
+                start.extend_from_slice(bytes);
+                if awaits_header(start, CHUNKED_MAGIC, CHUNKED_MAGIC.len())
+                    || (!self.sealed && awaits_header(start, SEALED_MAGIC, SEALED_HEADER_LENGTH))
+                {
+                    return Ok(());
+                }
+                let start = std::mem::take(start);
+                if start.starts_with(CHUNKED_MAGIC) {
+                    self.state = DecoderState::Chunked(ChunkedDecryptor::new(self.key, &[]));
+                    self.push(&start, on_row)
+                } else if self.sealed {
+                    Err(EnclaveError::InvalidInput(
+                        "sealed blob has no chunked body".to_string(),
+                    ))
+                } else if start.starts_with(SEALED_MAGIC) {
+                    let length = envelope_length(&start)?;
+                    self.state = DecoderState::Envelope(length);
+                    self.push(&start[SEALED_HEADER_LENGTH..], on_row)
+                } else {
+                    self.state = DecoderState::Plain;
+                    self.push(&start, on_row)
+                }
+            }
+        }
+    }
+
+    /// End of the blob: decrypt the last chunk and check the row list is complete.
+    pub fn finish<F>(self, on_row: &mut F) -> Result<(), EnclaveError>
+    where
+        F: FnMut(&[u8]) -> Result<(), EnclaveError>,
+    {
+        let mut parser = self.parser;
+        match self.state {
+            DecoderState::Start(_) if self.sealed => {
+                return Err(EnclaveError::InvalidInput(
+                    "sealed blob has no chunked body".to_string(),
+                ))
+            }
+            DecoderState::Envelope(_) => {
+                return Err(EnclaveError::InvalidInput(
+                    "truncated sealed blob envelope".to_string(),
+                ))
+            }
+            DecoderState::Start(start) => parser.push(&start, on_row)?,
+            DecoderState::Chunked(decryptor) => {
+                decryptor.finish(&mut |plaintext| parser.push(plaintext, on_row))?
+            }
+            DecoderState::Plain => {}
+        }
+        parser.finish()
+    }
+}
+
+/// Whether `start` may still begin a header of `length` bytes opening with `magic`.
+fn awaits_header(start: &[u8], magic: &[u8], length: usize) -> bool {
+    let n = start.len().min(magic.len());
+    start.len() < length && start[..n] == magic[..n]
+}
+
+/// Envelope length from the first `SEALED_HEADER_LENGTH` bytes of a sealed blob.
+pub fn envelope_length(header: &[u8]) -> Result<usize, EnclaveError> {
+    if header.len() < SEALED_HEADER_LENGTH
+        || !header.starts_with(SEALED_MAGIC)
+        || header[4] != SEALED_VERSION
+    {
+        return Err(EnclaveError::InvalidInput(
+            "unsupported sealed blob header".to_string(),
+        ));
+    }
+    let length = u32::from_le_bytes(header[5..9].try_into().expect("4 bytes")) as usize;
+    if length == 0 || length > MAX_ENVELOPE_BYTES {
+        return Err(EnclaveError::InvalidInput(format!(
+            "sealed blob envelope must be 1 to {MAX_ENVELOPE_BYTES} bytes"
+        )));
+    }
+    Ok(length)
+}
+
+/// Incremental decryption of the chunked format.
+pub struct ChunkedDecryptor {
+    key: [u8; 32],
+    /// Bound to every chunk along with the header.
+    context: Vec<u8>,
+    /// Header followed by `context`, once the whole header has arrived.
+    aad: Vec<u8>,
+    chunk_size: usize,
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl ChunkedDecryptor {
+    /// Data blobs have an empty `context`.
+    pub fn new(key: [u8; 32], context: &[u8]) -> Self {
+        Self {
+            key,
+            context: context.to_vec(),
+            aad: vec![],
+            chunk_size: 0,
+            counter: 0,
+            buffer: vec![],
+        }
+    }
+
+    /// Feed the next bytes, calling `out` with every chunk of plaintext they complete.
+    pub fn push<F>(&mut self, bytes: &[u8], out: &mut F) -> Result<(), EnclaveError>
+    where
+        F: FnMut(&[u8]) -> Result<(), EnclaveError>,
+    {
+        self.buffer.extend_from_slice(bytes);
+        if self.aad.is_empty() {
+            if self.buffer.len() < HEADER_LENGTH {
+                return Ok(());
+            }
+            let header: Vec<u8> = self.buffer.drain(..HEADER_LENGTH).collect();
+            let chunk_size = u32::from_le_bytes(header[5..9].try_into().expect("4 bytes"));
+            if !header.starts_with(CHUNKED_MAGIC)
+                || header[4] != CHUNKED_VERSION
+                || chunk_size == 0
+                || chunk_size as usize > MAX_CHUNK_SIZE
+            {
+                return Err(EnclaveError::InvalidInput(
+                    "unsupported chunked blob header".to_string(),
+                ));
+            }
+            self.chunk_size = chunk_size as usize;
+            self.aad = [&header[..], &self.context].concat();
+        }
+        // A full chunk is only known not to be the last once a byte past it
+        // has arrived.
+        let sealed = self.chunk_size + TAG_LENGTH;
+        while self.buffer.len() > sealed {
+            out(&self.open(sealed, false)?)?;
+        }
+        Ok(())
+    }
+
+    /// End of the input: decrypt the last chunk.
+    pub fn finish<F>(mut self, out: &mut F) -> Result<(), EnclaveError>
+    where
+        F: FnMut(&[u8]) -> Result<(), EnclaveError>,
+    {
+        if self.aad.is_empty() {
+            return Err(EnclaveError::InvalidInput(
+                "truncated chunked blob header".to_string(),
+            ));
+        }
+        out(&self.open(self.buffer.len(), true)?)
+    }
+
+    /// Decrypt the first `len` buffered bytes as the next chunk.
+    fn open(&mut self, len: usize, last: bool) -> Result<Vec<u8>, EnclaveError> {
+        let nonce = chunk_nonce(&self.aad[9..HEADER_LENGTH], self.counter, last);
+        let plaintext = decrypt_with_key(&self.key, &nonce, &self.aad, &self.buffer[..len])?;
+        self.buffer.drain(..len);
+        self.counter = self.counter.checked_add(1).ok_or_else(|| {
+            EnclaveError::InvalidInput("chunked blob has too many chunks".to_string())
+        })?;
+        Ok(plaintext)
+    }
+}
+
+/// Incremental encryption to the chunked format, with a random nonce prefix.
+pub struct ChunkedEncryptor {
+    key: [u8; 32],
+    aad: Vec<u8>,
+    chunk_size: usize,
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl ChunkedEncryptor {
+    /// Returns the encryptor and the header to write before its chunks.
+    pub fn new(key: [u8; 32], context: &[u8], chunk_size: usize) -> (Self, Vec<u8>) {
+        assert!(chunk_size > 0 && chunk_size <= MAX_CHUNK_SIZE);
+        let mut header = CHUNKED_MAGIC.to_vec();
+        header.push(CHUNKED_VERSION);
+        header.extend_from_slice(&(chunk_size as u32).to_le_bytes());
+        header.extend_from_slice(&rand::thread_rng().gen::<[u8; 7]>());
+        let encryptor = Self {
+            key,
+            aad: [&header[..], context].concat(),
+            chunk_size,
+            counter: 0,
+            buffer: vec![],
+        };
+        (encryptor, header)
+    }
+
+    /// Feed plaintext, calling `out` with every sealed chunk it completes.
+    pub fn push<F>(&mut self, bytes: &[u8], out: &mut F) -> Result<(), EnclaveError>
+    where
+        F: FnMut(&[u8]) -> Result<(), EnclaveError>,
+    {
+        self.buffer.extend_from_slice(bytes);
+        while self.buffer.len() > self.chunk_size {
+            let sealed = self.seal(self.chunk_size, false)?;
+            out(&sealed)?;
+        }
+        Ok(())
+    }
+
+    /// End of the plaintext: seal the last chunk.
+    pub fn finish<F>(mut self, out: &mut F) -> Result<(), EnclaveError>
+    where
+        F: FnMut(&[u8]) -> Result<(), EnclaveError>,
+    {
+        out(&self.seal(self.buffer.len(), true)?)
+    }
+
+    fn seal(&mut self, len: usize, last: bool) -> Result<Vec<u8>, EnclaveError> {
+        let nonce = chunk_nonce(&self.aad[9..HEADER_LENGTH], self.counter, last);
+        let sealed = encrypt_with_key(&self.key, &nonce, &self.aad, &self.buffer[..len]);
+        self.buffer.drain(..len);
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| EnclaveError::GenericError("too many chunks".to_string()))?;
+        Ok(sealed)
+    }
+}
+
+fn chunk_nonce(prefix: &[u8], counter: u32, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..7].copy_from_slice(prefix);
+    nonce[7..11].copy_from_slice(&counter.to_be_bytes());
+    nonce[11] = last as u8;
+    nonce
+}
+
+/// Splits a JSON list into the text of its elements without parsing them.
+#[derive(Default)]
+struct RowParser {
+    /// Bracket depth, 1 inside the top level list.
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    started: bool,
+    finished: bool,
+    rows: u64,
+    row: Vec<u8>,
+}
+
+impl RowParser {
+    fn push<F>(&mut self, bytes: &[u8], on_row: &mut F) -> Result<(), EnclaveError>
+    where
+        F: FnMut(&[u8]) -> Result<(), EnclaveError>,
+    {
+        let malformed =
+            || EnclaveError::InvalidInput("blob is not a JSON list of rows".to_string());
+        for &byte in bytes {
+            if self.in_string {
+                self.row.push(byte);
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+            } else if byte.is_ascii_whitespace() && self.depth <= 1 {
+                // Between rows
+            } else if self.depth == 0 {
+                if byte != b'[' || self.started {
+                    return Err(malformed());
+                }
+                self.started = true;
+                self.depth = 1;
+            } else if self.depth == 1 && matches!(byte, b',' | b']') {
+                if !self.row.is_empty() {
+                    on_row(&self.row)?;
+                    self.rows += 1;
+                    self.row.clear();
+                } else if byte == b',' || self.rows > 0 {
+                    // An empty element, only `[]` is allowed
+                    return Err(malformed());
+                }
+                if byte == b']' {
+                    self.depth = 0;
+                    self.finished = true;
+                }
+            } else {
+                match byte {
+                    b'[' | b'{' => self.depth += 1,
+                    b']' | b'}' if self.depth == 1 => return Err(malformed()),
+                    b']' | b'}' => self.depth -= 1,
+                    b'"' => self.in_string = true,
+                    _ => {}
+                }
+                self.row.push(byte);
+                if self.row.len() > MAX_ROW_BYTES {
+                    return Err(EnclaveError::InvalidInput(format!(
+                        "row longer than {MAX_ROW_BYTES} bytes"
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), EnclaveError> {
+        if self.finished {
+            Ok(())
+        } else {
+            Err(EnclaveError::InvalidInput(
+                "blob ends before its row list".to_string(),
+            ))
+        }
+    }
+}
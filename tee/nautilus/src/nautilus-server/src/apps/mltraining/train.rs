@@ -0,0 +1,378 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Mini-batch training loop.
+//!
+//! Every random draw (initialization, validation split, shuffling, dropout)
+//! comes from a single ChaCha20 stream seeded with the job seed, and floats
+//! are accumulated in a fixed order, so the same inputs and seed give the same
+//! weights on any enclave running the same image.
+
+use super::dataset::{DataLoader, Rows, WINDOW_SEGMENTS};
+use super::model::Mlp;
+use super::optim::{clip_grad_norm, LrSchedule, Optimizer, OptimizerConfig};
+use super::robust::{Aggregator, RobustAggregation};
+use crate::common::f32_bits;
+use crate::EnclaveError;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::info;
+
+/// `training` section of `ModelConfig`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrainingConfig {
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+    /// Sample every class with equal probability, for skewed label distributions.
+    #[serde(default)]
+    pub class_balanced: bool,
+    #[serde(default)]
+    pub optimizer: OptimizerConfig,
+    #[serde(default, with = "f32_bits")]
+    pub weight_decay: f32,
+    /// Clip the global gradient norm of each batch to this value.
+    #[serde(default, with = "f32_bits")]
+    pub clip_grad_norm: Option<f32>,
+    #[serde(default)]
+    pub schedule: LrSchedule,
+    /// Optimizer steps over which the learning rate ramps up linearly.
+    #[serde(default)]
+    pub warmup_steps: u64,
+    /// Stop once the validation metric stops improving. Needs a validation split.
+    #[serde(default)]
+    pub early_stopping: Option<EarlyStopping>,
+    /// Treat each data blob as a shard and aggregate per-shard gradients robustly.
+    #[serde(default)]
+    pub robust: Option<RobustAggregation>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct EarlyStopping {
+    /// Epochs without improvement before stopping.
+    pub patience: u64,
+    /// Smallest change of the metric that counts as an improvement.
+    #[serde(default, with = "f32_bits")]
+    pub min_delta: f32,
+    #[serde(default)]
+    pub metric: StopMetric,
+}
+
+/// Validation metric watched by early stopping.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StopMetric {
+    #[default]
+    Loss,
+    Accuracy,
+}
+
+/// What happened during training.
+#[derive(Debug, Clone)]
+pub struct TrainingReport {
+    /// Epochs actually run.
+    pub epochs_run: u64,
+    /// Epoch whose weights were kept, from 1.
+    pub best_epoch: u64,
+    /// `(shard, influence)` in robust mode, see [`Aggregator::influence`].
+    pub shard_influence: Vec<(usize, f32)>,
+}
+
+fn default_batch_size() -> usize {
+    32
+}
+
+impl Default for TrainingConfig {
+    fn default() -> Self {
+        Self {
+            batch_size: default_batch_size(),
+            class_balanced: false,
+            optimizer: OptimizerConfig::default(),
+            weight_decay: 0.0,
+            clip_grad_norm: None,
+            schedule: LrSchedule::default(),
+            warmup_steps: 0,
+            early_stopping: None,
+            robust: None,
+        }
+    }
+}
+
+impl TrainingConfig {
+    pub fn validate(&self) -> Result<(), EnclaveError> {
+        if self.batch_size == 0 {
+            return Err(EnclaveError::InvalidInput(
+                "batch_size must be non zero".to_string(),
+            ));
+        }
+        let valid = self.weight_decay >= 0.0 && self.clip_grad_norm.unwrap_or(1.0) > 0.0;
+        if !valid {
+            return Err(EnclaveError::InvalidInput(
+                "weight_decay must be non negative and clip_grad_norm positive".to_string(),
+            ));
+        }
+        if self
+            .early_stopping
+            .is_some_and(|e| e.patience == 0 || !(0.0..).contains(&e.min_delta))
+        {
+            return Err(EnclaveError::InvalidInput(
+                "early stopping needs a non zero patience and a non negative min_delta".to_string(),
+            ));
+        }
+        if self.robust.is_some() && self.class_balanced {
+            return Err(EnclaveError::InvalidInput(
+                "class_balanced cannot be combined with robust aggregation".to_string(),
+            ));
+        }
+        self.optimizer.validate()?;
+        self.schedule.validate()
+    }
+
+    pub fn loader(&self) -> DataLoader {
+        DataLoader {
+            batch_size: self.batch_size,
+            class_balanced: self.class_balanced,
+        }
+    }
+}
+
+/// Random stream of a training job, used for data splits.
+pub fn job_rng(seed: u64) -> ChaCha20Rng {
+    ChaCha20Rng::seed_from_u64(seed)
+}
+
+/// Independent random stream of trial `trial` of a job, so a trial's model
+/// does not depend on the trials run before it.
+pub fn trial_rng(seed: u64, trial: u64) -> ChaCha20Rng {
+    let mut rng = job_rng(seed);
+    rng.set_stream(trial + 1);
+    rng
+}
+
+/// Independent random stream of cross-validation fold `fold` of a job,
+/// disjoint from the trial streams.
+pub fn fold_rng(seed: u64, fold: u64) -> ChaCha20Rng {
+    let mut rng = job_rng(seed);
+    rng.set_stream((1 << 32) | fold);
+    rng
+}
+
+/// Independent random stream of worker `worker` of a federated job, used
+/// for its validation split and local training, disjoint from the trial and
+/// fold streams.
+pub fn worker_rng(seed: u64, worker: u64) -> ChaCha20Rng {
+    let mut rng = job_rng(seed);
+    rng.set_stream((2 << 32) | worker);
+    rng
+}
+
+/// Train `network` in place with mini-batches, starting from `learning_rate`.
+/// With early stopping, `network` ends up with the weights of the best epoch
+/// on `validation`.
+///
+/// Rows are read a window of `WINDOW_SEGMENTS` segments at a time. An epoch
+/// visits the windows in random order and shuffles the rows of each; rows
+/// left over from a window's last batch carry over to the next one. In robust
+/// mode each window holds segments of every shard.
+pub fn train<R: Rng>(
+    network: &mut Mlp,
+    training: Rows<'_>,
+    validation: Rows<'_>,
+    config: &TrainingConfig,
+    learning_rate: f32,
+    epochs: u64,
+    rng: &mut R,
+) -> Result<TrainingReport, EnclaveError> {
+    if config.early_stopping.is_some() && validation.is_empty() {
+        return Err(EnclaveError::InvalidInput(
+            "early stopping needs a validation_split".to_string(),
+        ));
+    }
+    let loader = config.loader();
+    let shards = training.shards();
+    let mut aggregator = match config.robust {
+        Some(method) => {
+            method.validate(shards.len())?;
+            Some(Aggregator::new(method, shards.len()))
+        }
+        None => None,
+    };
+    let rows_per_step = match aggregator {
+        Some(_) => config.batch_size * shards.len(),
+        None => config.batch_size,
+    };
+    let steps_per_epoch = training.len().div_ceil(rows_per_step);
+    let mut run = Run {
+        optimizer: Optimizer::new(config.optimizer, config.weight_decay, network),
+        config,
+        learning_rate,
+        step: 0,
+        total_steps: epochs * steps_per_epoch as u64,
+    };
+    // Best score so far (lower is better), its epoch and weights.
+    let mut best: Option<(f32, u64, Mlp)> = None;
+    let mut epochs_run = 0;
+    for epoch in 1..=epochs {
+        let mut loss = 0.0;
+        match &mut aggregator {
+            None => {
+                let mut segments = training.segments();
+                segments.shuffle(rng);
+                let mut pending: Vec<(Vec<f32>, usize)> = vec![];
+                for group in segments.chunks(WINDOW_SEGMENTS) {
+                    let window = training.window(group)?;
+                    let order = loader.order(&window.targets, rng);
+                    pending.extend(
+                        order
+                            .into_iter()
+                            .map(|i| (window.inputs[i].clone(), window.targets[i])),
+                    );
+                    let full = pending.len() / config.batch_size * config.batch_size;
+                    for batch in pending[..full].chunks(config.batch_size) {
+                        loss += run.step(network, None, &[batch], epoch, rng);
+                    }
+                    pending.drain(..full);
+                }
+                if !pending.is_empty() {
+                    loss += run.step(network, None, &[pending.as_slice()], epoch, rng);
+                }
+            }
+            Some(aggregator) => {
+                let shard_segments = training.shard_segments();
+                let per_shard = (WINDOW_SEGMENTS / shards.len()).max(1);
+                let mut steps = 0;
+                while steps < steps_per_epoch {
+                    // A few random segments of every shard
+                    let mut group: Vec<usize> = shard_segments
+                        .iter()
+                        .flat_map(|segments| {
+                            segments
+                                .choose_multiple(rng, per_shard)
+                                .copied()
+                                .collect::<Vec<_>>()
+                        })
+                        .collect();
+                    group.sort_unstable();
+                    group.dedup();
+                    let window = training.window(&group)?;
+                    let mut by_shard: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+                    for (i, shard) in window.shards.iter().enumerate() {
+                        by_shard.entry(*shard).or_default().push(i);
+                    }
+                    let shard_rows: Vec<Vec<usize>> = by_shard.into_values().collect();
+                    let count = (window.len() / rows_per_step)
+                        .max(1)
+                        .min(steps_per_epoch - steps);
+                    for indices in loader.sharded_steps(&shard_rows, count, rng) {
+                        let batches: Vec<Vec<(Vec<f32>, usize)>> = indices
+                            .iter()
+                            .map(|batch| {
+                                batch
+                                    .iter()
+                                    .map(|i| (window.inputs[*i].clone(), window.targets[*i]))
+                                    .collect()
+                            })
+                            .collect();
+                        let batches: Vec<&[(Vec<f32>, usize)]> =
+                            batches.iter().map(Vec::as_slice).collect();
+                        loss += run.step(network, Some(&mut *aggregator), &batches, epoch, rng);
+                    }
+                    steps += count;
+                }
+            }
+        }
+        epochs_run = epoch;
+        if epoch % 10 == 0 || epoch == epochs {
+            info!(
+                "Epoch {}: Loss = {:.4}",
+                epoch,
+                loss / steps_per_epoch.max(1) as f32
+            );
+        }
+
+        if let Some(stopping) = config.early_stopping {
+            let (accuracy, loss) = validation.evaluate(network)?;
+            let score = match stopping.metric {
+                StopMetric::Loss => loss,
+                StopMetric::Accuracy => -accuracy / 100.0,
+            };
+            match &best {
+                Some((best_score, best_epoch, _)) if score > best_score - stopping.min_delta => {
+                    if epoch - best_epoch >= stopping.patience {
+                        info!("Early stopping at epoch {epoch}, keeping epoch {best_epoch}");
+                        break;
+                    }
+                }
+                _ => best = Some((score, epoch, network.clone())),
+            }
+        }
+    }
+
+    let best_epoch = match best {
+        Some((_, epoch, weights)) => {
+            *network = weights;
+            epoch
+        }
+        None => epochs_run,
+    };
+    let shard_influence = match aggregator {
+        Some(aggregator) => shards.into_iter().zip(aggregator.influence()).collect(),
+        None => vec![],
+    };
+    Ok(TrainingReport {
+        epochs_run,
+        best_epoch,
+        shard_influence,
+    })
+}
+
+/// Optimizer state of a training run.
+struct Run<'a> {
+    optimizer: Optimizer,
+    config: &'a TrainingConfig,
+    learning_rate: f32,
+    step: u64,
+    total_steps: u64,
+}
+
+impl Run<'_> {
+    /// One optimizer step on `batches`, one batch or one per shard in robust
+    /// mode. Returns the mean loss of the batches.
+    fn step<R: Rng>(
+        &mut self,
+        network: &mut Mlp,
+        aggregator: Option<&mut Aggregator>,
+        batches: &[&[(Vec<f32>, usize)]],
+        epoch: u64,
+        rng: &mut R,
+    ) -> f32 {
+        let mut loss = 0.0;
+        let mut batch_grads = Vec::with_capacity(batches.len());
+        for batch in batches {
+            let rows: Vec<(&[f32], usize)> =
+                batch.iter().map(|(x, y)| (x.as_slice(), *y)).collect();
+            let (l, g) = network.batch_gradients(&rows, rng);
+            loss += l / batches.len() as f32;
+            batch_grads.push(g);
+        }
+        let mut grads = match aggregator {
+            Some(aggregator) => aggregator.aggregate(&batch_grads),
+            None => batch_grads.pop().expect("one batch per step"),
+        };
+        if let Some(max_norm) = self.config.clip_grad_norm {
+            clip_grad_norm(&mut grads, max_norm);
+        }
+        let lr = self.config.schedule.learning_rate(
+            self.learning_rate,
+            self.step,
+            epoch - 1,
+            self.total_steps,
+            self.config.warmup_steps,
+        );
+        self.optimizer.step(network, &grads, lr);
+        self.step += 1;
+        loss
+    }
+}
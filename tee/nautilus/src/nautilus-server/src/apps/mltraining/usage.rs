@@ -0,0 +1,196 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Usage counters of what the enclave already released.
+//!
+//! The `max_jobs_per_period` rate limit, the query budgets of `/predict`, the
+//! epsilon budgets of pools and the jobs that paid for an analytics query
+//! are counted here. The counters are kept in one file in the directory
+//! named by `USAGE_DIR`, sealed under the sealing key so that only enclaves
+//! built from the same image can read it. Every charge is written before the
+//! output it pays for is released, and a charge that cannot be written is
+//! refused.
+//!
+//! These limits are best effort. Within a boot the counters in memory are
+//! authoritative, but nothing anchors the file: a Nitro enclave has no
+//! persistent storage, so it lasts one boot, and where the host does keep it
+//! the host can withhold it or put back an older copy. Either resets the
+//! counters. The host cannot forge or edit the file: one that does not
+//! unseal refuses every charge instead of starting over.
+
+use super::crypto::{decrypt_with_key, encrypt_with_key, sealing_key};
+use crate::AppState;
+use crate::EnclaveError;
+use lazy_static::lazy_static;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Environment variable naming the directory of the usage file.
+pub const USAGE_DIR_ENV: &str = "USAGE_DIR";
+
+const DEFAULT_USAGE_DIR: &str = "/var/lib/cloakx";
+
+const USAGE_FILE: &str = "usage";
+
+/// Associated data of the usage file.
+const USAGE_AAD: &[u8] = b"cloakx-usage";
+
+const NONCE_LENGTH: usize = 12;
+
+lazy_static! {
+    /// The counters, read from the usage file on first use.
+    static ref USAGE: Mutex<Option<Usage>> = Mutex::new(None);
+}
+
+/// What the enclave released so far.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Usage {
+    /// Acceptance times of recent jobs per pool id.
+    pub jobs_accepted: HashMap<u64, VecDeque<u64>>,
+    /// Rows answered per model blob id.
+    pub queries_used: HashMap<String, u64>,
+    /// Epsilon spent by noisy outputs per pool id.
+    pub epsilon_spent: HashMap<u64, f64>,
+    /// Jobs that paid for an analytics query that was answered.
+    pub jobs_answered: HashSet<u64>,
+}
+
+/// Run `charge` against the counters and persist them if it succeeds. A
+/// refusal is returned as the inner error and leaves the counters unchanged.
+pub fn charge<T, E, F>(state: &AppState, charge: F) -> Result<Result<T, E>, EnclaveError>
+where
+    F: FnOnce(&mut Usage) -> Result<T, E>,
+{
+    let ledger = Ledger {
+        path: usage_dir().join(USAGE_FILE),
+        key: sealing_key(state)?,
+    };
+    let mut usage = USAGE.lock().expect("poisoned lock");
+    if usage.is_none() {
+        *usage = Some(ledger.load()?);
+    }
+    let mut updated = usage.clone().expect("loaded above");
+    let charged = match charge(&mut updated) {
+        Ok(charged) => charged,
+        Err(e) => return Ok(Err(e)),
+    };
+    ledger.store(&updated)?;
+    *usage = Some(updated);
+    Ok(Ok(charged))
+}
+
+fn usage_dir() -> PathBuf {
+    std::env::var(USAGE_DIR_ENV)
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(DEFAULT_USAGE_DIR))
+}
+
+/// The sealed usage file.
+struct Ledger {
+    path: PathBuf,
+    key: [u8; 32],
+}
+
+impl Ledger {
+    /// The stored counters, empty if there is no file yet.
+    fn load(&self) -> Result<Usage, EnclaveError> {
+        let sealed = match std::fs::read(&self.path) {
+            Ok(sealed) => sealed,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Usage::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let unreadable = || {
+            EnclaveError::GenericError(format!(
+                "usage file {} does not unseal",
+                self.path.display()
+            ))
+        };
+        let (nonce, ciphertext) = sealed
+            .split_first_chunk::<NONCE_LENGTH>()
+            .ok_or_else(unreadable)?;
+        let bytes =
+            decrypt_with_key(&self.key, nonce, USAGE_AAD, ciphertext).map_err(|_| unreadable())?;
+        serde_json::from_slice(&bytes).map_err(|_| unreadable())
+    }
+
+    /// Replace the file with `usage`. The new file is synced before it is
+    /// renamed over the old one, so a crash leaves either of them.
+    fn store(&self, usage: &Usage) -> Result<(), EnclaveError> {
+        let nonce: [u8; NONCE_LENGTH] = rand::thread_rng().gen();
+        let plaintext = serde_json::to_vec(usage).expect("should not fail");
+        let mut sealed = nonce.to_vec();
+        sealed.extend(encrypt_with_key(&self.key, &nonce, USAGE_AAD, &plaintext));
+
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        std::fs::create_dir_all(dir)?;
+        let staged = dir.join(format!("{USAGE_FILE}.{}", uuid::Uuid::new_v4()));
+        let written = std::fs::File::create(&staged).and_then(|mut file| {
+            file.write_all(&sealed)?;
+            file.sync_all()
+        });
+        if let Err(e) = written.and_then(|()| std::fs::rename(&staged, &self.path)) {
+            let _ = std::fs::remove_file(&staged);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ledger(dir: &Path) -> Ledger {
+        Ledger {
+            path: dir.join(USAGE_FILE),
+            key: [7; 32],
+        }
+    }
+
+    #[test]
+    fn test_ledger_round_trip() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let ledger = ledger(&dir);
+        assert_eq!(ledger.load().unwrap(), Usage::default());
+
+        let mut usage = Usage::default();
+        usage.jobs_accepted.insert(3, VecDeque::from([10, 20]));
+        usage.queries_used.insert("model".to_string(), 42);
+        usage.epsilon_spent.insert(3, 0.5);
+        usage.jobs_answered.insert(9);
+        ledger.store(&usage).unwrap();
+        // As read back after a reboot
+        assert_eq!(ledger.load().unwrap(), usage);
+
+        usage.queries_used.insert("model".to_string(), 43);
+        ledger.store(&usage).unwrap();
+        assert_eq!(ledger.load().unwrap(), usage);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_ledger_refuses_modified_file() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let ledger = ledger(&dir);
+        ledger.store(&Usage::default()).unwrap();
+
+        let mut sealed = std::fs::read(&ledger.path).unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        std::fs::write(&ledger.path, &sealed).unwrap();
+        assert!(ledger.load().is_err());
+
+        // Nor does a file sealed under another key unseal
+        ledger.store(&Usage::default()).unwrap();
+        let other = Ledger {
+            path: ledger.path.clone(),
+            key: [8; 32],
+        };
+        assert!(other.load().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,58 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encryption of outputs to the buyer of a job.
+//!
+//! A job's `buyer_public_key` is a NaCl box (X25519, XSalsa20-Poly1305) key,
+//! stored either as its 32 bytes or, as the frontend writes it, as their
+//! base64 text. Outputs are boxed from an ephemeral key into the JSON
+//! envelope `{"encrypted", "nonce", "ephemeralPublicKey"}` of base64 fields,
+//! the format the frontend's `decryptWithNaCl` opens.
+
+use crate::EnclaveError;
+use crypto_box::aead::{Aead, AeadCore, OsRng};
+use crypto_box::{PublicKey, SalsaBox, SecretKey};
+use fastcrypto::encoding::{Base64, Encoding};
+use serde::Serialize;
+
+const KEY_LENGTH: usize = 32;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Envelope {
+    encrypted: String,
+    nonce: String,
+    ephemeral_public_key: String,
+}
+
+/// The box key of an on-chain `buyer_public_key`.
+pub fn buyer_key(raw: &[u8]) -> Result<PublicKey, EnclaveError> {
+    let bytes = match raw.len() {
+        KEY_LENGTH => raw.to_vec(),
+        _ => std::str::from_utf8(raw)
+            .ok()
+            .and_then(|text| Base64::decode(text.trim()).ok())
+            .unwrap_or_default(),
+    };
+    let bytes: [u8; KEY_LENGTH] = bytes.try_into().map_err(|_| {
+        EnclaveError::InvalidInput(
+            "the job's buyer_public_key is not a 32 byte box key".to_string(),
+        )
+    })?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// Box `plaintext` to `buyer` under a fresh ephemeral key.
+pub fn encrypt_for_buyer(buyer: &PublicKey, plaintext: &[u8]) -> Vec<u8> {
+    let ephemeral = SecretKey::generate(&mut OsRng);
+    let nonce = SalsaBox::generate_nonce(&mut OsRng);
+    let encrypted = SalsaBox::new(buyer, &ephemeral)
+        .encrypt(&nonce, plaintext)
+        .expect("encryption of a buffer should not fail");
+    let envelope = Envelope {
+        encrypted: Base64::encode(encrypted),
+        nonce: Base64::encode(nonce),
+        ephemeral_public_key: Base64::encode(ephemeral.public_key().as_bytes()),
+    };
+    serde_json::to_vec(&envelope).expect("should not fail")
+}
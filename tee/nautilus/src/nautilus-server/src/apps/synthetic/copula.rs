@@ -0,0 +1,550 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Gaussian copula generator of synthetic rows.
+//!
+//! Each column, the label included, gets a binned marginal over its bounds
+//! and a missing rate. Values map through their marginal to normal scores,
+//! whose pairwise correlations tie the columns together. Sampling draws
+//! correlated normal scores and maps them back through the marginals,
+//! uniformly within a bin, so synthetic rows follow the pool's marginals and
+//! correlations without copying its values.
+//!
+//! With `epsilon`, half the budget goes to the marginal counts and half to
+//! the sums behind the correlations, each through the Laplace mechanism.
+//! Normal scores are clipped to `±Z_CLIP`, which bounds what one row moves
+//! those sums. Sampling then only reads the noisy fit. An exact fit has no
+//! such guarantee, so its synthetic rows are also drawn again while they
+//! equal a real row.
+
+use crate::apps::mltraining::dataset::Dataset;
+use crate::apps::mltraining::sampling::{laplace, standard_normal};
+use crate::EnclaveError;
+use rand::Rng;
+use serde::Deserialize;
+use std::collections::HashSet;
+
+pub const MAX_COLUMNS: usize = 64;
+
+pub const MAX_BINS: u64 = 1024;
+
+pub const MAX_ROWS: u64 = 1_000_000;
+
+/// Header of the label column of the CSV.
+pub const LABEL_COLUMN: &str = "label";
+
+const DEFAULT_BINS: u64 = 32;
+
+/// Largest magnitude of a normal score.
+const Z_CLIP: f64 = 3.0;
+
+/// Draws of a synthetic row before giving up on one unlike every real row.
+const MAX_DRAWS: usize = 100;
+
+/// Steps of identity shrinkage tried to make the correlations positive
+/// definite, the last being the identity itself.
+const SHRINKAGE_STEPS: usize = 20;
+
+/// Synthesis config, the blob the job's `model_wid` names.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SynthesisConfig {
+    /// Feature columns of the pool rows, in row order.
+    pub columns: Vec<ColumnSpec>,
+    /// Labels are below this.
+    pub classes: u64,
+    /// Synthetic rows to generate.
+    pub rows: u64,
+    /// Bins of the marginal of each continuous column.
+    #[serde(default = "default_bins")]
+    pub bins: u64,
+    /// Laplace budget of the fit. The fit is exact when absent.
+    pub epsilon: Option<f64>,
+}
+
+fn default_bins() -> u64 {
+    DEFAULT_BINS
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ColumnSpec {
+    pub name: String,
+    /// `[min, max]` of the column. Values are clipped to it, and synthetic
+    /// values drawn within it.
+    pub bounds: (f64, f64),
+    /// Integer valued, e.g. a category code: each integer within `bounds`
+    /// is a bin of its own.
+    #[serde(default)]
+    pub discrete: bool,
+}
+
+impl SynthesisConfig {
+    /// Binning of each column, the label last.
+    pub fn binnings(&self) -> Result<Vec<Binning>, EnclaveError> {
+        let invalid = |e: String| Err(EnclaveError::InvalidInput(e));
+        if self.columns.is_empty() || self.columns.len() > MAX_COLUMNS {
+            return invalid(format!("synthesis needs 1 to {MAX_COLUMNS} columns"));
+        }
+        if !(1..=MAX_ROWS).contains(&self.rows) {
+            return invalid(format!("rows must be 1 to {MAX_ROWS}"));
+        }
+        if !(1..=MAX_BINS).contains(&self.bins) {
+            return invalid(format!("bins must be 1 to {MAX_BINS}"));
+        }
+        if !(2..=MAX_BINS).contains(&self.classes) {
+            return invalid(format!("classes must be 2 to {MAX_BINS}"));
+        }
+        if self.epsilon.is_some_and(|e| !(e > 0.0 && e.is_finite())) {
+            return invalid("epsilon must be positive".to_string());
+        }
+        let mut names = HashSet::from([LABEL_COLUMN]);
+        let mut binnings = Vec::with_capacity(self.columns.len() + 1);
+        for column in &self.columns {
+            if column.name.is_empty() || !names.insert(column.name.as_str()) {
+                return invalid(format!("column name {:?} is empty or taken", column.name));
+            }
+            let (low, high) = column.bounds;
+            let binning = if column.discrete {
+                let values = high - low + 1.0;
+                if low.fract() != 0.0
+                    || high.fract() != 0.0
+                    || !(1.0..=MAX_BINS as f64).contains(&values)
+                {
+                    return invalid(format!(
+                        "discrete column {} needs integer bounds at most {MAX_BINS} values apart",
+                        column.name
+                    ));
+                }
+                Binning::discrete(low, values as usize)
+            } else {
+                if !(low.is_finite() && high.is_finite() && low < high) {
+                    return invalid(format!(
+                        "column {} needs finite increasing bounds",
+                        column.name
+                    ));
+                }
+                Binning {
+                    low,
+                    high,
+                    bins: self.bins as usize,
+                    discrete: false,
+                }
+            };
+            binnings.push(binning);
+        }
+        binnings.push(Binning::discrete(0.0, self.classes as usize));
+        Ok(binnings)
+    }
+}
+
+/// Bins of a column between its bounds, plus a cell for missing values.
+#[derive(Debug, Clone, Copy)]
+pub struct Binning {
+    low: f64,
+    high: f64,
+    pub bins: usize,
+    discrete: bool,
+}
+
+impl Binning {
+    fn discrete(low: f64, values: usize) -> Self {
+        Self {
+            low,
+            high: low + (values - 1) as f64,
+            bins: values,
+            discrete: true,
+        }
+    }
+
+    fn width(&self) -> f64 {
+        match self.discrete {
+            true => 1.0,
+            false => (self.high - self.low) / self.bins as f64,
+        }
+    }
+
+    pub fn clip(&self, x: f64) -> f64 {
+        x.clamp(self.low, self.high)
+    }
+
+    fn bin(&self, x: f64) -> usize {
+        let offset = (self.clip(x) - self.low) / self.width();
+        let bin = match self.discrete {
+            true => offset.round(),
+            false => offset.floor(),
+        };
+        (bin as usize).min(self.bins - 1)
+    }
+
+    /// Bin of a present value, `bins` for a missing one.
+    pub fn cell(&self, x: f64) -> usize {
+        match x.is_nan() {
+            true => self.bins,
+            false => self.bin(x),
+        }
+    }
+}
+
+/// Values of row `i`, the label last. Missing values are `NaN`.
+pub fn row_values(dataset: &Dataset, i: usize) -> impl Iterator<Item = f64> + '_ {
+    dataset.inputs[i]
+        .iter()
+        .map(|x| *x as f64)
+        .chain(std::iter::once(dataset.targets[i] as f64))
+}
+
+/// A fitted generator.
+pub struct Copula {
+    binnings: Vec<Binning>,
+    /// Probability of each column's values below each bin edge, among its
+    /// present values: `0` first and `1` last.
+    cumulative: Vec<Vec<f64>>,
+    /// Fraction of each column's values that are missing.
+    missing: Vec<f64>,
+    /// Lower Cholesky factor of the normal score correlations.
+    factor: Vec<Vec<f64>>,
+    /// Weight of the identity mixed into the correlations to make them
+    /// positive definite.
+    pub shrinkage: f64,
+}
+
+impl Copula {
+    pub fn fit<R: Rng>(
+        binnings: Vec<Binning>,
+        dataset: &Dataset,
+        epsilon: Option<f64>,
+        rng: &mut R,
+    ) -> Self {
+        let columns = binnings.len();
+
+        // 1. Marginals. Each row lands in one cell per column.
+        let mut counts: Vec<Vec<f64>> = binnings.iter().map(|b| vec![0.0; b.bins + 1]).collect();
+        for i in 0..dataset.len() {
+            for (c, x) in row_values(dataset, i).enumerate() {
+                counts[c][binnings[c].cell(x)] += 1.0;
+            }
+        }
+        if let Some(epsilon) = epsilon {
+            let scale = columns as f64 / (epsilon / 2.0);
+            for count in counts.iter_mut().flatten() {
+                *count = (*count + laplace(scale, rng)).max(0.0);
+            }
+        }
+        let mut cumulative = Vec::with_capacity(columns);
+        let mut missing = Vec::with_capacity(columns);
+        for (binning, counts) in binnings.iter().zip(&counts) {
+            let present: f64 = counts[..binning.bins].iter().sum();
+            let total = present + counts[binning.bins];
+            missing.push(if total > 0.0 {
+                counts[binning.bins] / total
+            } else {
+                0.0
+            });
+            let mut below = vec![0.0];
+            for (bin, count) in counts[..binning.bins].iter().enumerate() {
+                let p = match present > 0.0 {
+                    true => count / present,
+                    false => 1.0 / binning.bins as f64,
+                };
+                below.push(below[bin] + p);
+            }
+            *below.last_mut().expect("at least one bin") = 1.0;
+            cumulative.push(below);
+        }
+        // Labels are never missing, whatever the noise put in their cell
+        missing[columns - 1] = 0.0;
+
+        let mut copula = Self {
+            binnings,
+            cumulative,
+            missing,
+            factor: vec![],
+            shrinkage: 0.0,
+        };
+
+        // 2. Correlations of the normal scores over rows where both values
+        // are present, normalized by their sums of squares as discrete
+        // scores have less than unit variance. One row moves each of the
+        // three sums of a pair by at most `Z_CLIP²`.
+        let pairs: Vec<(usize, usize)> = (0..columns)
+            .flat_map(|i| (i + 1..columns).map(move |j| (i, j)))
+            .collect();
+        let mut sums = vec![[0.0; 3]; pairs.len()];
+        for i in 0..dataset.len() {
+            let scores = copula.scores(dataset, i);
+            for ((a, b), sum) in pairs.iter().zip(&mut sums) {
+                if let (Some(a), Some(b)) = (scores[*a], scores[*b]) {
+                    sum[0] += a * b;
+                    sum[1] += a * a;
+                    sum[2] += b * b;
+                }
+            }
+        }
+        if let Some(epsilon) = epsilon {
+            let scale = pairs.len() as f64 * 3.0 * Z_CLIP * Z_CLIP / (epsilon / 2.0);
+            for sum in sums.iter_mut().flatten() {
+                *sum += laplace(scale, rng);
+            }
+        }
+        let mut correlations = identity(columns);
+        for ((a, b), [product, square_a, square_b]) in pairs.iter().zip(sums) {
+            let r = match square_a > 0.0 && square_b > 0.0 {
+                true => (product / (square_a * square_b).sqrt()).clamp(-1.0, 1.0),
+                false => 0.0,
+            };
+            correlations[*a][*b] = r;
+            correlations[*b][*a] = r;
+        }
+
+        // 3. Shrink towards the identity until positive definite
+        for step in 0..=SHRINKAGE_STEPS {
+            let shrinkage = step as f64 / SHRINKAGE_STEPS as f64;
+            let shrunk: Vec<Vec<f64>> = correlations
+                .iter()
+                .enumerate()
+                .map(|(i, row)| {
+                    row.iter()
+                        .enumerate()
+                        .map(|(j, r)| match i == j {
+                            true => 1.0,
+                            false => (1.0 - shrinkage) * r,
+                        })
+                        .collect()
+                })
+                .collect();
+            if let Some(factor) = cholesky(&shrunk) {
+                copula.factor = factor;
+                copula.shrinkage = shrinkage;
+                break;
+            }
+        }
+        copula
+    }
+
+    pub fn binnings(&self) -> &[Binning] {
+        &self.binnings
+    }
+
+    /// Probability of each cell of column `c`: its bins, then missing.
+    pub fn cell_probabilities(&self, c: usize) -> Vec<f64> {
+        let present = 1.0 - self.missing[c];
+        let below = &self.cumulative[c];
+        below
+            .windows(2)
+            .map(|edges| present * (edges[1] - edges[0]))
+            .chain(std::iter::once(self.missing[c]))
+            .collect()
+    }
+
+    /// Correlation of the normal scores of columns `a` and `b` the
+    /// generator samples, after shrinkage.
+    pub fn correlation(&self, a: usize, b: usize) -> f64 {
+        self.factor[a]
+            .iter()
+            .zip(&self.factor[b])
+            .map(|(x, y)| x * y)
+            .sum()
+    }
+
+    /// Normal scores of row `i`, `None` for missing values.
+    pub fn scores(&self, dataset: &Dataset, i: usize) -> Vec<Option<f64>> {
+        row_values(dataset, i)
+            .enumerate()
+            .map(|(c, x)| (!x.is_nan()).then(|| self.score(c, x)))
+            .collect()
+    }
+
+    /// Normal score of present value `x` of column `c`.
+    fn score(&self, c: usize, x: f64) -> f64 {
+        let binning = &self.binnings[c];
+        let below = &self.cumulative[c];
+        let bin = binning.bin(x);
+        let within = match binning.discrete {
+            true => 0.5,
+            false => {
+                let edge = binning.low + bin as f64 * binning.width();
+                ((binning.clip(x) - edge) / binning.width()).clamp(0.0, 1.0)
+            }
+        };
+        let u = below[bin] + (below[bin + 1] - below[bin]) * within;
+        inverse_normal_cdf(u).clamp(-Z_CLIP, Z_CLIP)
+    }
+
+    /// Value of column `c` at probability `u` of its marginal.
+    fn quantile(&self, c: usize, u: f64) -> f64 {
+        let binning = &self.binnings[c];
+        let below = &self.cumulative[c];
+        let bin = below[1..]
+            .partition_point(|p| *p <= u)
+            .min(binning.bins - 1);
+        if binning.discrete {
+            return binning.low + bin as f64;
+        }
+        let p = below[bin + 1] - below[bin];
+        let within = match p > 0.0 {
+            true => ((u - below[bin]) / p).clamp(0.0, 1.0),
+            false => 0.5,
+        };
+        binning.clip(binning.low + (bin as f64 + within) * binning.width())
+    }
+
+    /// One synthetic row. Missing values are `NaN`.
+    fn sample<R: Rng>(&self, rng: &mut R) -> (Vec<f32>, usize) {
+        let columns = self.binnings.len();
+        let normals: Vec<f64> = (0..columns).map(|_| standard_normal(rng)).collect();
+        let mut input = Vec::with_capacity(columns - 1);
+        for c in 0..columns - 1 {
+            let z: f64 = (0..=c).map(|k| self.factor[c][k] * normals[k]).sum();
+            input.push(match rng.gen::<f64>() < self.missing[c] {
+                true => f32::NAN,
+                false => self.quantile(c, normal_cdf(z)) as f32,
+            });
+        }
+        let c = columns - 1;
+        let z: f64 = (0..=c).map(|k| self.factor[c][k] * normals[k]).sum();
+        (input, self.quantile(c, normal_cdf(z)) as usize)
+    }
+
+    /// `rows` synthetic rows, none equal to a row of `real` when given.
+    /// Returns them with the number of draws rejected for equalling one.
+    /// Which draws are rejected depends on the real rows, so only exact fits
+    /// pass them.
+    pub fn synthesize<R: Rng>(
+        &self,
+        rows: u64,
+        real: Option<&Dataset>,
+        rng: &mut R,
+    ) -> Result<(Dataset, u64), EnclaveError> {
+        let real_rows: HashSet<Vec<u32>> = real
+            .map(|real| {
+                (0..real.len())
+                    .map(|i| row_key(&real.inputs[i], real.targets[i]))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let mut synthetic = Dataset::default();
+        let mut redrawn = 0;
+        for _ in 0..rows {
+            let (input, target) = (0..MAX_DRAWS)
+                .map(|_| self.sample(rng))
+                .find(|(input, target)| {
+                    let copied = real_rows.contains(&row_key(input, *target));
+                    redrawn += copied as u64;
+                    !copied
+                })
+                .ok_or_else(|| {
+                    EnclaveError::InvalidInput(
+                        "the columns are too coarse to draw rows unlike the real ones".to_string(),
+                    )
+                })?;
+            synthetic.push(input, target, 0);
+        }
+        Ok((synthetic, redrawn))
+    }
+}
+
+/// Bits of a row's values, with one `NaN` and one zero.
+fn row_key(input: &[f32], target: usize) -> Vec<u32> {
+    input
+        .iter()
+        .map(|x| match x.is_nan() {
+            true => f32::NAN.to_bits(),
+            false => (x + 0.0).to_bits(),
+        })
+        .chain(std::iter::once(target as u32))
+        .collect()
+}
+
+fn identity(n: usize) -> Vec<Vec<f64>> {
+    (0..n)
+        .map(|i| (0..n).map(|j| if i == j { 1.0 } else { 0.0 }).collect())
+        .collect()
+}
+
+/// Lower triangular `L` with `L Lᵀ = m`, `None` unless `m` is positive
+/// definite.
+fn cholesky(m: &[Vec<f64>]) -> Option<Vec<Vec<f64>>> {
+    let n = m.len();
+    let mut l = vec![vec![0.0; n]; n];
+    for i in 0..n {
+        for j in 0..=i {
+            let dot: f64 = (0..j).map(|k| l[i][k] * l[j][k]).sum();
+            if i == j {
+                let pivot = m[i][i] - dot;
+                if pivot <= 1e-9 {
+                    return None;
+                }
+                l[i][i] = pivot.sqrt();
+            } else {
+                l[i][j] = (m[i][j] - dot) / l[j][j];
+            }
+        }
+    }
+    Some(l)
+}
+
+/// Standard normal CDF, from the Abramowitz and Stegun 7.1.26 approximation
+/// of `erf`, accurate to about `1e-7`.
+fn normal_cdf(z: f64) -> f64 {
+    let x = z.abs() / std::f64::consts::SQRT_2;
+    let t = 1.0 / (1.0 + 0.3275911 * x);
+    let poly = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let erf = 1.0 - poly * (-x * x).exp();
+    match z >= 0.0 {
+        true => 0.5 * (1.0 + erf),
+        false => 0.5 * (1.0 - erf),
+    }
+}
+
+/// Inverse standard normal CDF, Acklam's rational approximation. `u` is
+/// clamped into `(0, 1)`.
+fn inverse_normal_cdf(u: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e1,
+        2.209460984245205e2,
+        -2.759285104469687e2,
+        1.38357751867269e2,
+        -3.066479806614716e1,
+        2.506628277459239,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e1,
+        1.615858368580409e2,
+        -1.556989798598866e2,
+        6.680131188771972e1,
+        -1.328068155288572e1,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-3,
+        -3.223964580411365e-1,
+        -2.400758277161838,
+        -2.549671010366896,
+        4.374664141464968,
+        2.938163982698783,
+    ];
+    const D: [f64; 4] = [
+        7.784695709041462e-3,
+        3.224671290700398e-1,
+        2.445134137142996,
+        3.754408661907416,
+    ];
+    const LOW: f64 = 0.02425;
+    let u = u.clamp(1e-12, 1.0 - 1e-12);
+    let tail = |q: f64| {
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    };
+    if u < LOW {
+        tail((-2.0 * u.ln()).sqrt())
+    } else if u > 1.0 - LOW {
+        -tail((-2.0 * (1.0 - u).ln()).sqrt())
+    } else {
+        let q = u - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! How closely synthetic rows follow the fitted generator.
+//!
+//! The metrics compare the synthetic rows with the marginals and
+//! correlations of the copula, never with the pool rows. With `epsilon` they
+//! read nothing but the noisy fit and its samples, so the fit's budget
+//! covers them too.
+
+use super::copula::{row_values, Copula};
+use crate::apps::mltraining::dataset::Dataset;
+use crate::common::f32_bits;
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Fidelity {
+    /// One per column, the label last.
+    pub columns: Vec<ColumnFidelity>,
+    /// Mean absolute difference between the fitted and synthetic
+    /// correlation of the normal scores of each pair of columns, over the
+    /// pairs defined in the synthetic rows.
+    #[serde(with = "f32_bits")]
+    pub correlation_error: f64,
+    #[serde(with = "f32_bits")]
+    pub max_correlation_error: f64,
+    /// Weight of the identity mixed into the fitted correlations to make
+    /// them positive definite.
+    #[serde(with = "f32_bits")]
+    pub shrinkage: f64,
+    /// Synthetic rows of an exact fit drawn again because they equalled a
+    /// real row, `0` with `epsilon`.
+    pub redrawn_rows: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ColumnFidelity {
+    pub name: String,
+    /// Total variation distance between the fitted marginal and the
+    /// synthetic values over the column's bins and missing values, in
+    /// `[0, 1]`.
+    #[serde(with = "f32_bits")]
+    pub total_variation: f64,
+}
+
+/// Compare `synthetic` with the `copula` that drew it, where `names` are its
+/// columns, the label last.
+pub fn measure(
+    names: &[String],
+    copula: &Copula,
+    synthetic: &Dataset,
+    redrawn_rows: u64,
+) -> Fidelity {
+    let binnings = copula.binnings();
+    let columns = binnings.len();
+
+    // 1. Cells of each column and normal score sums of each pair, as the
+    // copula fits them
+    let mut cells: Vec<Vec<f64>> = binnings.iter().map(|b| vec![0.0; b.bins + 1]).collect();
+    let mut sums = vec![[0.0; 3]; columns * (columns - 1) / 2];
+    for i in 0..synthetic.len() {
+        for (c, x) in row_values(synthetic, i).enumerate() {
+            cells[c][binnings[c].cell(x)] += 1.0;
+        }
+        let scores = copula.scores(synthetic, i);
+        let mut k = 0;
+        for a in 0..columns {
+            for b in a + 1..columns {
+                if let (Some(x), Some(y)) = (scores[a], scores[b]) {
+                    sums[k][0] += x * y;
+                    sums[k][1] += x * x;
+                    sums[k][2] += y * y;
+                }
+                k += 1;
+            }
+        }
+    }
+
+    let columns_fidelity = names
+        .iter()
+        .enumerate()
+        .map(|(c, name)| {
+            let distance: f64 = copula
+                .cell_probabilities(c)
+                .iter()
+                .zip(&cells[c])
+                .map(|(p, s)| (p - s / synthetic.len() as f64).abs())
+                .sum();
+            ColumnFidelity {
+                name: name.clone(),
+                total_variation: distance / 2.0,
+            }
+        })
+        .collect();
+
+    // 2. Pairwise correlations
+    let mut errors = Vec::with_capacity(sums.len());
+    let mut k = 0;
+    for a in 0..columns {
+        for b in a + 1..columns {
+            let [product, square_a, square_b] = sums[k];
+            if square_a > 0.0 && square_b > 0.0 {
+                let r = product / (square_a * square_b).sqrt();
+                errors.push((copula.correlation(a, b) - r).abs());
+            }
+            k += 1;
+        }
+    }
+    let correlation_error = match errors.is_empty() {
+        true => 0.0,
+        false => errors.iter().sum::<f64>() / errors.len() as f64,
+    };
+    Fidelity {
+        columns: columns_fidelity,
+        correlation_error,
+        max_correlation_error: errors.iter().copied().fold(0.0, f64::max),
+        shrinkage: copula.shrinkage,
+        redrawn_rows,
+    }
+}
@@ -0,0 +1,246 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::apps::mltraining::chain::{OnChainJob, RpcReader, SuiReader};
+use crate::apps::mltraining::crypto::DATA_KEY_SECRET;
+use crate::apps::mltraining::dataset::Dataset;
+use crate::apps::mltraining::policy::{CohortReport, PolicyCheck};
+use crate::apps::mltraining::pool::{check_cohort, load_rows, verify_pool};
+use crate::apps::mltraining::quality::{BlobReport, QualityChecker, QualityConfig};
+use crate::apps::mltraining::seal::{EnclaveObject, SealedJob};
+use crate::apps::mltraining::usage;
+use crate::apps::mltraining::{download_blob, upload_blob};
+use crate::common::{
+    f32_bits, to_signed_response, IntentMessage, IntentScope, ProcessDataRequest,
+    ProcessedDataResponse,
+};
+use crate::registry::{parse_allowed_endpoints, EnclaveApp};
+use crate::AppState;
+use crate::EnclaveError;
+use axum::{extract::State, routing::post, Json, Router};
+use fastcrypto::hash::{HashFunction, Sha256};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+mod buyer;
+mod copula;
+mod fidelity;
+
+use buyer::{buyer_key, encrypt_for_buyer};
+use copula::{row_values, Copula, SynthesisConfig, LABEL_COLUMN};
+use fidelity::{measure, Fidelity};
+
+/// Synthetic stand-ins for pooled contributor data.
+pub struct SyntheticDataApp;
+
+impl EnclaveApp for SyntheticDataApp {
+    fn name(&self) -> &'static str {
+        "synthetic"
+    }
+
+    fn routes(&self) -> Router<Arc<AppState>> {
+        Router::new().route("/generate", post(generate))
+    }
+
+    fn intent_scopes(&self) -> &'static [IntentScope] {
+        &[IntentScope::SyntheticData, IntentScope::SyntheticRejected]
+    }
+
+    fn required_secrets(&self) -> &'static [&'static str] {
+        &[DATA_KEY_SECRET]
+    }
+
+    fn allowed_endpoints(&self) -> Vec<String> {
+        parse_allowed_endpoints(include_str!("allowed_endpoints.yaml"))
+    }
+}
+
+// === REQUEST ===
+#[derive(Deserialize, Debug)]
+pub struct SyntheticRequest {
+    /// Id of the `cloakx::jobs::Job` run. Its `model_wid` must be
+    /// `config_blob_id`, and `data_blob_ids` its pool's `pool_data` entry.
+    pub job_id: u64,
+    pub data_blob_ids: Vec<String>,
+    /// Blob of the `SynthesisConfig`.
+    pub config_blob_id: String,
+    /// Seal key id of sealed data blobs.
+    pub key_id: String,
+    /// Thresholds of the per-blob data quality checks.
+    #[serde(default)]
+    pub quality: QualityConfig,
+    /// This enclave's registered `Enclave<JOBS>` object. Needed for sealed
+    /// data blobs.
+    pub enclave_object: Option<EnclaveObject>,
+}
+
+// === RESPONSE ===
+/// Signed result of a synthetic data job.
+#[derive(Serialize, Clone, Debug)]
+pub struct SyntheticResponse {
+    /// The on-chain job the request was checked against.
+    pub job_id: u64,
+    pub pool_id: u64,
+    pub config_blob_id: String,
+    /// Walrus blob of the synthetic CSV, encrypted to the job's
+    /// `buyer_public_key`.
+    pub blob_id: String,
+    /// Sha256 of the CSV before encryption.
+    pub csv_hash: Vec<u8>,
+    /// Synthetic rows in the CSV.
+    pub rows: u64,
+    /// Privacy budget of the fit, `None` for an exact fit.
+    #[serde(with = "f32_bits")]
+    pub epsilon: Option<f64>,
+    /// Data quality of each blob, in request order.
+    pub data_quality: Vec<BlobReport>,
+    /// Contributors and rows fitted, with the pool's minimums they met.
+    pub cohort: CohortReport,
+    pub fidelity: Fidelity,
+}
+
+pub async fn generate(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ProcessDataRequest<SyntheticRequest>>,
+) -> Result<Json<ProcessedDataResponse<IntentMessage<SyntheticResponse>>>, EnclaveError> {
+    let payload = &req.payload;
+    let nonce = req.nonce()?;
+
+    // 1. Check the request against the on-chain job and its pool, and the
+    // synthesis config against the pool's policy
+    let (job, policy, contributors) = verify_job(&RpcReader::from_config()?, payload).await?;
+    let buyer = buyer_key(&job.buyer_public_key)?;
+    let reject = |reason: String| {
+        let rejection = policy.rejection(payload.job_id, reason.clone());
+        let stamp = match state.clock.stamp(&nonce) {
+            Ok(stamp) => stamp,
+            Err(e) => return e,
+        };
+        let signed = to_signed_response(
+            &state.eph_kp,
+            rejection,
+            &stamp,
+            IntentScope::SyntheticRejected,
+        );
+        EnclaveError::Rejected {
+            reason,
+            signed: serde_json::to_value(signed).expect("should not fail"),
+        }
+    };
+    let config_bytes = download_blob(&payload.config_blob_id).await?;
+    let config: SynthesisConfig = serde_json::from_slice(&config_bytes)
+        .map_err(|e| EnclaveError::InvalidInput(e.to_string()))?;
+    let binnings = config.binnings()?;
+    policy.check_synthesis(config.epsilon).map_err(reject)?;
+
+    // 2. Load the rows that pass the quality checks
+    let checker = QualityChecker::new(
+        config.columns.len(),
+        config.classes as usize,
+        &payload.quality,
+    )?;
+    let rows = load_rows(
+        &state,
+        &payload.data_blob_ids,
+        SealedJob {
+            job_id: payload.job_id,
+            pool_id: job.pool_id,
+            key_id: &payload.key_id,
+            enclave: payload.enclave_object.as_ref(),
+        },
+        checker,
+    )
+    .await?;
+    let cohort = check_cohort(&policy, &contributors, &rows.data_quality).map_err(reject)?;
+    if rows.dataset.is_empty() {
+        return Err(EnclaveError::InvalidInput(
+            "no data passed the quality checks".to_string(),
+        ));
+    }
+    let (now_ms, _) = state.clock.now_ms()?;
+    usage::charge(&state, |usage| {
+        policy.charge_job(usage, now_ms)?;
+        policy.charge_epsilon(usage, config.epsilon)
+    })?
+    .map_err(reject)?;
+
+    // 3. Fit the generator and draw rows from it. Only an exact fit, which
+    // promises no privacy, compares its rows with the real ones.
+    let dataset = rows.dataset.load()?;
+    let copula = Copula::fit(binnings, &dataset, config.epsilon, &mut OsRng);
+    let real = config.epsilon.is_none().then_some(&dataset);
+    let (synthetic, redrawn) = copula.synthesize(config.rows, real, &mut OsRng)?;
+    let mut names: Vec<String> = config.columns.iter().map(|c| c.name.clone()).collect();
+    names.push(LABEL_COLUMN.to_string());
+    let fidelity = measure(&names, &copula, &synthetic, redrawn);
+
+    // 4. Encrypt the CSV to the buyer and upload it
+    let stamp = state.clock.stamp(&nonce)?;
+    let csv = to_csv(&names, &synthetic);
+    let blob_id = upload_blob(&encrypt_for_buyer(&buyer, &csv)).await?;
+
+    let response = SyntheticResponse {
+        job_id: payload.job_id,
+        pool_id: job.pool_id,
+        config_blob_id: payload.config_blob_id.clone(),
+        blob_id,
+        csv_hash: Sha256::digest(&csv).digest.to_vec(),
+        rows: synthetic.len() as u64,
+        epsilon: config.epsilon,
+        data_quality: rows.data_quality,
+        cohort,
+        fidelity,
+    };
+    Ok(Json(to_signed_response(
+        &state.eph_kp,
+        response,
+        &stamp,
+        IntentScope::SyntheticData,
+    )))
+}
+
+/// Check that `payload` runs its on-chain job: still pending, with the same
+/// config, over exactly the data of an active pool. Returns the job, the
+/// pool's policy and the contributor of each data blob.
+async fn verify_job<R: SuiReader>(
+    reader: &R,
+    payload: &SyntheticRequest,
+) -> Result<(OnChainJob, PolicyCheck, Vec<String>), EnclaveError> {
+    let job = reader.job(payload.job_id).await?;
+    if job.status != "Pending" {
+        return Err(EnclaveError::JobMismatch(format!(
+            "job {} is {}",
+            job.job_id, job.status
+        )));
+    }
+    if job.model_config_blob_id != payload.config_blob_id {
+        return Err(EnclaveError::JobMismatch(format!(
+            "job {} synthesizes with config {}",
+            job.job_id, job.model_config_blob_id
+        )));
+    }
+    let (policy, contributors) = verify_pool(reader, job.pool_id, &payload.data_blob_ids).await?;
+    Ok((job, policy, contributors))
+}
+
+/// CSV of `dataset` under header `names`, missing values left empty.
+fn to_csv(names: &[String], dataset: &Dataset) -> Vec<u8> {
+    let quote = |name: &String| match name.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", name.replace('"', "\"\"")),
+        false => name.clone(),
+    };
+    let mut csv = names.iter().map(quote).collect::<Vec<_>>().join(",");
+    csv.push('\n');
+    for i in 0..dataset.len() {
+        let cells: Vec<String> = row_values(dataset, i)
+            .map(|x| match x.is_nan() {
+                true => String::new(),
+                false => (x as f32).to_string(),
+            })
+            .collect();
+        csv.push_str(&cells.join(","));
+        csv.push('\n');
+    }
+    csv.into_bytes()
+}
@@ -0,0 +1,269 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Verification of the attestation documents of peer enclaves.
+//!
+//! An NSM attestation document is a COSE_Sign1 structure whose payload is a
+//! CBOR map. It is signed with ECDSA P-384 by a leaf certificate, which chains
+//! through the document's `cabundle` up to the AWS Nitro Enclaves root CA,
+//! pinned here by its Sha256 fingerprint. A peer is authenticated when its
+//! document verifies, is recent, reports the same PCRs as this enclave (so it
+//! runs the same image) and binds a public key, which then checks the peer's
+//! signed messages.
+//!
+//! Outside an enclave there is no NSM. Debug builds started with
+//! `LOCAL_ATTESTATION` set serve and accept unsigned local documents instead,
+//! so several servers can authenticate each other as local processes. Release
+//! builds never accept them.
+
+use crate::common::read_pcrs;
+use crate::EnclaveError;
+use fastcrypto::ed25519::Ed25519PublicKey;
+use fastcrypto::encoding::{Encoding, Hex};
+use fastcrypto::hash::{HashFunction, Sha256};
+use fastcrypto::traits::ToFromBytes;
+use p384::ecdsa::signature::Verifier;
+use p384::ecdsa::{Signature, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+use std::collections::BTreeMap;
+use x509_cert::der::oid::ObjectIdentifier;
+use x509_cert::der::{Decode, Encode};
+use x509_cert::Certificate;
+
+/// Debug builds only: set to serve and accept unsigned local documents.
+pub const LOCAL_ATTESTATION_ENV: &str = "LOCAL_ATTESTATION";
+
+/// `module_id` of local documents.
+pub const LOCAL_MODULE_ID: &str = "local";
+
+/// Sha256 of the DER encoded AWS Nitro Enclaves root certificate, the first
+/// certificate of every `cabundle`.
+pub const NITRO_ROOT_SHA256: [u8; 32] = [
+    0x64, 0x1a, 0x03, 0x21, 0xa3, 0xe2, 0x44, 0xef, 0xe4, 0x56, 0x46, 0x31, 0x95, 0xd6, 0x06, 0x31,
+    0x7e, 0xd7, 0xcd, 0xcc, 0x3c, 0x17, 0x56, 0xe0, 0x98, 0x93, 0xf3, 0xc6, 0x8f, 0x79, 0xbb, 0x5b,
+];
+
+/// Largest difference between a peer document's timestamp and the local
+/// clock, so a document cannot be replayed long after it was issued.
+pub const MAX_DOCUMENT_AGE_MS: u64 = 5 * 60 * 1000;
+
+/// COSE algorithm id of ECDSA with SHA-384.
+const COSE_ES384: i128 = -35;
+
+/// CBOR tag of a COSE_Sign1 structure, which NSM documents may omit.
+const COSE_SIGN1_TAG: u64 = 18;
+
+/// `ecdsa-with-SHA384`, the signature algorithm of the Nitro certificates.
+const ECDSA_WITH_SHA384: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.4.3.3");
+
+/// Payload of an attestation document.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttestationDocument {
+    pub module_id: String,
+    /// Milliseconds since the unix epoch, when the NSM issued the document.
+    pub timestamp: u64,
+    #[serde(default)]
+    pub digest: String,
+    /// PCR values by index.
+    #[serde(default)]
+    pub pcrs: BTreeMap<u64, ByteBuf>,
+    /// DER encoded leaf certificate, empty in local documents.
+    #[serde(default)]
+    pub certificate: ByteBuf,
+    /// DER encoded certificates from the root down to the leaf's issuer.
+    #[serde(default)]
+    pub cabundle: Vec<ByteBuf>,
+    pub public_key: Option<ByteBuf>,
+    #[serde(default)]
+    pub user_data: Option<ByteBuf>,
+    #[serde(default)]
+    pub nonce: Option<ByteBuf>,
+}
+
+/// Whether this server runs with local documents, see the module docs.
+pub fn local_attestation() -> bool {
+    cfg!(debug_assertions) && std::env::var_os(LOCAL_ATTESTATION_ENV).is_some()
+}
+
+/// Unsigned local document binding `public_key`, served by
+/// `/get_attestation` in local mode.
+pub fn local_document(public_key: &[u8], timestamp_ms: u64) -> Vec<u8> {
+    let document = AttestationDocument {
+        module_id: LOCAL_MODULE_ID.to_string(),
+        timestamp: timestamp_ms,
+        digest: "SHA384".to_string(),
+        pcrs: BTreeMap::new(),
+        certificate: ByteBuf::new(),
+        cabundle: vec![],
+        public_key: Some(ByteBuf::from(public_key.to_vec())),
+        user_data: None,
+        nonce: None,
+    };
+    serde_cbor::to_vec(&document).expect("should not fail")
+}
+
+/// Authenticate a peer enclave from its hex encoded attestation document,
+/// checked against `now_ms`. Returns the public key its messages are signed
+/// with.
+pub fn authenticate_peer(
+    attestation_hex: &str,
+    now_ms: u64,
+) -> Result<Ed25519PublicKey, EnclaveError> {
+    let bytes = Hex::decode(attestation_hex)
+        .map_err(|e| rejected(format!("attestation is not hex: {e}")))?;
+    let document = if local_attestation() {
+        let document: AttestationDocument = serde_cbor::from_slice(&bytes)
+            .map_err(|e| rejected(format!("malformed local document: {e}")))?;
+        if document.module_id != LOCAL_MODULE_ID {
+            return Err(rejected("not a local document".to_string()));
+        }
+        document
+    } else {
+        let document = verify_document(&bytes)?;
+        for (index, pcr) in read_pcrs()?.iter().enumerate() {
+            let theirs = document.pcrs.get(&(index as u64)).map(|p| p.as_slice());
+            if theirs != Some(pcr.as_slice()) {
+                return Err(rejected(format!("PCR{index} differs from this enclave's")));
+            }
+        }
+        document
+    };
+    if now_ms.abs_diff(document.timestamp) > MAX_DOCUMENT_AGE_MS {
+        return Err(rejected(format!(
+            "document issued at {}, more than {MAX_DOCUMENT_AGE_MS} ms from now",
+            document.timestamp
+        )));
+    }
+    let key = document
+        .public_key
+        .ok_or_else(|| rejected("document binds no public key".to_string()))?;
+    Ed25519PublicKey::from_bytes(&key)
+        .map_err(|_| rejected("document binds no ed25519 public key".to_string()))
+}
+
+/// Verify the COSE signature of an NSM document and its certificate chain
+/// up to the pinned root, at the time the document was issued.
+pub fn verify_document(bytes: &[u8]) -> Result<AttestationDocument, EnclaveError> {
+    let (protected, payload, signature) = parse_cose_sign1(bytes)?;
+    let header: BTreeMap<i64, Value> = serde_cbor::from_slice(&protected)
+        .map_err(|e| rejected(format!("malformed protected header: {e}")))?;
+    if header.get(&1) != Some(&Value::Integer(COSE_ES384)) {
+        return Err(rejected("document is not signed with ES384".to_string()));
+    }
+    let document: AttestationDocument = serde_cbor::from_slice(&payload)
+        .map_err(|e| rejected(format!("malformed document: {e}")))?;
+
+    // 1. The chain, from the pinned root down to the leaf
+    let root = document
+        .cabundle
+        .first()
+        .ok_or_else(|| rejected("empty cabundle".to_string()))?;
+    if Sha256::digest(root.as_slice()).digest != NITRO_ROOT_SHA256 {
+        return Err(rejected(
+            "cabundle does not start at the AWS Nitro Enclaves root".to_string(),
+        ));
+    }
+    let chain = document
+        .cabundle
+        .iter()
+        .chain(std::iter::once(&document.certificate))
+        .map(|der| {
+            Certificate::from_der(der).map_err(|e| rejected(format!("malformed certificate: {e}")))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for certificate in &chain {
+        check_validity(certificate, document.timestamp)?;
+    }
+    for pair in chain.windows(2) {
+        check_issued_by(&pair[1], &pair[0])?;
+    }
+
+    // 2. The document, signed by the leaf
+    let leaf = public_key_of(chain.last().expect("at least the root"))?;
+    let sig_structure = serde_cbor::to_vec(&(
+        "Signature1",
+        ByteBuf::from(protected),
+        ByteBuf::new(),
+        ByteBuf::from(payload),
+    ))
+    .expect("should not fail");
+    let signature = Signature::from_slice(&signature)
+        .map_err(|_| rejected("malformed document signature".to_string()))?;
+    leaf.verify(&sig_structure, &signature)
+        .map_err(|_| rejected("document signature does not verify".to_string()))?;
+    Ok(document)
+}
+
+/// Protected header, payload and signature of a COSE_Sign1 structure.
+fn parse_cose_sign1(bytes: &[u8]) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), EnclaveError> {
+    let value: Value = serde_cbor::from_slice(bytes)
+        .map_err(|e| rejected(format!("document is not CBOR: {e}")))?;
+    let value = match value {
+        Value::Tag(COSE_SIGN1_TAG, inner) => *inner,
+        value => value,
+    };
+    match value {
+        Value::Array(items) => match <[Value; 4]>::try_from(items) {
+            Ok([Value::Bytes(protected), _, Value::Bytes(payload), Value::Bytes(signature)]) => {
+                Ok((protected, payload, signature))
+            }
+            _ => Err(rejected("document is not a COSE_Sign1".to_string())),
+        },
+        _ => Err(rejected("document is not a COSE_Sign1".to_string())),
+    }
+}
+
+fn check_validity(certificate: &Certificate, at_ms: u64) -> Result<(), EnclaveError> {
+    let validity = &certificate.tbs_certificate.validity;
+    let not_before = validity.not_before.to_unix_duration().as_millis();
+    let not_after = validity.not_after.to_unix_duration().as_millis();
+    if !(not_before..=not_after).contains(&(at_ms as u128)) {
+        return Err(rejected(format!(
+            "certificate {} is not valid when the document was issued",
+            certificate.tbs_certificate.subject
+        )));
+    }
+    Ok(())
+}
+
+fn check_issued_by(certificate: &Certificate, issuer: &Certificate) -> Result<(), EnclaveError> {
+    let tbs = &certificate.tbs_certificate;
+    if tbs.issuer != issuer.tbs_certificate.subject
+        || certificate.signature_algorithm.oid != ECDSA_WITH_SHA384
+    {
+        return Err(rejected(format!(
+            "certificate {} is not issued by {}",
+            tbs.subject, issuer.tbs_certificate.subject
+        )));
+    }
+    let message = tbs.to_der().expect("decoded certificates encode");
+    let signature = certificate
+        .signature
+        .as_bytes()
+        .and_then(|der| Signature::from_der(der).ok())
+        .ok_or_else(|| rejected(format!("malformed signature of {}", tbs.subject)))?;
+    public_key_of(issuer)?
+        .verify(&message, &signature)
+        .map_err(|_| rejected(format!("signature of {} does not verify", tbs.subject)))
+}
+
+fn public_key_of(certificate: &Certificate) -> Result<VerifyingKey, EnclaveError> {
+    certificate
+        .tbs_certificate
+        .subject_public_key_info
+        .subject_public_key
+        .as_bytes()
+        .and_then(|sec1| VerifyingKey::from_sec1_bytes(sec1).ok())
+        .ok_or_else(|| {
+            rejected(format!(
+                "certificate {} has no P-384 key",
+                certificate.tbs_certificate.subject
+            ))
+        })
+}
+
+fn rejected(reason: String) -> EnclaveError {
+    EnclaveError::PeerAuthentication(reason)
+}
@@ -0,0 +1,121 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Sui checkpoints as a trusted time reference.
+//!
+//! A checkpoint's `timestamp_ms` is certified by a quorum of its epoch's
+//! validators. A [`CheckpointProof`] is a chain of checkpoints, oldest first.
+//! It starts at the end-of-epoch checkpoint pinned in
+//! `checkpoint_config.yaml`, or in an epoch whose committee an earlier proof
+//! already established. Each end-of-epoch checkpoint hands over to the
+//! committee of the next epoch, and every other checkpoint must be certified
+//! by the committee of its epoch. The last checkpoint is the reference.
+
+use crate::clock::TimeReference;
+use crate::EnclaveError;
+use fastcrypto::encoding::{Encoding, Hex};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use sui_crypto::bls12381::ValidatorCommitteeSignatureVerifier;
+use sui_sdk_types::{CheckpointDigest, SignedCheckpointSummary, ValidatorCommittee};
+
+/// Environment variable naming a config to use instead of the measured
+/// `checkpoint_config.yaml`, e.g. to pin a checkpoint of a local network.
+/// Debug builds only: the host sets the environment of a release enclave.
+pub const CHECKPOINT_CONFIG_ENV: &str = "CHECKPOINT_CONFIG";
+
+lazy_static! {
+    /// Latest committee a proof established, so later proofs can start in
+    /// its epoch instead of at the pinned checkpoint.
+    static ref COMMITTEE: Mutex<Option<ValidatorCommittee>> = Mutex::new(None);
+}
+
+/// `checkpoint_config.yaml`.
+#[derive(Deserialize, Debug)]
+struct CheckpointConfig {
+    /// Base58 digest of the end-of-epoch checkpoint proofs start from.
+    trusted_checkpoint_digest: String,
+}
+
+/// Certified Sui checkpoints attesting to the time.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CheckpointProof {
+    /// Hex encoded BCS `SignedCheckpointSummary`s, oldest first.
+    pub checkpoints: Vec<String>,
+}
+
+impl TimeReference for CheckpointProof {
+    fn verified_timestamp_ms(&self) -> Result<u64, EnclaveError> {
+        let invalid = |reason: String| EnclaveError::InvalidInput(reason);
+        let pinned = trusted_checkpoint()?;
+        let mut committee = COMMITTEE.lock().expect("not poisoned").clone();
+        let mut timestamp_ms = None;
+        for (i, checkpoint) in self.checkpoints.iter().enumerate() {
+            let signed: SignedCheckpointSummary = Hex::decode(checkpoint)
+                .ok()
+                .and_then(|bytes| bcs::from_bytes(&bytes).ok())
+                .ok_or_else(|| {
+                    invalid(format!(
+                        "checkpoint {i} is not a hex encoded BCS SignedCheckpointSummary"
+                    ))
+                })?;
+            let summary = &signed.checkpoint;
+            match &committee {
+                Some(c) if c.epoch == summary.epoch => {
+                    ValidatorCommitteeSignatureVerifier::new(c.clone())
+                        .and_then(|v| v.verify_checkpoint_summary(summary, &signed.signature))
+                        .map_err(|e| {
+                            invalid(format!(
+                                "checkpoint {} is not certified by the committee of epoch {}: {e}",
+                                summary.sequence_number, summary.epoch
+                            ))
+                        })?;
+                }
+                _ if summary.digest() == pinned => {}
+                _ => {
+                    return Err(invalid(format!(
+                        "no trusted committee for epoch {} of checkpoint {}",
+                        summary.epoch, summary.sequence_number
+                    )))
+                }
+            }
+            if let Some(end_of_epoch) = &summary.end_of_epoch_data {
+                committee = Some(ValidatorCommittee {
+                    epoch: summary.epoch + 1,
+                    members: end_of_epoch.next_epoch_committee.clone(),
+                });
+            }
+            timestamp_ms = Some(summary.timestamp_ms);
+        }
+        let timestamp_ms =
+            timestamp_ms.ok_or_else(|| invalid("proof has no checkpoint".to_string()))?;
+
+        let mut cached = COMMITTEE.lock().expect("not poisoned");
+        if let Some(committee) = committee {
+            if cached.as_ref().map_or(true, |c| c.epoch < committee.epoch) {
+                *cached = Some(committee);
+            }
+        }
+        Ok(timestamp_ms)
+    }
+}
+
+/// The pinned checkpoint digest.
+fn trusted_checkpoint() -> Result<CheckpointDigest, EnclaveError> {
+    let text = match std::env::var(CHECKPOINT_CONFIG_ENV) {
+        Ok(path) if cfg!(debug_assertions) => std::fs::read_to_string(path)?,
+        _ => include_str!("checkpoint_config.yaml").to_string(),
+    };
+    let config: CheckpointConfig = serde_yaml::from_str(&text)
+        .map_err(|e| EnclaveError::GenericError(format!("invalid checkpoint config: {e}")))?;
+    if config.trusted_checkpoint_digest.is_empty() {
+        return Err(EnclaveError::GenericError(
+            "no trusted checkpoint pinned in checkpoint_config.yaml".to_string(),
+        ));
+    }
+    config
+        .trusted_checkpoint_digest
+        .parse()
+        .map_err(|e| EnclaveError::GenericError(format!("invalid trusted checkpoint digest: {e}")))
+}
@@ -0,0 +1,133 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! The time signed responses are stamped with.
+//!
+//! An enclave has no clock of its own: `SystemTime` is read from the host,
+//! which can set it at will. [`Clock`] reads a [`TimeSource`], the host clock
+//! by default, and checks it against the last trusted reference it was
+//! anchored to, e.g. a certified Sui checkpoint (see [`crate::checkpoint`]).
+//!
+//! A reference at `t` shows the time is at least `t`, and the enclave's
+//! monotonic clock how long ago the reference was accepted. The host time
+//! may fall behind that by at most [`MAX_SKEW_MS`], and run ahead of it by
+//! at most [`MAX_REFERENCE_AGE_MS`], the age a reference may have when it is
+//! supplied. Without a reference the host time is used unchecked, which
+//! signed responses state with a `reference_ms` of 0.
+
+use crate::common::{current_timestamp_ms, Stamp};
+use crate::EnclaveError;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// How far the host clock may lag behind the trusted time.
+pub const MAX_SKEW_MS: u64 = 30 * 1000;
+/// How old a reference may be when it is supplied, so also how far ahead of
+/// the trusted time the host clock may run.
+pub const MAX_REFERENCE_AGE_MS: u64 = 10 * 60 * 1000;
+
+/// A clock the enclave can read but not trust.
+pub trait TimeSource: Send + Sync {
+    /// Milliseconds since the unix epoch.
+    fn now_ms(&self) -> u64;
+}
+
+/// `SystemTime`, which inside an enclave is the host's clock.
+pub struct HostClock;
+
+impl TimeSource for HostClock {
+    fn now_ms(&self) -> u64 {
+        current_timestamp_ms()
+    }
+}
+
+/// A timestamp the enclave can check without trusting the host.
+pub trait TimeReference {
+    /// Milliseconds since the unix epoch, once verified.
+    fn verified_timestamp_ms(&self) -> Result<u64, EnclaveError>;
+}
+
+/// A verified reference and when, on the monotonic clock, it was accepted.
+struct Anchor {
+    reference_ms: u64,
+    accepted: Instant,
+}
+
+/// A [`TimeSource`] checked against the latest trusted reference.
+pub struct Clock {
+    source: Box<dyn TimeSource>,
+    anchor: Mutex<Option<Anchor>>,
+    /// Latest time handed out, so stamps never go back within a boot.
+    last_ms: AtomicU64,
+}
+
+impl Clock {
+    pub fn new(source: Box<dyn TimeSource>) -> Self {
+        Self {
+            source,
+            anchor: Mutex::new(None),
+            last_ms: AtomicU64::new(0),
+        }
+    }
+
+    /// Anchor the clock to `reference`, which must be newer than the current
+    /// anchor and recent by the host clock. A host clock behind the reference
+    /// is not checked here: [`Clock::now_ms`] fails until it catches up.
+    /// Returns the reference's timestamp.
+    pub fn anchor(&self, reference: &dyn TimeReference) -> Result<u64, EnclaveError> {
+        let reference_ms = reference.verified_timestamp_ms()?;
+        let host_ms = self.source.now_ms();
+        if host_ms > reference_ms.saturating_add(MAX_REFERENCE_AGE_MS) {
+            return Err(EnclaveError::InvalidInput(format!(
+                "reference at {reference_ms} ms is more than {MAX_REFERENCE_AGE_MS} ms old"
+            )));
+        }
+        let mut anchor = self.anchor.lock().expect("not poisoned");
+        if let Some(current) = anchor.as_ref() {
+            if reference_ms <= current.reference_ms {
+                return Err(EnclaveError::InvalidInput(format!(
+                    "reference at {reference_ms} ms is not newer than the current one at {} ms",
+                    current.reference_ms
+                )));
+            }
+        }
+        *anchor = Some(Anchor {
+            reference_ms,
+            accepted: Instant::now(),
+        });
+        Ok(reference_ms)
+    }
+
+    /// The host time, once checked against the anchor, and the timestamp of
+    /// the anchor, 0 when there is none.
+    pub fn now_ms(&self) -> Result<(u64, u64), EnclaveError> {
+        let host_ms = self.source.now_ms();
+        let reference_ms = match self.anchor.lock().expect("not poisoned").as_ref() {
+            None => 0,
+            Some(anchor) => {
+                let trusted_ms = anchor.reference_ms + anchor.accepted.elapsed().as_millis() as u64;
+                if host_ms.saturating_add(MAX_SKEW_MS) < trusted_ms
+                    || host_ms > trusted_ms.saturating_add(MAX_REFERENCE_AGE_MS)
+                {
+                    return Err(EnclaveError::UntrustedClock(format!(
+                        "host clock at {host_ms} ms, trusted time is about {trusted_ms} ms"
+                    )));
+                }
+                anchor.reference_ms
+            }
+        };
+        let last_ms = self.last_ms.fetch_max(host_ms, Ordering::SeqCst);
+        Ok((host_ms.max(last_ms), reference_ms))
+    }
+
+    /// A stamp for a response to a request with `nonce`.
+    pub fn stamp(&self, nonce: &[u8]) -> Result<Stamp, EnclaveError> {
+        let (timestamp_ms, reference_ms) = self.now_ms()?;
+        Ok(Stamp {
+            timestamp_ms,
+            reference_ms,
+            nonce: nonce.to_vec(),
+        })
+    }
+}
@@ -1,6 +1,8 @@
 // Copyright (c), Mysten Labs, Inc.
 // SPDX-License-Identifier: Apache-2.0
 
+use crate::attestation::{local_attestation, local_document};
+use crate::checkpoint::CheckpointProof;
 use crate::AppState;
 use crate::EnclaveError;
 use axum::{extract::State, Json};
@@ -16,6 +18,7 @@ use serde_repr::Deserialize_repr;
 use serde_repr::Serialize_repr;
 use std::collections::HashMap;
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 use tracing::info;
@@ -23,32 +26,86 @@ use tracing::info;
 use fastcrypto::ed25519::Ed25519KeyPair;
 /// ==== COMMON TYPES ====
 /// Intent message wrapper struct containing the intent scope and timestamp.
-/// This standardizes the serialized payload for signing.
+/// This standardizes the serialized payload for signing. Mirrors
+/// `enclave::enclave::IntentMessage`, whose `verify_signature` takes the
+/// fields after `data` as arguments.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct IntentMessage<T: Serialize> {
     pub intent: IntentScope,
     pub timestamp_ms: u64,
     pub data: T,
+    /// The request's nonce, empty when it had none.
+    pub nonce: Vec<u8>,
+    /// Responses signed since this enclave booted, this one included. With
+    /// the ephemeral key, which is new at every boot, it tells each response
+    /// apart and orders them.
+    pub counter: u64,
+    /// Timestamp of the trusted reference `timestamp_ms` was checked
+    /// against, 0 when the clock has none, see [`crate::clock`].
+    pub reference_ms: u64,
 }
 
 /// Intent scope enum. Add new scope here if needed, each corresponds to a
-/// scope for signing. Replace in with your own intent per message type being signed by the enclave.
-#[derive(Serialize_repr, Deserialize_repr, Debug)]
+/// scope for signing. Each app registers its own scopes, which must be distinct across apps.
+#[derive(Serialize_repr, Deserialize_repr, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum IntentScope {
     ProcessData = 0,
+    /// `MLTrainingResponse`, matches `PROCESS_DATA_INTENT` in `cloakx::jobs`.
+    MlTraining = 1,
+    /// `PredictResponse` of a privately trained model.
+    MlPredict = 2,
+    /// `JobRejection` of a training job refused by its pool's policy.
+    MlJobRejected = 3,
+    /// `AnalyticsResponse` of an aggregate query over a pool.
+    AnalyticsResult = 4,
+    /// `QueryRejection` of an analytics query refused by its pool's policy.
+    AnalyticsRejected = 5,
+    /// `SqlResponse` of a SQL query over a pool.
+    AnalyticsSqlResult = 6,
+    /// `SyntheticResponse` of a synthetic data job.
+    SyntheticData = 7,
+    /// `JobRejection` of a synthetic data job refused by its pool's policy.
+    SyntheticRejected = 8,
+    /// `FederatedTrainingResponse` of a training job run across enclaves.
+    MlFederatedTraining = 9,
+    /// Message of a federated training coordinator to its workers.
+    MlFederatedControl = 10,
+    /// Reply of a federated training worker to its coordinator.
+    MlFederatedUpdate = 11,
+    /// `TrainingReport` signed next to an `MLTrainingResponse`.
+    MlTrainingReport = 12,
 }
 
 impl<T: Serialize + Debug> IntentMessage<T> {
-    pub fn new(data: T, timestamp_ms: u64, intent: IntentScope) -> Self {
+    pub fn new(data: T, stamp: &Stamp, counter: u64, intent: IntentScope) -> Self {
         Self {
             data,
-            timestamp_ms,
+            timestamp_ms: stamp.timestamp_ms,
             intent,
+            nonce: stamp.nonce.clone(),
+            counter,
+            reference_ms: stamp.reference_ms,
         }
     }
 }
 
+/// When, and in answer to which request, a response is signed. See
+/// [`crate::clock::Clock::stamp`].
+#[derive(Debug, Clone, Default)]
+pub struct Stamp {
+    pub timestamp_ms: u64,
+    /// Timestamp of the clock's trusted reference, 0 when it has none.
+    pub reference_ms: u64,
+    pub nonce: Vec<u8>,
+}
+
+/// Longest request nonce, in bytes.
+pub const MAX_NONCE_LENGTH: usize = 64;
+
+/// Responses signed since boot, see `IntentMessage::counter`.
+static SIGNED_RESPONSES: AtomicU64 = AtomicU64::new(0);
+
 /// Wrapper struct containing the response (the intent message) and signature.
 #[derive(Serialize, Deserialize)]
 pub struct ProcessedDataResponse<T> {
@@ -60,19 +117,44 @@ pub struct ProcessedDataResponse<T> {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ProcessDataRequest<T> {
     pub payload: T,
+    /// Hex encoded bytes echoed in the signed response, so the caller can
+    /// tell it answers this request, e.g. a random challenge or the current
+    /// Sui epoch. At most `MAX_NONCE_LENGTH` bytes.
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
+impl<T> ProcessDataRequest<T> {
+    /// The decoded nonce, empty when the request has none.
+    pub fn nonce(&self) -> Result<Vec<u8>, EnclaveError> {
+        let Some(nonce) = &self.nonce else {
+            return Ok(vec![]);
+        };
+        let bytes = Hex::decode(nonce)
+            .map_err(|_| EnclaveError::InvalidInput("nonce is not hex encoded".to_string()))?;
+        if bytes.len() > MAX_NONCE_LENGTH {
+            return Err(EnclaveError::InvalidInput(format!(
+                "nonce is longer than {MAX_NONCE_LENGTH} bytes"
+            )));
+        }
+        Ok(bytes)
+    }
 }
 
 /// Sign the bcs bytes of the the payload with keypair.
 pub fn to_signed_response<T: Serialize + Clone>(
     kp: &Ed25519KeyPair,
     payload: T,
-    timestamp_ms: u64,
+    stamp: &Stamp,
     intent: IntentScope,
 ) -> ProcessedDataResponse<IntentMessage<T>> {
     let intent_msg = IntentMessage {
         intent,
-        timestamp_ms,
+        timestamp_ms: stamp.timestamp_ms,
         data: payload.clone(),
+        nonce: stamp.nonce.clone(),
+        counter: SIGNED_RESPONSES.fetch_add(1, Ordering::SeqCst) + 1,
+        reference_ms: stamp.reference_ms,
     };
 
     let signing_payload = bcs::to_bytes(&intent_msg).expect("should not fail");
@@ -83,6 +165,92 @@ pub fn to_signed_response<T: Serialize + Clone>(
     }
 }
 
+/// Milliseconds since the unix epoch by the host clock. Signed responses are
+/// stamped by `AppState::clock`, which checks it.
+pub fn current_timestamp_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("clock before unix epoch")
+        .as_millis() as u64
+}
+
+/// ==== CANONICAL FLOAT ENCODING ====
+/// Serde helper for float fields of signed or hashed structs. BCS has no float
+/// support, so non human readable formats encode floats as their IEEE-754 bit
+/// patterns while JSON keeps plain numbers. Use with `#[serde(with = "f32_bits")]`.
+pub mod f32_bits {
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    /// Float containers that have a bit pattern representation.
+    pub trait AsBits: Serialize + DeserializeOwned {
+        type Bits: Serialize + DeserializeOwned;
+        fn to_bits(&self) -> Self::Bits;
+        fn from_bits(bits: Self::Bits) -> Self;
+    }
+
+    impl AsBits for f32 {
+        type Bits = u32;
+        fn to_bits(&self) -> u32 {
+            f32::to_bits(*self)
+        }
+        fn from_bits(bits: u32) -> Self {
+            f32::from_bits(bits)
+        }
+    }
+
+    impl AsBits for f64 {
+        type Bits = u64;
+        fn to_bits(&self) -> u64 {
+            f64::to_bits(*self)
+        }
+        fn from_bits(bits: u64) -> Self {
+            f64::from_bits(bits)
+        }
+    }
+
+    impl<T: AsBits> AsBits for Option<T> {
+        type Bits = Option<T::Bits>;
+        fn to_bits(&self) -> Self::Bits {
+            self.as_ref().map(T::to_bits)
+        }
+        fn from_bits(bits: Self::Bits) -> Self {
+            bits.map(T::from_bits)
+        }
+    }
+
+    impl<T: AsBits> AsBits for Vec<T> {
+        type Bits = Vec<T::Bits>;
+        fn to_bits(&self) -> Self::Bits {
+            self.iter().map(T::to_bits).collect()
+        }
+        fn from_bits(bits: Self::Bits) -> Self {
+            bits.into_iter().map(T::from_bits).collect()
+        }
+    }
+
+    pub fn serialize<T: AsBits, S: Serializer>(
+        value: &T,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            value.serialize(serializer)
+        } else {
+            value.to_bits().serialize(serializer)
+        }
+    }
+
+    pub fn deserialize<'de, T: AsBits, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<T, D::Error> {
+        if deserializer.is_human_readable() {
+            T::deserialize(deserializer)
+        } else {
+            T::Bits::deserialize(deserializer).map(T::from_bits)
+        }
+    }
+}
+
 /// ==== HEALTHCHECK, GET ATTESTASTION ENDPOINT IMPL ====
 /// Response for get attestation.
 #[derive(Debug, Serialize, Deserialize)]
@@ -92,13 +260,20 @@ pub struct GetAttestationResponse {
 }
 
 /// Endpoint that returns an attestation committed
-/// to the enclave's public key.
+/// to the enclave's public key. Serves an unsigned local document in local
+/// mode, see [`crate::attestation`].
 pub async fn get_attestation(
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<GetAttestationResponse>, EnclaveError> {
     info!("get attestation called");
 
     let pk = state.eph_kp.public();
+    if local_attestation() {
+        let document = local_document(pk.as_bytes(), current_timestamp_ms());
+        return Ok(Json(GetAttestationResponse {
+            attestation: Hex::encode(document),
+        }));
+    }
     let fd = driver::nsm_init();
 
     // Send attestation request to NSM driver with public key set.
@@ -125,6 +300,50 @@ pub async fn get_attestation(
     }
 }
 
+/// Response for anchor clock.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AnchorClockResponse {
+    /// Timestamp of the checkpoint the clock is now anchored to.
+    pub reference_ms: u64,
+    /// Time by the host clock, once checked against it.
+    pub timestamp_ms: u64,
+}
+
+/// Endpoint that anchors the enclave's clock to certified Sui checkpoints,
+/// see [`crate::checkpoint`]. Anyone may call it: a proof only verifies if
+/// the validators signed it.
+pub async fn anchor_clock(
+    State(state): State<Arc<AppState>>,
+    Json(proof): Json<CheckpointProof>,
+) -> Result<Json<AnchorClockResponse>, EnclaveError> {
+    let reference_ms = state.clock.anchor(&proof)?;
+    info!("clock anchored to a checkpoint at {reference_ms} ms");
+    let (timestamp_ms, _) = state.clock.now_ms()?;
+    Ok(Json(AnchorClockResponse {
+        reference_ms,
+        timestamp_ms,
+    }))
+}
+
+/// Read the enclave's PCR values (image, kernel, application) from the NSM.
+pub fn read_pcrs() -> Result<Vec<Vec<u8>>, EnclaveError> {
+    let fd = driver::nsm_init();
+    let mut pcrs = Vec::with_capacity(3);
+    for index in 0..3u16 {
+        match driver::nsm_process_request(fd, NsmRequest::DescribePCR { index }) {
+            NsmResponse::DescribePCR { data, .. } => pcrs.push(data),
+            _ => {
+                driver::nsm_exit(fd);
+                return Err(EnclaveError::GenericError(format!(
+                    "failed to read PCR{index}"
+                )));
+            }
+        }
+    }
+    driver::nsm_exit(fd);
+    Ok(pcrs)
+}
+
 /// Health check response.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HealthCheckResponse {
@@ -135,7 +354,7 @@ pub struct HealthCheckResponse {
 }
 
 /// Endpoint that health checks the enclave connectivity to all
-/// domains and returns the enclave's public key.
+/// domains allowed by the enabled apps and returns the enclave's public key.
 pub async fn health_check(
     State(state): State<Arc<AppState>>,
 ) -> Result<Json<HealthCheckResponse>, EnclaveError> {
@@ -147,75 +366,77 @@ pub async fn health_check(
         .build()
         .map_err(|e| EnclaveError::GenericError(format!("Failed to create HTTP client: {e}")))?;
 
-    // Load allowed endpoints from YAML file
-    let endpoints_status = match std::fs::read_to_string("allowed_endpoints.yaml") {
-        Ok(yaml_content) => {
-            match serde_yaml::from_str::<serde_yaml::Value>(&yaml_content) {
-                Ok(yaml_value) => {
-                    let mut status_map = HashMap::new();
-
-                    if let Some(endpoints) =
-                        yaml_value.get("endpoints").and_then(|e| e.as_sequence())
-                    {
-                        for endpoint in endpoints {
-                            if let Some(endpoint_str) = endpoint.as_str() {
-                                // Check connectivity to each endpoint
-                                let url = if endpoint_str.contains(".amazonaws.com") {
-                                    format!("https://{endpoint_str}/ping")
-                                } else {
-                                    format!("https://{endpoint_str}")
-                                };
-
-                                let is_reachable = match client.get(&url).send().await {
-                                    Ok(response) => {
-                                        if endpoint_str.contains(".amazonaws.com") {
-                                            // For AWS endpoints, check if response body contains "healthy"
-                                            match response.text().await {
-                                                Ok(body) => body.to_lowercase().contains("healthy"),
-                                                Err(e) => {
-                                                    info!(
-                                                        "Failed to read response body from {}: {}",
-                                                        endpoint_str, e
-                                                    );
-                                                    false
-                                                }
-                                            }
-                                        } else {
-                                            // For non-AWS endpoints, check for 200 status
-                                            response.status().is_success()
-                                        }
-                                    }
-                                    Err(e) => {
-                                        info!("Failed to connect to {}: {}", endpoint_str, e);
-                                        false
-                                    }
-                                };
-
-                                status_map.insert(endpoint_str.to_string(), is_reachable);
-                                info!(
-                                    "Checked endpoint {}: reachable = {}",
-                                    endpoint_str, is_reachable
-                                );
-                            }
+    let mut endpoints_status = HashMap::new();
+    for endpoint_str in &state.allowed_endpoints {
+        // Check connectivity to each endpoint
+        let url = if endpoint_str.contains(".amazonaws.com") {
+            format!("https://{endpoint_str}/ping")
+        } else {
+            format!("https://{endpoint_str}")
+        };
+
+        let is_reachable = match client.get(&url).send().await {
+            Ok(response) => {
+                if endpoint_str.contains(".amazonaws.com") {
+                    // For AWS endpoints, check if response body contains "healthy"
+                    match response.text().await {
+                        Ok(body) => body.to_lowercase().contains("healthy"),
+                        Err(e) => {
+                            info!("Failed to read response body from {}: {}", endpoint_str, e);
+                            false
                         }
                     }
-
-                    status_map
-                }
-                Err(e) => {
-                    info!("Failed to parse YAML: {}", e);
-                    HashMap::new()
+                } else {
+                    // For non-AWS endpoints, check for 200 status
+                    response.status().is_success()
                 }
             }
-        }
-        Err(e) => {
-            info!("Failed to read allowed_endpoints.yaml: {}", e);
-            HashMap::new()
-        }
-    };
+            Err(e) => {
+                info!("Failed to connect to {}: {}", endpoint_str, e);
+                false
+            }
+        };
+
+        endpoints_status.insert(endpoint_str.to_string(), is_reachable);
+        info!(
+            "Checked endpoint {}: reachable = {}",
+            endpoint_str, is_reachable
+        );
+    }
 
     Ok(Json(HealthCheckResponse {
         pk: Hex::encode(pk.as_bytes()),
         endpoints_status,
     }))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Serialize, Debug)]
+    struct SigningPayload {
+        location: String,
+        temperature: u64,
+    }
+
+    #[test]
+    fn test_serde() {
+        // Must match `test_serde` in `move/enclave/sources/enclave.move`.
+        let stamp = Stamp {
+            timestamp_ms: 1744038900000,
+            reference_ms: 1744038890000,
+            nonce: vec![0x8f, 0x2b],
+        };
+        let payload = SigningPayload {
+            location: "San Francisco".to_string(),
+            temperature: 13,
+        };
+        let intent_msg = IntentMessage::new(payload, &stamp, 42, IntentScope::ProcessData);
+        let signing_payload = bcs::to_bytes(&intent_msg).expect("should not fail");
+        assert_eq!(
+            Hex::encode(signing_payload),
+            "0020b1d110960100000d53616e204672616e636973636f0d00000000000000028f2b2a00000000000000108ad11096010000"
+        );
+    }
+}
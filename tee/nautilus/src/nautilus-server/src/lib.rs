@@ -5,57 +5,64 @@ use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::response::Response;
 use axum::Json;
+use clock::Clock;
 use fastcrypto::ed25519::Ed25519KeyPair;
 use serde_json::json;
+use std::collections::HashMap;
 use std::fmt;
 
 mod apps {
-    #[cfg(feature = "twitter-example")]
-    #[path = "twitter-example/mod.rs"]
-    pub mod twitter_example;
-
-    #[cfg(feature = "weather-example")]
-    #[path = "weather-example/mod.rs"]
-    pub mod weather_example;
-
-    #[cfg(feature = "seal-example")]
-    #[path = "seal-example/mod.rs"]
-    pub mod seal_example;
-
-    #[cfg(feature = "mltraining")]          // 
-    #[path = "mltraining/mod.rs"]           // 
+    #[cfg(feature = "mltraining")]
+    #[path = "mltraining/mod.rs"]
     pub mod mltraining;
-}
-
-pub mod app {
-    #[cfg(feature = "twitter-example")]
-    pub use crate::apps::twitter_example::*;
 
-    #[cfg(feature = "weather-example")]
-    pub use crate::apps::weather_example::*;
+    #[cfg(feature = "analytics")]
+    #[path = "analytics/mod.rs"]
+    pub mod analytics;
 
-    #[cfg(feature = "seal-example")]
-    pub use crate::apps::seal_example::*;
-
-    #[cfg(feature = "mltraining")]
-    pub use crate::apps::mltraining::*;
+    #[cfg(feature = "synthetic")]
+    #[path = "synthetic/mod.rs"]
+    pub mod synthetic;
 }
 
+pub mod attestation;
+pub mod checkpoint;
+pub mod clock;
 pub mod common;
+pub mod registry;
 
 /// App state, at minimum needs to maintain the ephemeral keypair.  
 pub struct AppState {
     /// Ephemeral keypair on boot
     pub eph_kp: Ed25519KeyPair,
-    /// API key when querying api.weatherapi.com
-    pub api_key: String,
+    /// Secrets required by the enabled apps, keyed by name.
+    pub secrets: HashMap<String, String>,
+    /// Endpoints the enabled apps are allowed to reach.
+    pub allowed_endpoints: Vec<String>,
+    /// Time signed responses are stamped with.
+    pub clock: Clock,
 }
 
 /// Implement IntoResponse for EnclaveError.
 impl IntoResponse for EnclaveError {
     fn into_response(self) -> Response {
+        if let EnclaveError::Rejected { reason, signed } = self {
+            let body = Json(json!({
+                "error": reason,
+                "rejection": signed,
+            }));
+            return (StatusCode::FORBIDDEN, body).into_response();
+        }
         let (status, error_message) = match self {
             EnclaveError::GenericError(e) => (StatusCode::BAD_REQUEST, e),
+            EnclaveError::InvalidInput(e) => (StatusCode::BAD_REQUEST, e),
+            EnclaveError::Network(e) => (StatusCode::BAD_GATEWAY, e),
+            EnclaveError::Io(e) => (StatusCode::INTERNAL_SERVER_ERROR, e),
+            e @ EnclaveError::BlobIdMismatch { .. } => (StatusCode::BAD_GATEWAY, e.to_string()),
+            e @ (EnclaveError::JobMismatch(_)
+            | EnclaveError::Rejected { .. }
+            | EnclaveError::PeerAuthentication(_)) => (StatusCode::FORBIDDEN, e.to_string()),
+            e @ EnclaveError::UntrustedClock(_) => (StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
         };
         let body = Json(json!({
             "error": error_message,
@@ -68,14 +75,53 @@ impl IntoResponse for EnclaveError {
 #[derive(Debug)]
 pub enum EnclaveError {
     GenericError(String),
+    InvalidInput(String),
+    Network(String),
+    Io(String),
+    /// Downloaded or uploaded bytes that do not encode to the Walrus blob id
+    /// they were fetched under or stored as.
+    BlobIdMismatch {
+        blob_id: String,
+        computed: String,
+    },
+    /// A request that does not match the on-chain job it claims to run.
+    JobMismatch(String),
+    /// A job refused by policy, with the signed response stating why.
+    Rejected {
+        reason: String,
+        signed: serde_json::Value,
+    },
+    /// A peer enclave whose attestation or signed message does not verify.
+    PeerAuthentication(String),
+    /// A host clock that disagrees with the trusted time reference.
+    UntrustedClock(String),
 }
 
 impl fmt::Display for EnclaveError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             EnclaveError::GenericError(e) => write!(f, "{e}"),
+            EnclaveError::InvalidInput(e) => write!(f, "invalid input: {e}"),
+            EnclaveError::Network(e) => write!(f, "network error: {e}"),
+            EnclaveError::Io(e) => write!(f, "io error: {e}"),
+            EnclaveError::BlobIdMismatch { blob_id, computed } => {
+                write!(
+                    f,
+                    "blob {blob_id} does not match its content, which encodes to {computed}"
+                )
+            }
+            EnclaveError::JobMismatch(e) => write!(f, "request does not match the job: {e}"),
+            EnclaveError::Rejected { reason, .. } => write!(f, "job rejected: {reason}"),
+            EnclaveError::PeerAuthentication(e) => write!(f, "peer enclave not authenticated: {e}"),
+            EnclaveError::UntrustedClock(e) => write!(f, "host clock not trusted: {e}"),
         }
     }
 }
 
 impl std::error::Error for EnclaveError {}
+
+impl From<std::io::Error> for EnclaveError {
+    fn from(e: std::io::Error) -> Self {
+        EnclaveError::Io(e.to_string())
+    }
+}
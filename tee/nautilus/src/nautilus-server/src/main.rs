@@ -2,50 +2,69 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use anyhow::Result;
-use axum::{routing::get, routing::post, Router};
+use axum::{
+    routing::{get, post},
+    Router,
+};
 use fastcrypto::{ed25519::Ed25519KeyPair, traits::KeyPair};
-use nautilus_server::app::process_data;
-use nautilus_server::common::{get_attestation, health_check};
+use nautilus_server::clock::{Clock, HostClock};
+use nautilus_server::common::{anchor_clock, get_attestation, health_check};
+use nautilus_server::registry::AppRegistry;
 use nautilus_server::AppState;
+use std::collections::HashMap;
 use std::sync::Arc;
 use tower_http::cors::{Any, CorsLayer};
 use tracing::info;
 
+/// Debug builds only: port to listen on instead of 3000.
+const PORT_ENV: &str = "PORT";
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let eph_kp = Ed25519KeyPair::generate(&mut rand::thread_rng());
 
-    // This API_KEY value can be stored with secret-manager. To do that, follow the prompt `sh configure_enclave.sh`
-    // Answer `y` to `Do you want to use a secret?` and finish. Otherwise, uncomment this code to use a hardcoded value.
-    // let api_key = "045a27812dbe456392913223221306".to_string();
-    #[cfg(not(feature = "seal-example"))]
-    let api_key = std::env::var("API_KEY").expect("API_KEY must be set");
-
-    // NOTE: if built with `seal-example` flag the `process_data` does not use this api_key from AppState, instead
-    // it uses SEAL_API_KEY initialized with two phase bootstrap. Modify this as needed for your application.
-    #[cfg(feature = "seal-example")]
-    let api_key = String::new();
-
-    let state = Arc::new(AppState { eph_kp, api_key });
+    let registry = AppRegistry::enabled()?;
+    if registry.is_empty() {
+        anyhow::bail!("No app enabled, build with e.g. `--features mltraining`");
+    }
+    info!("enabled apps: {:?}", registry.names());
+    registry.check_config()?;
 
-    // Spawn host-only init server if seal-example feature is enabled
-    #[cfg(feature = "seal-example")]
-    {
-        nautilus_server::app::spawn_host_init_server(state.clone()).await?;
+    // Secrets can be stored with secret-manager. To do that, follow the prompt `sh configure_enclave.sh`
+    // Answer `y` to `Do you want to use a secret?` and finish. Each enabled app declares the
+    // environment variables it needs, they are all required here.
+    let mut secrets = HashMap::new();
+    for name in registry.required_secrets() {
+        let value = std::env::var(name).map_err(|_| anyhow::anyhow!("{name} must be set"))?;
+        secrets.insert(name.to_string(), value);
     }
 
+    let state = Arc::new(AppState {
+        eph_kp,
+        secrets,
+        allowed_endpoints: registry.allowed_endpoints(),
+        clock: Clock::new(Box::new(HostClock)),
+    });
+
     // Define your own restricted CORS policy here if needed.
     let cors = CorsLayer::new().allow_methods(Any).allow_headers(Any);
 
     let app = Router::new()
         .route("/", get(ping))
         .route("/get_attestation", get(get_attestation))
-        .route("/process_data", post(process_data))
         .route("/health_check", get(health_check))
+        .route("/anchor_clock", post(anchor_clock))
+        .merge(registry.router())
         .with_state(state)
         .layer(cors);
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await?;
+    // Debug builds can listen on another port, to run several servers on one
+    // machine, e.g. the workers of a federated job.
+    let port: u16 = match std::env::var(PORT_ENV) {
+        Ok(port) if cfg!(debug_assertions) => port.parse()?,
+        _ => 3000,
+    };
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
     info!("listening on {}", listener.local_addr().unwrap());
     axum::serve(listener, app.into_make_service())
         .await
@@ -0,0 +1,151 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+use crate::common::IntentScope;
+use crate::AppState;
+use crate::EnclaveError;
+use axum::Router;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// An application hosted by the enclave. Every enabled app is mounted by the
+/// [`AppRegistry`] under `/<name>` and signs its responses with its own
+/// intent scopes, so a signature produced by one app can never be replayed
+/// as the output of another.
+pub trait EnclaveApp: Send + Sync {
+    /// Unique app name, also used as the path prefix its routes are mounted under.
+    fn name(&self) -> &'static str;
+
+    /// Routes served by the app, relative to its path prefix.
+    fn routes(&self) -> Router<Arc<AppState>>;
+
+    /// Intent scopes the app signs with. Must not overlap with any other app.
+    fn intent_scopes(&self) -> &'static [IntentScope];
+
+    /// Names of the secrets (environment variables) the app needs at boot.
+    fn required_secrets(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    /// Domains the app is allowed to reach from inside the enclave.
+    fn allowed_endpoints(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Check the configuration compiled into the app, so that a broken image
+    /// fails at boot instead of on its first request.
+    fn check_config(&self) -> Result<(), EnclaveError> {
+        Ok(())
+    }
+}
+
+/// Set of apps compiled into this enclave image.
+#[derive(Default)]
+pub struct AppRegistry {
+    apps: Vec<Box<dyn EnclaveApp>>,
+}
+
+impl AppRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registry with every app enabled through cargo features.
+    pub fn enabled() -> Result<Self, EnclaveError> {
+        #[allow(unused_mut)]
+        let mut registry = Self::new();
+
+        #[cfg(feature = "mltraining")]
+        {
+            registry = registry.register(crate::apps::mltraining::MlTrainingApp)?;
+        }
+
+        #[cfg(feature = "analytics")]
+        {
+            registry = registry.register(crate::apps::analytics::AnalyticsApp)?;
+        }
+
+        #[cfg(feature = "synthetic")]
+        {
+            registry = registry.register(crate::apps::synthetic::SyntheticDataApp)?;
+        }
+
+        Ok(registry)
+    }
+
+    /// Add an app, rejecting duplicate names and intent scopes.
+    pub fn register<A: EnclaveApp + 'static>(mut self, app: A) -> Result<Self, EnclaveError> {
+        if self.apps.iter().any(|a| a.name() == app.name()) {
+            return Err(EnclaveError::GenericError(format!(
+                "app {} registered twice",
+                app.name()
+            )));
+        }
+        for scope in app.intent_scopes() {
+            if let Some(owner) = self.apps.iter().find(|a| a.intent_scopes().contains(scope)) {
+                return Err(EnclaveError::GenericError(format!(
+                    "intent scope {:?} of app {} is already used by app {}",
+                    scope,
+                    app.name(),
+                    owner.name()
+                )));
+            }
+        }
+        self.apps.push(Box::new(app));
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.apps.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.apps.iter().map(|a| a.name()).collect()
+    }
+
+    /// Router with every app nested under its own path prefix.
+    pub fn router(&self) -> Router<Arc<AppState>> {
+        self.apps.iter().fold(Router::new(), |router, app| {
+            router.nest(&format!("/{}", app.name()), app.routes())
+        })
+    }
+
+    /// Run the config checks of all apps.
+    pub fn check_config(&self) -> Result<(), EnclaveError> {
+        self.apps.iter().try_for_each(|a| a.check_config())
+    }
+
+    /// Union of the secrets required by all apps.
+    pub fn required_secrets(&self) -> Vec<&'static str> {
+        let mut seen = HashSet::new();
+        self.apps
+            .iter()
+            .flat_map(|a| a.required_secrets().iter().copied())
+            .filter(|s| seen.insert(*s))
+            .collect()
+    }
+
+    /// Union of the endpoints allowed for all apps.
+    pub fn allowed_endpoints(&self) -> Vec<String> {
+        let mut seen = HashSet::new();
+        self.apps
+            .iter()
+            .flat_map(|a| a.allowed_endpoints())
+            .filter(|e| seen.insert(e.clone()))
+            .collect()
+    }
+}
+
+/// Parse the `endpoints` list of an app's `allowed_endpoints.yaml`.
+pub fn parse_allowed_endpoints(yaml: &str) -> Vec<String> {
+    serde_yaml::from_str::<serde_yaml::Value>(yaml)
+        .ok()
+        .and_then(|v| {
+            v.get("endpoints").and_then(|e| e.as_sequence()).map(|s| {
+                s.iter()
+                    .filter_map(|e| e.as_str().map(str::to_string))
+                    .collect()
+            })
+        })
+        .unwrap_or_default()
+}
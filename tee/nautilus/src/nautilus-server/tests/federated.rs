@@ -0,0 +1,279 @@
+// Copyright (c), Mysten Labs, Inc.
+// SPDX-License-Identifier: Apache-2.0
+
+//! One federated training job end to end: a coordinator and two workers run
+//! as local processes of the debug server, authenticating each other with
+//! local attestation documents. The full node and Walrus are local stand-ins.
+
+#![cfg(feature = "mltraining")]
+
+use axum::body::Bytes;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post, put};
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::num::NonZeroU16;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use walrus_core::encoding::{EncodingConfig, EncodingConfigTrait as _};
+use walrus_core::EncodingType;
+
+/// Walrus stand-in: blobs by id.
+type Blobs = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+const JOB_ID: u64 = 7;
+const POOL_ID: u64 = 3;
+
+/// G2 generator, a valid key server public key.
+const PUBLIC_KEY: &str = "93e02b6052719f607dacd3a088274f65596bd0d09920b61ab5da61bbdc7f5049\
+    334cf11213945d57e5ac7d055d042b7e024aa2b2f08f0a91260805272dc51051c6e47ad4fa403b02b4510b\
+    647ae3d1770bac0326a805bbefd48056c8c121bdb8";
+
+fn blob_id(bytes: &[u8]) -> String {
+    let config = EncodingConfig::new(NonZeroU16::new(1000).unwrap());
+    let metadata = config
+        .get_for_type(EncodingType::RS2)
+        .compute_metadata(bytes)
+        .unwrap();
+    metadata.blob_id().to_string()
+}
+
+async fn serve(router: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    url
+}
+
+async fn walrus(blobs: Blobs) -> String {
+    async fn read(
+        State(blobs): State<Blobs>,
+        Path(id): Path<String>,
+    ) -> Result<Vec<u8>, StatusCode> {
+        blobs
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or(StatusCode::NOT_FOUND)
+    }
+    async fn store(State(blobs): State<Blobs>, body: Bytes) -> Json<Value> {
+        let id = blob_id(&body);
+        blobs.lock().unwrap().insert(id.clone(), body.to_vec());
+        Json(json!({"newlyCreated": {"blobObject": {"blobId": id}}}))
+    }
+    let router = Router::new()
+        .route("/v1/store", put(store))
+        .route("/v1/:blob_id", get(read))
+        .with_state(blobs);
+    serve(router).await
+}
+
+/// Full node stand-in answering `suix_getDynamicFieldObject` from `tables`,
+/// entry values by table id and key.
+async fn full_node(tables: HashMap<(String, String), Value>) -> String {
+    let tables = Arc::new(tables);
+    let rpc = move |Json(request): Json<Value>| {
+        let tables = tables.clone();
+        async move {
+            let params = &request["params"];
+            let key = (
+                params[0].as_str().unwrap_or_default().to_string(),
+                params[1]["value"].as_str().unwrap_or_default().to_string(),
+            );
+            let result = match tables.get(&key) {
+                Some(value) => json!({"data": {"content": {"fields": {"value": value}}}}),
+                None => json!({"error": {"code": "dynamicFieldNotFound"}}),
+            };
+            Json(json!({"jsonrpc": "2.0", "id": request["id"], "result": result}))
+        }
+    };
+    serve(Router::new().route("/", post(rpc))).await
+}
+
+fn bytes(text: &str) -> Value {
+    json!(text.as_bytes())
+}
+
+/// Server processes, killed on drop.
+struct Servers(Vec<Child>);
+
+impl Drop for Servers {
+    fn drop(&mut self) {
+        for child in &mut self.0 {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+fn free_port() -> u16 {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+async fn wait_ready(client: &reqwest::Client, url: &str) {
+    for _ in 0..300 {
+        if client.get(url).send().await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("{url} did not start");
+}
+
+#[tokio::test]
+async fn test_federated_job() {
+    let dir: PathBuf = std::env::temp_dir().join(format!("federated-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // 1. Two contributors' blobs of distinct rows and the model config
+    let blobs: Blobs = Arc::default();
+    let store = |bytes: Vec<u8>| {
+        let id = blob_id(&bytes);
+        blobs.lock().unwrap().insert(id.clone(), bytes);
+        id
+    };
+    let data_blob_ids: Vec<String> = (0..2)
+        .map(|k| {
+            let rows: Vec<Value> = (0..40)
+                .map(|i| {
+                    let x = (k * 40 + i) as f32 / 10.0;
+                    json!([[x, (x * 1.7).sin()], (i % 2)])
+                })
+                .collect();
+            store(serde_json::to_vec(&rows).unwrap())
+        })
+        .collect();
+    let model_config_blob_id = store(
+        serde_json::to_vec(&json!({
+            "input_size": 2,
+            "output_size": 2,
+            "layers": [{"neurons": 4, "activation": "relu"}],
+        }))
+        .unwrap(),
+    );
+    let walrus = walrus(blobs.clone()).await;
+
+    // 2. The job, its pool and the pool's data on chain
+    let tables = HashMap::from([
+        (
+            ("jobs".to_string(), JOB_ID.to_string()),
+            json!({"fields": {
+                "pool_id": POOL_ID.to_string(),
+                "model_wid": bytes(&model_config_blob_id),
+                "epochs": "2",
+                "learning_rate": "100",
+                "status": {"variant": "Pending"},
+                "buyer_public_key": [],
+            }}),
+        ),
+        (
+            ("pools".to_string(), POOL_ID.to_string()),
+            json!({"fields": {
+                "active": true,
+                "metadata": bytes(r#"{"policy": {"min_contributors": 2, "min_samples_per_job": 10}}"#),
+            }}),
+        ),
+        (
+            ("pool_data".to_string(), POOL_ID.to_string()),
+            json!(data_blob_ids.iter().map(|id| bytes(id)).collect::<Vec<_>>()),
+        ),
+        (
+            ("pool_users".to_string(), POOL_ID.to_string()),
+            json!(["0x1", "0x2"]),
+        ),
+    ]);
+    let rpc_url = full_node(tables).await;
+    let sui_config = dir.join("sui_config.yaml");
+    std::fs::write(
+        &sui_config,
+        format!(
+            "rpc_url: \"{rpc_url}\"\njobs_table_id: jobs\npools_table_id: pools\n\
+             pool_users_table_id: pool_users\npool_data_table_id: pool_data\n"
+        ),
+    )
+    .unwrap();
+    let seal_config = dir.join("seal_config.yaml");
+    std::fs::write(
+        &seal_config,
+        format!(
+            "package_id: \"0x{:064x}\"\nthreshold: 1\nkey_servers:\n  - object_id: \"0x{:064x}\"\n    \
+             url: \"http://127.0.0.1:1\"\n    public_key: \"{PUBLIC_KEY}\"\n\
+             job_registry: {{object_id: \"0x{:064x}\", initial_shared_version: 1}}\n\
+             pool_registry: {{object_id: \"0x{:064x}\", initial_shared_version: 1}}\n",
+            0xc0, 1, 0xa0, 0xa1
+        ),
+    )
+    .unwrap();
+
+    // 3. Coordinator and workers
+    let ports: Vec<u16> = (0..3).map(|_| free_port()).collect();
+    let mut servers = Servers(vec![]);
+    for (i, port) in ports.iter().enumerate() {
+        let child = Command::new(env!("CARGO_BIN_EXE_nautilus-server"))
+            .env("PORT", port.to_string())
+            .env("LOCAL_ATTESTATION", "1")
+            .env("SUI_CONFIG", &sui_config)
+            .env("SEAL_CONFIG", &seal_config)
+            .env("WALRUS_AGGREGATOR", &walrus)
+            .env("WALRUS_PUBLISHER", &walrus)
+            .env("MODEL_SEALING_KEY", "11".repeat(32))
+            .env("POOL_DATA_KEY", "22".repeat(32))
+            .env("USAGE_DIR", dir.join(format!("usage-{i}")))
+            .env("BLOB_CACHE_DIR", dir.join(format!("cache-{i}")))
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        servers.0.push(child);
+    }
+    let urls: Vec<String> = ports
+        .iter()
+        .map(|port| format!("http://127.0.0.1:{port}"))
+        .collect();
+    let client = reqwest::Client::new();
+    for url in &urls {
+        wait_ready(&client, url).await;
+    }
+
+    // 4. One job over both shards, two rounds
+    let response = client
+        .post(format!("{}/mltraining/federated/train", urls[0]))
+        .json(&json!({"payload": {
+            "job_id": JOB_ID,
+            "data_blob_ids": data_blob_ids,
+            "model_config_blob_id": model_config_blob_id,
+            "key_id": "",
+            "learning_rate": 100,
+            "epochs": 2,
+            "seed": 5,
+            "workers": urls[1..],
+        }}))
+        .send()
+        .await
+        .unwrap();
+    let status = response.status();
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(status, reqwest::StatusCode::OK, "{body}");
+
+    let result = &body["response"]["data"];
+    assert_eq!(result["job_id"], JOB_ID);
+    assert_eq!(result["rounds"], 2);
+    assert_eq!(result["num_samples"], 80);
+    let workers = result["workers"].as_array().unwrap();
+    assert_eq!(workers.len(), 2);
+    for (worker, id) in workers.iter().zip(&data_blob_ids) {
+        assert_eq!(worker["data_blob_ids"], json!([id]));
+        assert_eq!(worker["training_rows"], 40);
+    }
+    // The model went to the publisher, in the clear as not sealed
+    let model = blobs.lock().unwrap()[result["model_blob_id"].as_str().unwrap()].clone();
+    assert!(model.starts_with(b"CLKX"));
+
+    drop(servers);
+    let _ = std::fs::remove_dir_all(&dir);
+}